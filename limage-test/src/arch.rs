@@ -0,0 +1,64 @@
+// Port I/O and halting, which only x86_64 guests have. Other targets get stand-ins, so the
+// constants stay usable from the host and from kernels for other architectures
+
+#[cfg(target_arch = "x86_64")]
+mod imp {
+    use core::arch::asm;
+
+    // SAFETY (all four): the caller picks a port whose device tolerates the access
+    pub unsafe fn outb(port: u16, value: u8) {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub unsafe fn outw(port: u16, value: u16) {
+        asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub unsafe fn outl(port: u16, value: u32) {
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub unsafe fn inb(port: u16) -> u8 {
+        let value: u8;
+        asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    pub fn halt() {
+        // SAFETY: hlt only waits for the next interrupt
+        unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)) }
+    }
+
+    #[inline(always)]
+    pub fn frame_pointer() -> usize {
+        let rbp: usize;
+        // SAFETY: only reads rbp
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        rbp
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+    pub unsafe fn outb(_port: u16, _value: u8) {}
+
+    pub unsafe fn outw(_port: u16, _value: u16) {}
+
+    pub unsafe fn outl(_port: u16, _value: u32) {}
+
+    // Reads as an idle device with room to send, so writers never wait
+    pub unsafe fn inb(_port: u16) -> u8 {
+        u8::MAX
+    }
+
+    pub fn halt() {
+        core::hint::spin_loop();
+    }
+
+    // No frames to walk
+    pub fn frame_pointer() -> usize {
+        0
+    }
+}
+
+pub use imp::*;
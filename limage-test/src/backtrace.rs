@@ -0,0 +1,42 @@
+use crate::{arch, serial_println};
+
+// Deep enough for any panic, and a bound on walking a corrupted chain
+const MAX_FRAMES: usize = 64;
+
+// Prints the call sites on the stack, innermost first, for limage to resolve against the kernel's
+// symbols once the panic pattern has matched. Needs a kernel built with
+// `-C force-frame-pointers=yes`; without frame pointers the chain ends early or is garbage
+pub fn print_backtrace() {
+    serial_println!("backtrace:");
+    let mut number = 0;
+    walk(|address| {
+        serial_println!("  #{} {:#018x}", number, address);
+        number += 1;
+    });
+}
+
+fn walk(mut frame: impl FnMut(usize)) {
+    let mut fp = arch::frame_pointer();
+    for _ in 0..MAX_FRAMES {
+        if fp == 0 || !fp.is_multiple_of(core::mem::align_of::<usize>()) {
+            break;
+        }
+        // SAFETY: with frame pointers, every frame starts with the caller's frame pointer followed
+        // by the return address. The checks around this stop at the end of the chain, not at a
+        // corrupted one
+        let (next, ret) = unsafe {
+            let fp = fp as *const usize;
+            (*fp, *fp.add(1))
+        };
+        if ret == 0 {
+            break;
+        }
+        // The call instruction rather than the one after it, so it resolves to the calling line
+        frame(ret - 1);
+        // Callers' frames are further up the stack
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
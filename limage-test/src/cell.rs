@@ -0,0 +1,37 @@
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// A &'static str that can be set from anywhere, since a no_std kernel has no lock to share
+pub struct StrCell {
+    ptr: AtomicPtr<u8>,
+    len: AtomicUsize,
+}
+
+impl StrCell {
+    pub const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(core::ptr::null_mut()),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set(&self, value: &'static str) {
+        self.clear();
+        self.len.store(value.len(), Ordering::SeqCst);
+        self.ptr.store(value.as_ptr().cast_mut(), Ordering::SeqCst);
+    }
+
+    pub fn clear(&self) {
+        self.ptr.store(core::ptr::null_mut(), Ordering::SeqCst);
+    }
+
+    pub fn get(&self) -> Option<&'static str> {
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        if ptr.is_null() {
+            return None;
+        }
+        // SAFETY: `set` stores the length before the pointer of a &'static str, and clears the
+        // pointer before changing the length
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, self.len.load(Ordering::SeqCst)) };
+        core::str::from_utf8(bytes).ok()
+    }
+}
@@ -0,0 +1,47 @@
+use crate::cell::StrCell;
+use core::time::Duration;
+
+// `limage test --filter PATTERN` adds `limage.test.filter=PATTERN` to the kernel command line
+pub const FILTER_ARG: &str = "limage.test.filter";
+// `[test] case_timeout_secs` adds `limage.test.timeout_ms=MILLISECONDS`
+pub const TIMEOUT_ARG: &str = "limage.test.timeout_ms";
+// `[test.exit_device]` and `test.success_exit_code`, which limage adds to every test image, so
+// `exit_qemu` writes what limage's configuration expects
+pub const EXIT_PORT_ARG: &str = "limage.test.exit_port";
+pub const EXIT_PORT_SIZE_ARG: &str = "limage.test.exit_port_size";
+pub const SUCCESS_VALUE_ARG: &str = "limage.test.success_value";
+pub const FAILURE_VALUE_ARG: &str = "limage.test.failure_value";
+
+static CMDLINE: StrCell = StrCell::new();
+
+// Hands the harness the kernel command line, e.g. from Limine's executable command line request,
+// before `test_main` runs
+pub fn set_cmdline(cmdline: &'static str) {
+    CMDLINE.set(cmdline);
+}
+
+// Tests whose path does not contain the filter are skipped
+pub fn test_filter() -> Option<&'static str> {
+    arg(FILTER_ARG)
+}
+
+// How long each test may run before the timer interrupt ends it
+pub fn test_timeout() -> Option<Duration> {
+    arg(TIMEOUT_ARG)?.parse().ok().map(Duration::from_millis)
+}
+
+// A decimal or `0x` hexadecimal argument
+pub(crate) fn number(name: &str) -> Option<u32> {
+    let value = arg(name)?;
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn arg(name: &str) -> Option<&'static str> {
+    CMDLINE
+        .get()?
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+}
@@ -0,0 +1,53 @@
+use crate::{arch, cmdline};
+
+// The isa-debug-exit device limage attaches in test mode, the defaults of `[test.exit_device]`
+pub const EXIT_PORT: u16 = 0xf4;
+pub const EXIT_PORT_SIZE: u16 = 0x04;
+
+// Values the guest writes to the exit device, unless the command line says otherwise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+impl QemuExitCode {
+    // The status QEMU exits with once the guest wrote the default value, `(code << 1) | 1`
+    pub const fn status(self) -> i32 {
+        ((self as u32) << 1 | 1) as i32
+    }
+
+    // What `exit_qemu` writes: the value limage put on the command line for this code, or the
+    // default
+    pub fn value(self) -> u32 {
+        let arg = match self {
+            QemuExitCode::Success => cmdline::SUCCESS_VALUE_ARG,
+            QemuExitCode::Failed => cmdline::FAILURE_VALUE_ARG,
+        };
+        cmdline::number(arg).unwrap_or(self as u32)
+    }
+}
+
+// The default of limage's `test.success_exit_code`
+pub const SUCCESS_EXIT_STATUS: i32 = QemuExitCode::Success.status();
+
+// Ends the VM with `code`, through the exit device the command line names. Without the exit
+// device, e.g. under a plain `limage run`, the write does nothing and the guest halts instead
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    let port = cmdline::number(cmdline::EXIT_PORT_ARG)
+        .and_then(|port| u16::try_from(port).ok())
+        .unwrap_or(EXIT_PORT);
+    let value = code.value();
+    // SAFETY: isa-debug-exit only reacts to the write; without it the port is unused
+    unsafe {
+        match cmdline::number(cmdline::EXIT_PORT_SIZE_ARG).unwrap_or(EXIT_PORT_SIZE.into()) {
+            1 => arch::outb(port, value as u8),
+            2 => arch::outw(port, value as u16),
+            _ => arch::outl(port, value),
+        }
+    }
+    loop {
+        arch::halt();
+    }
+}
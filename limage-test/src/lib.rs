@@ -0,0 +1,30 @@
+#![no_std]
+
+// What a Limine kernel needs to run its `#[test_case]`s under `limage test`: output on the serial
+// port limage captures, a QEMU exit code limage's `[test]` defaults read as a pass or a failure,
+// and the test runner and panic handler of a custom_test_frameworks harness. The runner reports
+// every test as a `PROTOCOL_PREFIX` line limage parses into per-test results, and ends a test that
+// outlives its timeout once the kernel feeds it timer ticks. Only x86_64 guests do I/O; elsewhere
+// the crate builds but prints nothing and cannot exit QEMU
+
+mod arch;
+mod backtrace;
+mod cell;
+mod cmdline;
+mod exit;
+mod protocol;
+mod runner;
+mod serial;
+mod timeout;
+
+pub use backtrace::print_backtrace;
+pub use cmdline::{
+    set_cmdline, test_filter, test_timeout, EXIT_PORT_ARG, EXIT_PORT_SIZE_ARG, FAILURE_VALUE_ARG,
+    FILTER_ARG, SUCCESS_VALUE_ARG, TIMEOUT_ARG,
+};
+pub use exit::{exit_qemu, QemuExitCode, EXIT_PORT, EXIT_PORT_SIZE, SUCCESS_EXIT_STATUS};
+pub use protocol::PROTOCOL_PREFIX;
+pub use runner::{expect_panic, test_panic_handler, test_runner, Testable};
+#[doc(hidden)]
+pub use serial::_print;
+pub use timeout::{set_test_timeout, timer_tick};
@@ -0,0 +1,143 @@
+use crate::{cell::StrCell, serial, serial_println};
+use core::{
+    fmt::{self, Display, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+// Starts every line limage reads a test event from; the rest of the line is one JSON object
+pub const PROTOCOL_PREFIX: &str = "##limage-test## ";
+
+static PLANNED: AtomicUsize = AtomicUsize::new(0);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static FILTERED_OUT: AtomicUsize = AtomicUsize::new(0);
+// The test running now, so the panic handler can name it
+static CURRENT: StrCell = StrCell::new();
+
+// `count` tests will run, `filtered_out` others were skipped
+pub fn plan(count: usize, filtered_out: usize) {
+    PLANNED.store(count, Ordering::SeqCst);
+    FILTERED_OUT.store(filtered_out, Ordering::SeqCst);
+    serial_println!(
+        "{}{{\"event\":\"plan\",\"count\":{},\"filtered_out\":{}}}",
+        PROTOCOL_PREFIX,
+        count,
+        filtered_out
+    );
+}
+
+pub fn started(name: &'static str) {
+    CURRENT.set(name);
+    serial_println!(
+        "{}{{\"event\":\"started\",\"name\":{}}}",
+        PROTOCOL_PREFIX,
+        JsonStr(name)
+    );
+}
+
+pub fn passed(name: &'static str) {
+    CURRENT.clear();
+    PASSED.fetch_add(1, Ordering::SeqCst);
+    serial_println!(
+        "{}{{\"event\":\"passed\",\"name\":{}}}",
+        PROTOCOL_PREFIX,
+        JsonStr(name)
+    );
+}
+
+pub fn current() -> Option<&'static str> {
+    CURRENT.get()
+}
+
+// Reports the running test as failed; a panic outside a test only shows in the summary's count
+pub fn failed(info: &PanicInfo) {
+    let Some(name) = CURRENT.get() else {
+        return;
+    };
+    CURRENT.clear();
+    FAILED.fetch_add(1, Ordering::SeqCst);
+    serial::start_line();
+    serial_println!(
+        "{}{{\"event\":\"failed\",\"name\":{},\"message\":{},\"location\":{}}}",
+        PROTOCOL_PREFIX,
+        JsonStr(name),
+        JsonStr(info.message()),
+        Nullable(info.location().map(JsonStr))
+    );
+}
+
+// Reports the running test as out of time and returns its name. A timed-out test counts as
+// failed in the summary
+pub fn timed_out(timeout: Duration) -> Option<&'static str> {
+    let name = CURRENT.get()?;
+    CURRENT.clear();
+    FAILED.fetch_add(1, Ordering::SeqCst);
+    serial::start_line();
+    serial_println!(
+        "{}{{\"event\":\"timed_out\",\"name\":{},\"timeout_ms\":{}}}",
+        PROTOCOL_PREFIX,
+        JsonStr(name),
+        timeout.as_millis()
+    );
+    Some(name)
+}
+
+// Tests after a failure never ran, since the panic or the timeout ends the run
+pub fn summary() {
+    let passed = PASSED.load(Ordering::SeqCst);
+    let failed = FAILED.load(Ordering::SeqCst);
+    let not_run = PLANNED
+        .load(Ordering::SeqCst)
+        .saturating_sub(passed + failed);
+    serial_println!(
+        "{}{{\"event\":\"summary\",\"passed\":{},\"failed\":{},\"not_run\":{},\"filtered_out\":{}}}",
+        PROTOCOL_PREFIX,
+        passed,
+        failed,
+        not_run,
+        FILTERED_OUT.load(Ordering::SeqCst)
+    );
+}
+
+// A JSON string holding whatever `T` displays as
+struct JsonStr<T>(T);
+
+impl<T: Display> Display for JsonStr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        write!(Escaper(f), "{}", self.0)?;
+        f.write_char('"')
+    }
+}
+
+struct Nullable<T>(Option<T>);
+
+impl<T: Display> Display for Nullable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("null"),
+        }
+    }
+}
+
+struct Escaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl Write for Escaper<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if u32::from(c) < 0x20 => write!(self.0, "\\u{:04x}", u32::from(c))?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{backtrace, cmdline, exit_qemu, protocol, serial_println, timeout, QemuExitCode};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+// The tests `test_runner` was given and the index of the next one to run, so the panic handler
+// can carry on with the rest after an expected panic
+static TESTS: AtomicPtr<&'static dyn Testable> = AtomicPtr::new(core::ptr::null_mut());
+static TEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+// Set by `expect_panic` for the running test
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+// A `#[test_case]`, reported by its path
+pub trait Testable {
+    fn name(&self) -> &'static str;
+
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        EXPECT_PANIC.store(false, Ordering::SeqCst);
+        protocol::started(self.name());
+        timeout::start();
+        self();
+        timeout::stop();
+        if EXPECT_PANIC.swap(false, Ordering::SeqCst) {
+            panic!("test did not panic as expected");
+        }
+        protocol::passed(self.name());
+    }
+}
+
+// Called first thing in a test that passes by panicking, e.g. one checking that an assertion or a
+// page fault ends in the panic handler. The panic handler then reports the test as passed and runs
+// the remaining tests
+pub fn expect_panic() {
+    EXPECT_PANIC.store(true, Ordering::SeqCst);
+}
+
+// `#![test_runner(limage_test::test_runner)]`: runs every test the command line's filter selects,
+// then exits QEMU with success. The first failing test ends the run through the panic handler
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let filter = cmdline::test_filter();
+    let count = tests.iter().filter(|test| selected(**test, filter)).count();
+    protocol::plan(count, tests.len() - count);
+    TEST_COUNT.store(tests.len(), Ordering::SeqCst);
+    TESTS.store(
+        tests.as_ptr().cast::<&'static dyn Testable>().cast_mut(),
+        Ordering::SeqCst,
+    );
+    run_remaining();
+}
+
+// For the test build's `#[panic_handler]`. The events go out before the plain panic message,
+// which matches limage's default `test.panic_pattern` and makes it stop the VM, and the backtrace
+// limage symbolizes. An expected panic prints nothing, so the kernel's handler must not print it
+// either
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    if EXPECT_PANIC.swap(false, Ordering::SeqCst) {
+        if let Some(name) = protocol::current() {
+            timeout::stop();
+            protocol::passed(name);
+            // The panicking test's frames stay on the stack below the remaining tests, which is
+            // what keeps `TESTS` alive, since nothing unwinds
+            run_remaining();
+        }
+    }
+    protocol::failed(info);
+    protocol::summary();
+    serial_println!("{}", info);
+    backtrace::print_backtrace();
+    exit_qemu(QemuExitCode::Failed);
+}
+
+fn run_remaining() -> ! {
+    let filter = cmdline::test_filter();
+    loop {
+        let index = NEXT.fetch_add(1, Ordering::SeqCst);
+        if index >= TEST_COUNT.load(Ordering::SeqCst) {
+            break;
+        }
+        // SAFETY: `test_runner` stored the slice and its length, and the slice outlives every
+        // call, since `test_runner` never returns
+        let test = unsafe { *TESTS.load(Ordering::SeqCst).add(index) };
+        if selected(test, filter) {
+            test.run();
+        }
+    }
+    protocol::summary();
+    exit_qemu(QemuExitCode::Success);
+}
+
+fn selected(test: &dyn Testable, filter: Option<&str>) -> bool {
+    filter.is_none_or(|filter| test.name().contains(filter))
+}
@@ -0,0 +1,78 @@
+use crate::arch;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// COM1, which limage reads the test output and the panic pattern from
+const COM1: u16 = 0x3f8;
+const LINE_STATUS: u16 = COM1 + 5;
+const TRANSMIT_EMPTY: u8 = 0x20;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+// Whether the last byte written ended a line
+static AT_LINE_START: AtomicBool = AtomicBool::new(true);
+
+// Writes straight to the port, so there is no lock a panic while printing could leave held
+struct Com1;
+
+impl Write for Com1 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+// 38400 baud, 8N1, FIFOs on, interrupts off
+fn init() {
+    // SAFETY: programs the 16550 UART at COM1 that QEMU's `-serial` attaches
+    unsafe {
+        arch::outb(COM1 + 1, 0x00);
+        arch::outb(COM1 + 3, 0x80);
+        arch::outb(COM1, 0x03);
+        arch::outb(COM1 + 1, 0x00);
+        arch::outb(COM1 + 3, 0x03);
+        arch::outb(COM1 + 2, 0xc7);
+        arch::outb(COM1 + 4, 0x0b);
+    }
+}
+
+fn write_byte(byte: u8) {
+    // SAFETY: polls and writes the UART initialized above
+    unsafe {
+        while arch::inb(LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        arch::outb(COM1, byte);
+    }
+    AT_LINE_START.store(byte == b'\n', Ordering::Relaxed);
+}
+
+// Ends a line a test was printing when a panic or the timer interrupted it, so the event that
+// follows starts a line of its own
+pub fn start_line() {
+    if !AT_LINE_START.load(Ordering::Relaxed) {
+        _print(format_args!("\n"));
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if !INITIALIZED.swap(true, Ordering::AcqRel) {
+        init();
+    }
+    let _ = Com1.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,58 @@
+use crate::{cmdline, exit_qemu, protocol, serial_println, QemuExitCode};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// How far the kernel's timer has advanced, in nanoseconds
+static NOW: AtomicU64 = AtomicU64::new(0);
+// When the running test runs out of time on `NOW`'s clock, u64::MAX while it has no deadline
+static DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+// The timeout `DEADLINE` was set from, for the report
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+// For the kernel's timer interrupt handler, with the time between two interrupts. A test still
+// running past its deadline is reported as timed out and ends the run with a failure, leaving the
+// rest of the host's `test.timeout_secs` unused. Without these calls tests never time out
+pub fn timer_tick(period: Duration) {
+    let now = NOW
+        .fetch_add(nanos(period), Ordering::SeqCst)
+        .saturating_add(nanos(period));
+    if now < DEADLINE.load(Ordering::SeqCst) {
+        return;
+    }
+    DEADLINE.store(u64::MAX, Ordering::SeqCst);
+    let timeout = Duration::from_nanos(TIMEOUT.load(Ordering::SeqCst));
+    let Some(name) = protocol::timed_out(timeout) else {
+        return;
+    };
+    protocol::summary();
+    serial_println!("[timeout] {} ran longer than {:?}", name, timeout);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+// Gives the running test `timeout` from now, in place of the command line's timeout, e.g. for a
+// test known to be slow
+pub fn set_test_timeout(timeout: Duration) {
+    TIMEOUT.store(nanos(timeout), Ordering::SeqCst);
+    DEADLINE.store(
+        NOW.load(Ordering::SeqCst).saturating_add(nanos(timeout)),
+        Ordering::SeqCst,
+    );
+}
+
+// Arms the command line's timeout for a test about to start
+pub(crate) fn start() {
+    match cmdline::test_timeout() {
+        Some(timeout) => set_test_timeout(timeout),
+        None => stop(),
+    }
+}
+
+pub(crate) fn stop() {
+    DEADLINE.store(u64::MAX, Ordering::SeqCst);
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
@@ -0,0 +1,71 @@
+use std::{fs::OpenOptions, process::Command};
+use tracing::{debug, info, warn};
+
+pub fn resolve(accel: &str, qemu_binary: &str) -> Option<String> {
+    if accel == "auto" {
+        detect(qemu_binary)
+    } else {
+        Some(accel.to_string())
+    }
+}
+
+pub fn detect(qemu_binary: &str) -> Option<String> {
+    let candidate = if cfg!(target_os = "linux") {
+        kvm_available().then_some("kvm")
+    } else if cfg!(target_os = "macos") {
+        hvf_available().then_some("hvf")
+    } else if cfg!(target_os = "windows") {
+        // WHPX has no cheap host-side probe; rely on QEMU reporting it below
+        Some("whpx")
+    } else {
+        None
+    };
+
+    let Some(candidate) = candidate else {
+        info!("No hardware acceleration available, falling back to TCG");
+        return None;
+    };
+
+    if qemu_supports_accel(qemu_binary, candidate) {
+        info!("Using {} acceleration", candidate);
+        Some(candidate.to_string())
+    } else {
+        warn!(
+            "{} is available on the host but not supported by {}, falling back to TCG",
+            candidate, qemu_binary
+        );
+        None
+    }
+}
+
+fn kvm_available() -> bool {
+    let available = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .is_ok();
+    debug!("/dev/kvm accessible: {}", available);
+    available
+}
+
+fn hvf_available() -> bool {
+    let available = Command::new("sysctl")
+        .args(["-n", "kern.hv_support"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim() == "1")
+        .unwrap_or(false);
+    debug!("Hypervisor.framework supported: {}", available);
+    available
+}
+
+fn qemu_supports_accel(qemu_binary: &str, accel: &str) -> bool {
+    match Command::new(qemu_binary).args(["-accel", "help"]).output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.trim() == accel),
+        Err(e) => {
+            debug!("Failed to query {} for accelerators: {}", qemu_binary, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+pub const LIMINE_REPOSITORY: &str = "https://github.com/limine-bootloader/limine.git";
+pub const LIMINE_BRANCH: &str = "v8.x-binary";
+// Where GitHub serves tarballs of the repository's branches and commits
+const LIMINE_ARCHIVES: &str = "https://github.com/limine-bootloader/limine/archive";
+pub const OVMF_RELEASES: &str = "https://github.com/osdev0/edk2-ovmf-nightly/releases";
+
+// The UEFI firmware limage boots x86_64 guests with, cached in one directory
+#[derive(Clone, Debug)]
+pub struct OvmfManager {
+    dir: PathBuf,
+    // Tag of the edk2-ovmf-nightly release to download; the latest when unset
+    release: Option<String>,
+}
+
+impl OvmfManager {
+    pub const FILES: [&'static str; 2] = ["ovmf-code-x86_64.fd", "ovmf-vars-x86_64.fd"];
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            release: None,
+        }
+    }
+
+    // Pins the download to a release, as `limage.lock` does
+    pub fn release(mut self, release: Option<String>) -> Self {
+        self.release = release;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // The pinned release; the cached files carry no version of their own
+    pub fn version(&self) -> Option<&str> {
+        self.release.as_deref()
+    }
+
+    pub fn code_path(&self) -> PathBuf {
+        self.dir.join(Self::FILES[0])
+    }
+
+    pub fn vars_path(&self) -> PathBuf {
+        self.dir.join(Self::FILES[1])
+    }
+
+    pub fn url(&self, file: &str) -> String {
+        match &self.release {
+            Some(release) => format!("{}/download/{}/{}", OVMF_RELEASES, release, file),
+            None => format!("{}/latest/download/{}", OVMF_RELEASES, file),
+        }
+    }
+
+    pub fn is_cached(&self) -> bool {
+        self.verify().is_ok()
+    }
+
+    // Fails on the first firmware file that is missing
+    pub fn verify(&self) -> Result<(), ArtifactError> {
+        for file in Self::FILES {
+            verify_file(&self.dir.join(file))?;
+        }
+        Ok(())
+    }
+
+    // The curl invocations that download the files not cached yet, with their URL and destination
+    pub fn download_commands(&self) -> Vec<(String, PathBuf, Command)> {
+        self.download_commands_where(Path::exists)
+    }
+
+    // Same, with `cached` deciding which files are already there
+    pub fn download_commands_where(
+        &self,
+        cached: impl Fn(&Path) -> bool,
+    ) -> Vec<(String, PathBuf, Command)> {
+        Self::FILES
+            .iter()
+            .map(|file| (self.url(file), self.dir.join(file)))
+            .filter(|(_, path)| {
+                let cached = cached(path);
+                if cached {
+                    debug!("Using cached OVMF file {:?}", path);
+                }
+                !cached
+            })
+            .map(|(url, path)| {
+                let mut command = Command::new("curl");
+                command
+                    .arg("-Lo")
+                    .arg(&path)
+                    .arg(&url)
+                    .stdout(Stdio::piped());
+                (url, path, command)
+            })
+            .collect()
+    }
+
+    // Downloads whatever is missing from the cache
+    #[instrument(skip(self), err)]
+    pub fn fetch(&self) -> Result<(), ArtifactError> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| ArtifactError::Io {
+            path: self.dir.clone(),
+            source: e,
+        })?;
+        for (url, path, mut command) in self.download_commands() {
+            debug!("Downloading OVMF file from {} to {:?}", url, path);
+            run("curl", &mut command)?;
+        }
+        self.verify()
+    }
+
+    // `latest` redirects to the tagged release download
+    pub fn latest_release() -> Result<String, ArtifactError> {
+        let url = format!("{}/latest/download/{}", OVMF_RELEASES, Self::FILES[0]);
+        let output = run(
+            "curl",
+            Command::new("curl").args([
+                "-sSfIL",
+                "-o",
+                "/dev/null",
+                "-w",
+                "%{url_effective}",
+                &url,
+            ]),
+        )
+        .map_err(|e| e.resolving("ovmf"))?;
+        let effective = String::from_utf8_lossy(&output.stdout).into_owned();
+        debug!("OVMF latest release resolved to {}", effective);
+        effective
+            .split("/releases/download/")
+            .nth(1)
+            .and_then(|rest| rest.split('/').next())
+            .map(str::to_string)
+            .ok_or(ArtifactError::Resolve {
+                artifact: "ovmf",
+                detail: format!("unexpected release URL {}", effective),
+            })
+    }
+}
+
+// A checkout of Limine's binary branch, holding the bootloader files an image is staged from
+#[derive(Clone, Debug)]
+pub struct LimineArtifacts {
+    dir: PathBuf,
+    // Commit to check out after cloning; the branch head when unset
+    commit: Option<String>,
+}
+
+impl LimineArtifacts {
+    pub const FILES: [&'static str; 5] = [
+        "limine-bios.sys",
+        "limine-bios-cd.bin",
+        "limine-uefi-cd.bin",
+        "BOOTX64.EFI",
+        "BOOTIA32.EFI",
+    ];
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            commit: None,
+        }
+    }
+
+    // Pins the checkout to a commit, as `limage.lock` does
+    pub fn commit(mut self, commit: Option<String>) -> Self {
+        self.commit = commit;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn pinned_commit(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    // The `limine` host utility, built from the checkout, that installs the BIOS boot code
+    pub fn installer(&self) -> PathBuf {
+        self.dir.join("limine")
+    }
+
+    // Writes the BIOS boot code into a finished image
+    pub fn install_command(&self, image: &Path) -> Command {
+        let mut command = Command::new(self.installer());
+        command
+            .arg("bios-install")
+            .arg(image)
+            .stdout(Stdio::piped());
+        command
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.verify().is_ok()
+    }
+
+    // Fails on the first bootloader file that is missing
+    pub fn verify(&self) -> Result<(), ArtifactError> {
+        for file in Self::FILES {
+            verify_file(&self.dir.join(file))?;
+        }
+        Ok(())
+    }
+
+    // The commit checked out, if the directory is a git checkout
+    pub fn version(&self) -> Result<Option<String>, ArtifactError> {
+        if !self.dir.join(".git").exists() {
+            return Ok(None);
+        }
+        let output = run(
+            "git",
+            Command::new("git")
+                .arg("-C")
+                .arg(&self.dir)
+                .args(["rev-parse", "HEAD"]),
+        )
+        .map_err(|e| e.resolving("limine"))?;
+        Ok(Some(
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ))
+    }
+
+    pub fn clone_command(&self) -> Command {
+        let mut clone = Command::new("git");
+        clone
+            .arg("clone")
+            .arg(LIMINE_REPOSITORY)
+            .arg(format!("--branch={}", LIMINE_BRANCH))
+            .arg("--depth=1")
+            .arg(&self.dir)
+            .stdout(Stdio::piped());
+        clone
+    }
+
+    // Moves the shallow clone to the pinned commit; nothing without a pin
+    pub fn checkout_commands(&self) -> Vec<Command> {
+        let Some(commit) = &self.commit else {
+            return Vec::new();
+        };
+        [
+            vec!["fetch", "--depth=1", "origin", commit],
+            vec!["checkout", "--quiet", commit],
+        ]
+        .into_iter()
+        .map(|args| {
+            let mut command = Command::new("git");
+            command.arg("-C").arg(&self.dir).args(args);
+            command
+        })
+        .collect()
+    }
+
+    // A tarball of the pinned commit, or of the branch head, for fetching without git
+    pub fn tarball_url(&self) -> String {
+        match &self.commit {
+            Some(commit) => format!("{}/{}.tar.gz", LIMINE_ARCHIVES, commit),
+            None => format!("{}/refs/heads/{}.tar.gz", LIMINE_ARCHIVES, LIMINE_BRANCH),
+        }
+    }
+
+    pub fn make_command(&self) -> Command {
+        let mut make = Command::new("make");
+        make.arg("-C").arg(&self.dir);
+        make
+    }
+
+    // Clones and builds Limine unless every file is already there, replacing an incomplete checkout
+    #[instrument(skip(self), err)]
+    pub fn fetch(&self) -> Result<(), ArtifactError> {
+        if self.is_complete() {
+            return Ok(());
+        }
+        if self.dir.exists() {
+            info!("Removing incomplete Limine checkout in {:?}", self.dir);
+            std::fs::remove_dir_all(&self.dir).map_err(|e| ArtifactError::Io {
+                path: self.dir.clone(),
+                source: e,
+            })?;
+        }
+        info!("Cloning Limine repository to {:?}", self.dir);
+        run("git", &mut self.clone_command())?;
+        for mut checkout in self.checkout_commands() {
+            run("git", &mut checkout)?;
+        }
+        run("make", &mut self.make_command())?;
+        self.verify()
+    }
+
+    pub fn latest_commit() -> Result<String, ArtifactError> {
+        let output = run(
+            "git",
+            Command::new("git").args([
+                "ls-remote",
+                LIMINE_REPOSITORY,
+                &format!("refs/heads/{}", LIMINE_BRANCH),
+            ]),
+        )
+        .map_err(|e| e.resolving("limine"))?;
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .next()
+            .map(str::to_string)
+            .ok_or_else(|| ArtifactError::Resolve {
+                artifact: "limine",
+                detail: format!("branch {} not found", LIMINE_BRANCH),
+            })
+    }
+}
+
+fn verify_file(path: &Path) -> Result<(), ArtifactError> {
+    if !path.is_file() {
+        return Err(ArtifactError::Missing {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+fn run(tool: &'static str, command: &mut Command) -> Result<Output, ArtifactError> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| ArtifactError::Spawn { tool, source: e })?;
+    if !output.status.success() {
+        return Err(ArtifactError::Failed {
+            tool,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output)
+}
+
+#[derive(Debug, Error)]
+pub enum ArtifactError {
+    #[error("Failed to run {tool}: {source}")]
+    Spawn {
+        tool: &'static str,
+        source: std::io::Error,
+    },
+
+    #[error("{tool} failed: {stderr}")]
+    Failed { tool: &'static str, stderr: String },
+
+    #[error("Failed to determine the latest {artifact} version: {detail}")]
+    Resolve {
+        artifact: &'static str,
+        detail: String,
+    },
+
+    #[error("{path:?} is missing")]
+    Missing { path: PathBuf },
+
+    #[error("Failed to prepare {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl ArtifactError {
+    // The stable code `limage::Error` reports; a new variant takes the next free code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ArtifactError::Spawn { .. } => "E070",
+            ArtifactError::Failed { .. } => "E071",
+            ArtifactError::Resolve { .. } => "E072",
+            ArtifactError::Missing { .. } => "E073",
+            ArtifactError::Io { .. } => "E074",
+        }
+    }
+}
+
+impl ArtifactError {
+    // A failed lookup of the upstream version reports which artifact it was for
+    fn resolving(self, artifact: &'static str) -> Self {
+        match self {
+            ArtifactError::Failed { stderr, .. } => ArtifactError::Resolve {
+                artifact,
+                detail: stderr,
+            },
+            error => error,
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use crate::config::{BackendKind, ConfigError, Firmware, LimageConfig};
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+// A hypervisor that can boot the image produced by the Builder
+pub trait VmBackend {
+    fn name(&self) -> &'static str;
+
+    // One-time setup before the VM is started, e.g. writing config files or registering a VM
+    fn prepare(&self, _image: &Path) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    // The blocking host process whose lifetime matches the VM
+    fn command(
+        &self,
+        image: &Path,
+        is_test: bool,
+        mode: Option<&str>,
+    ) -> Result<Command, BackendError>;
+
+    fn stop(&self, child: &mut Child) -> Result<(), BackendError> {
+        child.kill().map_err(|e| BackendError::Stop { source: e })?;
+        child.wait().map_err(|e| BackendError::Stop { source: e })?;
+        Ok(())
+    }
+}
+
+pub fn for_config(config: &LimageConfig) -> Box<dyn VmBackend> {
+    match config.runner.backend {
+        BackendKind::Qemu => Box::new(Qemu {
+            config: config.clone(),
+        }),
+        BackendKind::Bochs => Box::new(Bochs {
+            config: config.clone(),
+        }),
+        BackendKind::VirtualBox => Box::new(VirtualBox {
+            config: config.clone(),
+        }),
+    }
+}
+
+pub struct Qemu {
+    config: LimageConfig,
+}
+
+impl VmBackend for Qemu {
+    fn name(&self) -> &'static str {
+        "qemu"
+    }
+
+    fn command(
+        &self,
+        image: &Path,
+        is_test: bool,
+        mode: Option<&str>,
+    ) -> Result<Command, BackendError> {
+        let cmd_args = self.config.get_qemu_command(image, is_test, mode)?;
+        let mut command = Command::new(&cmd_args[0]);
+        command.args(&cmd_args[1..]);
+
+        if let Some(mode) = mode {
+            command.envs(self.config.get_mode(mode)?.env);
+        }
+        Ok(command)
+    }
+}
+
+pub struct Bochs {
+    config: LimageConfig,
+}
+
+impl Bochs {
+    fn bochsrc_path(&self) -> PathBuf {
+        self.config.work_dir().join("bochsrc")
+    }
+
+    fn bochsrc(&self, image: &Path) -> String {
+        let bochs = &self.config.runner.bochs;
+        let mut lines = vec![
+            format!("megs: {}", bochs.megs),
+            format!(
+                "ata0-master: type=cdrom, path=\"{}\", status=inserted",
+                image.display()
+            ),
+            "boot: cdrom".to_string(),
+            "port_e9_hack: enabled=1".to_string(),
+            "magic_break: enabled=1".to_string(),
+            format!(
+                "com1: enabled=1, mode=file, dev=\"{}\"",
+                self.config.work_dir().join("bochs-serial.log").display()
+            ),
+        ];
+        if let Some(display_library) = &bochs.display_library {
+            lines.push(format!("display_library: {}", display_library));
+        }
+        lines.extend(bochs.extra_config.iter().cloned());
+        lines.join("\n") + "\n"
+    }
+}
+
+impl VmBackend for Bochs {
+    fn name(&self) -> &'static str {
+        "bochs"
+    }
+
+    #[instrument(skip(self), err)]
+    fn prepare(&self, image: &Path) -> Result<(), BackendError> {
+        let path = self.bochsrc_path();
+        debug!("Writing Bochs configuration to {:?}", path);
+        std::fs::write(&path, self.bochsrc(image))
+            .map_err(|e| BackendError::WriteConfig { source: e })
+    }
+
+    fn command(
+        &self,
+        _image: &Path,
+        _is_test: bool,
+        mode: Option<&str>,
+    ) -> Result<Command, BackendError> {
+        if let Some(mode) = mode {
+            return Err(BackendError::ModesUnsupported {
+                backend: self.name(),
+                mode: mode.to_string(),
+            });
+        }
+
+        let mut command = Command::new(&self.config.runner.bochs.binary);
+        command.arg("-q").arg("-f").arg(self.bochsrc_path());
+        Ok(command)
+    }
+}
+
+pub struct VirtualBox {
+    config: LimageConfig,
+}
+
+impl VirtualBox {
+    fn manage(&self, args: &[&str]) -> Result<String, BackendError> {
+        debug!("VBoxManage {}", args.join(" "));
+        let output = Command::new("VBoxManage")
+            .args(args)
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(|e| BackendError::Spawn {
+                tool: "VBoxManage",
+                source: e,
+            })?;
+
+        if !output.status.success() {
+            return Err(BackendError::VBoxManage {
+                command: args.join(" "),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl VmBackend for VirtualBox {
+    fn name(&self) -> &'static str {
+        "virtualbox"
+    }
+
+    #[instrument(skip(self), err)]
+    fn prepare(&self, image: &Path) -> Result<(), BackendError> {
+        let vbox = &self.config.runner.virtualbox;
+        let vm = vbox.vm_name.as_str();
+
+        let registered = self.manage(&["list", "vms"])?;
+        if !registered.contains(&format!("\"{}\"", vm)) {
+            info!("Registering VirtualBox VM '{}'", vm);
+            self.manage(&[
+                "createvm",
+                "--name",
+                vm,
+                "--ostype",
+                "Other_64",
+                "--register",
+            ])?;
+            self.manage(&["storagectl", vm, "--name", "IDE", "--add", "ide"])?;
+        }
+
+        let firmware = match self.config.qemu.firmware {
+            Firmware::Uefi => "efi",
+            Firmware::Bios => "bios",
+        };
+        let serial_log = self.config.work_dir().join("virtualbox-serial.log");
+        self.manage(&[
+            "modifyvm",
+            vm,
+            "--memory",
+            &vbox.memory_mb.to_string(),
+            "--firmware",
+            firmware,
+            "--uart1",
+            "0x3F8",
+            "4",
+            "--uartmode1",
+            "file",
+            &serial_log.display().to_string(),
+        ])?;
+
+        let image = image
+            .canonicalize()
+            .map_err(|e| BackendError::WriteConfig { source: e })?;
+        self.manage(&[
+            "storageattach",
+            vm,
+            "--storagectl",
+            "IDE",
+            "--port",
+            "0",
+            "--device",
+            "0",
+            "--type",
+            "dvddrive",
+            "--medium",
+            &image.display().to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn command(
+        &self,
+        _image: &Path,
+        _is_test: bool,
+        mode: Option<&str>,
+    ) -> Result<Command, BackendError> {
+        if let Some(mode) = mode {
+            return Err(BackendError::ModesUnsupported {
+                backend: self.name(),
+                mode: mode.to_string(),
+            });
+        }
+
+        // Both frontends block until the VM powers off, unlike `VBoxManage startvm`
+        let vbox = &self.config.runner.virtualbox;
+        let mut command = if vbox.headless {
+            Command::new("VBoxHeadless")
+        } else {
+            Command::new("VirtualBoxVM")
+        };
+        command.arg("--startvm").arg(&vbox.vm_name);
+        Ok(command)
+    }
+
+    fn stop(&self, child: &mut Child) -> Result<(), BackendError> {
+        let vm = self.config.runner.virtualbox.vm_name.as_str();
+        if self.manage(&["controlvm", vm, "poweroff"]).is_err() {
+            child.kill().map_err(|e| BackendError::Stop { source: e })?;
+        }
+        child.wait().map_err(|e| BackendError::Stop { source: e })?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error("Configuration error: {source}")]
+    Config { source: ConfigError },
+
+    #[error("Failed to run {tool}: {source}")]
+    Spawn {
+        tool: &'static str,
+        source: std::io::Error,
+    },
+
+    #[error("VBoxManage {command} failed: {stderr}")]
+    VBoxManage { command: String, stderr: String },
+
+    #[error("Failed to write VM configuration: {source}")]
+    WriteConfig { source: std::io::Error },
+
+    #[error("Run mode '{mode}' is only supported by the QEMU backend, not {backend}")]
+    ModesUnsupported { backend: &'static str, mode: String },
+
+    #[error("Failed to stop VM: {source}")]
+    Stop { source: std::io::Error },
+}
+
+impl From<ConfigError> for BackendError {
+    fn from(error: ConfigError) -> Self {
+        BackendError::Config { source: error }
+    }
+}
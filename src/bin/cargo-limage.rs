@@ -0,0 +1,39 @@
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    process::{self, Command},
+};
+
+// `cargo limage <args>` runs this binary as `cargo-limage limage <args>`; hand them to the
+// limage binary installed alongside it
+fn main() {
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "limage") {
+        args.remove(0);
+    }
+
+    let limage = std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name(format!("limage{}", std::env::consts::EXE_SUFFIX)))
+        .filter(|limage| limage.exists())
+        .unwrap_or_else(|| PathBuf::from("limage"));
+    let mut command = Command::new(&limage);
+    command.args(&args);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let e = command.exec();
+        eprintln!("Error: Failed to run {}: {}", limage.display(), e);
+        process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    match command.status() {
+        Ok(status) => process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("Error: Failed to run {}: {}", limage.display(), e);
+            process::exit(1);
+        }
+    }
+}
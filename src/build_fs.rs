@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Result},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+// The file operations a `Builder` stages the image with: creating the iso_root layout, copying
+// Limine, limine.conf and the kernel into it, checking what is cached and writing the resolved
+// config. Commands the build runs (the prebuilder, curl, git, make, xorriso) still see the real
+// disk
+pub trait BuildFs: Send + Sync {
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()>;
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()>;
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    // The absolute path `path` stands for, which `limage clean` checks before removing anything
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf>;
+}
+
+// The disk, through std::fs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl BuildFs for RealFs {
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::copy(from, to).map(|_| ())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        std::fs::write(path, contents)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Entry {
+    Dir,
+    File(Vec<u8>),
+}
+
+// A tree kept in memory, so the staging a build does can be checked without touching the disk.
+// Paths are taken as given apart from `.` and `..`, and a relative path is relative to the current
+// directory only for `canonicalize`. Like the disk, it needs a file's parent to exist before the
+// file is written or copied there
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a file, and the directories leading to it, before the build runs
+    pub fn file(self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        let path = normalize(path.as_ref());
+        {
+            let mut entries = self.lock();
+            insert_dirs(&mut entries, path.parent().unwrap_or(Path::new("")));
+            entries.insert(path, Entry::File(contents.into()));
+        }
+        self
+    }
+
+    // Every file, in path order
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.lock()
+            .iter()
+            .filter(|(_, entry)| matches!(entry, Entry::File(_)))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, Entry>> {
+        // A panic while holding the lock cannot leave the map half updated
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn check_parent(entries: &BTreeMap<PathBuf, Entry>, path: &Path) -> Result<()> {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        if is_root(parent) {
+            return Ok(());
+        }
+        match entries.get(parent) {
+            Some(Entry::Dir) => Ok(()),
+            Some(Entry::File(_)) => Err(Error::new(
+                ErrorKind::NotADirectory,
+                format!("{:?} is a file", parent),
+            )),
+            None => Err(not_found(parent)),
+        }
+    }
+}
+
+impl BuildFs for MemoryFs {
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let path = normalize(path);
+        let mut entries = self.lock();
+        if let Some(file) = path
+            .ancestors()
+            .find(|ancestor| matches!(entries.get(*ancestor), Some(Entry::File(_))))
+        {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} is a file", file),
+            ));
+        }
+        insert_dirs(&mut entries, &path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let path = normalize(path);
+        let mut entries = self.lock();
+        if !matches!(entries.get(&path), Some(Entry::Dir)) {
+            return Err(not_found(&path));
+        }
+        entries.retain(|entry, _| !entry.starts_with(&path));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let path = normalize(path);
+        let mut entries = self.lock();
+        match entries.get(&path) {
+            Some(Entry::File(_)) => {
+                entries.remove(&path);
+                Ok(())
+            }
+            _ => Err(not_found(&path)),
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let contents = self.read(from)?;
+        self.write(to, &contents)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let path = normalize(path);
+        let mut entries = self.lock();
+        Self::check_parent(&entries, &path)?;
+        if matches!(entries.get(&path), Some(Entry::Dir)) {
+            return Err(Error::new(
+                ErrorKind::IsADirectory,
+                format!("{:?} is a directory", path),
+            ));
+        }
+        entries.insert(path, Entry::File(contents.to_vec()));
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let path = normalize(path);
+        match self.lock().get(&path) {
+            Some(Entry::File(contents)) => Ok(contents.clone()),
+            _ => Err(not_found(&path)),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.lock().get(&normalize(path)), Some(Entry::File(_)))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        is_root(&path) || matches!(self.lock().get(&path), Some(Entry::Dir))
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        Ok(normalize(&std::env::current_dir()?.join(path)))
+    }
+}
+
+fn insert_dirs(entries: &mut BTreeMap<PathBuf, Entry>, path: &Path) {
+    for ancestor in path.ancestors() {
+        if is_root(ancestor) {
+            continue;
+        }
+        entries.entry(ancestor.to_path_buf()).or_insert(Entry::Dir);
+    }
+}
+
+// Resolves `.` and `..` without looking at the disk
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // `/..` is `/`
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// The current directory of a relative path, or the root of an absolute one, which always exists
+fn is_root(path: &Path) -> bool {
+    path.parent().is_none()
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{:?} does not exist", path))
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, path::PathBuf};
+
+// What a build did and produced, returned by `Builder::build` and saved next to the resolved
+// config as `target/limage/build-report.json`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BuildReport {
+    pub limage_version: String,
+    // Nothing ran; the steps say what would have, and artifacts carry no hashes
+    pub dry_run: bool,
+    pub duration_ms: u128,
+    // In the order the build declares them, not the order they finished in
+    pub steps: Vec<StepReport>,
+    pub artifacts: Vec<ArtifactReport>,
+    // First line of `--version` of every external tool the build ran, by name
+    pub tools: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepReport {
+    pub name: String,
+    pub status: StepStatus,
+    pub duration_ms: u128,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Ran,
+    // Everything the step fetches was already there, e.g. the OVMF files or the Limine checkout
+    Cached,
+    // Nothing to do, e.g. no prebuilder configured
+    Skipped,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArtifactReport {
+    // "image", "kernel" or "resolved_config", as in the JSON `artifact` messages
+    pub kind: String,
+    pub path: PathBuf,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+impl BuildReport {
+    pub fn step(&self, name: &str) -> Option<&StepReport> {
+        self.steps.iter().find(|step| step.name == name)
+    }
+
+    pub fn artifact(&self, kind: &str) -> Option<&ArtifactReport> {
+        self.artifacts.iter().find(|artifact| artifact.kind == kind)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+}
+
+impl ArtifactReport {
+    // An artifact with the size and hash of `contents`
+    pub fn new(kind: &str, path: PathBuf, contents: &[u8]) -> Self {
+        Self {
+            kind: kind.to_string(),
+            path,
+            size: Some(contents.len() as u64),
+            sha256: Some(format!("{:x}", Sha256::digest(contents))),
+        }
+    }
+
+    // An artifact a dry run would have produced
+    pub fn planned(kind: &str, path: PathBuf) -> Self {
+        Self {
+            kind: kind.to_string(),
+            path,
+            size: None,
+            sha256: None,
+        }
+    }
+}
@@ -1,42 +1,583 @@
-use crate::config::LimageConfig;
+use crate::{
+    artifacts::{LimineArtifacts, OvmfManager},
+    build_fs::{BuildFs, RealFs},
+    build_report::{ArtifactReport, BuildReport, StepReport, StepStatus},
+    cargo::{self, CargoError},
+    command,
+    config::{LimageConfig, ToolBackend},
+    interrupt, iso,
+    lockfile::{Lockfile, LockfileError, LOCKFILE},
+    message::{EventSink, Message, MessageFormat},
+    size::SizeReport,
+    target_spec, version,
+};
+#[cfg(feature = "native")]
+use crate::{
+    iso9660,
+    native::{self, NativeError},
+};
 use std::{
-    path::Path,
-    process::{Command, Stdio},
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 use tracing::{debug, error, info, instrument, warn};
+use wait_timeout::ChildExt;
+
+// Where the kernel is expected when cargo cannot say
+const DEFAULT_KERNEL: &str = "target/x86_64-unknown-none/debug/kernel";
+const DOWNLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
 
 pub struct Builder {
     config: LimageConfig,
+    dry_run: bool,
+    message_format: MessageFormat,
+    events: Option<Arc<dyn EventSink>>,
+    fs: Arc<dyn BuildFs>,
+    cmdline: Vec<String>,
+}
+
+// The steps of a build. Steps run as soon as the steps they need have finished, so the OVMF
+// downloads, the Limine checkout and the prebuilder overlap on a cold build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Prebuild,
+    Ovmf,
+    Limine,
+    Kernel,
+    Iso,
+}
+
+impl Step {
+    // In an order that satisfies every dependency, which dry runs print their commands in
+    const ALL: [Step; 5] = [
+        Step::Prebuild,
+        Step::Ovmf,
+        Step::Limine,
+        Step::Kernel,
+        Step::Iso,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Step::Prebuild => "prebuild",
+            Step::Ovmf => "ovmf",
+            Step::Limine => "limine",
+            Step::Kernel => "kernel",
+            Step::Iso => "iso",
+        }
+    }
+
+    // The steps whose output this one uses
+    fn needs(self) -> &'static [Step] {
+        match self {
+            // The prebuilder may be what builds the kernel
+            Step::Kernel => &[Step::Prebuild],
+            Step::Iso => &[Step::Limine, Step::Kernel],
+            Step::Prebuild | Step::Ovmf | Step::Limine => &[],
+        }
+    }
+}
+
+// Artifact groups removed by `limage clean`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CleanTargets {
+    pub ovmf: bool,
+    pub limine: bool,
+    pub iso: bool,
+    // Logs, snapshots, TPM state and other per-run files under target/limage
+    pub work_dir: bool,
 }
 
 impl Builder {
     pub fn new(config: LimageConfig) -> Result<Self, BuildError> {
         debug!("Creating new Builder with config: {:?}", config);
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            dry_run: false,
+            message_format: MessageFormat::Human,
+            events: None,
+            fs: Arc::new(RealFs),
+            cmdline: Vec::new(),
+        })
+    }
+
+    // Prints every command and file operation of a build instead of performing it
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // Reports build steps and the produced image as JSON messages
+    pub fn message_format(mut self, message_format: MessageFormat) -> Self {
+        self.message_format = message_format;
+        self
+    }
+
+    // Passes every build message to `sink` too, along with command output and download progress
+    pub fn events(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.events = Some(sink);
+        self
+    }
+
+    // Stages the image through `fs` instead of the disk, e.g. a `MemoryFs` to check the iso_root
+    // layout `stage` produces
+    pub fn fs(mut self, fs: Arc<dyn BuildFs>) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    // Appended to the kernel command line of every limine.conf entry in the image; the project's
+    // limine.conf is left alone
+    pub fn cmdline_arg(mut self, arg: impl Into<String>) -> Self {
+        self.cmdline.push(arg.into());
+        self
+    }
+
+    // Builds a test image, whose command line carries the `[test]` settings limage-test reads
+    pub fn test(mut self, test: bool) -> Self {
+        if test {
+            self.cmdline.extend(self.config.test.guest_cmdline());
+        }
+        self
     }
 
     #[instrument(skip(self), err)]
-    pub fn build(&self, kernel_path: Option<&Path>) -> Result<(), BuildError> {
+    pub fn build(&self, kernel_path: Option<&Path>) -> Result<BuildReport, BuildError> {
         info!("Starting build process");
-        self.execute_prebuilder()?;
-        self.prepare_ovmf_files()?;
-        self.prepare_limine_files()?;
-        self.copy_kernel(kernel_path)?;
-        self.create_limine_iso()?;
+        let start = Instant::now();
+        // Kept so `limage size` can show what this build changed
+        if !self.dry_run {
+            if let Err(e) = SizeReport::save_previous(&self.config) {
+                debug!("Not recording the size of the previous image: {}", e);
+            }
+        }
+        if let Some(path) = &self.config.build.target_json {
+            self.materialize_target_json(path)?;
+        }
+        let steps = self.run_steps(|step| match step {
+            Step::Prebuild => self.execute_prebuilder(),
+            Step::Ovmf => self.prepare_ovmf_files(),
+            Step::Limine => self.prepare_limine_files(),
+            Step::Kernel => self.copy_kernel(kernel_path).map(|()| StepStatus::Ran),
+            Step::Iso => self.create_limine_iso().map(|()| StepStatus::Ran),
+        })?;
+        self.emit(&Message::Artifact {
+            kind: "image",
+            path: &self.config.build.image_path,
+        });
+        let mut report = BuildReport {
+            limage_version: version::VERSION.to_string(),
+            dry_run: self.dry_run,
+            duration_ms: 0,
+            tools: self.tool_versions(&steps),
+            steps,
+            artifacts: Vec::new(),
+        };
+        let image = self.config.build.image_path.clone();
+        let kernel = self.config.staged_kernel();
+        if self.dry_run {
+            report.artifacts = vec![
+                ArtifactReport::planned("image", image),
+                ArtifactReport::planned("kernel", kernel),
+            ];
+        } else {
+            report.artifacts = vec![
+                self.artifact_report("image", image)?,
+                self.artifact_report("kernel", kernel)?,
+            ];
+            let resolved_config = self.write_resolved_config()?;
+            report
+                .artifacts
+                .push(self.artifact_report("resolved_config", resolved_config)?);
+            report.duration_ms = start.elapsed().as_millis();
+            self.write_build_report(&report)?;
+        }
         info!("Build completed successfully");
+        Ok(report)
+    }
+
+    // Runs every step on its own thread once its dependencies are done. After a failure no new
+    // step starts, the running ones finish and the first error is returned
+    fn run_steps(
+        &self,
+        run: impl Fn(Step) -> Result<StepStatus, BuildError> + Sync,
+    ) -> Result<Vec<StepReport>, BuildError> {
+        if self.dry_run {
+            return Step::ALL
+                .into_iter()
+                .map(|step| self.step(step.name(), || run(step)))
+                .collect();
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            let run = &run;
+            let mut started = Vec::new();
+            let mut done = Vec::new();
+            let mut reports = Vec::new();
+            let mut finished = 0;
+            let mut failure = None;
+            loop {
+                if failure.is_none() {
+                    for step in Step::ALL {
+                        if started.contains(&step)
+                            || !step.needs().iter().all(|need| done.contains(need))
+                        {
+                            continue;
+                        }
+                        debug!("Starting build step {}", step.name());
+                        started.push(step);
+                        let sender = sender.clone();
+                        scope.spawn(move || {
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                self.step(step.name(), || run(step))
+                            }));
+                            let _ = sender.send((step, result));
+                        });
+                    }
+                }
+                if finished == started.len() {
+                    break;
+                }
+                let Ok((step, result)) = receiver.recv() else {
+                    break;
+                };
+                finished += 1;
+                match result {
+                    Ok(Ok(report)) => {
+                        done.push(step);
+                        reports.push((step, report));
+                    }
+                    Ok(Err(e)) => {
+                        if failure.is_some() {
+                            debug!("Build step {} failed too: {}", step.name(), e);
+                        }
+                        failure.get_or_insert(e);
+                    }
+                    Err(panic) => panic::resume_unwind(panic),
+                }
+            }
+            if let Some(e) = failure {
+                return Err(e);
+            }
+            reports.sort_by_key(|(step, _)| Step::ALL.iter().position(|other| other == step));
+            Ok(reports.into_iter().map(|(_, report)| report).collect())
+        })
+    }
+
+    fn step(
+        &self,
+        step: &str,
+        run: impl FnOnce() -> Result<StepStatus, BuildError>,
+    ) -> Result<StepReport, BuildError> {
+        self.emit(&Message::StepStarted { step });
+        let start = Instant::now();
+        let status = match run() {
+            Ok(status) => status,
+            Err(e) => {
+                self.emit(&Message::StepFailed {
+                    step,
+                    error: &e.to_string(),
+                });
+                return Err(e);
+            }
+        };
+        let duration_ms = start.elapsed().as_millis();
+        self.emit(&Message::StepFinished { step, duration_ms });
+        Ok(StepReport {
+            name: step.to_string(),
+            status,
+            duration_ms,
+        })
+    }
+
+    // The external tools the steps that ran used; the native backend runs none
+    fn tool_versions(&self, steps: &[StepReport]) -> BTreeMap<String, String> {
+        let mut tools = BTreeMap::new();
+        if self.dry_run || self.config.build.tools != ToolBackend::External {
+            return tools;
+        }
+        let ran = |name: &str| {
+            steps
+                .iter()
+                .any(|step| step.name == name && step.status == StepStatus::Ran)
+        };
+        // By name, with the binary to ask
+        let mut used = Vec::new();
+        if ran("ovmf") {
+            used.push(("curl", PathBuf::from("curl")));
+        }
+        if ran("limine") {
+            used.push(("git", PathBuf::from("git")));
+            used.push(("make", PathBuf::from("make")));
+        }
+        if ran("iso") {
+            let limine = LimineArtifacts::new(&self.config.build.limine_path);
+            used.push(("xorriso", PathBuf::from("xorriso")));
+            used.push(("limine", limine.installer()));
+        }
+        for (name, binary) in used {
+            tools.insert(
+                name.to_string(),
+                version::tool_version(&binary.to_string_lossy()),
+            );
+        }
+        tools
+    }
+
+    fn artifact_report(&self, kind: &str, path: PathBuf) -> Result<ArtifactReport, BuildError> {
+        let contents = self.fs.read(&path).map_err(|e| BuildError::ReadArtifact {
+            path: path.clone(),
+            source: e,
+        })?;
+        Ok(ArtifactReport::new(kind, path, &contents))
+    }
+
+    fn write_build_report(&self, report: &BuildReport) -> Result<(), BuildError> {
+        let path = self.config.build_report_path();
+        let write_error = |e| BuildError::WriteReport {
+            path: path.clone(),
+            source: e,
+        };
+        let json = report.to_json().map_err(|e| write_error(e.into()))?;
+        self.fs.write(&path, json.as_bytes()).map_err(write_error)?;
+        self.emit(&Message::Artifact {
+            kind: "build_report",
+            path: &path,
+        });
+        Ok(())
+    }
+
+    fn emit(&self, message: &Message) {
+        self.message_format.emit(message);
+        if let Some(sink) = &self.events {
+            sink.event(message);
+        }
+    }
+
+    // Runs `command` to completion, streaming its output lines to the event sink if there is one
+    fn output(&self, command: &mut Command, step: &str) -> std::io::Result<Output> {
+        if self.events.is_none() {
+            return command.output();
+        }
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let (stdout, stderr) = thread::scope(|scope| {
+            let stdout = scope.spawn(|| self.forward_output(stdout, step, "stdout"));
+            let stderr = self.forward_output(stderr, step, "stderr");
+            (stdout.join().unwrap_or_default(), stderr)
+        });
+        Ok(Output {
+            status: child.wait()?,
+            stdout,
+            stderr,
+        })
+    }
+
+    fn forward_output(&self, stream: Option<impl Read>, step: &str, name: &str) -> Vec<u8> {
+        let mut captured = Vec::new();
+        let Some(stream) = stream else {
+            return captured;
+        };
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+            self.emit(&Message::CommandOutput {
+                step,
+                stream: name,
+                line: String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']),
+            });
+            captured.append(&mut line);
+        }
+        captured
+    }
+
+    // Runs curl, reporting how much of `path` has arrived to the event sink if there is one
+    fn download(&self, command: &mut Command, url: &str, path: &Path) -> std::io::Result<()> {
+        if self.events.is_none() {
+            return command.output().map(|_| ());
+        }
+        let mut child = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        loop {
+            let status = child.wait_timeout(DOWNLOAD_PROGRESS_INTERVAL)?;
+            let bytes = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+            self.emit(&Message::DownloadProgress {
+                url,
+                path,
+                bytes,
+                finished: status.is_some(),
+            });
+            if status.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Records the settings that produced the image, so a CI artifact can be traced back to them
+    fn write_resolved_config(&self) -> Result<PathBuf, BuildError> {
+        let path = self.config.resolved_config_path();
+        let resolved = self
+            .config
+            .to_toml_string()
+            .map_err(|e| BuildError::SerializeConfig { source: e })?;
+        let content = format!(
+            "# Configuration limage {} built {} with: every config file, [host.<os>] section,\n# LIMAGE_* variable and command-line flag applied\n\n{}",
+            version::VERSION,
+            self.config.build.image_path.display(),
+            resolved
+        );
+        if let Some(parent) = path.parent() {
+            self.fs
+                .create_dir_all(parent)
+                .map_err(|e| BuildError::WriteResolvedConfig {
+                    path: path.clone(),
+                    source: e,
+                })?;
+        }
+        self.fs
+            .write(&path, content.as_bytes())
+            .map_err(|e| BuildError::WriteResolvedConfig {
+                path: path.clone(),
+                source: e,
+            })?;
+        self.emit(&Message::Artifact {
+            kind: "resolved_config",
+            path: &path,
+        });
+        Ok(path)
+    }
+
+    // Downloads whatever OVMF and Limine files are missing from the caches
+    #[instrument(skip(self), err)]
+    pub fn fetch_artifacts(&self) -> Result<(), BuildError> {
+        thread::scope(|scope| {
+            let ovmf = scope.spawn(|| self.prepare_ovmf_files());
+            let limine = self.clone_limine_binary();
+            ovmf.join().unwrap_or_else(|e| panic::resume_unwind(e))?;
+            limine.map(|_| ())
+        })
+    }
+
+    // Existing paths the selected targets resolve to under the current config
+    pub fn clean_paths(&self, targets: &CleanTargets) -> Vec<PathBuf> {
+        let build = &self.config.build;
+        let mut paths = Vec::new();
+        if targets.ovmf {
+            paths.push(build.ovmf_path.clone());
+        }
+        if targets.limine {
+            paths.push(build.limine_path.clone());
+        }
+        if targets.iso {
+            paths.push(build.iso_root.clone());
+            paths.push(build.image_path.clone());
+        }
+        if targets.work_dir {
+            paths.push(self.config.work_dir());
+        }
+        paths.retain(|path| self.fs.exists(path));
+        paths
+    }
+
+    // Removes the selected artifacts and returns what was (or, on a dry run, would be) removed
+    #[instrument(skip(self), err)]
+    pub fn clean(&self, targets: &CleanTargets, dry_run: bool) -> Result<Vec<PathBuf>, BuildError> {
+        let paths = self.clean_paths(targets);
+        for path in &paths {
+            self.check_clean_path(path)?;
+        }
+        if dry_run {
+            return Ok(paths);
+        }
+
+        for path in &paths {
+            info!("Removing {:?}", path);
+            let result = if self.fs.is_dir(path) {
+                self.fs.remove_dir_all(path)
+            } else {
+                self.fs.remove_file(path)
+            };
+            result.map_err(|e| BuildError::Clean {
+                path: path.clone(),
+                source: e,
+            })?;
+        }
+        Ok(paths)
+    }
+
+    // A misconfigured path must never take the project (or anything containing it) with it
+    fn check_clean_path(&self, path: &Path) -> Result<(), BuildError> {
+        let resolved = self.fs.canonicalize(path)?;
+        let cwd = std::env::current_dir()?.canonicalize()?;
+        if cwd.starts_with(&resolved) {
+            return Err(BuildError::UnsafeClean {
+                path: path.to_path_buf(),
+            });
+        }
         Ok(())
     }
 
+    // Prints `command` on a dry run, returning whether the caller should skip running it
+    fn print_if_dry_run(&self, command: &Command) -> bool {
+        if self.dry_run {
+            println!("{}", command::render(command));
+        }
+        self.dry_run
+    }
+
+    fn create_dir(&self, dir: &Path) -> std::io::Result<()> {
+        if self.dry_run {
+            println!("mkdir -p {}", command::quote(&dir.to_string_lossy()));
+            return Ok(());
+        }
+        self.fs.create_dir_all(dir)
+    }
+
+    fn remove_dir(&self, dir: &Path) -> std::io::Result<()> {
+        if self.dry_run {
+            println!("rm -rf {}", command::quote(&dir.to_string_lossy()));
+            return Ok(());
+        }
+        self.fs.remove_dir_all(dir)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        if self.dry_run {
+            println!(
+                "cp {} {}",
+                command::quote(&from.to_string_lossy()),
+                command::quote(&to.to_string_lossy())
+            );
+            return Ok(());
+        }
+        self.fs.copy(from, to)
+    }
+
     #[instrument(skip(self), err)]
-    fn execute_prebuilder(&self) -> Result<(), BuildError> {
+    fn execute_prebuilder(&self) -> Result<StepStatus, BuildError> {
         if let Some(cmd) = &self.config.build.prebuilder {
             info!("Executing prebuilder command: {}", cmd);
-            let output = Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .stdout(Stdio::piped())
-                .output()
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(cmd).stdout(Stdio::piped());
+            if self.print_if_dry_run(&command) {
+                return Ok(StepStatus::Ran);
+            }
+            let output = self
+                .output(&mut command, "prebuild")
                 .map_err(|e| BuildError::PrebuilderFailed { source: e })?;
 
             if !output.status.success() {
@@ -47,140 +588,309 @@ impl Builder {
             }
         } else {
             debug!("No prebuilder command specified, skipping");
+            return Ok(StepStatus::Skipped);
         }
-        Ok(())
+        Ok(StepStatus::Ran)
     }
 
     #[instrument(skip(self), err)]
-    fn prepare_ovmf_files(&self) -> Result<(), BuildError> {
-        info!("Preparing OVMF files in: {:?}", self.config.build.ovmf_path);
-        std::fs::create_dir_all(&self.config.build.ovmf_path)?;
-
-        for arch in &["x86_64"] {
-            for kind in &["code", "vars"] {
-                let url = format!(
-                    "https://github.com/osdev0/edk2-ovmf-nightly/releases/latest/download/ovmf-{}-{}.fd",
-                    kind, arch
-                );
-                let path = self
-                    .config
-                    .build
-                    .ovmf_path
-                    .join(format!("ovmf-{}-{}.fd", kind, arch));
-
-                debug!("Downloading OVMF file from {} to {:?}", url, path);
-                let result = Command::new("curl")
-                    .arg("-Lo")
-                    .arg(&path)
-                    .arg(&url)
-                    .stdout(Stdio::piped())
-                    .output()
-                    .map_err(|e| BuildError::DownloadOvmfFailed { source: e });
-
-                if let Err(e) = &result {
-                    error!("Failed to download OVMF file: {}", e);
-                }
-                result?;
-                info!("Downloaded OVMF {}-{}.fd successfully", kind, arch);
+    fn prepare_ovmf_files(&self) -> Result<StepStatus, BuildError> {
+        let ovmf = self.ovmf()?;
+        info!("Preparing OVMF files in: {:?}", ovmf.dir());
+        self.create_dir(ovmf.dir())?;
+
+        let downloads = ovmf.download_commands_where(|path| self.fs.exists(path));
+        if downloads.is_empty() {
+            return Ok(StepStatus::Cached);
+        }
+        if self.dry_run {
+            for (url, path, command) in downloads {
+                self.download_ovmf_file(&url, &path, command)?;
             }
+            return Ok(StepStatus::Ran);
+        }
+        // The code and vars files download side by side
+        thread::scope(|scope| {
+            let handles: Vec<_> = downloads
+                .into_iter()
+                .map(|(url, path, command)| {
+                    scope.spawn(move || self.download_ovmf_file(&url, &path, command))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+        })?;
+        Ok(StepStatus::Ran)
+    }
+
+    fn download_ovmf_file(
+        &self,
+        url: &str,
+        path: &Path,
+        mut command: Command,
+    ) -> Result<(), BuildError> {
+        debug!("Downloading OVMF file from {} to {:?}", url, path);
+        #[cfg(feature = "native")]
+        if self.config.build.tools == ToolBackend::Native {
+            return self.download_natively(url, path);
+        }
+        if self.print_if_dry_run(&command) {
+            return Ok(());
         }
+        // A partial download would pass for a cached file on the next build
+        let _partial = interrupt::remove_on_interrupt(path);
+        let result = self
+            .download(&mut command, url, path)
+            .map_err(|e| BuildError::DownloadOvmfFailed { source: e });
+
+        if let Err(e) = &result {
+            error!("Failed to download OVMF file: {}", e);
+        }
+        result?;
+        info!("Downloaded {:?} successfully", path);
         Ok(())
     }
 
+    #[cfg(feature = "native")]
+    fn download_natively(&self, url: &str, path: &Path) -> Result<(), BuildError> {
+        if self.dry_run {
+            println!(
+                "# download {} to {}",
+                url,
+                command::quote(&path.to_string_lossy())
+            );
+            return Ok(());
+        }
+        let _partial = interrupt::remove_on_interrupt(path);
+        let mut reported = Instant::now();
+        native::download(url, path, |bytes| {
+            if self.events.is_some() && reported.elapsed() >= DOWNLOAD_PROGRESS_INTERVAL {
+                reported = Instant::now();
+                self.emit(&Message::DownloadProgress {
+                    url,
+                    path,
+                    bytes,
+                    finished: false,
+                });
+            }
+        })
+        .map_err(|e| BuildError::Native { source: e })?;
+        let bytes = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        self.emit(&Message::DownloadProgress {
+            url,
+            path,
+            bytes,
+            finished: true,
+        });
+        info!("Downloaded {:?} successfully", path);
+        Ok(())
+    }
+
+    // The firmware cache, pinned to the release in the lockfile
+    pub fn ovmf(&self) -> Result<OvmfManager, BuildError> {
+        let lockfile = Lockfile::load(Path::new(LOCKFILE))?;
+        Ok(OvmfManager::new(&self.config.build.ovmf_path)
+            .release(lockfile.ovmf.map(|ovmf| ovmf.release)))
+    }
+
+    // The Limine checkout, pinned to the commit in the lockfile
+    pub fn limine(&self) -> Result<LimineArtifacts, BuildError> {
+        let lockfile = Lockfile::load(Path::new(LOCKFILE))?;
+        Ok(LimineArtifacts::new(&self.config.build.limine_path)
+            .commit(lockfile.limine.map(|limine| limine.commit)))
+    }
+
     #[instrument(skip(self), err)]
-    fn prepare_limine_files(&self) -> Result<(), BuildError> {
+    fn prepare_limine_files(&self) -> Result<StepStatus, BuildError> {
         info!("Preparing Limine files");
-        self.clone_limine_binary()?;
+        let status = self.clone_limine_binary()?;
+        self.stage_limine()?;
+        Ok(status)
+    }
+
+    // Lays out iso_root from the fetched Limine files, limine.conf and the kernel, without running
+    // any tool or downloading anything. `build` does the same between fetching and writing the image
+    #[instrument(skip(self), err)]
+    pub fn stage(&self, kernel_path: Option<&Path>) -> Result<(), BuildError> {
+        self.stage_limine()?;
+        self.copy_kernel(kernel_path)
+    }
+
+    fn stage_limine(&self) -> Result<(), BuildError> {
         self.copy_limine_config()?;
-        self.copy_limine_binary()?;
-        Ok(())
+        self.copy_dtb()?;
+        self.copy_limine_binary()
     }
 
     #[instrument(skip(self), err)]
-    fn clone_limine_binary(&self) -> Result<(), BuildError> {
-        let required_files = [
-            "limine-bios.sys",
-            "limine-bios-cd.bin",
-            "limine-uefi-cd.bin",
-            "BOOTX64.EFI",
-            "BOOTIA32.EFI",
-        ];
-
-        let should_clone = !self.config.build.limine_path.exists()
-            || required_files.iter().any(|file| {
-                let file_path = self.config.build.limine_path.join(file);
-                !file_path.exists()
-            });
+    fn clone_limine_binary(&self) -> Result<StepStatus, BuildError> {
+        let limine = self.limine()?;
+        if LimineArtifacts::FILES
+            .iter()
+            .all(|file| self.fs.is_file(&limine.path(file)))
+        {
+            debug!("Limine repository exists with all required files, skipping clone");
+            return Ok(StepStatus::Cached);
+        }
 
-        if should_clone {
-            // If directory exists but is incomplete, remove it first
-            if self.config.build.limine_path.exists() {
-                info!(
-                    "Limine directory exists but missing required files, removing and re-cloning"
-                );
-                std::fs::remove_dir_all(&self.config.build.limine_path).map_err(|e| {
-                    BuildError::CloneLimineFailed {
-                        source: std::io::Error::new(
-                            e.kind(),
-                            format!("Failed to remove incomplete Limine directory: {}", e),
-                        ),
-                    }
+        // If directory exists but is incomplete, remove it first
+        if self.fs.exists(limine.dir()) {
+            info!("Limine directory exists but missing required files, removing and re-cloning");
+            self.remove_dir(limine.dir())
+                .map_err(|e| BuildError::CloneLimineFailed {
+                    source: std::io::Error::new(
+                        e.kind(),
+                        format!("Failed to remove incomplete Limine directory: {}", e),
+                    ),
                 })?;
-            } else {
-                info!(
-                    "Cloning Limine repository to {:?}",
-                    self.config.build.limine_path
+        } else {
+            info!("Cloning Limine repository to {:?}", limine.dir());
+        }
+
+        self.create_dir(limine.dir())?; // Create first
+                                        // A checkout missing the built `limine` utility would pass for a complete one
+        let _partial = (!self.dry_run).then(|| interrupt::remove_on_interrupt(limine.dir()));
+        #[cfg(feature = "native")]
+        if self.config.build.tools == ToolBackend::Native {
+            if self.dry_run {
+                println!(
+                    "# download {} and unpack it to {}",
+                    limine.tarball_url(),
+                    command::quote(&limine.dir().to_string_lossy())
                 );
+                return Ok(StepStatus::Ran);
             }
-
-            std::fs::create_dir_all(&self.config.build.limine_path)?; // Create first
-            let clone_result = Command::new("git")
-                .args(&[
-                    "clone",
-                    "https://github.com/limine-bootloader/limine.git",
-                    "--branch=v8.x-binary",
-                    "--depth=1",
-                ])
-                .arg(&self.config.build.limine_path)
-                .stdout(Stdio::piped())
-                .output()
+            native::fetch_limine(&limine).map_err(|e| BuildError::Native { source: e })?;
+            info!("Limine downloaded successfully");
+            return Ok(StepStatus::Ran);
+        }
+        let mut clone = limine.clone_command();
+        if !self.print_if_dry_run(&clone) {
+            let clone_result = self
+                .output(&mut clone, "limine")
                 .map_err(|e| BuildError::CloneLimineFailed { source: e });
 
             if let Err(e) = &clone_result {
                 error!("Failed to clone Limine repository: {}", e);
             }
-            clone_result?;
-
-            info!("Building Limine");
-            let build_result = Command::new("make")
-                .arg("-C")
-                .arg(&self.config.build.limine_path)
-                .status()
-                .map_err(|e| BuildError::CloneLimineFailed { source: (e) });
+            check_status("git", &clone_result?)?;
+        }
 
-            if let Err(e) = &build_result {
-                error!("Failed to build Limine: {}", e);
+        if let Some(commit) = limine.pinned_commit() {
+            info!("Checking out locked Limine commit {}", commit);
+        }
+        for mut checkout in limine.checkout_commands() {
+            if self.print_if_dry_run(&checkout) {
+                continue;
+            }
+            let output = self
+                .output(&mut checkout, "limine")
+                .map_err(|e| BuildError::CloneLimineFailed { source: e })?;
+            if !output.status.success() {
+                return Err(BuildError::CheckoutLimine {
+                    commit: limine.pinned_commit().unwrap_or_default().to_string(),
+                    stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                });
             }
-            build_result?;
+        }
 
-            info!("Limine repository cloned and built successfully");
+        info!("Building Limine");
+        let mut make = limine.make_command();
+        // Keep stdout free for JSON messages
+        if self.message_format.is_json() {
+            make.stdout(std::io::stderr());
+        }
+        if self.print_if_dry_run(&make) {
+            return Ok(StepStatus::Ran);
+        }
+        // Without an event sink make prints straight to the terminal, so there is no stderr to keep
+        let build_result = if self.events.is_some() {
+            self.output(&mut make, "limine")
         } else {
-            debug!("Limine repository exists with all required files, skipping clone");
+            make.status().map(|status| Output {
+                status,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
         }
-        Ok(())
+        .map_err(|e| BuildError::CloneLimineFailed { source: (e) });
+
+        if let Err(e) = &build_result {
+            error!("Failed to build Limine: {}", e);
+        }
+        check_status("make", &build_result?)?;
+
+        info!("Limine repository cloned and built successfully");
+        Ok(StepStatus::Ran)
     }
 
     #[instrument(skip(self), err)]
     fn copy_limine_config(&self) -> Result<(), BuildError> {
         let config_dir = self.config.build.iso_root.join("boot").join("limine");
         debug!("Creating Limine config directory: {:?}", config_dir);
-        std::fs::create_dir_all(&config_dir)?;
+        self.create_dir(&config_dir)?;
 
         info!("Copying limine.conf to {:?}", config_dir);
-        std::fs::copy("limine.conf", config_dir.join("limine.conf"))
+        let staged = config_dir.join("limine.conf");
+        self.copy(&self.config.limine_conf(), &staged)
             .map_err(|e| BuildError::CopyLimineConfig { source: e })?;
+        let dtb_path = self.config.staged_dtb().map(|(_, boot_path)| boot_path);
+        if self.cmdline.is_empty() && dtb_path.is_none() {
+            return Ok(());
+        }
 
-        Ok(())
+        let args = self.cmdline.join(" ");
+        if self.dry_run {
+            if !args.is_empty() {
+                println!(
+                    "# append {:?} to the cmdline of every entry in {}",
+                    args,
+                    command::quote(&staged.to_string_lossy())
+                );
+            }
+            if let Some(dtb_path) = &dtb_path {
+                println!(
+                    "# set dtb_path: {} for every entry in {}",
+                    dtb_path,
+                    command::quote(&staged.to_string_lossy())
+                );
+            }
+            return Ok(());
+        }
+        let content = self
+            .fs
+            .read(&staged)
+            .map_err(|e| BuildError::CopyLimineConfig { source: e })?;
+        let mut content = String::from_utf8_lossy(&content).into_owned();
+        if !args.is_empty() {
+            debug!("Appending {:?} to the kernel command lines", args);
+            content = with_cmdline(&content, &args);
+        }
+        if let Some(dtb_path) = &dtb_path {
+            debug!("Pointing the entries' dtb_path at {}", dtb_path);
+            content = with_dtb_path(&content, dtb_path);
+        }
+        self.fs
+            .write(&staged, content.as_bytes())
+            .map_err(|e| BuildError::CopyLimineConfig { source: e })
+    }
+
+    // Limine hands the staged device tree to the kernel through its DTB request
+    #[instrument(skip(self), err)]
+    fn copy_dtb(&self) -> Result<(), BuildError> {
+        let (Some(dtb), Some((staged, _))) = (&self.config.qemu.dtb, self.config.staged_dtb())
+        else {
+            return Ok(());
+        };
+        if let Some(dir) = staged.parent() {
+            self.create_dir(dir)?;
+        }
+        info!("Copying device tree {:?} to {:?}", dtb, staged);
+        self.copy(dtb, &staged).map_err(|e| BuildError::CopyDtb {
+            path: dtb.clone(),
+            source: e,
+        })
     }
 
     #[instrument(skip(self), err)]
@@ -192,8 +902,9 @@ impl Builder {
             "Creating Limine binary directories: {:?} and {:?}",
             limine_boot_dir, limine_efi_dir
         );
-        std::fs::create_dir_all(&limine_boot_dir)?;
-        std::fs::create_dir_all(&limine_efi_dir)?;
+        self.create_dir(&limine_boot_dir)?;
+        self.create_dir(&limine_efi_dir)?;
+        let limine = LimineArtifacts::new(&self.config.build.limine_path);
 
         // Copy BIOS files
         info!("Copying Limine BIOS files");
@@ -202,27 +913,29 @@ impl Builder {
             "limine-bios-cd.bin",
             "limine-uefi-cd.bin",
         ] {
-            let src = self.config.build.limine_path.join(file);
+            let src = limine.path(file);
             let dst = limine_boot_dir.join(file);
             debug!("Copying {} from {:?} to {:?}", file, src, dst);
 
-            std::fs::copy(&src, &dst).map_err(|e| BuildError::CopyLimineBinary {
-                file: file.to_string(),
-                source: e,
-            })?;
+            self.copy(&src, &dst)
+                .map_err(|e| BuildError::CopyLimineBinary {
+                    file: file.to_string(),
+                    source: e,
+                })?;
         }
 
         // Copy UEFI files
         info!("Copying Limine UEFI files");
         for file in &["BOOTX64.EFI", "BOOTIA32.EFI"] {
-            let src = self.config.build.limine_path.join(file);
+            let src = limine.path(file);
             let dst = limine_efi_dir.join(file);
             debug!("Copying {} from {:?} to {:?}", file, src, dst);
 
-            std::fs::copy(&src, &dst).map_err(|e| BuildError::CopyLimineBinary {
-                file: file.to_string(),
-                source: e,
-            })?;
+            self.copy(&src, &dst)
+                .map_err(|e| BuildError::CopyLimineBinary {
+                    file: file.to_string(),
+                    source: e,
+                })?;
         }
 
         Ok(())
@@ -232,92 +945,285 @@ impl Builder {
     fn copy_kernel(&self, kernel_path: Option<&Path>) -> Result<(), BuildError> {
         let kernel_dir = self.config.build.iso_root.join("boot").join("kernel");
         debug!("Creating kernel directory: {:?}", kernel_dir);
-        std::fs::create_dir_all(&kernel_dir)?;
+        self.create_dir(&kernel_dir)?;
 
-        let kernel_binary =
-            kernel_path.unwrap_or_else(|| Path::new("target/x86_64-unknown-none/debug/kernel"));
+        let kernel_binary = match kernel_path {
+            Some(kernel_path) => kernel_path.to_path_buf(),
+            None => self.cargo_kernel()?,
+        };
 
         info!(
             "Copying kernel from {:?} to {:?}",
             kernel_binary,
             kernel_dir.join("kernel")
         );
-        std::fs::copy(kernel_binary, kernel_dir.join("kernel"))
-            .map_err(|e| BuildError::CopyKernel { source: e })?;
+        self.copy(&kernel_binary, &kernel_dir.join("kernel"))
+            .map_err(|e| BuildError::CopyKernel {
+                path: kernel_binary.clone(),
+                source: e,
+            })?;
+
+        match &self.config.build.target_json {
+            Some(target) if !self.dry_run => {
+                self.check_kernel_target(&kernel_binary, &kernel_dir.join("kernel"), target)
+            }
+            _ => Ok(()),
+        }
+    }
 
+    // Writes `build.target_json` for the next `cargo build` if the project has none yet
+    fn materialize_target_json(&self, path: &Path) -> Result<(), BuildError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let written =
+            target_spec::materialize(&*self.fs, path).map_err(|e| BuildError::WriteTargetJson {
+                path: path.to_path_buf(),
+                source: e,
+            })?;
+        if written {
+            info!("Wrote target JSON {:?}", path);
+        }
         Ok(())
     }
 
+    // A kernel cargo built for another target, such as the host, would not boot
+    fn check_kernel_target(
+        &self,
+        kernel: &Path,
+        staged: &Path,
+        target: &Path,
+    ) -> Result<(), BuildError> {
+        let mismatch = |detail: String| BuildError::KernelTarget {
+            path: kernel.to_path_buf(),
+            target: target.to_path_buf(),
+            detail,
+        };
+        let spec = self
+            .fs
+            .read(target)
+            .map_err(|e| mismatch(format!("the target JSON cannot be read: {}", e)))?;
+        let contents = self
+            .fs
+            .read(staged)
+            .map_err(|e| mismatch(format!("it cannot be read: {}", e)))?;
+        target_spec::check_kernel(&contents, &String::from_utf8_lossy(&spec)).map_err(mismatch)
+    }
+
+    // The kernel `cargo build` last produced for this project
+    pub(crate) fn cargo_kernel(&self) -> Result<PathBuf, BuildError> {
+        let build = &self.config.build;
+        match cargo::kernel_binary(
+            self.config.kernel_target().as_deref(),
+            build.profile.as_deref(),
+            build.kernel_bin.as_deref(),
+        ) {
+            Ok(kernel) => Ok(kernel),
+            // Outside a Cargo project there is nothing to ask, so the conventional path is tried
+            Err(CargoError::Metadata { source }) => {
+                debug!(
+                    "No cargo metadata ({}), using the default kernel path",
+                    source
+                );
+                Ok(self.config.root.join(DEFAULT_KERNEL))
+            }
+            Err(e) => Err(BuildError::LocateKernel { source: e }),
+        }
+    }
+
     #[instrument(skip(self), err)]
     fn create_limine_iso(&self) -> Result<(), BuildError> {
         // Create parent directory for the ISO if it doesn't exist
         if let Some(parent) = self.config.build.image_path.parent() {
             debug!("Creating parent directory for ISO: {:?}", parent);
-            std::fs::create_dir_all(parent)?;
+            self.create_dir(parent)?;
         }
 
+        let _partial =
+            (!self.dry_run).then(|| interrupt::remove_on_interrupt(&self.config.build.image_path));
+        #[cfg(feature = "native")]
+        if self.config.build.tools == ToolBackend::Native {
+            return self.write_iso_natively();
+        }
         self.create_raw_iso()?;
         self.install_limine_to_iso()?;
         info!("ISO creation completed");
         Ok(())
     }
 
+    // The image boots from CD on BIOS and UEFI; without the `limine` utility there is no BIOS
+    // boot code for disks, which `limine bios-install` would add
+    #[cfg(feature = "native")]
+    fn write_iso_natively(&self) -> Result<(), BuildError> {
+        let build = &self.config.build;
+        if self.dry_run {
+            println!(
+                "# write {} to {}",
+                command::quote(&build.iso_root.to_string_lossy()),
+                command::quote(&build.image_path.to_string_lossy())
+            );
+            return Ok(());
+        }
+        info!("Writing ISO to {:?}", build.image_path);
+        iso9660::write(&build.iso_root, &build.image_path)
+            .map_err(|e| BuildError::Native { source: e })
+    }
+
     #[instrument(skip(self), err)]
     fn create_raw_iso(&self) -> Result<(), BuildError> {
         info!("Creating raw ISO at {:?}", self.config.build.image_path);
-        let result = Command::new("xorriso")
-            .args(&[
-                "-as",
-                "mkisofs",
-                "-b",
-                "boot/limine/limine-bios-cd.bin",
-                "-no-emul-boot",
-                "-boot-load-size",
-                "4",
-                "-boot-info-table",
-                "--efi-boot",
-                "boot/limine/limine-uefi-cd.bin",
-                "-efi-boot-part",
-                "--efi-boot-image",
-                "--protective-msdos-label",
-            ])
-            .arg(&self.config.build.iso_root)
-            .arg("-o")
-            .arg(&self.config.build.image_path)
-            .stdout(Stdio::piped())
-            .output()
+        let mut command =
+            iso::mkisofs_command(&self.config.build.iso_root, &self.config.build.image_path);
+        if self.print_if_dry_run(&command) {
+            return Ok(());
+        }
+        let result = self
+            .output(&mut command, "iso")
             .map_err(|e| BuildError::CreateIso { source: e });
 
         if let Err(e) = &result {
             error!("Failed to create ISO: {}", e);
         }
-        result?;
+        check_status("xorriso", &result?)?;
         debug!("Raw ISO created successfully");
         Ok(())
     }
 
     #[instrument(skip(self), err)]
     fn install_limine_to_iso(&self) -> Result<(), BuildError> {
-        let limine_binary = self.config.build.limine_path.join("limine");
-        info!("Installing Limine to ISO using binary: {:?}", limine_binary);
-        let result = Command::new(limine_binary)
-            .args(&[
-                "bios-install",
-                &self.config.build.image_path.display().to_string(),
-            ])
-            .stdout(Stdio::piped())
-            .output()
+        let limine = LimineArtifacts::new(&self.config.build.limine_path);
+        info!(
+            "Installing Limine to ISO using binary: {:?}",
+            limine.installer()
+        );
+        let mut command = limine.install_command(&self.config.build.image_path);
+        if self.print_if_dry_run(&command) {
+            return Ok(());
+        }
+        let result = self
+            .output(&mut command, "iso")
             .map_err(|e| BuildError::InstallLimine { source: e });
 
         if let Err(e) = &result {
             error!("Failed to install Limine to ISO: {}", e);
         }
-        result?;
+        check_status("limine", &result?)?;
         info!("Limine installed to ISO successfully");
         Ok(())
     }
 }
 
+// A build tool that ran but failed, with whatever it printed to stderr
+fn check_status(tool: &'static str, output: &Output) -> Result<(), BuildError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(BuildError::CommandFailed {
+        tool,
+        status: output.status,
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    })
+}
+
+// Limine protocols that boot a kernel and take a command line, as opposed to chainloading
+const CMDLINE_PROTOCOLS: &[&str] = &["limine", "linux", "multiboot", "multiboot1", "multiboot2"];
+// The ones that hand the kernel a device tree
+const DTB_PROTOCOLS: &[&str] = &["limine", "linux"];
+
+// Appends `args` to the `cmdline` of every entry that boots a kernel, adding one after the kernel
+// `path` where an entry has none
+fn with_cmdline(conf: &str, args: &str) -> String {
+    edit_entries(conf, CMDLINE_PROTOCOLS, |entry| {
+        if let Some(line) = entry
+            .iter_mut()
+            .find(|line| matches!(key(line).as_deref(), Some("cmdline" | "kernel_cmdline")))
+        {
+            *line = format!("{} {}", line.trim_end(), args);
+        } else {
+            insert_after_path(entry, &format!("cmdline: {}", args));
+        }
+    })
+}
+
+// Adds `dtb_path` after the kernel `path` of every entry that does not set one itself
+fn with_dtb_path(conf: &str, dtb_path: &str) -> String {
+    edit_entries(conf, DTB_PROTOCOLS, |entry| {
+        if !entry
+            .iter()
+            .any(|line| key(line).as_deref() == Some("dtb_path"))
+        {
+            insert_after_path(entry, &format!("dtb_path: {}", dtb_path));
+        }
+    })
+}
+
+// Runs `edit` on the lines of every entry whose `protocol` is one of `protocols`, its `/` line
+// first; global options and the other entries are kept as they are
+fn edit_entries(conf: &str, protocols: &[&str], mut edit: impl FnMut(&mut Vec<String>)) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut entry: Vec<String> = Vec::new();
+    let mut finish_entry = |lines: &mut Vec<String>, entry: &mut Vec<String>| {
+        let protocol = entry
+            .iter()
+            .find(|line| key(line).as_deref() == Some("protocol"))
+            .and_then(|line| line.split_once(':'))
+            .map(|(_, protocol)| protocol.trim().to_ascii_lowercase());
+        let is_entry = entry
+            .first()
+            .is_some_and(|line| line.trim_start().starts_with('/'));
+        if is_entry && protocol.is_some_and(|protocol| protocols.contains(&protocol.as_str())) {
+            edit(entry);
+        }
+        lines.append(entry);
+    };
+
+    for line in conf.lines() {
+        if line.trim_start().starts_with('/') {
+            finish_entry(&mut lines, &mut entry);
+        }
+        entry.push(line.to_string());
+    }
+    finish_entry(&mut lines, &mut entry);
+
+    let mut content = lines.join("\n");
+    if conf.ends_with('\n') {
+        content.push('\n');
+    }
+    content
+}
+
+// Puts `option` right after the entry's kernel path, indented like it
+fn insert_after_path(entry: &mut Vec<String>, option: &str) {
+    let Some(index) = entry
+        .iter()
+        .position(|line| matches!(key(line).as_deref(), Some("path" | "kernel_path")))
+    else {
+        return;
+    };
+    let line = &entry[index];
+    let indent = &line[..line.len() - line.trim_start().len()];
+    entry.insert(index + 1, format!("{}{}", indent, option));
+}
+
+// The lowercased key of a `key: value` line
+fn key(line: &str) -> Option<String> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('/') || trimmed.starts_with('#') {
+        return None;
+    }
+    trimmed
+        .split_once(':')
+        .map(|(key, _)| key.trim().to_ascii_lowercase())
+}
+
+fn stderr_detail(stderr: &str) -> String {
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", stderr)
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum BuildError {
     #[error("Failed to locate Cargo.toml")]
@@ -332,6 +1238,9 @@ pub enum BuildError {
     #[error("Failed to clone Limine repository: {source}")]
     CloneLimineFailed { source: std::io::Error },
 
+    #[error("Failed to check out locked Limine commit {commit}: {stderr}")]
+    CheckoutLimine { commit: String, stderr: String },
+
     #[error("Failed to copy Limine config: {source}")]
     CopyLimineConfig { source: std::io::Error },
 
@@ -341,8 +1250,14 @@ pub enum BuildError {
         source: std::io::Error,
     },
 
-    #[error("Failed to copy kernel binary: {source}")]
-    CopyKernel { source: std::io::Error },
+    #[error("Failed to copy kernel binary {path:?}: {source}")]
+    CopyKernel {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to locate the kernel binary: {source}")]
+    LocateKernel { source: CargoError },
 
     #[error("Failed to create ISO: {source}")]
     CreateIso { source: std::io::Error },
@@ -350,6 +1265,286 @@ pub enum BuildError {
     #[error("Failed to install Limine to ISO: {source}")]
     InstallLimine { source: std::io::Error },
 
+    #[error("{tool} failed ({status}){}", stderr_detail(.stderr))]
+    CommandFailed {
+        tool: &'static str,
+        status: ExitStatus,
+        stderr: String,
+    },
+
+    #[error("Failed to serialize the resolved config: {source}")]
+    SerializeConfig { source: toml::ser::Error },
+
+    #[error("Failed to write {path:?}: {source}")]
+    WriteResolvedConfig {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to remove {path:?}: {source}")]
+    Clean {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Refusing to remove {path:?}: it contains the current directory")]
+    UnsafeClean { path: PathBuf },
+
+    #[error("Failed to read {path:?} for the build report: {source}")]
+    ReadArtifact {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to write the build report to {path:?}: {source}")]
+    WriteReport {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to copy device tree {path:?}: {source}")]
+    CopyDtb {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Kernel {path:?} was not built for {target:?}: {detail}")]
+    KernelTarget {
+        path: PathBuf,
+        target: PathBuf,
+        detail: String,
+    },
+
+    #[error("Failed to write target JSON {path:?}: {source}")]
+    WriteTargetJson {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Lockfile(#[from] LockfileError),
+
+    #[cfg(feature = "native")]
+    #[error(transparent)]
+    Native { source: NativeError },
+
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+impl BuildError {
+    // The stable code `limage::Error` reports; a new variant takes the next free code
+    pub fn code(&self) -> &'static str {
+        match self {
+            BuildError::LocateManifest { .. } => "E030",
+            BuildError::PrebuilderFailed { .. } => "E031",
+            BuildError::DownloadOvmfFailed { .. } => "E032",
+            BuildError::CloneLimineFailed { .. } => "E033",
+            BuildError::CheckoutLimine { .. } => "E034",
+            BuildError::CopyLimineConfig { .. } => "E035",
+            BuildError::CopyLimineBinary { .. } => "E036",
+            BuildError::CopyKernel { .. } => "E037",
+            BuildError::LocateKernel { .. } => "E038",
+            BuildError::CreateIso { .. } => "E039",
+            BuildError::InstallLimine { .. } => "E040",
+            BuildError::CommandFailed { .. } => "E041",
+            BuildError::SerializeConfig { .. } => "E042",
+            BuildError::WriteResolvedConfig { .. } => "E043",
+            BuildError::Clean { .. } => "E044",
+            BuildError::UnsafeClean { .. } => "E045",
+            BuildError::Lockfile { .. } => "E046",
+            BuildError::Io { .. } => "E047",
+            BuildError::ReadArtifact { .. } => "E048",
+            BuildError::WriteReport { .. } => "E049",
+            BuildError::KernelTarget { .. } => "E097",
+            BuildError::WriteTargetJson { .. } => "E098",
+            BuildError::CopyDtb { .. } => "E099",
+            #[cfg(feature = "native")]
+            BuildError::Native { source } => source.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{artifacts::LimineArtifacts, build_fs::MemoryFs};
+
+    const LIMINE_CONF: &str = "timeout: 0\n\n/Kernel\n    protocol: limine\n    path: boot():/boot/kernel/kernel\n    module_path: boot():/boot/initrd.tar\n";
+
+    fn config() -> LimageConfig {
+        LimageConfig::builder()
+            .iso_root("/iso")
+            .limine_path("/limine")
+            .ovmf_path("/ovmf")
+            .build()
+            .expect("the test config is valid")
+    }
+
+    // A project with a built kernel, its limine.conf and a Limine checkout
+    fn project() -> MemoryFs {
+        let fs = MemoryFs::new()
+            .file("limine.conf", LIMINE_CONF)
+            .file("/build/kernel", "kernel-bytes");
+        LimineArtifacts::FILES.iter().fold(fs, |fs, file| {
+            fs.file(Path::new("/limine").join(file), *file)
+        })
+    }
+
+    fn staged(fs: &MemoryFs) -> Vec<PathBuf> {
+        fs.files()
+            .into_iter()
+            .filter(|path| path.starts_with("/iso"))
+            .collect()
+    }
+
+    fn read(fs: &MemoryFs, path: &str) -> String {
+        String::from_utf8(fs.read(Path::new(path)).expect("the file was staged"))
+            .expect("the file is UTF-8")
+    }
+
+    #[test]
+    fn stage_lays_out_iso_root() {
+        let fs = Arc::new(project());
+        Builder::new(config())
+            .unwrap()
+            .fs(fs.clone())
+            .stage(Some(Path::new("/build/kernel")))
+            .unwrap();
+
+        let expected: Vec<PathBuf> = [
+            "/iso/EFI/BOOT/BOOTIA32.EFI",
+            "/iso/EFI/BOOT/BOOTX64.EFI",
+            "/iso/boot/kernel/kernel",
+            "/iso/boot/limine/limine-bios-cd.bin",
+            "/iso/boot/limine/limine-bios.sys",
+            "/iso/boot/limine/limine-uefi-cd.bin",
+            "/iso/boot/limine/limine.conf",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(staged(&fs), expected);
+        assert_eq!(read(&fs, "/iso/boot/kernel/kernel"), "kernel-bytes");
+        // Without a command line to add, limine.conf is copied as is, modules included
+        assert_eq!(read(&fs, "/iso/boot/limine/limine.conf"), LIMINE_CONF);
+    }
+
+    #[test]
+    fn stage_adds_cmdline_and_dtb_to_limine_conf() {
+        let fs = Arc::new(project().file("/dtb/virt.dtb", "dtb-bytes"));
+        let config = LimageConfig::builder()
+            .iso_root("/iso")
+            .limine_path("/limine")
+            .dtb("/dtb/virt.dtb")
+            .build()
+            .unwrap();
+        Builder::new(config)
+            .unwrap()
+            .fs(fs.clone())
+            .cmdline_arg("limage.test.filter=math")
+            .stage(Some(Path::new("/build/kernel")))
+            .unwrap();
+
+        assert_eq!(read(&fs, "/iso/boot/dtb/virt.dtb"), "dtb-bytes");
+        assert_eq!(
+            read(&fs, "/iso/boot/limine/limine.conf"),
+            "timeout: 0\n\n/Kernel\n    protocol: limine\n    path: boot():/boot/kernel/kernel\n    dtb_path: boot():/boot/dtb/virt.dtb\n    cmdline: limage.test.filter=math\n    module_path: boot():/boot/initrd.tar\n"
+        );
+        // The project's own limine.conf is left alone
+        assert_eq!(read(&fs, "limine.conf"), LIMINE_CONF);
+    }
+
+    #[test]
+    fn stage_without_kernel_fails() {
+        let fs = Arc::new(project());
+        let error = Builder::new(config())
+            .unwrap()
+            .fs(fs)
+            .stage(Some(Path::new("/build/missing")))
+            .unwrap_err();
+        assert!(matches!(error, BuildError::CopyKernel { .. }), "{error}");
+    }
+
+    #[test]
+    fn stage_without_limine_checkout_fails() {
+        let fs = Arc::new(MemoryFs::new().file("limine.conf", LIMINE_CONF));
+        let error = Builder::new(config())
+            .unwrap()
+            .fs(fs)
+            .stage(Some(Path::new("/build/kernel")))
+            .unwrap_err();
+        assert!(
+            matches!(error, BuildError::CopyLimineBinary { .. }),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn cached_ovmf_files_are_not_downloaded() {
+        let fs = Arc::new(
+            MemoryFs::new()
+                .file("/ovmf/ovmf-code-x86_64.fd", "code")
+                .file("/ovmf/ovmf-vars-x86_64.fd", "vars"),
+        );
+        let builder = Builder::new(config()).unwrap().fs(fs);
+        assert_eq!(builder.prepare_ovmf_files().unwrap(), StepStatus::Cached);
+    }
+
+    #[test]
+    fn missing_ovmf_file_is_downloaded() {
+        let fs = Arc::new(MemoryFs::new().file("/ovmf/ovmf-code-x86_64.fd", "code"));
+        let builder = Builder::new(config()).unwrap().fs(fs.clone());
+        let ovmf = builder.ovmf().unwrap();
+        let downloads = ovmf.download_commands_where(|path| fs.exists(path));
+        let paths: Vec<_> = downloads.into_iter().map(|(_, path, _)| path).collect();
+        assert_eq!(paths, [PathBuf::from("/ovmf/ovmf-vars-x86_64.fd")]);
+        // A dry run prints the download instead of running curl
+        let builder = builder.dry_run(true);
+        assert_eq!(builder.prepare_ovmf_files().unwrap(), StepStatus::Ran);
+        assert!(!fs.exists(Path::new("/ovmf/ovmf-vars-x86_64.fd")));
+    }
+
+    #[test]
+    fn cmdline_is_appended_to_existing_cmdline() {
+        let conf = "/K\n    protocol: limine\n    path: boot():/k\n    cmdline: quiet\n";
+        assert_eq!(
+            with_cmdline(conf, "a=1 b=2"),
+            "/K\n    protocol: limine\n    path: boot():/k\n    cmdline: quiet a=1 b=2\n"
+        );
+    }
+
+    #[test]
+    fn cmdline_is_inserted_after_path() {
+        let conf =
+            "timeout: 0\n/K\n  path: boot():/k\n  protocol: limine\n  module_path: boot():/m";
+        assert_eq!(
+            with_cmdline(conf, "a=1"),
+            "timeout: 0\n/K\n  path: boot():/k\n  cmdline: a=1\n  protocol: limine\n  module_path: boot():/m"
+        );
+    }
+
+    #[test]
+    fn cmdline_handles_kernel_path_and_kernel_cmdline() {
+        let conf = "/A\nprotocol: multiboot2\nkernel_path: boot():/a\n/B\nprotocol: linux\nkernel_path: boot():/b\nkernel_cmdline: ro\n";
+        assert_eq!(
+            with_cmdline(conf, "a=1"),
+            "/A\nprotocol: multiboot2\nkernel_path: boot():/a\ncmdline: a=1\n/B\nprotocol: linux\nkernel_path: boot():/b\nkernel_cmdline: ro a=1\n"
+        );
+    }
+
+    #[test]
+    fn cmdline_skips_chainload_entries() {
+        let conf = "/Shell\n    protocol: efi_chainload\n    path: boot():/shell.efi\n/Directory\n/+Sub\n    path: boot():/x\n";
+        assert_eq!(with_cmdline(conf, "a=1"), conf);
+    }
+
+    #[test]
+    fn dtb_path_is_added_once() {
+        let conf = "/A\nprotocol: limine\npath: boot():/a\n/B\nprotocol: limine\npath: boot():/b\ndtb_path: boot():/own.dtb\n/C\nprotocol: multiboot2\npath: boot():/c\n";
+        assert_eq!(
+            with_dtb_path(conf, "boot():/boot/dtb/virt.dtb"),
+            "/A\nprotocol: limine\npath: boot():/a\ndtb_path: boot():/boot/dtb/virt.dtb\n/B\nprotocol: limine\npath: boot():/b\ndtb_path: boot():/own.dtb\n/C\nprotocol: multiboot2\npath: boot():/c\n"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use cargo_metadata::{Message, Metadata, MetadataCommand, Package};
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+#[instrument(err)]
+pub fn build_test_executables(cargo_args: &[String]) -> Result<Vec<PathBuf>, CargoError> {
+    let executables = build_executables(&["test", "--no-run"], cargo_args, true)?;
+    info!("Found {} test executables", executables.len());
+    Ok(executables)
+}
+
+// The kernel binary `cargo build` produces; a package with several binaries needs `--bin`
+#[instrument(err)]
+pub fn build_kernel(cargo_args: &[String]) -> Result<PathBuf, CargoError> {
+    let mut executables = build_executables(&["build"], cargo_args, false)?;
+    match executables.len() {
+        1 => Ok(executables.remove(0)),
+        0 => Err(CargoError::NoKernel),
+        _ => Err(CargoError::AmbiguousKernel {
+            executables: executables
+                .iter()
+                .map(|executable| executable.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }),
+    }
+}
+
+// The cargo invocation `build_test_executables` runs, e.g. for a dry run to print
+pub fn test_executables_command(cargo_args: &[String]) -> Command {
+    cargo_command(&["test", "--no-run"], cargo_args)
+}
+
+fn cargo_command(command: &[&str], cargo_args: &[String]) -> Command {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut cargo = Command::new(cargo);
+    cargo
+        .args(command)
+        .arg("--message-format=json-render-diagnostics")
+        .args(cargo_args);
+    cargo
+}
+
+// Runs `cargo <command>` and collects the executables it reports, either test harnesses or binaries
+fn build_executables(
+    command: &[&str],
+    cargo_args: &[String],
+    test: bool,
+) -> Result<Vec<PathBuf>, CargoError> {
+    info!("Building executables with cargo {}", command.join(" "));
+
+    let mut child = cargo_command(command, cargo_args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| CargoError::SpawnCargo { source: e })?;
+
+    let mut executables = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        for message in cargo_metadata::parse_messages(BufReader::new(stdout)) {
+            let message = message.map_err(|e| CargoError::ParseMessage { source: e })?;
+            if let Message::CompilerArtifact(artifact) = message {
+                if let (true, Some(executable)) =
+                    (artifact.profile.test == test, artifact.executable)
+                {
+                    debug!("Found executable {:?}", executable);
+                    executables.push(executable);
+                }
+            }
+        }
+    }
+
+    let status = child
+        .wait()
+        .map_err(|e| CargoError::SpawnCargo { source: e })?;
+    if !status.success() {
+        return Err(CargoError::BuildFailed {
+            command: command.join(" "),
+            status,
+        });
+    }
+    Ok(executables)
+}
+
+// Directory of the Cargo workspace enclosing the current directory
+#[instrument]
+pub fn workspace_root() -> Result<PathBuf, CargoError> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let output = Command::new(&cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| CargoError::SpawnCargo { source: e })?;
+    if !output.status.success() {
+        return Err(CargoError::NoWorkspace);
+    }
+
+    let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    manifest
+        .parent()
+        .map(PathBuf::from)
+        .ok_or(CargoError::NoWorkspace)
+}
+
+// Name and version of the package in the current directory, or of the workspace's only member
+#[instrument(err)]
+pub fn package_info() -> Result<(String, String), CargoError> {
+    let metadata = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .map_err(|e| CargoError::Metadata { source: e })?;
+    let current = std::env::current_dir()
+        .map_err(|e| CargoError::SpawnCargo { source: e })?
+        .join("Cargo.toml");
+
+    let package = match metadata
+        .packages
+        .iter()
+        .find(|package| package.manifest_path == current)
+    {
+        Some(package) => package,
+        None => match metadata.packages.as_slice() {
+            [package] => package,
+            _ => return Err(CargoError::AmbiguousPackage),
+        },
+    };
+    Ok((package.name.clone(), package.version.to_string()))
+}
+
+// Where `cargo build` leaves the kernel, without building it: the target directory from cargo
+// metadata (which honours CARGO_TARGET_DIR and `build.target-dir`), the target, the profile's
+// directory and the package's binary. `target`, `profile` and `bin` override what cargo would use
+#[instrument(err)]
+pub fn kernel_binary(
+    target: Option<&str>,
+    profile: Option<&str>,
+    bin: Option<&str>,
+) -> Result<PathBuf, CargoError> {
+    let metadata = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .map_err(|e| CargoError::Metadata { source: e })?;
+    let package = kernel_package(&metadata)?;
+    let bins: Vec<&str> = package
+        .targets
+        .iter()
+        .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+        .map(|target| target.name.as_str())
+        .collect();
+    let bin = match (bin, bins.as_slice()) {
+        (Some(bin), _) => bin,
+        (None, [bin]) => bin,
+        (None, []) => return Err(CargoError::NoKernel),
+        // A package's default binary is named after it
+        (None, bins) => bins
+            .iter()
+            .find(|bin| **bin == package.name)
+            .ok_or_else(|| CargoError::AmbiguousBinary {
+                package: package.name.clone(),
+                binaries: bins.join(", "),
+            })?,
+    };
+
+    let mut dir = metadata.target_directory.clone();
+    if let Some(target) = target.map(str::to_string).or_else(build_target) {
+        // Custom targets are named after their JSON file
+        let name = match target.strip_suffix(".json") {
+            Some(_) => Path::new(&target)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or(target),
+            None => target,
+        };
+        dir.push(name);
+    }
+
+    let profile_dir = |profile: &str| match profile {
+        "dev" | "test" => "debug".to_string(),
+        "bench" => "release".to_string(),
+        profile => profile.to_string(),
+    };
+    let kernel = match profile {
+        Some(profile) => dir.join(profile_dir(profile)).join(bin),
+        // Without a profile, whichever of debug and release was built last
+        None => ["debug", "release"]
+            .iter()
+            .map(|profile| dir.join(profile).join(bin))
+            .filter_map(|kernel| Some((kernel.metadata().ok()?.modified().ok()?, kernel)))
+            .max_by_key(|(modified, _)| *modified)
+            .map(|(_, kernel)| kernel)
+            .unwrap_or_else(|| dir.join("debug").join(bin)),
+    };
+    debug!("Kernel binary of {} is {:?}", package.name, kernel);
+    Ok(kernel)
+}
+
+// The package in the current directory, or else the workspace's only package with a binary
+fn kernel_package(metadata: &Metadata) -> Result<&Package, CargoError> {
+    let current = std::env::current_dir()
+        .map_err(|e| CargoError::SpawnCargo { source: e })?
+        .join("Cargo.toml");
+    if let Some(package) = metadata
+        .packages
+        .iter()
+        .find(|package| package.manifest_path == current)
+    {
+        return Ok(package);
+    }
+    let mut with_bins = metadata.packages.iter().filter(|package| {
+        package
+            .targets
+            .iter()
+            .any(|target| target.kind.iter().any(|kind| kind == "bin"))
+    });
+    match (with_bins.next(), with_bins.next()) {
+        (Some(package), None) => Ok(package),
+        _ => Err(CargoError::AmbiguousPackage),
+    }
+}
+
+// `build.target` as cargo reads it: CARGO_BUILD_TARGET, then the nearest .cargo/config.toml
+fn build_target() -> Option<String> {
+    if let Ok(target) = std::env::var("CARGO_BUILD_TARGET") {
+        return Some(target);
+    }
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .flat_map(|dir| ["config.toml", "config"].map(|name| dir.join(".cargo").join(name)))
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .filter_map(|content| content.parse::<toml::Value>().ok())
+        .find_map(|config| match config.get("build")?.get("target")? {
+            toml::Value::String(target) => Some(target.clone()),
+            // Several targets build the kernel for each; the first is as good as any
+            toml::Value::Array(targets) => targets.first()?.as_str().map(str::to_string),
+            _ => None,
+        })
+}
+
+#[derive(Debug, Error)]
+pub enum CargoError {
+    #[error("Failed to run cargo: {source}")]
+    SpawnCargo { source: std::io::Error },
+
+    #[error("Failed to parse cargo output: {source}")]
+    ParseMessage { source: serde_json::Error },
+
+    #[error("Failed to read cargo metadata: {source}")]
+    Metadata { source: cargo_metadata::Error },
+
+    #[error("The workspace has several packages and none in the current directory")]
+    AmbiguousPackage,
+
+    #[error("No Cargo workspace encloses the current directory")]
+    NoWorkspace,
+
+    #[error("cargo {command} failed ({status})")]
+    BuildFailed { command: String, status: ExitStatus },
+
+    #[error("cargo build produced no kernel binary")]
+    NoKernel,
+
+    #[error("cargo build produced several binaries ({executables}), pick the kernel with --bin")]
+    AmbiguousKernel { executables: String },
+
+    #[error("Package {package} has several binaries ({binaries}), set `build.kernel_bin` to the kernel's")]
+    AmbiguousBinary { package: String, binaries: String },
+}
@@ -0,0 +1,363 @@
+use crate::{
+    config::{ConfigError, LimageConfig, PLACEHOLDER_PATTERN},
+    suggest::UnknownKey,
+};
+use regex::Regex;
+use std::path::Path;
+
+// Protocols Limine v8 boots, and whether they load a file from `path`
+const PROTOCOLS: [(&str, bool); 9] = [
+    ("limine", true),
+    ("linux", true),
+    ("multiboot", true),
+    ("multiboot1", true),
+    ("multiboot2", true),
+    ("efi", true),
+    ("efi_chainload", true),
+    ("bios", false),
+    ("bios_chainload", false),
+];
+
+// Files every build places in the ISO root, relative to it
+const BUILD_FILES: [&str; 7] = [
+    "boot/kernel/kernel",
+    "boot/limine/limine.conf",
+    "boot/limine/limine-bios.sys",
+    "boot/limine/limine-bios-cd.bin",
+    "boot/limine/limine-uefi-cd.bin",
+    "EFI/BOOT/BOOTX64.EFI",
+    "EFI/BOOT/BOOTIA32.EFI",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Finding {
+    pub severity: Severity,
+    // `file` or `file:line`
+    pub location: String,
+    pub message: String,
+}
+
+impl Finding {
+    fn error(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    fn warning(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+// Validates the config file at `config_path` and `limine.conf` without building anything
+pub fn run(config_path: &Path) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let config = check_config(config_path, &mut findings);
+    let config = config.unwrap_or_default();
+    check_limine_conf(&config.limine_conf(), &config, &mut findings);
+    findings
+}
+
+fn check_config(path: &Path, findings: &mut Vec<Finding>) -> Option<LimageConfig> {
+    let location = path.display().to_string();
+    if !path.exists() {
+        return Some(LimageConfig::default());
+    }
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            findings.push(Finding::error(&location, format!("cannot be read: {}", e)));
+            return None;
+        }
+    };
+
+    // Collect the keys serde skips over, which the regular load silently ignores
+    let mut unknown = Vec::new();
+    let mut deserializer = toml::Deserializer::new(&content);
+    let parsed: Result<LimageConfig, _> = serde_ignored::deserialize(&mut deserializer, |path| {
+        unknown.push(UnknownKey::new(&path))
+    });
+    if let Err(e) = parsed {
+        findings.push(Finding::error(&location, e.to_string()));
+        return None;
+    }
+    for key in unknown {
+        findings.push(Finding::error(&location, key.to_string()));
+    }
+
+    // The regular load also runs the semantic checks of every section
+    let config = match LimageConfig::from_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            let message = match std::error::Error::source(&e) {
+                Some(source) => format!("{}: {}", e, source),
+                None => e.to_string(),
+            };
+            findings.push(Finding::error(&location, message));
+            return None;
+        }
+    };
+
+    // The keys of [host.<os>] sections are free-form to serde and only checked by the load
+    for key in config
+        .unknown_keys()
+        .iter()
+        .filter(|key| key.key.starts_with("host."))
+    {
+        findings.push(Finding::error(&location, key.to_string()));
+    }
+
+    for rename in config.deprecated_keys() {
+        findings.push(Finding::warning(
+            &location,
+            format!("{}; `limage config migrate` updates the file", rename),
+        ));
+    }
+
+    // Problems found across sections do not stop the other checks
+    match config.validate(None, false) {
+        Ok(()) => {}
+        Err(ConfigError::Invalid { problems }) => findings.extend(
+            problems
+                .into_iter()
+                .map(|problem| Finding::error(&location, problem)),
+        ),
+        Err(e) => findings.push(Finding::error(&location, e.to_string())),
+    }
+
+    check_placeholders(&config, &location, findings);
+    Some(config)
+}
+
+fn check_placeholders(config: &LimageConfig, location: &str, findings: &mut Vec<Finding>) {
+    let placeholder = Regex::new(PLACEHOLDER_PATTERN).expect("placeholder pattern is valid");
+
+    // Unknown placeholders are among the problems `validate` reports
+    for (key, value) in config.placeholder_args() {
+        for caps in placeholder.captures_iter(value) {
+            let name = &caps[1];
+            if let Some(variable) = name.strip_prefix("env:") {
+                if std::env::var_os(variable).is_none() {
+                    findings.push(Finding::warning(
+                        location,
+                        format!(
+                            "`{}` uses {{{}}}, but {} is not set in this environment",
+                            key, name, variable
+                        ),
+                    ));
+                }
+            }
+        }
+        if value.matches('{').count() != value.matches('}').count() {
+            findings.push(Finding::warning(
+                location,
+                format!("`{}` has an unbalanced brace in {:?}", key, value),
+            ));
+        }
+    }
+
+    if !config
+        .qemu
+        .base_args
+        .iter()
+        .any(|arg| arg.contains("{image}"))
+    {
+        findings.push(Finding::warning(
+            location,
+            "`qemu.base_args` does not reference {image}; the image is attached as -cdrom",
+        ));
+    }
+}
+
+// The subset of limine.conf that limage relies on: entries, their protocol and the files they load
+#[derive(Default)]
+struct Entry {
+    name: String,
+    line: usize,
+    depth: usize,
+    protocol: Option<String>,
+    kernel: Option<(usize, String)>,
+    modules: Vec<(usize, String)>,
+}
+
+fn check_limine_conf(path: &Path, config: &LimageConfig, findings: &mut Vec<Finding>) {
+    let name = path.display().to_string();
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            findings.push(Finding::error(
+                &name,
+                "not found; every build copies it into the image",
+            ));
+            return;
+        }
+        Err(e) => {
+            findings.push(Finding::error(&name, format!("cannot be read: {}", e)));
+            return;
+        }
+    };
+
+    let mut entries: Vec<Entry> = Vec::new();
+    for (index, raw) in content.lines().enumerate() {
+        let number = index + 1;
+        let location = format!("{}:{}", name, number);
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // `/Name` starts an entry, `//Name` a sub-entry
+        if line.starts_with('/') {
+            let depth = line.chars().take_while(|&c| c == '/').count();
+            // A leading `+` expands a directory entry by default
+            let title = line[depth..].trim_start_matches('+').trim();
+            if title.is_empty() {
+                findings.push(Finding::error(&location, "entry has no name"));
+            }
+            entries.push(Entry {
+                name: title.to_string(),
+                line: number,
+                depth,
+                ..Default::default()
+            });
+            continue;
+        }
+
+        // `${NAME}=value` defines a macro
+        if line.starts_with("${") {
+            if !line.contains("}=") {
+                findings.push(Finding::error(
+                    &location,
+                    "malformed macro, expected `${NAME}=value`",
+                ));
+            }
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once(':') else {
+            findings.push(Finding::error(
+                &location,
+                format!("expected `key: value`, found {:?}", line),
+            ));
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+        let Some(entry) = entries.last_mut() else {
+            // Global options come before the first entry
+            continue;
+        };
+        match key.as_str() {
+            "protocol" => entry.protocol = Some(value.to_ascii_lowercase()),
+            "path" | "kernel_path" | "image_path" => entry.kernel = Some((number, value)),
+            "module_path" => entry.modules.push((number, value)),
+            _ => {}
+        }
+    }
+
+    if entries.is_empty() {
+        findings.push(Finding::error(&name, "defines no boot entries"));
+    }
+
+    for (index, entry) in entries.iter().enumerate() {
+        let location = format!("{}:{}", name, entry.line);
+        let Some(protocol) = &entry.protocol else {
+            // Directory entries only group the sub-entries below them
+            let is_directory = entries
+                .get(index + 1)
+                .is_some_and(|next| next.depth > entry.depth);
+            if !is_directory {
+                findings.push(Finding::error(
+                    &location,
+                    format!("entry {:?} has no `protocol`", entry.name),
+                ));
+            }
+            continue;
+        };
+        match PROTOCOLS.iter().find(|(name, _)| name == protocol) {
+            None => findings.push(Finding::warning(
+                &location,
+                format!(
+                    "entry {:?} uses unknown protocol {:?}",
+                    entry.name, protocol
+                ),
+            )),
+            Some((_, true)) if entry.kernel.is_none() => findings.push(Finding::error(
+                &location,
+                format!("entry {:?} has no kernel `path`", entry.name),
+            )),
+            Some(_) => {}
+        }
+
+        if let Some((line, kernel)) = &entry.kernel {
+            check_boot_file(&name, *line, kernel, "kernel", config, findings);
+        }
+        for (line, module) in &entry.modules {
+            check_boot_file(&name, *line, module, "module", config, findings);
+        }
+    }
+}
+
+// Resolves `boot():/path` against what the build stages into the ISO root
+fn check_boot_file(
+    name: &str,
+    line: usize,
+    resource: &str,
+    kind: &str,
+    config: &LimageConfig,
+    findings: &mut Vec<Finding>,
+) {
+    let location = format!("{}:{}", name, line);
+    // Other resources (hdd, guid, fslabel, ...) live outside the image and cannot be checked here
+    let Some(file) = resource.strip_prefix("boot():") else {
+        return;
+    };
+    // A trailing `#<hash>` pins the file's BLAKE2B checksum
+    let file = file.split('#').next().unwrap_or_default();
+    if file.contains("${") {
+        return;
+    }
+    let relative = file.trim_start_matches('/');
+    if BUILD_FILES.contains(&relative) || config.build.iso_root.join(relative).exists() {
+        return;
+    }
+
+    if kind == "kernel" {
+        findings.push(Finding::error(
+            &location,
+            format!(
+                "kernel {} is not part of the image; limage copies the kernel to /boot/kernel/kernel",
+                file
+            ),
+        ));
+        return;
+    }
+    // Modules are put into the ISO root by hand or by the prebuilder, so on a fresh checkout they
+    // are not there yet
+    let stager = if config.build.prebuilder.is_some() {
+        "the prebuilder stages it"
+    } else {
+        "it is copied there before building"
+    };
+    findings.push(Finding::warning(
+        &location,
+        format!(
+            "{} {} is not in {} yet; make sure {}",
+            kind,
+            file,
+            config.build.iso_root.display(),
+            stager
+        ),
+    ));
+}
@@ -0,0 +1,320 @@
+use crate::{
+    build_fs::RealFs,
+    builder::Builder,
+    cargo,
+    config::LimageConfig,
+    runner::{RunStatus, Runner},
+    target_spec,
+};
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+// One step of the pipeline as it ends up in the reports
+pub struct StepResult {
+    pub name: String,
+    pub duration: Duration,
+    // None when the step passed
+    pub failure: Option<String>,
+}
+
+pub struct CiReport {
+    pub steps: Vec<StepResult>,
+}
+
+impl CiReport {
+    pub fn failed(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| step.failure.is_some())
+            .count()
+    }
+
+    // 0 when every step passed, 1 otherwise
+    pub fn exit_code(&self) -> i32 {
+        i32::from(self.failed() > 0)
+    }
+
+    pub fn junit(&self) -> String {
+        let seconds = |duration: Duration| format!("{:.3}", duration.as_secs_f64());
+        let total: Duration = self.steps.iter().map(|step| step.duration).sum();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites name=\"limage\" tests=\"{}\" failures=\"{}\" time=\"{}\">",
+            self.steps.len(),
+            self.failed(),
+            seconds(total)
+        );
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"limage ci\" tests=\"{}\" failures=\"{}\" time=\"{}\">",
+            self.steps.len(),
+            self.failed(),
+            seconds(total)
+        );
+        for step in &self.steps {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"limage\" name=\"{}\" time=\"{}\"",
+                escape_xml(&step.name),
+                seconds(step.duration)
+            );
+            match &step.failure {
+                None => xml.push_str("/>\n"),
+                Some(failure) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure message=\"{}\"/>\n    </testcase>",
+                        escape_xml(failure)
+                    );
+                }
+            }
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    // Markdown table for the GitHub Actions job summary
+    pub fn summary(&self) -> String {
+        let mut markdown =
+            String::from("## limage ci\n\n| Step | Result | Time |\n| --- | --- | --- |\n");
+        for step in &self.steps {
+            let result = match &step.failure {
+                None => "passed".to_string(),
+                Some(failure) => format!("**{}**", failure.replace('|', "\\|")),
+            };
+            let _ = writeln!(
+                markdown,
+                "| {} | {} | {:.1}s |",
+                step.name.replace('|', "\\|"),
+                result,
+                step.duration.as_secs_f64()
+            );
+        }
+        let _ = writeln!(
+            markdown,
+            "\n{} of {} steps passed",
+            self.steps.len() - self.failed(),
+            self.steps.len()
+        );
+        markdown
+    }
+}
+
+// Build, test and report in one non-interactive pass, grouping the output per step
+pub struct Pipeline {
+    config: LimageConfig,
+    cargo_args: Vec<String>,
+    junit: PathBuf,
+    github: bool,
+    dry_run: bool,
+}
+
+impl Pipeline {
+    pub fn new(mut config: LimageConfig) -> Self {
+        // Nobody is at the terminal: no windows, no keyboard forwarding, no prompts
+        config.qemu.headless = true;
+        config.qemu.interactive = false;
+        config.run.pick_mode = false;
+        let junit = config.work_dir().join("ci").join("junit.xml");
+        Self {
+            config,
+            cargo_args: Vec::new(),
+            junit,
+            github: std::env::var("GITHUB_ACTIONS").is_ok_and(|value| value == "true"),
+            dry_run: false,
+        }
+    }
+
+    // Forwarded to both `cargo build` and `cargo test --no-run`, e.g. `--release`
+    pub fn cargo_args(mut self, cargo_args: Vec<String>) -> Self {
+        self.cargo_args = cargo_args;
+        self
+    }
+
+    // Prints the image builds and QEMU invocations instead of running them; cargo still builds
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn junit(mut self, junit: PathBuf) -> Self {
+        self.junit = junit;
+        self
+    }
+
+    pub fn run(&self) -> Result<CiReport, CiError> {
+        let mut report = CiReport { steps: Vec::new() };
+
+        let cargo_args = self.config.target_cargo_args(&self.cargo_args);
+        let built = self.step(&mut report, "Build image", || {
+            if let Some(path) = &self.config.build.target_json {
+                target_spec::materialize(&RealFs, path)
+                    .map_err(|e| format!("Failed to write target JSON {:?}: {}", path, e))?;
+            }
+            let kernel = cargo::build_kernel(&cargo_args).map_err(|e| e.to_string())?;
+            Builder::new(self.config.clone())
+                .and_then(|builder| builder.dry_run(self.dry_run).build(Some(&kernel)))
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        });
+
+        let executables = if built {
+            let mut executables = Vec::new();
+            self.step(&mut report, "Build tests", || {
+                executables =
+                    cargo::build_test_executables(&cargo_args).map_err(|e| e.to_string())?;
+                Ok(())
+            });
+            executables
+        } else {
+            Vec::new()
+        };
+
+        // Test images go next to the other CI output so the image built above stays intact
+        let mut test_config = self.config.clone();
+        test_config.build.image_path = self.config.work_dir().join("ci").join("test.iso");
+        for executable in &executables {
+            let name = executable
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| executable.display().to_string());
+            self.step(&mut report, &format!("Test {}", name), || {
+                Builder::new(test_config.clone())
+                    .and_then(|builder| {
+                        builder
+                            .dry_run(self.dry_run)
+                            .test(true)
+                            .build(Some(executable))
+                    })
+                    .map_err(|e| e.to_string())?;
+                let outcome = Runner::new(test_config.clone(), true)
+                    .kernel(Some(executable.clone()))
+                    .dry_run(self.dry_run)
+                    .run(None)
+                    .map_err(|e| e.to_string())?;
+                let exit_code = outcome.raw_exit_code(&test_config);
+                match outcome.status {
+                    RunStatus::Passed => Ok(()),
+                    RunStatus::Failed => Err(format!("failed with exit code {}", exit_code)),
+                    RunStatus::TimedOut => Err("timed out".to_string()),
+                    RunStatus::QemuError => Err("QEMU was terminated".to_string()),
+                }
+            });
+        }
+
+        self.group("Report", || self.write_reports(&report))?;
+        Ok(report)
+    }
+
+    // Runs `run` in its own log group and records whether it passed
+    fn step(
+        &self,
+        report: &mut CiReport,
+        name: &str,
+        run: impl FnOnce() -> Result<(), String>,
+    ) -> bool {
+        let start = Instant::now();
+        let result = self.group(name, run);
+        let passed = result.is_ok();
+        if let Err(failure) = &result {
+            if self.github {
+                println!("::error title={}::{}", name, failure.replace('\n', "%0A"));
+            } else {
+                println!("{} failed: {}", name, failure);
+            }
+        }
+        report.steps.push(StepResult {
+            name: name.to_string(),
+            duration: start.elapsed(),
+            failure: result.err(),
+        });
+        passed
+    }
+
+    // GitHub Actions folds `::group::` sections; elsewhere a heading separates the steps
+    fn group<T>(&self, name: &str, run: impl FnOnce() -> T) -> T {
+        if self.github {
+            println!("::group::{}", name);
+        } else {
+            println!("==> {}", name);
+        }
+        let _ = std::io::stdout().flush();
+        let result = run();
+        if self.github {
+            println!("::endgroup::");
+        }
+        result
+    }
+
+    fn write_reports(&self, report: &CiReport) -> Result<(), CiError> {
+        if self.dry_run {
+            println!("Would write JUnit report to {}", self.junit.display());
+        } else {
+            write_file(&self.junit, &report.junit())?;
+            println!("Wrote JUnit report to {}", self.junit.display());
+        }
+
+        // Set by GitHub Actions to a file whose Markdown is shown on the run's summary page
+        if let Some(path) = std::env::var_os("GITHUB_STEP_SUMMARY").filter(|_| !self.dry_run) {
+            let path = PathBuf::from(path);
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| CiError::WriteReport {
+                    path: path.clone(),
+                    source: e,
+                })?;
+            file.write_all(report.summary().as_bytes())
+                .map_err(|e| CiError::WriteReport { path, source: e })?;
+        }
+
+        for step in &report.steps {
+            match &step.failure {
+                None => println!("    passed  {}", step.name),
+                Some(failure) => println!("    FAILED  {} ({})", step.name, failure),
+            }
+        }
+        println!(
+            "{} of {} steps passed",
+            report.steps.len() - report.failed(),
+            report.steps.len()
+        );
+        Ok(())
+    }
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), CiError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| CiError::WriteReport {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+    }
+    std::fs::write(path, content).map_err(|e| CiError::WriteReport {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Debug, Error)]
+pub enum CiError {
+    #[error("Failed to write report {path:?}: {source}")]
+    WriteReport {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
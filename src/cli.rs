@@ -1,31 +1,425 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use crate::{
+    config::{ArchiveFormat, LimageConfig},
+    message::MessageFormat,
+    serial::GuestLogLevel,
+};
+use clap::{builder::PossibleValuesParser, ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 #[derive(Parser)]
 #[command(name = "limage")]
 #[command(about = "A tool for building and running kernels", long_about = None)]
 #[command(version)]
 pub struct Cli {
+    // Config file to use instead of limage_config.toml in the project directory
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    // Project directory that limine.conf, target/ and other relative paths resolve against
+    #[arg(long, global = true, value_name = "DIR")]
+    pub manifest_dir: Option<PathBuf>,
+
+    // -v shows progress, -vv debug logs and -vvv traces
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    // Only errors and guest serial output
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    // Print the external commands (and file operations) instead of running them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    // Fails on config keys that match no setting instead of warning about them
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    #[arg(long, global = true, value_enum, value_name = "FORMAT", default_value_t = MessageFormat::Human)]
+    pub message_format: MessageFormat,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+impl Cli {
+    // Pins path arguments to the invocation directory before limage switches to the project directory
+    pub fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        };
+
+        if let Some(config) = &mut self.config {
+            resolve(config);
+        }
+        match &mut self.command {
+            Some(Commands::Build { image, kernel }) => {
+                for path in [image, kernel].into_iter().flatten() {
+                    resolve(path);
+                }
+            }
+            Some(Commands::Run {
+                kernel,
+                kernel_path,
+                image,
+                screenshot_on_exit,
+                expect,
+                ..
+            }) => {
+                for path in [kernel, kernel_path, image, screenshot_on_exit, expect]
+                    .into_iter()
+                    .flatten()
+                {
+                    resolve(path);
+                }
+            }
+            Some(Commands::Runner { kernel, .. }) => resolve(kernel),
+            Some(Commands::Ci {
+                junit: Some(junit), ..
+            }) => resolve(junit),
+            Some(Commands::Addr2line {
+                kernel: Some(kernel),
+                ..
+            }) => resolve(kernel),
+            Some(Commands::Package {
+                out_dir: Some(out_dir),
+                ..
+            }) => resolve(out_dir),
+            Some(Commands::Flash { device, .. }) => resolve(device),
+            Some(Commands::Init { dir, .. }) => resolve(dir),
+            _ => {}
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
-    Build,
+    Build {
+        #[arg(long, value_name = "PATH")]
+        image: Option<PathBuf>,
+
+        #[arg(long, value_name = "PATH")]
+        kernel: Option<PathBuf>,
+    },
 
     Run {
         #[arg(value_name = "KERNEL")]
         kernel: Option<PathBuf>,
 
+        // Same as the positional KERNEL, for scripts that prefer named options
+        #[arg(long = "kernel", value_name = "PATH", conflicts_with = "kernel")]
+        kernel_path: Option<PathBuf>,
+
+        #[arg(long, value_name = "PATH")]
+        image: Option<PathBuf>,
+
+        #[arg(long)]
+        headless: bool,
+
+        // Run the kernel as a test executable: [test] exit codes, timeout and arguments apply
+        #[arg(long)]
+        test: bool,
+
+        #[arg(long)]
+        no_build: bool,
+
+        #[arg(long)]
+        hot: bool,
+
+        #[arg(long)]
+        tui: bool,
+
+        #[arg(long)]
+        interactive: bool,
+
+        #[arg(long)]
+        wait_debugger: bool,
+
+        #[arg(long, value_name = "BACKEND")]
+        display: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "PATH",
+            num_args = 0..=1,
+            default_missing_value = "target/limage/screenshot.ppm"
+        )]
+        screenshot_on_exit: Option<PathBuf>,
+
+        #[arg(long, value_name = "SECS")]
+        timeout: Option<u32>,
+
+        #[arg(long, value_name = "CODE")]
+        success_code: Option<i32>,
+
+        // Exit with the guest's own exit code instead of 0 (passed), 1 (failed) or 2 (timed out)
+        #[arg(long, conflicts_with = "scenario")]
+        raw_exit_code: bool,
+
+        #[arg(long, value_enum, value_name = "LEVEL")]
+        log_level: Option<GuestLogLevel>,
+
+        #[arg(long, value_name = "PATH")]
+        expect: Option<PathBuf>,
+
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["hot", "tui"])]
+        scenario: Option<String>,
+
+        // Same as `run mode <NAME>`
+        #[arg(long = "mode", value_name = "NAME", conflicts_with = "scenario")]
+        mode_name: Option<String>,
+
+        // Choose the run mode from a list
+        #[arg(long, conflicts_with_all = ["mode_name", "scenario"])]
+        pick: bool,
+
         #[command(subcommand)]
         mode: Option<RunMode>,
     },
 
-    Clean,
+    // Target of cargo's `runner` setting, invoked with the path of the kernel cargo just built
+    Runner {
+        // Test executables are recognized by their path; these override that
+        #[arg(long)]
+        test: bool,
+
+        #[arg(long, conflicts_with = "test")]
+        no_test: bool,
+
+        #[arg(long)]
+        raw_exit_code: bool,
+
+        #[arg(value_name = "KERNEL")]
+        kernel: PathBuf,
+
+        // Forwarded by cargo after the kernel path, e.g. from `cargo run -- <args>`
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "ARGS"
+        )]
+        args: Vec<String>,
+    },
+
+    Test {
+        #[arg(long, value_enum, value_name = "LEVEL")]
+        log_level: Option<GuestLogLevel>,
+
+        // Only runs the guest tests whose path contains PATTERN; needs the limage-test harness
+        #[arg(long, value_name = "PATTERN")]
+        filter: Option<String>,
+
+        #[arg(last = true, value_name = "CARGO_ARGS")]
+        cargo_args: Vec<String>,
+    },
+
+    // Builds the image, runs every test executable and writes a JUnit report, without prompts
+    Ci {
+        // Defaults to target/limage/ci/junit.xml
+        #[arg(long, value_name = "PATH")]
+        junit: Option<PathBuf>,
+
+        #[arg(last = true, value_name = "CARGO_ARGS")]
+        cargo_args: Vec<String>,
+    },
+
+    Clean {
+        #[arg(long)]
+        ovmf: bool,
+
+        #[arg(long)]
+        limine: bool,
+
+        #[arg(long)]
+        iso: bool,
+
+        #[arg(long)]
+        all: bool,
+    },
+
+    Modes,
+
+    // Lists recorded runs, or prints the guest serial output of one of them
+    Logs {
+        // Position in the list (1 is the latest run) or run id
+        #[arg(value_name = "RUN", conflicts_with = "last")]
+        run: Option<String>,
+
+        #[arg(long)]
+        last: bool,
+
+        // Only lines matching this regex; without a run, every recorded run is searched
+        #[arg(long, value_name = "PATTERN")]
+        grep: Option<String>,
+
+        #[arg(long, value_name = "LINES")]
+        tail: Option<usize>,
+
+        // Keeps printing the output of a run that is still going
+        #[arg(long, short = 'f')]
+        follow: bool,
+    },
+
+    // Prints the QEMU command line a run would use, one argument per line and as a shell line
+    QemuArgs {
+        #[arg(long, value_name = "NAME")]
+        mode: Option<String>,
+
+        // The command line of a test run, with the exit device and test arguments
+        #[arg(long)]
+        test: bool,
+
+        // Only the shell-quoted line
+        #[arg(long)]
+        shell: bool,
+    },
+
+    // Breaks down the built image and kernel, compared with the image the last build replaced
+    Size {
+        // Also lists every file staged into the image
+        #[arg(long)]
+        files: bool,
+    },
+
+    // Resolves kernel addresses to functions and source lines; without addresses, the kernel
+    // addresses in panic output read from stdin
+    Addr2line {
+        #[arg(value_name = "ADDRESS")]
+        addresses: Vec<String>,
+
+        // Kernel ELF to resolve against instead of the one in the last built image
+        #[arg(long, value_name = "PATH")]
+        kernel: Option<PathBuf>,
+    },
+
+    Package {
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        format: Option<ArchiveFormat>,
+
+        #[arg(long, value_name = "DIR")]
+        out_dir: Option<PathBuf>,
+
+        // Archive name template, overriding package.name
+        #[arg(long, value_name = "TEMPLATE")]
+        name: Option<String>,
+
+        #[arg(long)]
+        no_build: bool,
+    },
+
+    Flash {
+        #[arg(value_name = "DEVICE")]
+        device: PathBuf,
+
+        #[arg(long, short = 'y')]
+        yes: bool,
+
+        #[arg(long)]
+        force: bool,
+    },
+
+    Shell,
+
+    Update {
+        #[arg(long, short = 'y')]
+        yes: bool,
+
+        #[arg(long)]
+        check: bool,
+    },
+
+    // Replaces this executable with the binary of the latest GitHub release
+    SelfUpdate {
+        #[arg(long, short = 'y')]
+        yes: bool,
+
+        // Only reports whether a newer release exists, exiting with 1 if it does
+        #[arg(long)]
+        check: bool,
+    },
+
+    Doctor,
+
+    // Validates limage_config.toml and limine.conf without building
+    Check,
+
+    // With -v/--verbose, also the host, cached boot artifacts and tool versions
+    Version,
+
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    // Creates a kernel project from an embedded template or a git repository
+    Init {
+        #[arg(value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+
+        // minimal, framebuffer, smp, or a git URL
+        #[arg(long, default_value = "minimal")]
+        template: String,
+
+        // Crate name; by default the name of DIR
+        #[arg(long)]
+        name: Option<String>,
+
+        #[arg(long)]
+        force: bool,
+
+        // Lists the embedded templates instead
+        #[arg(long)]
+        list: bool,
+    },
+
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    Show,
+
+    Init {
+        #[arg(long)]
+        force: bool,
+    },
+
+    // Rewrites deprecated keys to their current names, keeping comments; --dry-run shows the diff
+    Migrate,
 }
 
 #[derive(Subcommand)]
 pub enum RunMode {
     Mode { name: String },
 }
+
+// Completion script for `shell` with the run modes and scenarios of `config` baked in
+pub fn write_completions(shell: Shell, config: &LimageConfig, out: &mut impl Write) {
+    let modes: Vec<String> = config.modes().into_keys().collect();
+    let scenarios: Vec<String> = config.scenarios.keys().cloned().collect();
+
+    let mut command = Cli::command().mut_subcommand("run", |run| {
+        let run = run.mut_subcommand("mode", |mode| {
+            mode.mut_arg("name", |arg| {
+                arg.value_parser(PossibleValuesParser::new(modes))
+            })
+        });
+        if scenarios.is_empty() {
+            run
+        } else {
+            run.mut_arg("scenario", |arg| {
+                arg.value_parser(PossibleValuesParser::new(scenarios))
+            })
+        }
+    });
+    clap_complete::generate(shell, &mut command, "limage", out);
+}
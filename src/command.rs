@@ -0,0 +1,33 @@
+use std::process::Command;
+
+// Renders `command` as a line that can be pasted into a POSIX shell
+pub fn render(command: &Command) -> String {
+    let mut parts = Vec::new();
+    if let Some(dir) = command.get_current_dir() {
+        parts.push(format!("cd {} &&", quote(&dir.to_string_lossy())));
+    }
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            parts.push(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                quote(&value.to_string_lossy())
+            ));
+        }
+    }
+    parts.push(quote(&command.get_program().to_string_lossy()));
+    parts.extend(command.get_args().map(|arg| quote(&arg.to_string_lossy())));
+    parts.join(" ")
+}
+
+pub fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
@@ -1,18 +1,77 @@
+use crate::{
+    accel,
+    artifacts::OvmfManager,
+    expect::{ExpectError, Normalizer},
+    migrate::{self, Rename},
+    monitor::{MonitorError, MonitorTarget},
+    serial::GuestLogLevel,
+    suggest::{self, UnknownKey},
+    target_spec,
+    tpm::Swtpm,
+};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
+use toml::{value::Table, Value};
+use tracing::{debug, warn};
+
+pub const CONFIG_FILE: &str = "limage_config.toml";
+// Current `config_version`; see `migrate` for the keys earlier versions used
+pub const CONFIG_VERSION: u32 = 1;
+// Environment variables starting with this override single keys, e.g. LIMAGE_QEMU_BINARY
+pub const ENV_PREFIX: &str = "LIMAGE_";
+
+// A LIMAGE_* variable and the key it overrides
+#[derive(Clone, Debug)]
+pub struct EnvOverride {
+    pub variable: String,
+    pub key: Vec<String>,
+    pub value: String,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LimageConfig {
+    // Format of the file, bumped when a key changes meaning; `limage config migrate` updates it
+    #[serde(default = "default_config_version")]
+    pub config_version: u32,
     #[serde(default = "default_build_config")]
     pub build: BuildConfig,
     #[serde(default = "default_qemu_config")]
     pub qemu: QemuConfig,
     #[serde(default = "default_test_config")]
     pub test: TestConfig,
-    #[serde(default)]
+    #[serde(default = "default_runner_config")]
+    pub runner: RunnerConfig,
+    #[serde(default = "default_run_config")]
+    pub run: RunConfig,
+    #[serde(default = "default_package_config")]
+    pub package: PackageConfig,
+    // Multi-VM setups started with `limage run --scenario <name>`
+    #[serde(default, rename = "scenario")]
+    pub scenarios: BTreeMap<String, ScenarioConfig>,
+    // A config file merged beneath this one, relative to this file's directory
+    #[serde(default, skip_serializing)]
+    pub extends: Option<String>,
+    // Settings for one host OS only, e.g. [host.macos.qemu], merged over the rest of the file
+    #[serde(default, skip_serializing)]
+    pub host: BTreeMap<String, Table>,
+    // Pre-0.6 location of run modes, kept as read from the file; they are folded into
+    // `qemu.modes` on load, which is what limage uses
+    #[deprecated(note = "run modes live in `qemu.modes`; `modes()` lists them all")]
+    #[serde(default, skip_serializing)]
     pub modes: HashMap<String, ModeConfig>,
+    // Directory of the config file, which relative paths in it are resolved against; empty for
+    // the current directory
+    #[serde(skip)]
+    pub root: PathBuf,
+    // Keys in the file that match no setting, reported by `unknown_keys`
+    #[serde(skip)]
+    unknown_keys: Vec<UnknownKey>,
+    // Old spellings of keys in the file, reported by `deprecated_keys`
+    #[serde(skip)]
+    deprecated_keys: Vec<Rename>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,6 +88,63 @@ pub struct BuildConfig {
     pub limine_path: PathBuf,
     #[serde(default = "default_iso_root")]
     pub iso_root: PathBuf,
+    // Where cargo's kernel is looked for; unset, they come from the cargo config and the package
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub kernel_bin: Option<String>,
+    // A target JSON limage writes if it is missing and builds the kernel for, with build-std
+    #[serde(default)]
+    pub target_json: Option<PathBuf>,
+    // Whether OVMF and Limine are fetched and the image written by external tools or built-in code
+    #[serde(default)]
+    pub tools: ToolBackend,
+}
+
+// What fetches the firmware and bootloader and writes the image. `external` runs curl, git, make
+// and xorriso; `native` uses limage's own HTTP client, tarball fetch and ISO writer. Each is only
+// available with the cargo feature of the same name (`external-tools`, `native`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolBackend {
+    External,
+    Native,
+}
+
+impl Default for ToolBackend {
+    fn default() -> Self {
+        if cfg!(feature = "external-tools") {
+            ToolBackend::External
+        } else {
+            ToolBackend::Native
+        }
+    }
+}
+
+impl ToolBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            ToolBackend::External => "external",
+            ToolBackend::Native => "native",
+        }
+    }
+
+    // The cargo feature limage needs to be built with for this backend
+    pub fn feature(self) -> &'static str {
+        match self {
+            ToolBackend::External => "external-tools",
+            ToolBackend::Native => "native",
+        }
+    }
+
+    pub fn is_available(self) -> bool {
+        match self {
+            ToolBackend::External => cfg!(feature = "external-tools"),
+            ToolBackend::Native => cfg!(feature = "native"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -39,6 +155,266 @@ pub struct QemuConfig {
     pub base_args: Vec<String>,
     #[serde(default)]
     pub extra_args: Vec<String>,
+    #[serde(default)]
+    pub accel: Option<String>,
+    #[serde(default)]
+    pub headless: bool,
+    #[serde(default)]
+    pub modes: HashMap<String, ModeConfig>,
+    #[serde(default)]
+    pub screenshot_on_exit: Option<PathBuf>,
+    #[serde(default)]
+    pub snapshot: bool,
+    #[serde(default)]
+    pub drives: Vec<DriveConfig>,
+    #[serde(default)]
+    pub network: Option<NetworkConfig>,
+    #[serde(default)]
+    pub usb: Option<UsbConfig>,
+    #[serde(default)]
+    pub tpm: bool,
+    #[serde(default)]
+    pub serials: Vec<SerialConfig>,
+    #[serde(default)]
+    pub debugcon: Option<SerialConfig>,
+    // Device tree passed with -dtb and staged into the image for Limine's DTB request
+    #[serde(default)]
+    pub dtb: Option<PathBuf>,
+    #[serde(default)]
+    pub fw_cfg: Vec<FwCfgConfig>,
+    #[serde(default)]
+    pub monitor: Option<String>,
+    #[serde(default)]
+    pub firmware: Firmware,
+    #[serde(default = "default_qemu_memory")]
+    pub memory: String,
+    #[serde(default)]
+    pub cpus: Option<u32>,
+    #[serde(default)]
+    pub cpu_model: Option<String>,
+    #[serde(default)]
+    pub machine: Option<String>,
+    #[serde(default)]
+    pub display: Option<String>,
+    // Hide guest serial lines below this level; also enables coloring of ordinary runs
+    #[serde(default)]
+    pub log_level: Option<GuestLogLevel>,
+    // Forward host keystrokes to the guest's stdio serial in non-test runs
+    #[serde(default)]
+    pub interactive: bool,
+    // gdbstub port used by `limage run --wait-debugger`
+    #[serde(default = "default_gdb_port")]
+    pub gdb_port: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    #[default]
+    Uefi,
+    Bios,
+}
+
+// Behavior of `limage run`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunConfig {
+    // Rebuild the image before booting it; `limage run --no-build` skips a single build
+    #[serde(default = "default_true")]
+    pub auto_build: bool,
+    // Ask for a run mode when several are configured and none is given on the command line
+    #[serde(default)]
+    pub pick_mode: bool,
+    // Runs kept with their serial output for `limage logs`; 0 stops recording
+    #[serde(default = "default_run_history")]
+    pub history: usize,
+}
+
+// Distributable archives written by `limage package`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackageConfig {
+    #[serde(default = "default_package_out_dir")]
+    pub out_dir: PathBuf,
+    // Archive name without extension; {name}, {version}, {date} and {commit} are substituted
+    #[serde(default = "default_package_name")]
+    pub name: String,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    // Unstripped kernel ELF shipped alongside the image for debugging; unset, the kernel cargo
+    // built, found the way the build finds it
+    #[serde(default)]
+    pub symbols: Option<PathBuf>,
+    // Additional files copied into the archive root
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.zst")]
+    #[value(name = "tar.zst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    #[value(name = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunnerConfig {
+    #[serde(default)]
+    pub backend: BackendKind,
+    #[serde(default = "default_bochs_config")]
+    pub bochs: BochsConfig,
+    #[serde(default = "default_virtualbox_config")]
+    pub virtualbox: VirtualBoxConfig,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Qemu,
+    Bochs,
+    VirtualBox,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BochsConfig {
+    #[serde(default = "default_bochs_binary")]
+    pub binary: String,
+    #[serde(default = "default_bochs_megs")]
+    pub megs: u32,
+    #[serde(default)]
+    pub display_library: Option<String>,
+    // Raw bochsrc lines appended to the generated configuration
+    #[serde(default)]
+    pub extra_config: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VirtualBoxConfig {
+    #[serde(default = "default_virtualbox_vm_name")]
+    pub vm_name: String,
+    #[serde(default = "default_virtualbox_memory_mb")]
+    pub memory_mb: u32,
+    #[serde(default)]
+    pub headless: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScenarioConfig {
+    #[serde(default)]
+    pub description: Option<String>,
+    // Multicast group joined by every VM's scenario NIC, forming one shared L2 segment
+    #[serde(default = "default_scenario_mcast")]
+    pub mcast: String,
+    #[serde(default = "default_scenario_nic_model")]
+    pub nic_model: String,
+    #[serde(default, rename = "vm")]
+    pub vms: Vec<ScenarioVmConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScenarioVmConfig {
+    pub name: String,
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    #[serde(default)]
+    pub mac: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DriveConfig {
+    pub file: String,
+    #[serde(default = "default_drive_format")]
+    pub format: String,
+    // Any QEMU `if=` value, plus `nvme` and `ahci` which attach the matching controller
+    #[serde(default = "default_drive_interface")]
+    pub interface: String,
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde(default = "default_network_model")]
+    pub model: String,
+    // `user` (SLIRP) or `tap`
+    #[serde(default = "default_network_backend")]
+    pub backend: String,
+    #[serde(default)]
+    pub hostfwd: Vec<String>,
+    #[serde(default)]
+    pub ifname: Option<String>,
+    #[serde(default)]
+    pub mac: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsbConfig {
+    // `uhci`, `ehci` or `xhci`
+    #[serde(default = "default_usb_controller")]
+    pub controller: String,
+    #[serde(default)]
+    pub devices: Vec<UsbDeviceConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsbDeviceConfig {
+    // Any QEMU USB device, e.g. `usb-kbd`, `usb-tablet`, `usb-storage` or `usb-host`
+    #[serde(rename = "type")]
+    pub kind: String,
+    // Backing image for `usb-storage`
+    #[serde(default)]
+    pub file: Option<String>,
+    // `vid:pid` of the host device for `usb-host` passthrough
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SerialConfig {
+    // `stdio`, `pty`, `file:<path>` or `tcp:<port>`
+    pub target: String,
+}
+
+// A firmware config blob the guest reads through QEMU's fw_cfg interface
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FwCfgConfig {
+    // The fw_cfg file name, e.g. "opt/org.example/config"
+    pub name: String,
+    // Exactly one of a host file, which may use placeholders, or an inline string
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub string: Option<String>,
+}
+
+impl SerialConfig {
+    fn log_file(&self, placeholders: &[(&str, String)]) -> Result<Option<PathBuf>, ConfigError> {
+        self.target
+            .strip_prefix("file:")
+            .map(|path| expand_placeholders(path, placeholders).map(PathBuf::from))
+            .transpose()
+    }
+
+    fn chardev(&self, placeholders: &[(&str, String)]) -> Result<String, ConfigError> {
+        match self.target.strip_prefix("tcp:") {
+            Some(port) => Ok(format!("tcp::{},server=on,wait=off", port)),
+            None => expand_placeholders(&self.target, placeholders),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -51,12 +427,156 @@ pub struct TestConfig {
     pub no_reboot: bool,
     #[serde(default)]
     pub extra_args: Vec<String>,
+    #[serde(default = "default_test_panic_pattern")]
+    pub panic_pattern: String,
+    #[serde(default = "default_exit_device")]
+    pub exit_device: ExitDeviceConfig,
+    // QEMU exit codes (as strings, TOML keys) mapped to test outcomes
+    #[serde(default)]
+    pub exit_codes: BTreeMap<String, TestOutcome>,
+    #[serde(default)]
+    pub retries: u32,
+    // Fail the run early once the guest has printed nothing to serial for this long
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u32>,
+    // How long each `#[test_case]` of a limage-test guest may run, handed to the guest on its
+    // command line so one hung test does not use up `timeout_secs`
+    #[serde(default)]
+    pub case_timeout_secs: Option<u32>,
+    // How long a timed-out guest gets to honor an ACPI shutdown before QEMU is stopped
+    #[serde(default = "default_test_shutdown_grace")]
+    pub shutdown_grace_secs: u32,
+    #[serde(default = "default_expect_config")]
+    pub expect: ExpectConfig,
+    // Attach gdb to a panicked guest through QEMU's gdbstub and save registers and a backtrace
+    #[serde(default)]
+    pub gdb_on_panic: bool,
+    #[serde(default = "default_gdb_binary")]
+    pub gdb_binary: String,
+    #[serde(default)]
+    pub gdb_commands: Vec<String>,
+    // Resolve the kernel addresses in panic output to functions and source lines
+    #[serde(default = "default_true")]
+    pub symbolize_panics: bool,
+}
+
+impl TestConfig {
+    // The kernel command line arguments a limage-test guest reads these settings from
+    pub fn guest_cmdline(&self) -> Vec<String> {
+        // Written values come back as `(value << 1) | 1`; `validate` makes sure the code is odd
+        let success = (self.success_exit_code - 1) >> 1;
+        let mut args = vec![
+            format!(
+                "{}={:#x}",
+                limage_test::EXIT_PORT_ARG,
+                self.exit_device.iobase
+            ),
+            format!(
+                "{}={}",
+                limage_test::EXIT_PORT_SIZE_ARG,
+                self.exit_device.iosize
+            ),
+            format!("{}={:#x}", limage_test::SUCCESS_VALUE_ARG, success),
+            // Any other status fails the test, and the neighbouring value keeps the default 0x11
+            format!("{}={:#x}", limage_test::FAILURE_VALUE_ARG, success ^ 1),
+        ];
+        if let Some(secs) = self.case_timeout_secs {
+            args.push(format!(
+                "{}={}",
+                limage_test::TIMEOUT_ARG,
+                u64::from(secs) * 1000
+            ));
+        }
+        args
+    }
+}
+
+// Golden transcript the guest's stdio serial output is compared against
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExpectConfig {
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default = "default_true")]
+    pub normalize_timestamps: bool,
+    #[serde(default = "default_true")]
+    pub normalize_addresses: bool,
+    #[serde(default)]
+    pub replace: Vec<ReplaceRule>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplaceRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub with: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExitDeviceConfig {
+    #[serde(default = "default_exit_device_iobase")]
+    pub iobase: u16,
+    #[serde(default = "default_exit_device_iosize")]
+    pub iosize: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestOutcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ModeConfig {
     #[serde(default)]
-    pub args: Vec<String>,
+    pub description: Option<String>,
+    #[serde(default, alias = "args")]
+    pub extra_args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub ovmf_code: Option<PathBuf>,
+    #[serde(default)]
+    pub ovmf_vars: Option<PathBuf>,
+    #[serde(default)]
+    pub firmware: Option<Firmware>,
+    // Another mode this one starts from: its arguments come first and its settings apply underneath
+    #[serde(default)]
+    pub inherits: Option<String>,
+    // [qemu] and [test] settings replaced while this mode runs; anything not set is inherited
+    #[serde(default)]
+    pub qemu: Option<Table>,
+    #[serde(default)]
+    pub test: Option<Table>,
+}
+
+impl ModeConfig {
+    // `self` layered on top of the mode it inherits from
+    fn inherit(self, parent: ModeConfig) -> ModeConfig {
+        let mut extra_args = parent.extra_args;
+        extra_args.extend(self.extra_args);
+        let mut env = parent.env;
+        env.extend(self.env);
+        let layer = |parent: Option<Table>, child: Option<Table>| match (parent, child) {
+            (Some(mut parent), Some(child)) => {
+                merge_tables(&mut parent, &child);
+                Some(parent)
+            }
+            (parent, child) => child.or(parent),
+        };
+        ModeConfig {
+            description: self.description.or(parent.description),
+            extra_args,
+            env,
+            ovmf_code: self.ovmf_code.or(parent.ovmf_code),
+            ovmf_vars: self.ovmf_vars.or(parent.ovmf_vars),
+            firmware: self.firmware.or(parent.firmware),
+            inherits: None,
+            qemu: layer(parent.qemu, self.qemu),
+            test: layer(parent.test, self.test),
+        }
+    }
 }
 
 fn default_build_config() -> BuildConfig {
@@ -67,6 +587,11 @@ fn default_build_config() -> BuildConfig {
         ovmf_path: default_ovmf_path(),
         limine_path: default_limine_path(),
         iso_root: default_iso_root(),
+        target: None,
+        profile: None,
+        kernel_bin: None,
+        target_json: None,
+        tools: ToolBackend::default(),
     }
 }
 
@@ -75,6 +600,29 @@ fn default_qemu_config() -> QemuConfig {
         binary: default_qemu_binary(),
         base_args: default_qemu_args(),
         extra_args: Vec::new(),
+        accel: None,
+        headless: false,
+        modes: HashMap::new(),
+        screenshot_on_exit: None,
+        snapshot: false,
+        drives: Vec::new(),
+        network: None,
+        usb: None,
+        tpm: false,
+        serials: Vec::new(),
+        debugcon: None,
+        dtb: None,
+        fw_cfg: Vec::new(),
+        monitor: None,
+        firmware: Firmware::default(),
+        memory: default_qemu_memory(),
+        cpus: None,
+        cpu_model: None,
+        machine: None,
+        display: None,
+        log_level: None,
+        interactive: false,
+        gdb_port: default_gdb_port(),
     }
 }
 
@@ -84,9 +632,100 @@ fn default_test_config() -> TestConfig {
         success_exit_code: default_test_success_code(),
         no_reboot: default_test_no_reboot(),
         extra_args: Vec::new(),
+        panic_pattern: default_test_panic_pattern(),
+        exit_device: default_exit_device(),
+        exit_codes: BTreeMap::new(),
+        retries: 0,
+        idle_timeout_secs: None,
+        case_timeout_secs: None,
+        shutdown_grace_secs: default_test_shutdown_grace(),
+        expect: default_expect_config(),
+        gdb_on_panic: false,
+        gdb_binary: default_gdb_binary(),
+        gdb_commands: Vec::new(),
+        symbolize_panics: true,
+    }
+}
+
+fn default_run_config() -> RunConfig {
+    RunConfig {
+        auto_build: true,
+        pick_mode: false,
+        history: default_run_history(),
+    }
+}
+
+fn default_run_history() -> usize {
+    20
+}
+
+fn default_package_config() -> PackageConfig {
+    PackageConfig {
+        out_dir: default_package_out_dir(),
+        name: default_package_name(),
+        format: ArchiveFormat::default(),
+        symbols: None,
+        include: Vec::new(),
+    }
+}
+
+fn default_package_out_dir() -> PathBuf {
+    PathBuf::from("dist")
+}
+
+fn default_package_name() -> String {
+    "{name}-{version}".to_string()
+}
+
+fn default_runner_config() -> RunnerConfig {
+    RunnerConfig {
+        backend: BackendKind::default(),
+        bochs: default_bochs_config(),
+        virtualbox: default_virtualbox_config(),
+    }
+}
+
+fn default_scenario_mcast() -> String {
+    "230.0.0.1:1234".to_string()
+}
+
+fn default_scenario_nic_model() -> String {
+    "virtio-net".to_string()
+}
+
+fn default_bochs_config() -> BochsConfig {
+    BochsConfig {
+        binary: default_bochs_binary(),
+        megs: default_bochs_megs(),
+        display_library: None,
+        extra_config: Vec::new(),
+    }
+}
+
+fn default_virtualbox_config() -> VirtualBoxConfig {
+    VirtualBoxConfig {
+        vm_name: default_virtualbox_vm_name(),
+        memory_mb: default_virtualbox_memory_mb(),
+        headless: false,
     }
 }
 
+fn default_bochs_binary() -> String {
+    "bochs".to_string()
+}
+
+fn default_bochs_megs() -> u32 {
+    256
+}
+
+fn default_virtualbox_vm_name() -> String {
+    "limage".to_string()
+}
+
+fn default_virtualbox_memory_mb() -> u32 {
+    2048
+}
+
 fn default_image_path() -> PathBuf {
     PathBuf::from("target/kernel.iso")
 }
@@ -108,131 +747,1774 @@ fn default_qemu_binary() -> String {
 }
 
 fn default_qemu_args() -> Vec<String> {
+    vec!["-cdrom".to_string(), "{image}".to_string()]
+}
+
+fn default_gdb_port() -> u16 {
+    1234
+}
+
+fn default_qemu_memory() -> String {
+    "2G".to_string()
+}
+
+fn uefi_firmware_args() -> Vec<String> {
     vec![
-        "-m".to_string(),
-        "2G".to_string(),
-        "-cdrom".to_string(),
-        "{image}".to_string(),
         "-drive".to_string(),
-        "if=pflash,unit=0,format=raw,file={ovmf}/ovmf-code-x86_64.fd,readonly=on".to_string(),
+        "if=pflash,unit=0,format=raw,file={ovmf_code},readonly=on".to_string(),
         "-drive".to_string(),
-        "if=pflash,unit=1,format=raw,file={ovmf}/ovmf-vars-x86_64.fd".to_string(),
+        "if=pflash,unit=1,format=raw,file={ovmf_vars}".to_string(),
     ]
 }
 
-fn default_test_timeout() -> u32 {
-    300 // 5 minutes
+// Drops `-drive if=pflash,...` pairs that older configs carry in base_args
+fn strip_pflash_drives(args: &[String]) -> Vec<String> {
+    let mut stripped = Vec::with_capacity(args.len());
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        if arg == "-drive" && iter.peek().is_some_and(|spec| spec.contains("if=pflash")) {
+            iter.next();
+            continue;
+        }
+        stripped.push(arg.clone());
+    }
+    stripped
 }
 
-fn default_test_success_code() -> i32 {
-    33
+fn builtin_modes() -> BTreeMap<String, ModeConfig> {
+    let mode = |description: &str, args: &[&str]| ModeConfig {
+        description: Some(description.to_string()),
+        extra_args: args.iter().map(|arg| arg.to_string()).collect(),
+        ..ModeConfig::default()
+    };
+
+    BTreeMap::from([
+        (
+            "bios".to_string(),
+            ModeConfig {
+                firmware: Some(Firmware::Bios),
+                ..mode("Boot through SeaBIOS instead of OVMF", &[])
+            },
+        ),
+        (
+            "debug".to_string(),
+            mode("Start paused with a GDB stub on tcp::1234", &["-s", "-S"]),
+        ),
+        (
+            "trace".to_string(),
+            mode(
+                "Log interrupts and CPU resets to {target_dir}/limage/qemu.log, stop instead of rebooting",
+                &[
+                    "-d",
+                    "int,cpu_reset,guest_errors",
+                    "-D",
+                    "{target_dir}/limage/qemu.log",
+                    "-no-reboot",
+                    "-no-shutdown",
+                ],
+            ),
+        ),
+        (
+            "headless".to_string(),
+            mode(
+                "No display, guest serial on stdio",
+                &["-display", "none", "-serial", "stdio"],
+            ),
+        ),
+        (
+            "nographic".to_string(),
+            mode(
+                "Serial and QEMU monitor multiplexed on stdio",
+                &["-nographic"],
+            ),
+        ),
+    ])
 }
 
-fn default_test_no_reboot() -> bool {
-    true
+fn default_drive_format() -> String {
+    "raw".to_string()
 }
 
-impl LimageConfig {
-    pub fn load() -> Result<Self, ConfigError> {
-        let config_path = Path::new("limage_config.toml");
+fn default_drive_interface() -> String {
+    "ide".to_string()
+}
 
-        if config_path.exists() {
-            Self::from_file(config_path)
-        } else {
-            Ok(Self::default())
-        }
-    }
+fn default_network_model() -> String {
+    "virtio-net".to_string()
+}
 
-    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
-        let content =
-            std::fs::read_to_string(path).map_err(|e| ConfigError::ReadConfig { source: e })?;
+fn default_network_backend() -> String {
+    "user".to_string()
+}
 
-        toml::from_str(&content).map_err(|e| ConfigError::ParseConfig { source: e })
-    }
+fn default_usb_controller() -> String {
+    "xhci".to_string()
+}
 
-    pub fn get_mode_args(&self, mode: &str) -> Result<Vec<String>, ConfigError> {
-        self.modes
-            .get(mode)
-            .map(|m| m.args.clone())
-            .ok_or_else(|| ConfigError::ModeNotFound {
-                mode: mode.to_string(),
-            })
+// The nearest limage_config.toml, looking upwards from the current directory like cargo does for
+// Cargo.toml; one in the current directory is returned as a relative path
+pub fn find_config() -> Option<PathBuf> {
+    if Path::new(CONFIG_FILE).is_file() {
+        return Some(PathBuf::from(CONFIG_FILE));
     }
+    let dir = std::env::current_dir().ok()?;
+    dir.ancestors()
+        .skip(1)
+        .map(|dir| dir.join(CONFIG_FILE))
+        .find(|path| path.is_file())
+}
 
-    pub fn get_qemu_command(
-        &self,
-        image_path: &Path,
-        is_test: bool,
-        mode: Option<&str>,
-    ) -> Result<Vec<String>, ConfigError> {
-        let mut cmd = vec![self.qemu.binary.clone()];
+// Machine-wide defaults every project's config is layered over, e.g. the path of a custom QEMU
+// build: $XDG_CONFIG_HOME/limage/config.toml, ~/.config/limage/config.toml or, on Windows,
+// %APPDATA%\limage\config.toml
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("limage").join("config.toml"))
+}
 
-        for arg in &self.qemu.base_args {
-            cmd.push(
-                arg.replace("{image}", &image_path.display().to_string())
-                    .replace("{ovmf}", &self.build.ovmf_path.display().to_string()),
-            );
-        }
+// The user config as a table, after checking that it holds valid settings
+pub fn user_config() -> Result<Option<(PathBuf, Table)>, ConfigError> {
+    let Some(path) = user_config_path() else {
+        return Ok(None);
+    };
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ConfigError::ReadConfig { source: e }),
+    };
+    let table = parse_layer(&path, &content).map_err(|e| ConfigError::ParseUserConfig {
+        path: path.clone(),
+        source: e,
+    })?;
+    debug!("Layering the config over {:?}", path);
+    Ok(Some((path, table)))
+}
 
-        if let Some(mode_name) = mode {
-            let mode_args = self.get_mode_args(mode_name)?;
-            cmd.extend(mode_args);
-        }
+// Hosts a [host.<os>] section can apply to, named as in `std::env::consts::OS`
+pub const HOSTS: [&str; 3] = ["linux", "macos", "windows"];
 
-        cmd.extend(self.qemu.extra_args.clone());
+// The [host.<os>] section of `table` for the host limage runs on
+pub fn host_section(table: &Table) -> Option<&Table> {
+    table
+        .get("host")?
+        .as_table()?
+        .get(std::env::consts::OS)?
+        .as_table()
+}
 
-        // Add test-specific args
-        if is_test {
-            if self.test.no_reboot {
-                cmd.push("-no-reboot".to_string());
-            }
-            cmd.extend(self.test.extra_args.clone());
+// Checks every [host.<os>] section on its own, so a typo in the macOS settings also shows up on
+// Linux, and returns the one for this host
+fn check_host_sections(
+    table: &Table,
+    unknown_keys: &mut Vec<UnknownKey>,
+) -> Result<Option<Table>, ConfigError> {
+    let Some(Value::Table(hosts)) = table.get("host") else {
+        return Ok(None);
+    };
+    for (host, section) in hosts {
+        if !HOSTS.contains(&host.as_str()) {
+            unknown_keys.push(UnknownKey {
+                key: format!("host.{}", host),
+                suggestion: suggest::closest(host, &HOSTS).map(|known| format!("host.{}", known)),
+            });
+            continue;
         }
-
-        Ok(cmd)
+        let invalid = |reason: String| ConfigError::InvalidHost {
+            host: host.clone(),
+            reason,
+        };
+        let Value::Table(section) = section else {
+            return Err(invalid("expected a table".to_string()));
+        };
+        if let Some(key) = ["host", "extends"]
+            .into_iter()
+            .find(|key| section.contains_key(*key))
+        {
+            return Err(invalid(format!("`{}` cannot be set per host", key)));
+        }
+        let _: LimageConfig = serde_ignored::deserialize(Value::Table(section.clone()), |path| {
+            let key = UnknownKey::new(&path);
+            unknown_keys.push(UnknownKey {
+                key: format!("host.{}.{}", host, key.key),
+                suggestion: key
+                    .suggestion
+                    .map(|suggestion| format!("host.{}.{}", host, suggestion)),
+            });
+        })
+        .map_err(|e| invalid(e.to_string()))?;
     }
+    Ok(host_section(table).cloned())
+}
 
-    pub fn validate(&self) -> Result<(), ConfigError> {
-        /*// Create necessary directories if they don't exist
-        let dirs = [
-            (&self.build.ovmf_path, "OVMF"),
-            (&self.build.limine_path, "Limine"),
-            (&self.build.iso_root, "ISO root"),
-        ];
+// A config file merged beneath another one, checked on its own so errors point into it
+fn parse_layer(path: &Path, content: &str) -> Result<Table, toml::de::Error> {
+    let table: Table = toml::from_str(content)?;
+    let _: LimageConfig = serde_ignored::deserialize(
+        &mut toml::Deserializer::new(content),
+        |key: serde_ignored::Path| warn!("{}: {}", path.display(), UnknownKey::new(&key)),
+    )?;
+    Ok(table)
+}
 
-        for (path, name) in dirs {
-            if !path.exists() {
-                std::fs::create_dir_all(path).map_err(|e| ConfigError::CreateDirectory {
-                    path: path.to_path_buf(),
-                    name: name.to_string(),
-                    source: e,
-                })?;
-            }
+// The files `table` extends, nearest last, each one's `extends` resolved against its own directory
+pub fn extends_chain(
+    table: &Table,
+    dir: &Path,
+    origin: Option<&Path>,
+) -> Result<Vec<(PathBuf, Table)>, ConfigError> {
+    let mut seen: Vec<PathBuf> = origin.into_iter().map(Path::to_path_buf).collect();
+    let mut chain = Vec::new();
+    let mut next = extends_path(table, dir)?;
+    while let Some(path) = next {
+        let canonical = path.canonicalize().map_err(|e| ConfigError::ReadExtends {
+            path: path.clone(),
+            source: e,
+        })?;
+        if seen.contains(&canonical) {
+            seen.push(canonical);
+            return Err(ConfigError::ExtendsCycle {
+                chain: seen
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> "),
+            });
+        }
+        let content = std::fs::read_to_string(&path).map_err(|e| ConfigError::ReadExtends {
+            path: path.clone(),
+            source: e,
+        })?;
+        let base = parse_layer(&path, &content).map_err(|e| ConfigError::ParseExtends {
+            path: path.clone(),
+            source: e,
+        })?;
+        debug!("Extending {:?}", path);
+        next = extends_path(&base, canonical.parent().unwrap_or(Path::new("")))?;
+        seen.push(canonical);
+        chain.push((path, base));
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+fn extends_path(table: &Table, dir: &Path) -> Result<Option<PathBuf>, ConfigError> {
+    match table.get("extends") {
+        None => Ok(None),
+        Some(Value::String(path)) => Ok(Some(dir.join(path))),
+        Some(value) => Err(ConfigError::InvalidExtends {
+            value: value.to_string(),
+        }),
+    }
+}
+
+// Whether the dotted `key` (as reported for unknown keys) is set in `table`
+fn contains_key(table: &Table, key: &str) -> bool {
+    let mut value = None;
+    let mut current = Some(table);
+    for segment in key.split('.') {
+        value = current.and_then(|table| table.get(segment));
+        current = value.and_then(Value::as_table);
+    }
+    value.is_some()
+}
+
+fn default_target_dir() -> PathBuf {
+    std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("target"))
+}
+
+// LIMAGE_* variables in the environment, resolved against the keys the defaults and `file` define.
+// Underscores separate both tables and words, so the longest matching table name wins and the rest
+// of the variable names the key, e.g. LIMAGE_BUILD_IMAGE_PATH is `build.image_path`
+pub fn env_overrides(file: &Table) -> Vec<EnvOverride> {
+    let schema = table_schema(file);
+    let mut overrides: Vec<EnvOverride> = std::env::vars_os()
+        .filter_map(|(variable, value)| {
+            Some((variable.into_string().ok()?, value.into_string().ok()?))
+        })
+        .filter_map(|(variable, value)| {
+            let name = variable.strip_prefix(ENV_PREFIX)?;
+            if name.is_empty() {
+                return None;
+            }
+            let tokens: Vec<&str> = name.split('_').collect();
+            Some(EnvOverride {
+                key: env_key(&schema, &tokens),
+                variable,
+                value,
+            })
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.variable.cmp(&b.variable));
+    overrides
+}
+
+// The defaults with `file` merged over them, so both built-in keys and user-named tables
+// (run modes, scenarios) can be addressed
+fn table_schema(file: &Table) -> Table {
+    let mut schema = match Value::try_from(LimageConfig::default()) {
+        Ok(Value::Table(table)) => table,
+        _ => Table::new(),
+    };
+    merge_tables(&mut schema, file);
+    schema
+}
+
+// `base` with the keys of `overrides` replaced; going through TOML lets a mode override any field
+fn override_section<T>(base: &T, section: &str, overrides: Table) -> Result<T, String>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+{
+    let mut table = match Value::try_from(base) {
+        Ok(Value::Table(table)) => table,
+        Ok(_) => Table::new(),
+        Err(e) => return Err(e.to_string()),
+    };
+    merge_tables(&mut table, &overrides);
+    let mut unknown = Vec::new();
+    let parsed = serde_ignored::deserialize(Value::Table(table), |path| {
+        unknown.push(UnknownKey::in_table(Some(section), &path))
+    })
+    .map_err(|e| e.to_string())?;
+    match unknown.first() {
+        Some(key) => Err(key.to_string()),
+        None => Ok(parsed),
+    }
+}
+
+pub fn merge_tables(base: &mut Table, overlay: &Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge_tables(base, overlay),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn env_key(schema: &Table, tokens: &[&str]) -> Vec<String> {
+    let env_name = |key: &str| key.to_uppercase().replace('-', "_");
+    for end in (1..tokens.len()).rev() {
+        let name = tokens[..end].join("_");
+        if let Some((key, Value::Table(child))) =
+            schema.iter().find(|(key, _)| env_name(key) == name)
+        {
+            let mut path = vec![key.clone()];
+            path.extend(env_key(child, &tokens[end..]));
+            return path;
+        }
+    }
+
+    // Unset optional keys are missing from the schema, so fall back to the lowercased name
+    let name = tokens.join("_");
+    let key = schema
+        .keys()
+        .find(|key| env_name(key) == name)
+        .cloned()
+        .unwrap_or_else(|| name.to_lowercase());
+    vec![key]
+}
+
+// Strings stay strings; everything else is parsed as a TOML value, e.g. `true`, `30` or `["-s"]`
+fn set_env_value(table: &mut Table, schema: &Table, env: &EnvOverride) {
+    let Some((key, parents)) = env.key.split_last() else {
+        return;
+    };
+    let mut table = table;
+    let mut schema = Some(schema);
+    for parent in parents {
+        let entry = table
+            .entry(parent.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+        table = entry.as_table_mut().expect("entry was just made a table");
+        schema = schema
+            .and_then(|schema| schema.get(parent))
+            .and_then(Value::as_table);
+    }
+
+    let is_string = schema
+        .and_then(|schema| schema.get(key))
+        .is_some_and(Value::is_str);
+    let value = if is_string {
+        Value::String(env.value.clone())
+    } else {
+        toml::from_str::<Table>(&format!("value = {}", env.value))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or_else(|| Value::String(env.value.clone()))
+    };
+    table.insert(key.clone(), value);
+}
+
+// Placeholders substituted in QEMU arguments, drive files and serial targets, besides
+// `{env:VAR}` for environment variables
+pub const PLACEHOLDERS: [&str; 10] = [
+    "image",
+    "ovmf_code",
+    "ovmf_vars",
+    "ovmf",
+    "target_dir",
+    "kernel",
+    "iso_root",
+    "arch",
+    "mode",
+    "project_root",
+];
+
+// Only lowercase names and `env:` count as placeholders, so JSON arguments such as
+// `-device {"driver":"virtio-net-pci"}` pass through untouched
+pub const PLACEHOLDER_PATTERN: &str = r"\{([a-z_]+|env:[^{}]*)\}";
+
+fn expand_placeholders(arg: &str, placeholders: &[(&str, String)]) -> Result<String, ConfigError> {
+    let pattern = Regex::new(PLACEHOLDER_PATTERN).expect("placeholder pattern is valid");
+    let mut expanded = String::new();
+    let mut end = 0;
+    for caps in pattern.captures_iter(arg) {
+        let whole = caps.get(0).expect("capture 0 is the whole match");
+        expanded.push_str(&arg[end..whole.start()]);
+        end = whole.end();
+
+        let name = &caps[1];
+        let value = match name.strip_prefix("env:") {
+            Some(variable) => {
+                std::env::var(variable).map_err(|_| ConfigError::UnsetEnvPlaceholder {
+                    variable: variable.to_string(),
+                    arg: arg.to_string(),
+                })?
+            }
+            None => placeholders
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, value)| value.clone())
+                .ok_or_else(|| ConfigError::UnknownPlaceholder {
+                    placeholder: name.to_string(),
+                    arg: arg.to_string(),
+                })?,
+        };
+        expanded.push_str(&value);
+    }
+    expanded.push_str(&arg[end..]);
+    Ok(expanded)
+}
+
+fn fw_cfg_args(
+    entries: &[FwCfgConfig],
+    placeholders: &[(&str, String)],
+) -> Result<Vec<String>, ConfigError> {
+    let mut args = Vec::new();
+    for entry in entries {
+        let value = match (&entry.file, &entry.string) {
+            (Some(file), _) => format!("file={}", expand_placeholders(file, placeholders)?),
+            (None, string) => format!("string={}", string.as_deref().unwrap_or_default()),
+        };
+        // QEMU reads a doubled comma as a literal one
+        let spec = format!("name={},{}", entry.name, value.replace(',', ",,"));
+        args.extend(["-fw_cfg".to_string(), spec]);
+    }
+    Ok(args)
+}
+
+fn drive_args(
+    drives: &[DriveConfig],
+    placeholders: &[(&str, String)],
+) -> Result<Vec<String>, ConfigError> {
+    // The ports of one AHCI controller; further drives get another controller
+    const AHCI_PORTS: usize = 6;
+    let mut args = Vec::new();
+    let mut ahci_drives = 0;
+
+    for (index, drive) in drives.iter().enumerate() {
+        let id = format!("limage-drive{}", index);
+        let interface = match drive.interface.as_str() {
+            "nvme" | "ahci" => "none",
+            other => other,
+        };
+
+        let mut spec = format!(
+            "file={},format={},if={}",
+            expand_placeholders(&drive.file, placeholders)?,
+            drive.format,
+            interface
+        );
+        if interface == "none" {
+            spec.push_str(&format!(",id={}", id));
+        }
+        if drive.readonly {
+            spec.push_str(",readonly=on");
+        }
+        args.extend(["-drive".to_string(), spec]);
+
+        match drive.interface.as_str() {
+            "nvme" => args.extend([
+                "-device".to_string(),
+                format!("nvme,drive={},serial=limage{}", id, index),
+            ]),
+            "ahci" => {
+                let controller = match ahci_drives / AHCI_PORTS {
+                    0 => "ahci".to_string(),
+                    n => format!("ahci{}", n),
+                };
+                let port = ahci_drives % AHCI_PORTS;
+                if port == 0 {
+                    args.extend(["-device".to_string(), format!("ahci,id={}", controller)]);
+                }
+                args.extend([
+                    "-device".to_string(),
+                    format!("ide-hd,drive={},bus={}.{}", id, controller, port),
+                ]);
+                ahci_drives += 1;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(args)
+}
+
+fn network_args(network: &NetworkConfig) -> Vec<String> {
+    const NETDEV_ID: &str = "limage-net0";
+
+    let mut netdev = format!("{},id={}", network.backend, NETDEV_ID);
+    match network.backend.as_str() {
+        "tap" => {
+            if let Some(ifname) = &network.ifname {
+                netdev.push_str(&format!(",ifname={}", ifname));
+            }
+            netdev.push_str(",script=no,downscript=no");
+        }
+        _ => {
+            for rule in &network.hostfwd {
+                netdev.push_str(&format!(",hostfwd={}", rule));
+            }
+        }
+    }
+
+    let model = match network.model.as_str() {
+        "virtio-net" => "virtio-net-pci",
+        other => other,
+    };
+    let mut device = format!("{},netdev={}", model, NETDEV_ID);
+    if let Some(mac) = &network.mac {
+        device.push_str(&format!(",mac={}", mac));
+    }
+
+    vec!["-netdev".to_string(), netdev, "-device".to_string(), device]
+}
+
+fn usb_args(usb: &UsbConfig, placeholders: &[(&str, String)]) -> Result<Vec<String>, ConfigError> {
+    const BUS_ID: &str = "limage-usb";
+
+    let controller = match usb.controller.as_str() {
+        "uhci" => "piix3-usb-uhci",
+        "ehci" => "usb-ehci",
+        _ => "qemu-xhci",
+    };
+    let mut args = vec![
+        "-device".to_string(),
+        format!("{},id={}", controller, BUS_ID),
+    ];
+
+    for (index, device) in usb.devices.iter().enumerate() {
+        let mut spec = format!("{},bus={}.0", device.kind, BUS_ID);
+        match device.kind.as_str() {
+            "usb-storage" => {
+                let drive_id = format!("limage-usb-drive{}", index);
+                let file = device.file.as_deref().unwrap_or_default();
+                args.extend([
+                    "-drive".to_string(),
+                    format!(
+                        "if=none,id={},format=raw,file={}",
+                        drive_id,
+                        expand_placeholders(file, placeholders)?
+                    ),
+                ]);
+                spec.push_str(&format!(",drive={}", drive_id));
+            }
+            "usb-host" => {
+                if let Some((vid, pid)) = device.host.as_deref().and_then(|h| h.split_once(':')) {
+                    spec.push_str(&format!(",vendorid=0x{},productid=0x{}", vid, pid));
+                }
+            }
+            _ => {}
+        }
+        args.extend(["-device".to_string(), spec]);
+    }
+
+    Ok(args)
+}
+
+// `nographic`, `vnc[:[<host>:]<port>]`, `spice[:[<host>:]<port>]`, or any `-display` backend
+// (gtk, sdl, none, ...). VNC and SPICE have no password, so they listen on localhost unless a
+// host is given
+fn display_args(display: &str) -> Result<Vec<String>, ConfigError> {
+    const LOCALHOST: &str = "127.0.0.1";
+    let invalid = || ConfigError::InvalidDisplay {
+        display: display.to_string(),
+    };
+    let (backend, option) = match display.split_once(':') {
+        Some((backend, option)) => (backend, Some(option)),
+        None => (display, None),
+    };
+    let (host, port) = match option.map(|option| option.rsplit_once(':').unwrap_or(("", option))) {
+        Some((host, port)) => (host, Some(port)),
+        None => ("", None),
+    };
+    let host = if host.is_empty() { LOCALHOST } else { host };
+    let port = |default: u16| -> Result<u16, ConfigError> {
+        port.map_or(Ok(default), |port| port.parse().map_err(|_| invalid()))
+    };
+
+    let args = match backend {
+        "nographic" => vec!["-nographic".to_string()],
+        "vnc" => {
+            let display_number = port(5900)?.checked_sub(5900).ok_or_else(invalid)?;
+            vec![
+                "-display".to_string(),
+                "none".to_string(),
+                "-vnc".to_string(),
+                format!("{}:{}", host, display_number),
+            ]
+        }
+        "spice" => vec![
+            "-display".to_string(),
+            "none".to_string(),
+            "-spice".to_string(),
+            format!(
+                "addr={},port={},disable-ticketing=on",
+                host.trim_start_matches('[').trim_end_matches(']'),
+                port(5930)?
+            ),
+        ],
+        "" => return Err(invalid()),
+        _ => vec!["-display".to_string(), display.to_string()],
+    };
+    Ok(args)
+}
+
+fn default_test_timeout() -> u32 {
+    300 // 5 minutes
+}
+
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
+// The guest harness in limage-test exits with these, so a kernel using it needs no `[test]` setup
+fn default_test_success_code() -> i32 {
+    limage_test::SUCCESS_EXIT_STATUS
+}
+
+fn default_test_no_reboot() -> bool {
+    true
+}
+
+fn default_test_panic_pattern() -> String {
+    "panicked at".to_string()
+}
+
+fn default_expect_config() -> ExpectConfig {
+    ExpectConfig {
+        file: None,
+        normalize_timestamps: true,
+        normalize_addresses: true,
+        replace: Vec::new(),
+    }
+}
+
+fn default_gdb_binary() -> String {
+    "gdb".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_test_shutdown_grace() -> u32 {
+    5
+}
+
+fn default_exit_device() -> ExitDeviceConfig {
+    ExitDeviceConfig {
+        iobase: default_exit_device_iobase(),
+        iosize: default_exit_device_iosize(),
+    }
+}
+
+fn default_exit_device_iobase() -> u16 {
+    limage_test::EXIT_PORT
+}
+
+fn default_exit_device_iosize() -> u16 {
+    limage_test::EXIT_PORT_SIZE
+}
+
+impl LimageConfig {
+    // Uses the nearest limage_config.toml in the current directory or one of its parents
+    pub fn load() -> Result<Self, ConfigError> {
+        match find_config() {
+            Some(config_path) => Self::from_file(&config_path),
+            None => Self::parse("", Path::new(""), None),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::ReadConfig { source: e })?;
+        // Made absolute so that resolving a path twice leaves it unchanged
+        let root = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => {
+                dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())
+            }
+            _ => PathBuf::new(),
+        };
+        let origin = path.canonicalize().ok();
+        Self::parse(&content, &root, origin.as_deref())
+    }
+
+    // Parses the content of a config file with the LIMAGE_* environment variables layered over it.
+    // `origin` is the file itself, so that a base config extending it is reported as a cycle
+    fn parse(content: &str, root: &Path, origin: Option<&Path>) -> Result<Self, ConfigError> {
+        let table: Table =
+            toml::from_str(content).map_err(|e| ConfigError::ParseConfig { source: e })?;
+        // Beneath the file go the configs it extends, farthest first, and beneath those the user config
+        let layers: Vec<(PathBuf, Table)> = user_config()?
+            .into_iter()
+            .chain(extends_chain(&table, root, origin)?)
+            .collect();
+
+        // Keys serde skips over are collected instead of being dropped without a word
+        let mut unknown_keys = Vec::new();
+        let mut unknown = |path: serde_ignored::Path| unknown_keys.push(UnknownKey::new(&path));
+
+        // Deserializing the file directly keeps line numbers in parse errors
+        let mut config: Self =
+            serde_ignored::deserialize(&mut toml::Deserializer::new(content), &mut unknown)
+                .map_err(|e| ConfigError::ParseConfig { source: e })?;
+
+        // The [host.<os>] section for this host and then the LIMAGE_* variables go above the file
+        let mut layered = Table::new();
+        for (_, layer) in &layers {
+            merge_tables(&mut layered, layer);
+        }
+        merge_tables(&mut layered, &table);
+        let host = check_host_sections(&layered, &mut unknown_keys)?;
+        if let Some(section) = &host {
+            debug!("Applying [host.{}]", std::env::consts::OS);
+            merge_tables(&mut layered, section);
+        }
+        let overrides = env_overrides(&layered);
+        if !layers.is_empty() || host.is_some() || !overrides.is_empty() {
+            let schema = table_schema(&layered);
+            for env in &overrides {
+                debug!("{} overrides {}", env.variable, env.key.join("."));
+                set_env_value(&mut layered, &schema, env);
+            }
+            // Unknown keys of the file were collected above and those of the other layers are
+            // reported as they are read, so only the variables can add new ones
+            let mut env_unknown = Vec::new();
+            config = serde_ignored::deserialize(Value::Table(layered), |path| {
+                env_unknown.push(UnknownKey::new(&path))
+            })
+            .map_err(|e| match overrides.is_empty() {
+                true => ConfigError::ParseConfig { source: e },
+                false => ConfigError::EnvOverride {
+                    variables: overrides
+                        .iter()
+                        .map(|env| env.variable.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    source: e,
+                },
+            })?;
+            for key in env_unknown {
+                let from_layer = layers
+                    .iter()
+                    .map(|(_, layer)| layer)
+                    .chain(&host)
+                    .any(|layer| contains_key(layer, &key.key));
+                if !from_layer && !unknown_keys.iter().any(|known| known.key == key.key) {
+                    unknown_keys.push(key);
+                }
+            }
+        }
+
+        if config.config_version > CONFIG_VERSION {
+            return Err(ConfigError::NewerVersion {
+                version: config.config_version,
+            });
+        }
+        #[allow(deprecated)]
+        for (name, mode) in &config.modes {
+            config
+                .qemu
+                .modes
+                .entry(name.clone())
+                .or_insert_with(|| mode.clone());
+        }
+
+        config.unknown_keys = unknown_keys;
+        config.deprecated_keys = migrate::deprecated_keys(&table);
+        config.root = root.to_path_buf();
+        config.resolve_paths();
+        config.check_settings()?;
+        Ok(config)
+    }
+
+    // The checks every config passes on load, whether it was read from a file or built in code
+    pub(crate) fn check_settings(&self) -> Result<(), ConfigError> {
+        self.validate_modes()?;
+        self.validate_network()?;
+        self.validate_usb()?;
+        self.validate_serials()?;
+        self.validate_fw_cfg()?;
+        self.monitor_target()?;
+        self.validate_exit_codes()?;
+        self.validate_scenarios()?;
+        if !self.build.tools.is_available() {
+            return Err(ConfigError::ToolsUnavailable {
+                tools: self.build.tools.name(),
+                feature: self.build.tools.feature(),
+            });
+        }
+        Normalizer::new(&self.test.expect).map_err(|e| ConfigError::InvalidExpect { source: e })?;
+        if let Some(display) = &self.qemu.display {
+            display_args(display)?;
+        }
+        Ok(())
+    }
+
+    // The config as limage_config.toml content, every setting spelled out. Paths are written as
+    // the config holds them: resolved for a loaded config, as given for a built one
+    pub fn to_toml_string(&self) -> Result<String, toml::ser::Error> {
+        // Going through a Value puts plain keys before the tables, which TOML requires
+        toml::Value::try_from(self).and_then(|value| toml::to_string(&value))
+    }
+
+    pub fn modes(&self) -> BTreeMap<String, ModeConfig> {
+        let mut modes = builtin_modes();
+        modes.extend(self.qemu.modes.clone());
+        modes
+    }
+
+    // The mode with everything it inherits resolved
+    pub fn get_mode(&self, mode: &str) -> Result<ModeConfig, ConfigError> {
+        let modes = self.modes();
+        let mut chain = vec![mode.to_string()];
+        let mut resolved = modes
+            .get(mode)
+            .cloned()
+            .ok_or_else(|| ConfigError::ModeNotFound {
+                mode: mode.to_string(),
+            })?;
+        while let Some(parent) = resolved.inherits.clone() {
+            if chain.contains(&parent) {
+                chain.push(parent);
+                return Err(ConfigError::InvalidMode {
+                    mode: mode.to_string(),
+                    reason: format!("`inherits` forms a cycle: {}", chain.join(" -> ")),
+                });
+            }
+            let parent_mode =
+                modes
+                    .get(&parent)
+                    .cloned()
+                    .ok_or_else(|| ConfigError::InvalidMode {
+                        mode: chain[chain.len() - 1].clone(),
+                        reason: format!("inherits from unknown mode '{}'", parent),
+                    })?;
+            chain.push(parent);
+            resolved = ModeConfig {
+                inherits: parent_mode.inherits.clone(),
+                ..resolved.inherit(parent_mode)
+            };
+        }
+        Ok(resolved)
+    }
+
+    // The configuration a run in `mode` uses, with the mode's [qemu] and [test] settings applied
+    pub fn with_mode(&self, mode: &str) -> Result<LimageConfig, ConfigError> {
+        let resolved = self.get_mode(mode)?;
+        let invalid = |reason: String| ConfigError::InvalidMode {
+            mode: mode.to_string(),
+            reason,
+        };
+
+        let mut config = self.clone();
+        if let Some(overrides) = resolved.qemu {
+            if overrides.contains_key("modes") {
+                return Err(invalid("modes cannot be defined inside a mode".to_string()));
+            }
+            config.qemu = override_section(&self.qemu, "qemu", overrides)
+                .map_err(|e| invalid(format!("invalid override: {}", e)))?;
+            config.qemu.modes = self.qemu.modes.clone();
+        }
+        if let Some(overrides) = resolved.test {
+            config.test = override_section(&self.test, "test", overrides)
+                .map_err(|e| invalid(format!("invalid override: {}", e)))?;
+        }
+        config.resolve_paths();
+        Ok(config)
+    }
+
+    pub fn get_mode_args(&self, mode: &str) -> Result<Vec<String>, ConfigError> {
+        self.get_mode(mode).map(|m| m.extra_args)
+    }
+
+    fn validate_modes(&self) -> Result<(), ConfigError> {
+        for (name, mode) in &self.qemu.modes {
+            let invalid = |reason: String| ConfigError::InvalidMode {
+                mode: name.clone(),
+                reason,
+            };
+
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(invalid(
+                    "mode names must be non-empty and contain no whitespace".to_string(),
+                ));
+            }
+
+            for key in mode.env.keys() {
+                if key.is_empty() || key.contains(['=', '\0']) {
+                    return Err(invalid(format!(
+                        "invalid environment variable name {:?}",
+                        key
+                    )));
+                }
+            }
+
+            for firmware in [&mode.ovmf_code, &mode.ovmf_vars].into_iter().flatten() {
+                if !firmware.exists() {
+                    return Err(invalid(format!(
+                        "firmware file {:?} does not exist",
+                        firmware
+                    )));
+                }
+            }
+
+            // Catches unknown parents, cycles and overrides of the wrong type up front
+            self.with_mode(name)?;
+        }
+        Ok(())
+    }
+
+    fn validate_network(&self) -> Result<(), ConfigError> {
+        let Some(network) = &self.qemu.network else {
+            return Ok(());
+        };
+        let invalid = |reason: &str| ConfigError::InvalidNetwork {
+            reason: reason.to_string(),
+        };
+
+        match network.backend.as_str() {
+            "user" => {
+                if network.ifname.is_some() {
+                    return Err(invalid("`ifname` is only supported by the tap backend"));
+                }
+            }
+            "tap" => {
+                if !network.hostfwd.is_empty() {
+                    return Err(invalid("`hostfwd` is only supported by the user backend"));
+                }
+            }
+            _ => return Err(invalid("`backend` must be either \"user\" or \"tap\"")),
+        }
+
+        if network.hostfwd.iter().any(|rule| !rule.contains('-')) {
+            return Err(invalid("`hostfwd` rules must look like \"tcp::8080-:80\""));
+        }
+        Ok(())
+    }
+
+    fn validate_serials(&self) -> Result<(), ConfigError> {
+        for serial in self.qemu.serials.iter().chain(&self.qemu.debugcon) {
+            let target = serial.target.as_str();
+            let valid = match target.split_once(':') {
+                Some(("file", path)) => !path.is_empty(),
+                Some(("tcp", port)) => port.parse::<u16>().is_ok(),
+                None => matches!(target, "stdio" | "pty"),
+                _ => false,
+            };
+            if !valid {
+                return Err(ConfigError::InvalidSerial {
+                    target: target.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_fw_cfg(&self) -> Result<(), ConfigError> {
+        for entry in &self.qemu.fw_cfg {
+            let invalid = |reason: &str| ConfigError::InvalidFwCfg {
+                name: entry.name.clone(),
+                reason: reason.to_string(),
+            };
+            // QEMU reserves everything outside opt/ for its own files
+            if entry.name.strip_prefix("opt/").is_none_or(str::is_empty) {
+                return Err(invalid("the name must start with \"opt/\""));
+            }
+            if entry.name.len() > 55 {
+                return Err(invalid("the name must be at most 55 bytes long"));
+            }
+            if entry.file.is_some() == entry.string.is_some() {
+                return Err(invalid("set exactly one of `file` and `string`"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_scenarios(&self) -> Result<(), ConfigError> {
+        for (name, scenario) in &self.scenarios {
+            let invalid = |reason: String| ConfigError::InvalidScenario {
+                scenario: name.clone(),
+                reason,
+            };
+
+            if scenario.vms.is_empty() {
+                return Err(invalid(
+                    "at least one [[scenario.<name>.vm]] is required".to_string(),
+                ));
+            }
+            let mut names = Vec::new();
+            for vm in &scenario.vms {
+                if vm.name.is_empty() || vm.name.contains(['/', '\\', ' ']) {
+                    return Err(invalid(format!("invalid VM name {:?}", vm.name)));
+                }
+                if names.contains(&&vm.name) {
+                    return Err(invalid(format!("duplicate VM name {:?}", vm.name)));
+                }
+                names.push(&vm.name);
+                if let Some(mode) = &vm.mode {
+                    self.get_mode(mode)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_scenario(&self, name: &str) -> Result<ScenarioConfig, ConfigError> {
+        self.scenarios
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ConfigError::ScenarioNotFound {
+                scenario: name.to_string(),
+            })
+    }
+
+    // QEMU arguments attaching a VM to its scenario's shared multicast segment
+    pub fn scenario_network_args(
+        scenario: &ScenarioConfig,
+        index: usize,
+        vm: &ScenarioVmConfig,
+    ) -> Vec<String> {
+        const NETDEV_ID: &str = "limage-scenario0";
+
+        let model = match scenario.nic_model.as_str() {
+            "virtio-net" => "virtio-net-pci",
+            other => other,
+        };
+        let mac = vm
+            .mac
+            .clone()
+            .unwrap_or_else(|| format!("52:54:00:12:34:{:02x}", index + 1));
+        vec![
+            "-netdev".to_string(),
+            format!("socket,id={},mcast={}", NETDEV_ID, scenario.mcast),
+            "-device".to_string(),
+            format!("{},netdev={},mac={}", model, NETDEV_ID, mac),
+        ]
+    }
+
+    fn validate_exit_codes(&self) -> Result<(), ConfigError> {
+        for code in self.test.exit_codes.keys() {
+            if code.parse::<i32>().is_err() {
+                return Err(ConfigError::InvalidExitCode { code: code.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    // Guest-defined mapping first, then the plain success code
+    pub fn test_outcome(&self, exit_code: i32) -> TestOutcome {
+        self.test
+            .exit_codes
+            .iter()
+            .find(|(code, _)| code.parse() == Ok(exit_code))
+            .map(|(_, outcome)| *outcome)
+            .unwrap_or(if exit_code == self.test.success_exit_code {
+                TestOutcome::Pass
+            } else {
+                TestOutcome::Fail
+            })
+    }
+
+    fn validate_usb(&self) -> Result<(), ConfigError> {
+        let Some(usb) = &self.qemu.usb else {
+            return Ok(());
+        };
+        let invalid = |reason: String| ConfigError::InvalidUsb { reason };
+
+        if !["uhci", "ehci", "xhci"].contains(&usb.controller.as_str()) {
+            return Err(invalid(format!(
+                "unknown controller {:?}, expected uhci, ehci or xhci",
+                usb.controller
+            )));
+        }
+
+        for device in &usb.devices {
+            match device.kind.as_str() {
+                "usb-storage" if device.file.is_none() => {
+                    return Err(invalid("usb-storage devices require a `file`".to_string()));
+                }
+                "usb-host" => {
+                    let valid = device
+                        .host
+                        .as_deref()
+                        .and_then(|host| host.split_once(':'))
+                        .is_some_and(|(vid, pid)| {
+                            u16::from_str_radix(vid, 16).is_ok()
+                                && u16::from_str_radix(pid, 16).is_ok()
+                        });
+                    if !valid {
+                        return Err(invalid(
+                            "usb-host devices require `host = \"vid:pid\"` in hex".to_string(),
+                        ));
+                    }
+                }
+                _ => {}
+            }
         }
-            */
         Ok(())
     }
+
+    // Host files written by the configured serial and debugcon channels
+    pub fn serial_log_files(&self) -> Result<Vec<PathBuf>, ConfigError> {
+        let placeholders = self.placeholders(&self.build.image_path, None, None);
+        let mut files = Vec::new();
+        for serial in self.qemu.serials.iter().chain(&self.qemu.debugcon) {
+            files.extend(serial.log_file(&placeholders)?);
+        }
+        Ok(files)
+    }
+
+    pub fn monitor_target(&self) -> Result<Option<MonitorTarget>, ConfigError> {
+        self.qemu
+            .monitor
+            .as_deref()
+            .map(MonitorTarget::parse)
+            .transpose()
+            .map_err(|e| ConfigError::InvalidMonitor { source: e })
+    }
+
+    // Relative paths in the config point below the config file's directory
+    fn resolve_paths(&mut self) {
+        let root = self.root.clone();
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        // `~/` is mostly for cache locations in the user config, which has no directory of its own
+        let resolve = |path: &mut PathBuf| {
+            *path = match (path.strip_prefix("~"), &home) {
+                (Ok(rest), Some(home)) => home.join(rest),
+                _ => root.join(&*path),
+            }
+        };
+        for path in [
+            &mut self.build.image_path,
+            &mut self.build.ovmf_path,
+            &mut self.build.limine_path,
+            &mut self.build.iso_root,
+            &mut self.package.out_dir,
+        ] {
+            resolve(path);
+        }
+        self.package.include.iter_mut().for_each(resolve);
+        let optional = [
+            &mut self.build.target_json,
+            &mut self.qemu.screenshot_on_exit,
+            &mut self.qemu.dtb,
+            &mut self.package.symbols,
+            &mut self.test.expect.file,
+        ];
+        optional.into_iter().flatten().for_each(resolve);
+        for mode in self.qemu.modes.values_mut() {
+            [&mut mode.ovmf_code, &mut mode.ovmf_vars]
+                .into_iter()
+                .flatten()
+                .for_each(resolve);
+        }
+    }
+
+    // Keys of the loaded file that no setting uses, most likely typos
+    pub fn unknown_keys(&self) -> &[UnknownKey] {
+        &self.unknown_keys
+    }
+
+    // Keys of the loaded file spelled the way older versions of limage did
+    pub fn deprecated_keys(&self) -> &[Rename] {
+        &self.deprecated_keys
+    }
+
+    // Turns unknown keys into an error instead of the warnings they cause by default
+    pub fn deny_unknown_keys(&self) -> Result<(), ConfigError> {
+        if self.unknown_keys.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::UnknownKeys {
+            keys: self
+                .unknown_keys
+                .iter()
+                .map(|key| key.to_string())
+                .collect::<Vec<_>>()
+                .join("; "),
+        })
+    }
+
+    pub fn limine_conf(&self) -> PathBuf {
+        self.root.join("limine.conf")
+    }
+
+    // Cargo's target directory of the project the config belongs to
+    pub fn target_dir(&self) -> PathBuf {
+        self.root.join(default_target_dir())
+    }
+
+    // Scratch directory for logs, sockets and other per-run state
+    pub fn work_dir(&self) -> PathBuf {
+        self.target_dir().join("limage")
+    }
+
+    // Snapshot of the configuration the last build used
+    pub fn resolved_config_path(&self) -> PathBuf {
+        self.work_dir().join("resolved-config.toml")
+    }
+
+    // What the last build did and produced, as JSON
+    pub fn build_report_path(&self) -> PathBuf {
+        self.work_dir().join("build-report.json")
+    }
+
+    // The cargo target the kernel is built for: `build.target`, else `build.target_json`
+    pub fn kernel_target(&self) -> Option<String> {
+        self.build.target.clone().or_else(|| {
+            self.build
+                .target_json
+                .as_ref()
+                .map(|path| path.display().to_string())
+        })
+    }
+
+    // `cargo_args` for a cargo command limage runs, behind `--target` and build-std when
+    // `build.target_json` is set and they name no target of their own
+    pub fn target_cargo_args(&self, cargo_args: &[String]) -> Vec<String> {
+        let own_target = cargo_args.iter().any(|arg| arg.starts_with("--target"));
+        match &self.build.target_json {
+            Some(path) if !own_target => target_spec::cargo_args(path)
+                .into_iter()
+                .chain(cargo_args.iter().cloned())
+                .collect(),
+            _ => cargo_args.to_vec(),
+        }
+    }
+
+    // Where the build stages `qemu.dtb`, and the path Limine's `dtb_path` gives for it
+    pub fn staged_dtb(&self) -> Option<(PathBuf, String)> {
+        let name = self.qemu.dtb.as_ref()?.file_name()?.to_string_lossy();
+        Some((
+            self.build.iso_root.join("boot").join("dtb").join(&*name),
+            format!("boot():/boot/dtb/{}", name),
+        ))
+    }
+
+    // The kernel as the last build copied it into the image
+    pub fn staged_kernel(&self) -> PathBuf {
+        self.build
+            .iso_root
+            .join("boot")
+            .join("kernel")
+            .join("kernel")
+    }
+
+    pub fn tpm_state_dir(&self) -> PathBuf {
+        self.work_dir().join("tpm")
+    }
+
+    pub fn ovmf_vars(&self, mode: Option<&str>) -> Result<PathBuf, ConfigError> {
+        let mode = mode.map(|name| self.get_mode(name)).transpose()?;
+        Ok(self.ovmf_vars_for(mode.as_ref()))
+    }
+
+    // Per-process copy of the OVMF vars used by snapshot runs
+    pub fn snapshot_ovmf_vars() -> PathBuf {
+        std::env::temp_dir().join(format!("limage-{}-ovmf-vars.fd", std::process::id()))
+    }
+
+    fn ovmf_vars_for(&self, mode: Option<&ModeConfig>) -> PathBuf {
+        mode.and_then(|m| m.ovmf_vars.clone())
+            .unwrap_or_else(|| OvmfManager::new(&self.build.ovmf_path).vars_path())
+    }
+
+    // Typed machine settings, skipped when base_args already sets the same flag under any of its
+    // spellings (`-M`/`-machine`, `--flag`, `-flag=value`)
+    fn machine_args(&self, base_args: &[String]) -> Vec<String> {
+        let settings: [(&[&str], Option<String>); 4] = [
+            (&["-M", "-machine"], self.qemu.machine.clone()),
+            (&["-m"], Some(self.qemu.memory.clone())),
+            (&["-smp"], self.qemu.cpus.map(|cpus| cpus.to_string())),
+            (&["-cpu"], self.qemu.cpu_model.clone()),
+        ];
+        let sets = |flags: &[&str]| {
+            base_args.iter().any(|arg| {
+                let name = arg.split('=').next().unwrap_or_default();
+                let name = name
+                    .strip_prefix('-')
+                    .filter(|name| name.starts_with('-'))
+                    .unwrap_or(name);
+                flags.contains(&name)
+            })
+        };
+
+        let mut args = Vec::new();
+        for (flags, value) in settings {
+            if let Some(value) = value {
+                if sets(flags) {
+                    debug!(
+                        "{} is set in qemu.base_args, ignoring the typed setting",
+                        flags[0]
+                    );
+                } else {
+                    args.extend([flags[0].to_string(), value]);
+                }
+            }
+        }
+        args
+    }
+
+    // Values of the `{name}` placeholders for a run of `image_path` in `mode_name`
+    fn placeholders(
+        &self,
+        image_path: &Path,
+        mode_name: Option<&str>,
+        mode: Option<&ModeConfig>,
+    ) -> Vec<(&'static str, String)> {
+        let ovmf_code = mode
+            .and_then(|m| m.ovmf_code.clone())
+            .unwrap_or_else(|| OvmfManager::new(&self.build.ovmf_path).code_path());
+        let ovmf_vars = if self.qemu.snapshot {
+            Self::snapshot_ovmf_vars()
+        } else {
+            self.ovmf_vars_for(mode)
+        };
+        // qemu-system-<arch>, so a custom QEMU build for another target reports its own
+        let arch = Path::new(&self.qemu.binary)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("qemu-system-"))
+            .unwrap_or("x86_64");
+
+        vec![
+            ("image", image_path.display().to_string()),
+            ("ovmf_code", ovmf_code.display().to_string()),
+            ("ovmf_vars", ovmf_vars.display().to_string()),
+            ("ovmf", self.build.ovmf_path.display().to_string()),
+            ("target_dir", self.target_dir().display().to_string()),
+            ("kernel", self.staged_kernel().display().to_string()),
+            ("iso_root", self.build.iso_root.display().to_string()),
+            ("arch", arch.to_string()),
+            ("mode", mode_name.unwrap_or_default().to_string()),
+            ("project_root", self.project_root().display().to_string()),
+        ]
+    }
+
+    // Absolute directory the config's relative paths resolve against
+    pub fn project_root(&self) -> PathBuf {
+        if self.root.as_os_str().is_empty() {
+            std::env::current_dir().unwrap_or_default()
+        } else {
+            self.root.clone()
+        }
+    }
+
+    pub fn get_qemu_command(
+        &self,
+        image_path: &Path,
+        is_test: bool,
+        mode: Option<&str>,
+    ) -> Result<Vec<String>, ConfigError> {
+        let mut cmd = vec![self.qemu.binary.clone()];
+        let mode_name = mode;
+        let mode = mode.map(|name| self.get_mode(name)).transpose()?;
+        let placeholders = self.placeholders(image_path, mode_name, mode.as_ref());
+
+        let firmware = mode
+            .as_ref()
+            .and_then(|m| m.firmware)
+            .unwrap_or(self.qemu.firmware);
+        let base_args = match firmware {
+            Firmware::Uefi => {
+                let mut args = self.qemu.base_args.clone();
+                if !args.iter().any(|arg| arg.contains("if=pflash")) {
+                    args.extend(uefi_firmware_args());
+                }
+                args
+            }
+            // SeaBIOS is QEMU's default when no pflash firmware is attached
+            Firmware::Bios => strip_pflash_drives(&self.qemu.base_args),
+        };
+
+        for arg in &base_args {
+            cmd.push(expand_placeholders(arg, &placeholders)?);
+        }
+
+        if !base_args.iter().any(|arg| arg.contains("{image}")) {
+            warn!("qemu.base_args does not reference {{image}}, attaching it as -cdrom");
+            cmd.extend(["-cdrom".to_string(), image_path.display().to_string()]);
+        }
+
+        cmd.extend(self.machine_args(&base_args));
+
+        cmd.extend(drive_args(&self.qemu.drives, &placeholders)?);
+
+        if let Some(network) = &self.qemu.network {
+            cmd.extend(network_args(network));
+        }
+
+        if let Some(usb) = &self.qemu.usb {
+            cmd.extend(usb_args(usb, &placeholders)?);
+        }
+
+        if self.qemu.tpm {
+            cmd.extend(Swtpm::qemu_args(&self.tpm_state_dir()));
+        }
+
+        for serial in &self.qemu.serials {
+            cmd.extend(["-serial".to_string(), serial.chardev(&placeholders)?]);
+        }
+
+        if let Some(debugcon) = &self.qemu.debugcon {
+            cmd.extend(["-debugcon".to_string(), debugcon.chardev(&placeholders)?]);
+        }
+
+        if let Some(dtb) = &self.qemu.dtb {
+            cmd.extend(["-dtb".to_string(), dtb.display().to_string()]);
+        }
+
+        cmd.extend(fw_cfg_args(&self.qemu.fw_cfg, &placeholders)?);
+
+        if let Some(monitor) = self.monitor_target()? {
+            cmd.extend(["-monitor".to_string(), monitor.qemu_arg()]);
+        }
+
+        if let Some(accel) = &self.qemu.accel {
+            if let Some(name) = accel::resolve(accel, &self.qemu.binary) {
+                cmd.push("-accel".to_string());
+                cmd.push(name);
+            }
+        }
+
+        if self.qemu.snapshot {
+            cmd.push("-snapshot".to_string());
+        }
+
+        if let Some(display) = &self.qemu.display {
+            cmd.extend(display_args(display)?);
+        }
+
+        if self.qemu.headless {
+            cmd.extend(
+                ["-display", "none", "-serial", "stdio"]
+                    .iter()
+                    .map(|arg| arg.to_string()),
+            );
+        }
+
+        let mode_args = mode.iter().flat_map(|mode| &mode.extra_args);
+        for arg in mode_args.chain(&self.qemu.extra_args) {
+            cmd.push(expand_placeholders(arg, &placeholders)?);
+        }
+
+        // Add test-specific args
+        if is_test {
+            if self.test.no_reboot {
+                cmd.push("-no-reboot".to_string());
+            }
+            for arg in &self.test.extra_args {
+                cmd.push(expand_placeholders(arg, &placeholders)?);
+            }
+
+            // Older configs attach the exit device themselves through extra_args
+            if !cmd.iter().any(|arg| arg.contains("isa-debug-exit")) {
+                cmd.extend([
+                    "-device".to_string(),
+                    format!(
+                        "isa-debug-exit,iobase={:#x},iosize={:#04x}",
+                        self.test.exit_device.iobase, self.test.exit_device.iosize
+                    ),
+                ]);
+            }
+        }
+
+        Ok(cmd)
+    }
+
+    // Checks that need more than one key, or the command line, to judge, reporting every
+    // problem at once. `mode` is the run mode named on the command line; `runs_qemu` is set for
+    // commands that start QEMU, so a build-only CI job does not need it installed
+    pub fn validate(&self, mode: Option<&str>, runs_qemu: bool) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let pattern = Regex::new(PLACEHOLDER_PATTERN).expect("placeholder pattern is valid");
+        for (key, value) in self.placeholder_args() {
+            for caps in pattern.captures_iter(value) {
+                let name = &caps[1];
+                if name.starts_with("env:") || PLACEHOLDERS.contains(&name) {
+                    continue;
+                }
+                let suggestion = suggest::closest(name, &PLACEHOLDERS)
+                    .map(|known| format!(", did you mean {{{}}}?", known))
+                    .unwrap_or_default();
+                problems.push(format!(
+                    "`{}` uses unknown placeholder {{{}}}{}",
+                    key, name, suggestion
+                ));
+            }
+        }
+
+        let mut binary = self.qemu.binary.clone();
+        if let Some(mode) = mode {
+            match self.with_mode(mode) {
+                Ok(config) => binary = config.qemu.binary,
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+
+        if self.test.timeout_secs == 0 {
+            problems.push("`test.timeout_secs` must be greater than 0".to_string());
+        }
+        if ![1, 2, 4].contains(&self.test.exit_device.iosize) {
+            problems.push(format!(
+                "`test.exit_device.iosize` is {}, but isa-debug-exit only takes 1, 2 or 4",
+                self.test.exit_device.iosize
+            ));
+        }
+        if self.test.case_timeout_secs == Some(0) {
+            problems.push("`test.case_timeout_secs` must be greater than 0".to_string());
+        }
+
+        // isa-debug-exit makes QEMU exit with `(value << 1) | 1`, which the host truncates to 8 bits
+        let code = self.test.success_exit_code;
+        if code % 2 == 0 || !(1..=255).contains(&code) {
+            problems.push(format!(
+                "`test.success_exit_code` is {}, but isa-debug-exit only produces odd codes from 1 to 255, e.g. 33 for a guest writing 0x10",
+                code
+            ));
+        }
+
+        // Relative paths climbing out of the project are more likely mistakes than shared
+        // locations, which are better written as absolute or `~/` paths
+        let project_root = self.project_root();
+        for (key, path) in [
+            ("build.image_path", Some(&self.build.image_path)),
+            ("build.iso_root", Some(&self.build.iso_root)),
+            ("build.ovmf_path", Some(&self.build.ovmf_path)),
+            ("build.limine_path", Some(&self.build.limine_path)),
+            ("package.out_dir", Some(&self.package.out_dir)),
+            (
+                "qemu.screenshot_on_exit",
+                self.qemu.screenshot_on_exit.as_ref(),
+            ),
+            ("qemu.dtb", self.qemu.dtb.as_ref()),
+        ] {
+            let Some(path) = path else { continue };
+            let path = project_root.join(path);
+            if path.starts_with(&project_root) && !normalize(&path).starts_with(&project_root) {
+                problems.push(format!(
+                    "`{}` resolves to {:?}, outside the project; make it absolute if that is intended",
+                    key,
+                    normalize(&path)
+                ));
+            }
+        }
+
+        if let Some(dtb) = self.qemu.dtb.as_ref().filter(|dtb| !dtb.is_file()) {
+            problems.push(format!("`qemu.dtb` {:?} does not exist", dtb));
+        }
+
+        if runs_qemu && self.runner.backend == BackendKind::Qemu && !on_path(&binary) {
+            problems.push(format!("QEMU binary {:?} was not found", binary));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid { problems })
+        }
+    }
+
+    // Every QEMU argument, drive file and serial target that may hold placeholders, with the key
+    // it is set under
+    pub fn placeholder_args(&self) -> Vec<(String, &String)> {
+        let mut fields: Vec<(String, &String)> = Vec::new();
+        for (key, args) in [
+            ("qemu.base_args", &self.qemu.base_args),
+            ("qemu.extra_args", &self.qemu.extra_args),
+            ("test.extra_args", &self.test.extra_args),
+        ] {
+            fields.extend(args.iter().map(|arg| (key.to_string(), arg)));
+        }
+        for (name, mode) in &self.qemu.modes {
+            let key = format!("qemu.modes.{}.extra_args", name);
+            fields.extend(mode.extra_args.iter().map(|arg| (key.clone(), arg)));
+        }
+        fields.extend(
+            self.qemu
+                .drives
+                .iter()
+                .map(|drive| ("qemu.drives.file".to_string(), &drive.file)),
+        );
+        fields.extend(
+            self.qemu
+                .fw_cfg
+                .iter()
+                .filter_map(|entry| entry.file.as_ref())
+                .map(|file| ("qemu.fw_cfg.file".to_string(), file)),
+        );
+        fields.extend(
+            self.qemu
+                .usb
+                .iter()
+                .flat_map(|usb| &usb.devices)
+                .filter_map(|device| device.file.as_ref())
+                .map(|file| ("qemu.usb.devices.file".to_string(), file)),
+        );
+        fields.extend(
+            self.qemu
+                .serials
+                .iter()
+                .map(|serial| ("qemu.serials.target".to_string(), &serial.target)),
+        );
+        fields.extend(
+            self.qemu
+                .debugcon
+                .iter()
+                .map(|debugcon| ("qemu.debugcon.target".to_string(), &debugcon.target)),
+        );
+        fields
+    }
+}
+
+// Removes `.` and `..` components without touching the filesystem, which the paths may not exist in yet
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// Whether `binary` names an existing file, or an executable on PATH when it is a bare name
+fn on_path(binary: &str) -> bool {
+    if Path::new(binary).components().count() > 1 {
+        return Path::new(binary).is_file();
+    }
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| {
+            dir.join(binary).is_file()
+                || dir
+                    .join(format!("{}{}", binary, std::env::consts::EXE_SUFFIX))
+                    .is_file()
+        })
+    })
 }
 
 impl Default for LimageConfig {
     fn default() -> Self {
         Self {
+            config_version: default_config_version(),
             build: default_build_config(),
             qemu: default_qemu_config(),
             test: default_test_config(),
+            runner: default_runner_config(),
+            run: default_run_config(),
+            package: default_package_config(),
+            scenarios: BTreeMap::new(),
+            extends: None,
+            host: BTreeMap::new(),
+            #[allow(deprecated)]
             modes: HashMap::new(),
+            root: PathBuf::new(),
+            unknown_keys: Vec::new(),
+            deprecated_keys: Vec::new(),
         }
     }
 }
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
+    #[error("Config has {} problem(s):{}", problems.len(), problems.iter().map(|problem| format!("\n  - {}", problem)).collect::<String>())]
+    Invalid { problems: Vec<String> },
+
     #[error("Failed to read config file")]
     ReadConfig { source: std::io::Error },
 
+    #[error("Config contains {keys}")]
+    UnknownKeys { keys: String },
+
+    #[error("Failed to parse user config {path:?}")]
+    ParseUserConfig {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Failed to read {path:?}, which the config extends")]
+    ReadExtends {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse {path:?}, which the config extends")]
+    ParseExtends {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("`extends` must be the path of a config file, not {value}")]
+    InvalidExtends { value: String },
+
+    #[error("Invalid [host.{host}] configuration: {reason}")]
+    InvalidHost { host: String, reason: String },
+
+    #[error(
+        "Config version {version} is newer than this limage supports ({})",
+        CONFIG_VERSION
+    )]
+    NewerVersion { version: u32 },
+
+    #[error("Config files extend each other in a cycle: {chain}")]
+    ExtendsCycle { chain: String },
+
     #[error("Failed to parse config file")]
     ParseConfig { source: toml::de::Error },
 
+    #[error("Invalid value in {variables}: {source}")]
+    EnvOverride {
+        variables: String,
+        source: toml::de::Error,
+    },
+
     #[error("Failed to create {name} directory at {path:?}")]
     CreateDirectory {
         path: PathBuf,
@@ -242,4 +2524,165 @@ pub enum ConfigError {
 
     #[error("Mode '{mode}' not found in configuration file")]
     ModeNotFound { mode: String },
+
+    #[error("Invalid mode '{mode}': {reason}")]
+    InvalidMode { mode: String, reason: String },
+
+    #[error("Invalid [qemu.network] configuration: {reason}")]
+    InvalidNetwork { reason: String },
+
+    #[error("Invalid [qemu.usb] configuration: {reason}")]
+    InvalidUsb { reason: String },
+
+    #[error("Invalid display '{display}', expected nographic, vnc[:[<host>:]<port>], spice[:[<host>:]<port>] or a QEMU display backend")]
+    InvalidDisplay { display: String },
+
+    #[error("Invalid [test.exit_codes] key '{code}', expected an integer exit code")]
+    InvalidExitCode { code: String },
+
+    #[error("Scenario '{scenario}' not found in configuration file")]
+    ScenarioNotFound { scenario: String },
+
+    #[error("Invalid scenario '{scenario}': {reason}")]
+    InvalidScenario { scenario: String, reason: String },
+
+    #[error("Invalid [test.expect] configuration: {source}")]
+    InvalidExpect { source: ExpectError },
+
+    #[error("Invalid monitor configuration: {source}")]
+    InvalidMonitor { source: MonitorError },
+
+    #[error("Unknown placeholder {{{placeholder}}} in {arg:?}, known placeholders are {}{{env:VAR}}", PLACEHOLDERS.map(|name| format!("{{{}}}, ", name)).concat())]
+    UnknownPlaceholder { placeholder: String, arg: String },
+
+    #[error("Environment variable {variable} used by {arg:?} is not set")]
+    UnsetEnvPlaceholder { variable: String, arg: String },
+
+    #[error("Invalid serial target '{target}', expected stdio, pty, file:<path> or tcp:<port>")]
+    InvalidSerial { target: String },
+
+    #[error("Invalid fw_cfg entry '{name}': {reason}")]
+    InvalidFwCfg { name: String, reason: String },
+
+    #[error("build.tools = \"{tools}\" needs limage built with the `{feature}` feature")]
+    ToolsUnavailable {
+        tools: &'static str,
+        feature: &'static str,
+    },
+}
+
+impl ConfigError {
+    // The stable code `limage::Error` reports; a new variant takes the next free code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConfigError::Invalid { .. } => "E001",
+            ConfigError::ReadConfig { .. } => "E002",
+            ConfigError::UnknownKeys { .. } => "E003",
+            ConfigError::ParseUserConfig { .. } => "E004",
+            ConfigError::ReadExtends { .. } => "E005",
+            ConfigError::ParseExtends { .. } => "E006",
+            ConfigError::InvalidExtends { .. } => "E007",
+            ConfigError::InvalidHost { .. } => "E008",
+            ConfigError::NewerVersion { .. } => "E009",
+            ConfigError::ExtendsCycle { .. } => "E010",
+            ConfigError::ParseConfig { .. } => "E011",
+            ConfigError::EnvOverride { .. } => "E012",
+            ConfigError::CreateDirectory { .. } => "E013",
+            ConfigError::ModeNotFound { .. } => "E014",
+            ConfigError::InvalidMode { .. } => "E015",
+            ConfigError::InvalidNetwork { .. } => "E016",
+            ConfigError::InvalidUsb { .. } => "E017",
+            ConfigError::InvalidDisplay { .. } => "E018",
+            ConfigError::InvalidExitCode { .. } => "E019",
+            ConfigError::ScenarioNotFound { .. } => "E020",
+            ConfigError::InvalidScenario { .. } => "E021",
+            ConfigError::InvalidExpect { .. } => "E022",
+            ConfigError::InvalidMonitor { .. } => "E023",
+            ConfigError::UnknownPlaceholder { .. } => "E024",
+            ConfigError::UnsetEnvPlaceholder { .. } => "E025",
+            ConfigError::InvalidSerial { .. } => "E026",
+            ConfigError::ToolsUnavailable { .. } => "E027",
+            ConfigError::InvalidFwCfg { .. } => "E028",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vnc_and_spice_listen_on_localhost_by_default() {
+        assert_eq!(
+            display_args("vnc:5901").unwrap(),
+            ["-display", "none", "-vnc", "127.0.0.1:1"]
+        );
+        assert_eq!(
+            display_args("spice").unwrap(),
+            [
+                "-display",
+                "none",
+                "-spice",
+                "addr=127.0.0.1,port=5930,disable-ticketing=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn vnc_and_spice_take_an_explicit_host() {
+        assert_eq!(
+            display_args("vnc:0.0.0.0:5902").unwrap(),
+            ["-display", "none", "-vnc", "0.0.0.0:2"]
+        );
+        assert_eq!(
+            display_args("spice:[::]:5931").unwrap(),
+            [
+                "-display",
+                "none",
+                "-spice",
+                "addr=::,port=5931,disable-ticketing=on"
+            ]
+        );
+        assert!(display_args("vnc:0.0.0.0:5800").is_err());
+        assert!(display_args("spice:host:port").is_err());
+    }
+
+    fn machine_config(base_args: &[&str]) -> LimageConfig {
+        let mut config = LimageConfig::builder().build().unwrap();
+        config.qemu.machine = Some("q35".to_string());
+        config.qemu.cpus = Some(2);
+        config.qemu.base_args = base_args.iter().map(|arg| arg.to_string()).collect();
+        config
+    }
+
+    #[test]
+    fn machine_args_skip_flags_base_args_set() {
+        let config = machine_config(&[]);
+        assert_eq!(
+            config.machine_args(&config.qemu.base_args),
+            ["-M", "q35", "-m", &config.qemu.memory, "-smp", "2"]
+        );
+        let spellings: [&[&str]; 3] = [
+            &["-machine", "q35", "-m", "1G", "-smp", "4"],
+            &["-M", "q35", "--m", "1G", "--smp", "4"],
+            &["-machine=q35", "-m=1G", "-smp=4", "-s", "-S"],
+        ];
+        for base_args in spellings {
+            let config = machine_config(base_args);
+            assert!(
+                config.machine_args(&config.qemu.base_args).is_empty(),
+                "{:?}",
+                base_args
+            );
+        }
+    }
+
+    #[test]
+    fn machine_args_ignore_lookalike_flags() {
+        let config = machine_config(&["-machine-type", "-mem-path", "/dev/hugepages"]);
+        assert_eq!(
+            config.machine_args(&config.qemu.base_args),
+            ["-M", "q35", "-m", &config.qemu.memory, "-smp", "2"]
+        );
+    }
 }
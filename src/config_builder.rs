@@ -0,0 +1,224 @@
+use crate::config::{
+    ConfigError, DriveConfig, Firmware, FwCfgConfig, LimageConfig, ModeConfig, NetworkConfig,
+    ScenarioConfig, SerialConfig, TestOutcome,
+};
+use std::path::PathBuf;
+
+// Builds a config in code, starting from the defaults an empty limage_config.toml has. Unlike
+// `LimageConfig::load` it reads no files and no LIMAGE_* variables, so the result only depends on
+// the calls made; `to_toml_string` turns it into a file
+#[derive(Clone, Debug, Default)]
+pub struct LimageConfigBuilder {
+    config: LimageConfig,
+}
+
+impl LimageConfig {
+    pub fn builder() -> LimageConfigBuilder {
+        LimageConfigBuilder::default()
+    }
+}
+
+impl LimageConfigBuilder {
+    pub fn image_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.build.image_path = path.into();
+        self
+    }
+
+    pub fn prebuilder(mut self, command: impl Into<String>) -> Self {
+        self.config.build.prebuilder = Some(command.into());
+        self
+    }
+
+    pub fn ovmf_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.build.ovmf_path = path.into();
+        self
+    }
+
+    pub fn limine_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.build.limine_path = path.into();
+        self
+    }
+
+    pub fn iso_root(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.build.iso_root = path.into();
+        self
+    }
+
+    // The cargo target, profile and binary the kernel is looked up with
+    pub fn kernel_target(mut self, target: impl Into<String>) -> Self {
+        self.config.build.target = Some(target.into());
+        self
+    }
+
+    pub fn kernel_profile(mut self, profile: impl Into<String>) -> Self {
+        self.config.build.profile = Some(profile.into());
+        self
+    }
+
+    pub fn kernel_bin(mut self, bin: impl Into<String>) -> Self {
+        self.config.build.kernel_bin = Some(bin.into());
+        self
+    }
+
+    pub fn target_json(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.build.target_json = Some(path.into());
+        self
+    }
+
+    pub fn qemu_binary(mut self, binary: impl Into<String>) -> Self {
+        self.config.qemu.binary = binary.into();
+        self
+    }
+
+    pub fn qemu_memory(mut self, memory: impl Into<String>) -> Self {
+        self.config.qemu.memory = memory.into();
+        self
+    }
+
+    pub fn qemu_cpus(mut self, cpus: u32) -> Self {
+        self.config.qemu.cpus = Some(cpus);
+        self
+    }
+
+    pub fn qemu_cpu_model(mut self, model: impl Into<String>) -> Self {
+        self.config.qemu.cpu_model = Some(model.into());
+        self
+    }
+
+    pub fn qemu_machine(mut self, machine: impl Into<String>) -> Self {
+        self.config.qemu.machine = Some(machine.into());
+        self
+    }
+
+    // "auto", "kvm", "hvf", "whpx" or "tcg"
+    pub fn qemu_accel(mut self, accel: impl Into<String>) -> Self {
+        self.config.qemu.accel = Some(accel.into());
+        self
+    }
+
+    pub fn qemu_display(mut self, display: impl Into<String>) -> Self {
+        self.config.qemu.display = Some(display.into());
+        self
+    }
+
+    pub fn qemu_headless(mut self, headless: bool) -> Self {
+        self.config.qemu.headless = headless;
+        self
+    }
+
+    pub fn qemu_firmware(mut self, firmware: Firmware) -> Self {
+        self.config.qemu.firmware = firmware;
+        self
+    }
+
+    pub fn qemu_snapshot(mut self, snapshot: bool) -> Self {
+        self.config.qemu.snapshot = snapshot;
+        self
+    }
+
+    pub fn qemu_tpm(mut self, tpm: bool) -> Self {
+        self.config.qemu.tpm = tpm;
+        self
+    }
+
+    // Appended to qemu.extra_args
+    pub fn qemu_arg(mut self, arg: impl Into<String>) -> Self {
+        self.config.qemu.extra_args.push(arg.into());
+        self
+    }
+
+    pub fn qemu_monitor(mut self, monitor: impl Into<String>) -> Self {
+        self.config.qemu.monitor = Some(monitor.into());
+        self
+    }
+
+    pub fn qemu_gdb_port(mut self, port: u16) -> Self {
+        self.config.qemu.gdb_port = port;
+        self
+    }
+
+    pub fn drive(mut self, drive: DriveConfig) -> Self {
+        self.config.qemu.drives.push(drive);
+        self
+    }
+
+    pub fn network(mut self, network: NetworkConfig) -> Self {
+        self.config.qemu.network = Some(network);
+        self
+    }
+
+    // Serial ports in order, COM1 first
+    pub fn serial(mut self, target: impl Into<String>) -> Self {
+        self.config.qemu.serials.push(SerialConfig {
+            target: target.into(),
+        });
+        self
+    }
+
+    pub fn dtb(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.qemu.dtb = Some(path.into());
+        self
+    }
+
+    pub fn fw_cfg(mut self, entry: FwCfgConfig) -> Self {
+        self.config.qemu.fw_cfg.push(entry);
+        self
+    }
+
+    // Adds a run mode, replacing a configured or built-in one of the same name
+    pub fn mode(mut self, name: impl Into<String>, mode: ModeConfig) -> Self {
+        self.config.qemu.modes.insert(name.into(), mode);
+        self
+    }
+
+    pub fn test_timeout_secs(mut self, secs: u32) -> Self {
+        self.config.test.timeout_secs = secs;
+        self
+    }
+
+    pub fn test_success_exit_code(mut self, code: i32) -> Self {
+        self.config.test.success_exit_code = code;
+        self
+    }
+
+    pub fn test_panic_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.config.test.panic_pattern = pattern.into();
+        self
+    }
+
+    pub fn test_retries(mut self, retries: u32) -> Self {
+        self.config.test.retries = retries;
+        self
+    }
+
+    // Appended to test.extra_args
+    pub fn test_arg(mut self, arg: impl Into<String>) -> Self {
+        self.config.test.extra_args.push(arg.into());
+        self
+    }
+
+    pub fn test_exit_code(mut self, code: i32, outcome: TestOutcome) -> Self {
+        self.config
+            .test
+            .exit_codes
+            .insert(code.to_string(), outcome);
+        self
+    }
+
+    pub fn scenario(mut self, name: impl Into<String>, scenario: ScenarioConfig) -> Self {
+        self.config.scenarios.insert(name.into(), scenario);
+        self
+    }
+
+    // For settings without a method of their own
+    pub fn with(mut self, edit: impl FnOnce(&mut LimageConfig)) -> Self {
+        edit(&mut self.config);
+        self
+    }
+
+    // Checks the settings the way loading a file does
+    pub fn build(self) -> Result<LimageConfig, ConfigError> {
+        self.config.check_settings()?;
+        Ok(self.config)
+    }
+}
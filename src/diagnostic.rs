@@ -0,0 +1,302 @@
+use crate::{
+    artifacts::ArtifactError,
+    builder::BuildError,
+    config::{ConfigError, ToolBackend, PLACEHOLDERS},
+    gdb::GdbError,
+    iso::IsoError,
+    migrate::MigrateError,
+    runner::RunError,
+    self_update::SelfUpdateError,
+    suggest,
+    tpm::TpmError,
+};
+use miette::{LabeledSpan, MietteDiagnostic, NamedSource, Report};
+use std::{io::ErrorKind, path::Path};
+
+// Turns an error from `run` into a report with the failing config span and a hint on how to fix it.
+// `config_path` is the config file that was loaded, used to show parse errors in context
+pub fn report(error: &anyhow::Error, config_path: &Path) -> Report {
+    let help = help(error);
+    let code = code(error);
+
+    // A parse error points into the config file, so it is shown with the offending line
+    let parse_error = match find::<ConfigError>(error) {
+        Some(ConfigError::ParseConfig { source }) => Some((source, config_path)),
+        None => match find::<MigrateError>(error) {
+            Some(MigrateError::Parse { source }) => Some((source, config_path)),
+            _ => None,
+        },
+        Some(ConfigError::ParseUserConfig { path, source })
+        | Some(ConfigError::ParseExtends { path, source }) => Some((source, path.as_path())),
+        _ => None,
+    };
+    if let Some((source, config_path)) = parse_error {
+        if let (Some((line, column)), Ok(content)) =
+            (source.line_col(), std::fs::read_to_string(config_path))
+        {
+            let offset = content
+                .split_inclusive('\n')
+                .take(line)
+                .map(str::len)
+                .sum::<usize>()
+                + column;
+            // toml appends the position to its message, which the label already shows
+            let message = source.to_string();
+            let label = message
+                .rsplit_once(" at line ")
+                .map_or(message.as_str(), |(label, _)| label);
+            let mut diagnostic =
+                MietteDiagnostic::new(format!("Failed to parse {}", config_path.display()))
+                    .with_label(LabeledSpan::at_offset(offset.min(content.len()), label));
+            if let Some(code) = code {
+                diagnostic = diagnostic.with_code(code);
+            }
+            if let Some(help) = help {
+                diagnostic = diagnostic.with_help(help);
+            }
+            return Report::new(diagnostic)
+                .with_source_code(NamedSource::new(config_path.display().to_string(), content));
+        }
+    }
+
+    // Most messages already embed their source, so only causes that add something are appended
+    let mut message = error.to_string();
+    for cause in error.chain().skip(1) {
+        let cause = cause.to_string();
+        if !message.contains(&cause) {
+            message = format!("{}: {}", message, cause);
+        }
+    }
+    let mut diagnostic = MietteDiagnostic::new(message);
+    if let Some(code) = code {
+        diagnostic = diagnostic.with_code(code);
+    }
+    if let Some(help) = help {
+        diagnostic = diagnostic.with_help(help);
+    }
+    Report::new(diagnostic)
+}
+
+// The first error of type `E` in the chain, since library errors are often wrapped in context
+fn find<E: std::error::Error + Send + Sync + 'static>(error: &anyhow::Error) -> Option<&E> {
+    error.chain().find_map(|cause| cause.downcast_ref::<E>())
+}
+
+// The stable code of the first library error in the chain, as `limage::Error::code` reports it
+fn code(error: &anyhow::Error) -> Option<&'static str> {
+    find::<BuildError>(error)
+        .map(BuildError::code)
+        .or_else(|| find::<RunError>(error).map(RunError::code))
+        .or_else(|| find::<ConfigError>(error).map(ConfigError::code))
+        .or_else(|| find::<ArtifactError>(error).map(ArtifactError::code))
+        .or_else(|| find::<IsoError>(error).map(IsoError::code))
+}
+
+// Errors nest (a run error can wrap a config error), so every layer gets a chance to suggest a fix
+fn help(error: &anyhow::Error) -> Option<String> {
+    find::<BuildError>(error)
+        .and_then(build_help)
+        .or_else(|| find::<RunError>(error).and_then(run_help))
+        .or_else(|| find::<ConfigError>(error).and_then(config_help))
+        .or_else(|| find::<ArtifactError>(error).and_then(artifact_help))
+        .or_else(|| find::<IsoError>(error).and_then(iso_help))
+        .or_else(|| find::<SelfUpdateError>(error).and_then(self_update_help))
+        .or_else(|| find::<MigrateError>(error).and_then(migrate_help))
+}
+
+pub(crate) fn build_help(error: &BuildError) -> Option<String> {
+    match error {
+        BuildError::CreateIso { source } if source.kind() == ErrorKind::NotFound => {
+            Some(tool_help("xorriso"))
+        }
+        BuildError::DownloadOvmfFailed { source } if source.kind() == ErrorKind::NotFound => {
+            Some(tool_help("curl"))
+        }
+        BuildError::CloneLimineFailed { source } if source.kind() == ErrorKind::NotFound => {
+            Some(with_native_hint(
+                "git",
+                "git and make fetch and build Limine: install them with `apt install git make`, `brew install git make` or `pacman -S git make`"
+                    .to_string(),
+            ))
+        }
+        BuildError::CopyLimineConfig { source } if source.kind() == ErrorKind::NotFound => {
+            Some(
+                "limine.conf is missing: create one next to Cargo.toml with a boot entry for boot():/boot/kernel/kernel (see the README)"
+                    .to_string(),
+            )
+        }
+        BuildError::CommandFailed { tool: "limine", .. } => Some(
+            "The limine utility could not install the BIOS boot code; `limage clean --limine` makes the next build fetch and rebuild Limine"
+                .to_string(),
+        ),
+        BuildError::CopyKernel { source, .. } if source.kind() == ErrorKind::NotFound => Some(
+            "The kernel has not been built yet: run `cargo build` first or pass `--kernel <PATH>`; if it was, set `build.target`, `build.profile` or `build.kernel_bin` to where it is"
+                .to_string(),
+        ),
+        BuildError::KernelTarget { target, .. } => Some(format!(
+            "Build the kernel for the target JSON: set `build.target = \"{}\"` in .cargo/config.toml, or let `limage test` and `limage ci` pass it to cargo",
+            target.display()
+        )),
+        _ => None,
+    }
+}
+
+pub(crate) fn run_help(error: &RunError) -> Option<String> {
+    match error {
+        RunError::StartQemu { .. } => Some(install(
+            "QEMU",
+            "qemu-system-x86",
+            "qemu",
+            "qemu-system-x86",
+        )),
+        RunError::InteractiveSerial => Some(
+            "Add a `[[qemu.serials]]` with `target = \"stdio\"` or pass `--headless`, or drop `--interactive` for a mode that routes serial elsewhere"
+                .to_string(),
+        ),
+        RunError::StartBackend { backend, .. } => Some(format!(
+            "Install {} and make sure it is on PATH, or switch `runner.backend` back to \"qemu\"",
+            backend
+        )),
+        RunError::Gdb {
+            source: GdbError::Spawn { .. },
+        } => Some(install("gdb", "gdb", "gdb", "gdb")),
+        RunError::Tpm {
+            source: TpmError::StartSwtpm { .. },
+        } => Some(format!(
+            "{}, or set `qemu.tpm = false`",
+            install("swtpm", "swtpm", "swtpm", "swtpm")
+        )),
+        _ => None,
+    }
+}
+
+pub(crate) fn config_help(error: &ConfigError) -> Option<String> {
+    match error {
+        ConfigError::ParseConfig { .. } | ConfigError::EnvOverride { .. } => Some(
+            "Run `limage check` to list every problem in the config, or `limage config init` for a commented template"
+                .to_string(),
+        ),
+        ConfigError::ParseUserConfig { .. } => Some(
+            "The user config holds machine-wide defaults layered beneath every project's config; fix it or move it away"
+                .to_string(),
+        ),
+        ConfigError::ExtendsCycle { .. } => Some(
+            "Remove `extends` from one of the files; a base config must not extend the configs built on it"
+                .to_string(),
+        ),
+        ConfigError::InvalidExtends { .. } | ConfigError::ReadExtends { .. } => Some(
+            "`extends` takes a path relative to the file declaring it, e.g. extends = \"../common/limage_base.toml\""
+                .to_string(),
+        ),
+        ConfigError::NewerVersion { .. } => {
+            Some("Update limage with `limage self-update` or `cargo install limage`".to_string())
+        }
+        ConfigError::InvalidHost { .. } => Some(
+            "A [host.<os>] section takes the same keys as the top level of the config, e.g. [host.macos.qemu] accel = \"hvf\""
+                .to_string(),
+        ),
+        ConfigError::UnknownPlaceholder { placeholder, .. } => {
+            suggest::closest(placeholder, &PLACEHOLDERS)
+                .map(|known| format!("Did you mean {{{}}}?", known))
+        }
+        ConfigError::UnknownKeys { .. } => Some(
+            "Fix or remove the keys; without --strict they are only reported as warnings".to_string(),
+        ),
+        ConfigError::ModeNotFound { .. } => {
+            Some("`limage modes` lists the available run modes".to_string())
+        }
+        ConfigError::ToolsUnavailable { feature, .. } => Some(format!(
+            "Reinstall with `cargo install limage --features {}`, or remove build.tools to use the tools this limage was built with",
+            feature
+        )),
+        _ => None,
+    }
+}
+
+pub(crate) fn artifact_help(error: &ArtifactError) -> Option<String> {
+    match error {
+        ArtifactError::Spawn { tool, .. } => Some(tool_help(tool)),
+        _ => None,
+    }
+}
+
+pub(crate) fn iso_help(error: &IsoError) -> Option<String> {
+    match error {
+        IsoError::Spawn { tool, .. } => Some(tool_help(tool)),
+        IsoError::Limine { .. } => Some(
+            "`LimineArtifacts::fetch` clones and builds the bootloader files into its directory"
+                .to_string(),
+        ),
+        IsoError::InvalidModuleName { .. } => Some(
+            "Modules are copied to boot/modules/<name>, so the name cannot contain a path separator"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+fn self_update_help(error: &SelfUpdateError) -> Option<String> {
+    match error {
+        SelfUpdateError::Curl { source } if source.kind() == ErrorKind::NotFound => {
+            Some(install("curl", "curl", "curl", "curl"))
+        }
+        SelfUpdateError::MissingAsset { .. } => Some(
+            "There is no prebuilt binary for this platform; `cargo install limage` builds the latest release from source"
+                .to_string(),
+        ),
+        SelfUpdateError::Replace { .. } => Some(
+            "Make sure the limage executable is writable, or rerun with the permissions of whoever installed it"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+fn migrate_help(error: &MigrateError) -> Option<String> {
+    match error {
+        MigrateError::NewerVersion { .. } => {
+            Some("Update limage with `limage self-update` or `cargo install limage`".to_string())
+        }
+        MigrateError::Incomplete { .. } => Some(
+            "Move the keys to their new names by hand, e.g. [modes.debug] becomes [qemu.modes.debug]"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+// The build tools limage runs, by the name it reports them under
+fn tool_help(tool: &str) -> String {
+    with_native_hint(tool, external_tool_help(tool))
+}
+
+// The tools the native backend replaces are optional when limage has it
+fn with_native_hint(tool: &str, hint: String) -> String {
+    match tool {
+        "xorriso" | "curl" | "git" | "make" if ToolBackend::Native.is_available() => format!(
+            "{}, or set build.tools = \"native\" to build without it",
+            hint
+        ),
+        _ => hint,
+    }
+}
+
+fn external_tool_help(tool: &str) -> String {
+    match tool {
+        "xorriso" => install("xorriso", "xorriso", "xorriso", "libisoburn"),
+        "curl" => install("curl", "curl", "curl", "curl"),
+        "git" | "make" => install(tool, tool, tool, tool),
+        "limine" => {
+            "The limine utility is built with the bootloader; fetch Limine again to rebuild it"
+                .to_string()
+        }
+        tool => format!("Make sure {} is installed and on PATH", tool),
+    }
+}
+
+fn install(tool: &str, apt: &str, brew: &str, pacman: &str) -> String {
+    format!(
+        "Make sure {} is installed and on PATH: install it with `apt install {}`, `brew install {}` or `pacman -S {}`",
+        tool, apt, brew, pacman
+    )
+}
@@ -0,0 +1,238 @@
+#[cfg(feature = "native")]
+use crate::native;
+use crate::{
+    accel,
+    artifacts::OvmfManager,
+    config::{BackendKind, ConfigError, LimageConfig, ToolBackend},
+};
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(name: &str, status: Status, detail: impl Into<String>, hint: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            detail: detail.into(),
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+// Checks the host for everything a build and run needs; `loaded` is the unvalidated result of
+// loading `config_path`
+pub fn run(loaded: &Result<LimageConfig, ConfigError>, config_path: &Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let default_config = LimageConfig::default();
+    let config = match loaded {
+        Ok(config) => {
+            checks.push(match config.validate(None, false) {
+                Ok(()) if config_path.exists() => {
+                    Check::ok("config", format!("{} is valid", config_path.display()))
+                }
+                Ok(()) => Check::ok(
+                    "config",
+                    format!("no {}, using defaults", config_path.display()),
+                ),
+                Err(e) => Check::problem(
+                    "config",
+                    Status::Fail,
+                    e.to_string(),
+                    "Fix the reported key in the config file",
+                ),
+            });
+            config
+        }
+        Err(e) => {
+            checks.push(Check::problem(
+                "config",
+                Status::Fail,
+                match std::error::Error::source(e) {
+                    Some(source) => format!("{}: {}", e, source),
+                    None => e.to_string(),
+                },
+                "Fix the config file; the remaining checks use the defaults",
+            ));
+            &default_config
+        }
+    };
+
+    checks.push(if config.limine_conf().exists() {
+        Check::ok("limine.conf", "found")
+    } else {
+        Check::problem(
+            "limine.conf",
+            Status::Fail,
+            "not found in the current directory",
+            "Create a limine.conf next to Cargo.toml; it is copied into the image on every build",
+        )
+    });
+
+    match config.runner.backend {
+        BackendKind::Qemu => {
+            checks.push(tool(
+                &config.qemu.binary,
+                "--version",
+                Status::Fail,
+                "Install QEMU, e.g. `apt install qemu-system-x86` or `brew install qemu`",
+            ));
+            checks.push(match accel::resolve("auto", &config.qemu.binary) {
+                Some(accel) => Check::ok("acceleration", accel),
+                None => Check::problem(
+                    "acceleration",
+                    Status::Warn,
+                    "no hardware acceleration, QEMU will fall back to slow TCG emulation",
+                    "On Linux, enable virtualization in firmware and make sure you can access /dev/kvm (e.g. join the `kvm` group)",
+                ),
+            });
+        }
+        BackendKind::Bochs => checks.push(tool(
+            &config.runner.bochs.binary,
+            "--help",
+            Status::Fail,
+            "Install Bochs, e.g. `apt install bochs bochs-x bochsbios vgabios`",
+        )),
+        BackendKind::VirtualBox => checks.push(tool(
+            "VBoxManage",
+            "--version",
+            Status::Fail,
+            "Install VirtualBox and make sure VBoxManage is on PATH",
+        )),
+    }
+
+    match config.build.tools {
+        ToolBackend::External => {
+            checks.push(tool(
+                "xorriso",
+                "--version",
+                Status::Fail,
+                "Install xorriso, e.g. `apt install xorriso` or `brew install xorriso`",
+            ));
+            checks.push(tool(
+                "git",
+                "--version",
+                Status::Fail,
+                "Install git; it is used to fetch the Limine binaries",
+            ));
+            checks.push(tool(
+                "make",
+                "--version",
+                Status::Fail,
+                "Install make (e.g. `apt install build-essential`); it builds the Limine host utility",
+            ));
+            checks.push(tool(
+                "curl",
+                "--version",
+                Status::Fail,
+                "Install curl; it downloads the OVMF firmware",
+            ));
+        }
+        ToolBackend::Native => checks.push(Check::ok(
+            "build tools",
+            "native (no curl, git, make or xorriso needed)",
+        )),
+    }
+    if config.qemu.tpm {
+        checks.push(tool(
+            "swtpm",
+            "--version",
+            Status::Fail,
+            "Install swtpm (e.g. `apt install swtpm`) or set qemu.tpm = false",
+        ));
+    }
+    if config.test.gdb_on_panic {
+        checks.push(tool(
+            &config.test.gdb_binary,
+            "--version",
+            Status::Warn,
+            "Install gdb or set test.gdb_on_panic = false",
+        ));
+    }
+
+    checks.push(ovmf_reachable(config));
+    checks
+}
+
+// Runs `<binary> <version_arg>` and reports the first line of its output
+fn tool(binary: &str, version_arg: &str, missing: Status, hint: &str) -> Check {
+    match Command::new(binary)
+        .arg(version_arg)
+        .stdin(Stdio::null())
+        .output()
+    {
+        Ok(output) => {
+            let text = if output.stdout.is_empty() {
+                String::from_utf8_lossy(&output.stderr).into_owned()
+            } else {
+                String::from_utf8_lossy(&output.stdout).into_owned()
+            };
+            Check::ok(binary, text.lines().next().unwrap_or("found").trim())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Check::problem(binary, missing, "not found on PATH", hint)
+        }
+        Err(e) => Check::problem(binary, missing, format!("not usable: {}", e), hint),
+    }
+}
+
+fn ovmf_reachable(config: &LimageConfig) -> Check {
+    let ovmf = OvmfManager::new(&config.build.ovmf_path);
+    let url = ovmf.url(OvmfManager::FILES[0]);
+    let cached = ovmf.code_path().exists();
+    let reachable = match config.build.tools {
+        #[cfg(feature = "native")]
+        ToolBackend::Native => native::reachable(&url),
+        _ => curl_reachable(&url),
+    };
+
+    match (reachable, cached) {
+        (true, _) => Check::ok("ovmf download", "release URL is reachable"),
+        (false, true) => Check::problem(
+            "ovmf download",
+            Status::Warn,
+            "release URL is unreachable, but cached firmware exists",
+            "Builds use the cached firmware, but `limage update` needs network access",
+        ),
+        (false, false) => Check::problem(
+            "ovmf download",
+            Status::Fail,
+            format!("cannot reach {}", url),
+            "Check your network or proxy settings; OVMF is required for UEFI boot",
+        ),
+    }
+}
+
+fn curl_reachable(url: &str) -> bool {
+    Command::new("curl")
+        .args(["-sSfIL", "--max-time", "10", url])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
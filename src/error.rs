@@ -0,0 +1,66 @@
+use crate::{
+    artifacts::ArtifactError, builder::BuildError, config::ConfigError, diagnostic, iso::IsoError,
+    runner::RunError,
+};
+use thiserror::Error;
+
+// Any error of the library API, for callers that want one type to match on. Every error has a
+// stable code (E001-E099) that the command line tool prints too
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Build(#[from] BuildError),
+
+    #[error(transparent)]
+    Run(#[from] RunError),
+
+    #[error(transparent)]
+    Artifact(#[from] ArtifactError),
+
+    #[error(transparent)]
+    Iso(#[from] IsoError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Config(error) => error.code(),
+            Error::Build(error) => error.code(),
+            Error::Run(error) => error.code(),
+            Error::Artifact(error) => error.code(),
+            Error::Iso(error) => error.code(),
+        }
+    }
+
+    // How to fix the problem, where there is a common fix
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            Error::Config(error) => diagnostic::config_help(error),
+            Error::Build(error) => diagnostic::build_help(error),
+            Error::Run(error) => match error {
+                RunError::Config { source } => diagnostic::config_help(source),
+                error => diagnostic::run_help(error),
+            },
+            Error::Artifact(error) => diagnostic::artifact_help(error),
+            Error::Iso(error) => diagnostic::iso_help(error),
+        }
+    }
+
+    // What the failed tool printed to stderr, where it was captured
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            Error::Build(BuildError::CommandFailed { stderr, .. })
+            | Error::Build(BuildError::CheckoutLimine { stderr, .. })
+            | Error::Artifact(ArtifactError::Failed { stderr, .. })
+            | Error::Iso(IsoError::Failed { stderr, .. }) => {
+                Some(stderr.as_str()).filter(|stderr| !stderr.is_empty())
+            }
+            _ => None,
+        }
+    }
+}
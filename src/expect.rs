@@ -0,0 +1,114 @@
+use crate::config::ExpectConfig;
+use regex::Regex;
+use similar::TextDiff;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Monotonic boot clocks such as `[    0.012345]` and wall-clock times such as `12:34:56.789`
+const TIMESTAMP_PATTERNS: [&str; 2] = [r"\[\s*\d+\.\d+\]", r"\b\d{1,2}:\d{2}:\d{2}(\.\d+)?\b"];
+// Long hex numbers are pointers far more often than meaningful values
+const ADDRESS_PATTERN: &str = r"\b0x[0-9a-fA-F]{8,}\b";
+
+pub struct Normalizer {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Normalizer {
+    pub fn new(config: &ExpectConfig) -> Result<Self, ExpectError> {
+        let mut rules = Vec::new();
+        if config.normalize_timestamps {
+            for pattern in TIMESTAMP_PATTERNS {
+                rules.push((compile(pattern)?, "<TIME>".to_string()));
+            }
+        }
+        if config.normalize_addresses {
+            rules.push((compile(ADDRESS_PATTERN)?, "<ADDR>".to_string()));
+        }
+        for rule in &config.replace {
+            rules.push((compile(&rule.pattern)?, rule.with.clone()));
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn normalize(&self, text: &str) -> String {
+        let mut lines: Vec<String> = text
+            .lines()
+            .map(|line| {
+                let mut line = strip_ansi(line).trim_end().to_string();
+                for (regex, replacement) in &self.rules {
+                    line = regex.replace_all(&line, replacement.as_str()).into_owned();
+                }
+                line
+            })
+            .collect();
+
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        lines.join("\n") + "\n"
+    }
+
+    // Returns a unified diff of the normalized transcripts, or None if they match
+    pub fn compare(&self, expected: &str, actual: &str) -> Option<String> {
+        let expected = self.normalize(expected);
+        let actual = self.normalize(actual);
+        if expected == actual {
+            return None;
+        }
+
+        Some(
+            TextDiff::from_lines(&expected, &actual)
+                .unified_diff()
+                .context_radius(3)
+                .header("expected", "actual")
+                .to_string(),
+        )
+    }
+}
+
+pub fn read_expected(path: &Path) -> Result<String, ExpectError> {
+    std::fs::read_to_string(path).map_err(|e| ExpectError::ReadExpected {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+fn compile(pattern: &str) -> Result<Regex, ExpectError> {
+    Regex::new(pattern).map_err(|e| ExpectError::InvalidPattern {
+        pattern: pattern.to_string(),
+        source: e,
+    })
+}
+
+// The host-side log level coloring must not leak into the comparison
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[derive(Debug, Error)]
+pub enum ExpectError {
+    #[error("Failed to read expected output {path:?}: {source}")]
+    ReadExpected {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid normalization pattern '{pattern}': {source}")]
+    InvalidPattern {
+        pattern: String,
+        source: regex::Error,
+    },
+}
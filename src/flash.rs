@@ -0,0 +1,316 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument, warn};
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// Anything larger than this is more likely an internal disk than a boot stick
+const MAX_SANE_DEVICE_SIZE: u64 = 256 * 1024 * 1024 * 1024;
+
+pub struct FlashOptions {
+    pub assume_yes: bool,
+    pub force: bool,
+}
+
+#[instrument(skip(options), err)]
+pub fn flash(image: &Path, device: &Path, options: &FlashOptions) -> Result<(), FlashError> {
+    let image_size = std::fs::metadata(image)
+        .map_err(|e| FlashError::MissingImage {
+            path: image.to_path_buf(),
+            source: e,
+        })?
+        .len();
+
+    let info = BlockDevice::inspect(device)?;
+    debug!("Target device: {:?}", info);
+
+    if info.is_partition {
+        return Err(FlashError::Partition {
+            device: device.to_path_buf(),
+        });
+    }
+    if info.mounted {
+        return Err(FlashError::Mounted {
+            device: device.to_path_buf(),
+        });
+    }
+    if !info.holders.is_empty() {
+        return Err(FlashError::Held {
+            device: device.to_path_buf(),
+            holders: info.holders.join(", "),
+        });
+    }
+    if info.size < image_size {
+        return Err(FlashError::TooSmall {
+            device: device.to_path_buf(),
+            device_size: info.size,
+            image_size,
+        });
+    }
+    if !options.force {
+        if !info.removable {
+            return Err(FlashError::NotRemovable {
+                device: device.to_path_buf(),
+            });
+        }
+        if info.size > MAX_SANE_DEVICE_SIZE {
+            return Err(FlashError::SuspiciousSize {
+                device: device.to_path_buf(),
+                size: info.size,
+            });
+        }
+    }
+
+    if !options.assume_yes && !confirm(image, device, &info)? {
+        return Err(FlashError::Aborted);
+    }
+
+    write_image(image, image_size, device)?;
+    info!("Flashed {:?} to {:?}", image, device);
+    Ok(())
+}
+
+#[derive(Debug)]
+struct BlockDevice {
+    model: String,
+    size: u64,
+    removable: bool,
+    is_partition: bool,
+    mounted: bool,
+    // Devices stacked on it or its partitions, such as dm-crypt, LVM or RAID
+    holders: Vec<String>,
+}
+
+impl BlockDevice {
+    #[cfg(target_os = "linux")]
+    fn inspect(device: &Path) -> Result<Self, FlashError> {
+        let canonical = device
+            .canonicalize()
+            .map_err(|e| FlashError::Inspect { source: e })?;
+        let name = canonical
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| FlashError::NotBlockDevice {
+                device: device.to_path_buf(),
+            })?
+            .to_string();
+
+        let sys = PathBuf::from("/sys/class/block").join(&name);
+        if !sys.exists() {
+            return Err(FlashError::NotBlockDevice {
+                device: device.to_path_buf(),
+            });
+        }
+        let read = |file: &str| {
+            std::fs::read_to_string(sys.join(file))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+
+        // Many USB SSDs report removable=0, so the USB transport counts as well
+        let on_usb = sys
+            .canonicalize()
+            .map(|path| path.to_string_lossy().contains("/usb"))
+            .unwrap_or(false);
+
+        // The device itself and its partitions, which sysfs lists as sda1, nvme0n1p1, ...
+        let mut names = vec![name.clone()];
+        if let Ok(entries) = std::fs::read_dir(&sys) {
+            names.extend(
+                entries
+                    .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                    .filter(|entry| entry.starts_with(&name) && entry != &name),
+            );
+        }
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+        let holders = names
+            .iter()
+            .flat_map(|name| {
+                std::fs::read_dir(PathBuf::from("/sys/class/block").join(name).join("holders"))
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            })
+            .collect();
+
+        Ok(Self {
+            model: read("device/model"),
+            size: read("size").parse::<u64>().unwrap_or(0) * 512,
+            removable: read("removable") == "1" || on_usb,
+            is_partition: sys.join("partition").exists(),
+            mounted: is_mounted(&mounts, &names),
+            holders,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn inspect(_device: &Path) -> Result<Self, FlashError> {
+        Err(FlashError::Unsupported)
+    }
+}
+
+// Whether /proc/mounts has a filesystem on one of the block devices `names`
+#[cfg(target_os = "linux")]
+fn is_mounted(mounts: &str, names: &[String]) -> bool {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|source| {
+            // Sources such as /dev/disk/by-uuid/... are links to the device node
+            let source = Path::new(source);
+            let node = source
+                .canonicalize()
+                .unwrap_or_else(|_| source.to_path_buf());
+            node.strip_prefix("/dev").ok()?.to_str().map(str::to_string)
+        })
+        .any(|device| names.contains(&device))
+}
+
+fn confirm(image: &Path, device: &Path, info: &BlockDevice) -> Result<bool, FlashError> {
+    warn!("All data on {:?} will be destroyed", device);
+    print!(
+        "Write {} to {} ({}, {:.1} GiB)? Type 'yes' to continue: ",
+        image.display(),
+        device.display(),
+        if info.model.is_empty() {
+            "unknown model"
+        } else {
+            &info.model
+        },
+        info.size as f64 / (1024.0 * 1024.0 * 1024.0)
+    );
+    std::io::stdout()
+        .flush()
+        .map_err(|e| FlashError::Write { source: e })?;
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| FlashError::Write { source: e })?;
+    Ok(answer.trim() == "yes")
+}
+
+fn write_image(image: &Path, image_size: u64, device: &Path) -> Result<(), FlashError> {
+    let mut source = File::open(image).map_err(|e| FlashError::MissingImage {
+        path: image.to_path_buf(),
+        source: e,
+    })?;
+    let mut target =
+        OpenOptions::new()
+            .write(true)
+            .open(device)
+            .map_err(|e| FlashError::OpenDevice {
+                device: device.to_path_buf(),
+                source: e,
+            })?;
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut written = 0u64;
+    loop {
+        let read = source
+            .read(&mut buffer)
+            .map_err(|e| FlashError::Write { source: e })?;
+        if read == 0 {
+            break;
+        }
+        target
+            .write_all(&buffer[..read])
+            .map_err(|e| FlashError::Write { source: e })?;
+        written += read as u64;
+        eprint!(
+            "\rWriting... {:>3}% ({} / {} MiB)",
+            written * 100 / image_size.max(1),
+            written / (1024 * 1024),
+            image_size / (1024 * 1024)
+        );
+    }
+    eprintln!();
+
+    info!("Syncing {:?}", device);
+    target
+        .sync_all()
+        .map_err(|e| FlashError::Write { source: e })?;
+    let _ = Command::new("sync").status();
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum FlashError {
+    #[error("Image {path:?} not found, run `limage` to build it first: {source}")]
+    MissingImage {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to inspect target device: {source}")]
+    Inspect { source: std::io::Error },
+
+    #[error("{device:?} is not a block device")]
+    NotBlockDevice { device: PathBuf },
+
+    #[error("{device:?} is a partition, pass the whole device (e.g. /dev/sdb, not /dev/sdb1)")]
+    Partition { device: PathBuf },
+
+    #[error("{device:?} has mounted filesystems, unmount them first")]
+    Mounted { device: PathBuf },
+
+    #[error("{device:?} is in use by {holders} (e.g. LVM, RAID or dm-crypt), release it first")]
+    Held { device: PathBuf, holders: String },
+
+    #[error("{device:?} ({device_size} bytes) is smaller than the image ({image_size} bytes)")]
+    TooSmall {
+        device: PathBuf,
+        device_size: u64,
+        image_size: u64,
+    },
+
+    #[error("{device:?} is not removable media, pass --force if you are sure")]
+    NotRemovable { device: PathBuf },
+
+    #[error(
+        "{device:?} is unusually large ({size} bytes) for boot media, pass --force if you are sure"
+    )]
+    SuspiciousSize { device: PathBuf, size: u64 },
+
+    #[error("Failed to open {device:?} for writing: {source}")]
+    OpenDevice {
+        device: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to write image: {source}")]
+    Write { source: std::io::Error },
+
+    #[error("Aborted, nothing was written")]
+    Aborted,
+
+    #[error("Flashing is only supported on Linux")]
+    Unsupported,
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    const MOUNTS: &str = "/dev/sdaa1 /mnt/backup ext4 rw,relatime 0 0\nproc /proc proc rw 0 0\ntmpfs /tmp tmpfs rw 0 0\n";
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn another_disk_with_a_longer_name_is_not_mounted() {
+        assert!(!is_mounted(MOUNTS, &names(&["sda", "sda1", "sda2"])));
+    }
+
+    #[test]
+    fn a_mounted_partition_counts() {
+        assert!(is_mounted(MOUNTS, &names(&["sdaa", "sdaa1"])));
+        assert!(!is_mounted(MOUNTS, &names(&["sdaa"])));
+    }
+}
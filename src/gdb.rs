@@ -0,0 +1,117 @@
+use std::{
+    io::Read,
+    net::TcpListener,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{debug, instrument};
+use wait_timeout::ChildExt;
+
+const GDB_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Registers, backtrace and the faulting instructions of every vCPU
+const REPORT_COMMANDS: [&str; 4] = [
+    "info registers",
+    "thread apply all bt",
+    "x/8i $pc",
+    "info threads",
+];
+
+// Picks a free localhost port for QEMU's gdbstub
+pub fn allocate_port() -> Result<u16, GdbError> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .map_err(|e| GdbError::AllocatePort { source: e })
+}
+
+pub fn qemu_args(port: u16) -> Vec<String> {
+    vec!["-gdb".to_string(), format!("tcp:127.0.0.1:{}", port)]
+}
+
+// Attaches gdb in batch mode to the stopped guest and returns everything it printed
+#[instrument(skip(extra_commands), err)]
+pub fn capture_report(
+    binary: &str,
+    port: u16,
+    kernel: Option<&Path>,
+    extra_commands: &[String],
+) -> Result<String, GdbError> {
+    let mut command = Command::new(binary);
+    command
+        .arg("-batch")
+        .arg("-nx")
+        .args(["-ex", "set pagination off"])
+        .args(["-ex", &format!("target remote 127.0.0.1:{}", port)]);
+    for gdb_command in REPORT_COMMANDS
+        .iter()
+        .copied()
+        .chain(extra_commands.iter().map(String::as_str))
+    {
+        command.args(["-ex", gdb_command]);
+    }
+    if let Some(kernel) = kernel {
+        command.arg(kernel);
+    }
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    debug!("Running {:?}", command);
+    let mut child = command.spawn().map_err(|e| GdbError::Spawn { source: e })?;
+
+    // Drain both pipes while waiting so a long backtrace can't fill them and stall gdb
+    let readers: Vec<_> = [
+        child
+            .stdout
+            .take()
+            .map(|out| Box::new(out) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|err| Box::new(err) as Box<dyn Read + Send>),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|mut output| {
+        thread::spawn(move || {
+            let mut text = String::new();
+            let _ = output.read_to_string(&mut text);
+            text
+        })
+    })
+    .collect();
+
+    if child
+        .wait_timeout(GDB_TIMEOUT)
+        .map_err(|e| GdbError::Spawn { source: e })?
+        .is_none()
+    {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(GdbError::TimedOut {
+            timeout: GDB_TIMEOUT,
+        });
+    }
+
+    Ok(readers
+        .into_iter()
+        .filter_map(|reader| reader.join().ok())
+        .collect())
+}
+
+#[derive(Debug, Error)]
+pub enum GdbError {
+    #[error("Failed to allocate a gdbstub port: {source}")]
+    AllocatePort { source: std::io::Error },
+
+    #[error("Failed to run gdb: {source}")]
+    Spawn { source: std::io::Error },
+
+    #[error("gdb did not finish within {timeout:?}")]
+    TimedOut { timeout: Duration },
+}
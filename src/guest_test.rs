@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+pub use limage_test::PROTOCOL_PREFIX;
+
+// A line of the serial protocol limage-test's runner speaks, one JSON object after the prefix
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GuestEvent {
+    Plan {
+        count: usize,
+        #[serde(default)]
+        filtered_out: usize,
+    },
+    Started {
+        name: String,
+    },
+    Passed {
+        name: String,
+    },
+    Failed {
+        name: String,
+        message: String,
+        location: Option<String>,
+    },
+    // The guest's timer caught the test past its deadline
+    TimedOut {
+        name: String,
+        timeout_ms: u64,
+    },
+    Summary {
+        passed: usize,
+        // Timed-out tests included
+        failed: usize,
+        not_run: usize,
+        // Skipped by `limage test --filter`
+        #[serde(default)]
+        filtered_out: usize,
+    },
+}
+
+impl GuestEvent {
+    // None for lines that are not protocol lines, or whose event this limage does not know
+    pub fn parse(line: &str) -> Option<Self> {
+        let json = line
+            .trim_end_matches(['\r', '\n'])
+            .strip_prefix(PROTOCOL_PREFIX)?;
+        serde_json::from_str(json).ok()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuestTestStatus {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+impl GuestTestStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            GuestTestStatus::Passed => "passed",
+            GuestTestStatus::Failed => "failed",
+            GuestTestStatus::TimedOut => "timed_out",
+        }
+    }
+}
+
+// One `#[test_case]` the guest ran, timed by the host between its started and finished events
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuestTest {
+    pub name: String,
+    pub status: GuestTestStatus,
+    pub duration: Duration,
+    // The panic message and location of a failed test, or the timeout of a timed-out one
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub not_run: usize,
+    pub filtered_out: usize,
+}
+
+// Collects the results of one run as the guest reports them
+#[derive(Debug, Default)]
+pub struct GuestTests {
+    started: Option<(String, Instant)>,
+    // The planned and filtered-out counts the guest started with
+    plan: Option<(usize, usize)>,
+    pub results: Vec<GuestTest>,
+    pub summary: Option<GuestSummary>,
+}
+
+impl GuestTests {
+    // Returns the test that `event` finished, if any
+    pub fn record(&mut self, event: GuestEvent) -> Option<&GuestTest> {
+        let (name, status, message) = match event {
+            GuestEvent::Plan {
+                count,
+                filtered_out,
+            } => {
+                self.plan = Some((count, filtered_out));
+                return None;
+            }
+            GuestEvent::Started { name } => {
+                self.started = Some((name, Instant::now()));
+                return None;
+            }
+            GuestEvent::Summary {
+                passed,
+                failed,
+                not_run,
+                filtered_out,
+            } => {
+                self.summary = Some(GuestSummary {
+                    passed,
+                    failed,
+                    not_run,
+                    filtered_out,
+                });
+                return None;
+            }
+            GuestEvent::Passed { name } => (name, GuestTestStatus::Passed, None),
+            GuestEvent::Failed {
+                name,
+                message,
+                location,
+            } => {
+                let message = match location {
+                    Some(location) => format!("{} at {}", message, location),
+                    None => message,
+                };
+                (name, GuestTestStatus::Failed, Some(message))
+            }
+            GuestEvent::TimedOut { name, timeout_ms } => {
+                let message = format!("ran longer than its {} ms timeout", timeout_ms);
+                (name, GuestTestStatus::TimedOut, Some(message))
+            }
+        };
+        let duration = match self.started.take() {
+            Some((started, start)) if started == name => start.elapsed(),
+            _ => Duration::ZERO,
+        };
+        self.results.push(GuestTest {
+            name,
+            status,
+            duration,
+            message,
+        });
+        self.results.last()
+    }
+
+    // For a guest that planned tests but stopped before its summary, e.g. on a crash, makes up the
+    // summary from the tests that finished, the rest counting as not run
+    pub fn finish(&mut self) -> Option<GuestSummary> {
+        let (count, filtered_out) = self.plan.filter(|_| self.summary.is_none())?;
+        let passed = self
+            .results
+            .iter()
+            .filter(|test| test.status == GuestTestStatus::Passed)
+            .count();
+        let summary = GuestSummary {
+            passed,
+            failed: self.results.len() - passed,
+            not_run: count.saturating_sub(self.results.len()),
+            filtered_out,
+        };
+        self.summary = Some(summary);
+        Some(summary)
+    }
+
+    // Whether the guest reported a failure, whatever exit code it then managed to produce
+    pub fn failed(&self) -> bool {
+        self.results
+            .iter()
+            .any(|test| test.status != GuestTestStatus::Passed)
+            || self.summary.is_some_and(|summary| summary.failed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(json: &str) -> String {
+        format!("{}{}\r\n", PROTOCOL_PREFIX, json)
+    }
+
+    fn record_all(events: &[&str]) -> GuestTests {
+        let mut tests = GuestTests::default();
+        for event in events {
+            tests.record(GuestEvent::parse(&line(event)).expect("the event parses"));
+        }
+        tests
+    }
+
+    #[test]
+    fn parse_needs_the_prefix_at_the_start_of_the_line() {
+        let event = r#"{"event":"passed","name":"math::adds"}"#;
+        assert_eq!(
+            GuestEvent::parse(&line(event)),
+            Some(GuestEvent::Passed {
+                name: "math::adds".to_string()
+            })
+        );
+        assert_eq!(GuestEvent::parse(event), None);
+        assert_eq!(GuestEvent::parse(&format!("[ok] {}", line(event))), None);
+    }
+
+    #[test]
+    fn parse_ignores_malformed_and_unknown_events() {
+        assert_eq!(GuestEvent::parse(&line(r#"{"event":"passed""#)), None);
+        assert_eq!(GuestEvent::parse(&line(r#"{"event":"passed"}"#)), None);
+        assert_eq!(
+            GuestEvent::parse(&line(r#"{"event":"skipped","name":"a"}"#)),
+            None
+        );
+        assert_eq!(GuestEvent::parse(&line("")), None);
+    }
+
+    #[test]
+    fn record_collects_results_and_messages() {
+        let tests = record_all(&[
+            r#"{"event":"plan","count":3}"#,
+            r#"{"event":"started","name":"a"}"#,
+            r#"{"event":"passed","name":"a"}"#,
+            r#"{"event":"started","name":"b"}"#,
+            r#"{"event":"failed","name":"b","message":"assertion failed","location":"src/b.rs:4:5"}"#,
+            r#"{"event":"started","name":"c"}"#,
+            r#"{"event":"timed_out","name":"c","timeout_ms":500}"#,
+        ]);
+        let results: Vec<_> = tests
+            .results
+            .iter()
+            .map(|test| (test.name.as_str(), test.status, test.message.as_deref()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("a", GuestTestStatus::Passed, None),
+                (
+                    "b",
+                    GuestTestStatus::Failed,
+                    Some("assertion failed at src/b.rs:4:5")
+                ),
+                (
+                    "c",
+                    GuestTestStatus::TimedOut,
+                    Some("ran longer than its 500 ms timeout")
+                ),
+            ]
+        );
+        assert!(tests.failed());
+    }
+
+    #[test]
+    fn plan_without_summary_counts_unfinished_tests_as_not_run() {
+        let mut tests = record_all(&[
+            r#"{"event":"plan","count":4,"filtered_out":2}"#,
+            r#"{"event":"started","name":"a"}"#,
+            r#"{"event":"passed","name":"a"}"#,
+            r#"{"event":"started","name":"b"}"#,
+            r#"{"event":"failed","name":"b","message":"boom","location":null}"#,
+            r#"{"event":"started","name":"c"}"#,
+        ]);
+        assert_eq!(tests.summary, None);
+        let summary = GuestSummary {
+            passed: 1,
+            failed: 1,
+            not_run: 2,
+            filtered_out: 2,
+        };
+        assert_eq!(tests.finish(), Some(summary));
+        assert_eq!(tests.summary, Some(summary));
+        // Only made up once
+        assert_eq!(tests.finish(), None);
+    }
+
+    #[test]
+    fn finish_keeps_the_guest_summary() {
+        let mut tests = record_all(&[
+            r#"{"event":"plan","count":2}"#,
+            r#"{"event":"started","name":"a"}"#,
+            r#"{"event":"passed","name":"a"}"#,
+            r#"{"event":"summary","passed":1,"failed":0,"not_run":1}"#,
+        ]);
+        assert_eq!(tests.finish(), None);
+        assert_eq!(
+            tests.summary,
+            Some(GuestSummary {
+                passed: 1,
+                failed: 0,
+                not_run: 1,
+                filtered_out: 0,
+            })
+        );
+        assert!(!tests.failed());
+    }
+
+    #[test]
+    fn finish_without_plan_has_no_summary() {
+        let mut tests = record_all(&[r#"{"event":"passed","name":"a"}"#]);
+        assert_eq!(tests.finish(), None);
+        assert_eq!(tests.summary, None);
+    }
+}
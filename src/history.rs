@@ -0,0 +1,266 @@
+use crate::{
+    config::LimageConfig,
+    package::utc_date,
+    runner::{RunOutcome, RunStatus},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+const RECORD_FILE: &str = "run.json";
+const SERIAL_LOG: &str = "serial.log";
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+// One run, stored as `run.json` next to its serial log when it starts and again when it ends
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    // Milliseconds since the epoch the run started at, which also names its directory
+    pub id: String,
+    pub started: u64,
+    pub mode: Option<String>,
+    pub test: bool,
+    // False while the run is still going
+    pub finished: bool,
+    // None while the run is still going or when limage failed before QEMU exited
+    pub status: Option<RunStatus>,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+impl RunRecord {
+    // `YYYY-MM-DD HH:MM:SS` in UTC
+    pub fn started_at(&self) -> String {
+        let time = UNIX_EPOCH + Duration::from_secs(self.started);
+        let secs = self.started % 86400;
+        format!(
+            "{} {:02}:{:02}:{:02}",
+            utc_date(time),
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
+// The runs recorded under the work directory, newest first
+pub struct History {
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl History {
+    pub fn new(config: &LimageConfig) -> Self {
+        Self {
+            dir: config.work_dir().join("runs"),
+            keep: config.run.history,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.keep > 0
+    }
+
+    pub fn list(&self) -> Result<Vec<RunRecord>, HistoryError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(HistoryError::Read { source: e }),
+        };
+
+        let mut records = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| HistoryError::Read { source: e })?
+                .path()
+                .join(RECORD_FILE);
+            let Ok(content) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let record: RunRecord = serde_json::from_str(&content)
+                .map_err(|e| HistoryError::ParseRecord { path, source: e })?;
+            records.push(record);
+        }
+        records.sort_by_key(|record| std::cmp::Reverse(record.id.parse::<u64>().unwrap_or(0)));
+        Ok(records)
+    }
+
+    // `1` is the latest run, `2` the one before; anything else is matched against the run ids
+    pub fn find(&self, run: &str) -> Result<RunRecord, HistoryError> {
+        let records = self.list()?;
+        let found = match run.parse::<usize>() {
+            Ok(index) if index >= 1 && index <= records.len() => records.get(index - 1),
+            _ => records.iter().find(|record| record.id == run),
+        };
+        found.cloned().ok_or_else(|| HistoryError::NotFound {
+            run: run.to_string(),
+        })
+    }
+
+    pub fn serial_log(&self, record: &RunRecord) -> PathBuf {
+        self.dir.join(&record.id).join(SERIAL_LOG)
+    }
+
+    pub fn read_log(&self, record: &RunRecord) -> Result<String, HistoryError> {
+        std::fs::read(self.serial_log(record))
+            .map(|log| String::from_utf8_lossy(&log).into_owned())
+            .map_err(|e| HistoryError::Read { source: e })
+    }
+
+    // Passes every line written to the serial log after its first `offset` bytes to `on_line`
+    // until the run has finished
+    pub fn follow(
+        &self,
+        record: &RunRecord,
+        offset: u64,
+        mut on_line: impl FnMut(&str),
+    ) -> Result<(), HistoryError> {
+        let mut log =
+            File::open(self.serial_log(record)).map_err(|e| HistoryError::Read { source: e })?;
+        log.seek(SeekFrom::Start(offset))
+            .map_err(|e| HistoryError::Read { source: e })?;
+        let mut reader = BufReader::new(log);
+        let mut line = Vec::new();
+        loop {
+            // Checked before reading so the output written right before the end is not lost
+            let finished = self.reload(record)?.finished;
+            loop {
+                let read = reader
+                    .read_until(b'\n', &mut line)
+                    .map_err(|e| HistoryError::Read { source: e })?;
+                if read == 0 || !line.ends_with(b"\n") {
+                    break;
+                }
+                on_line(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']));
+                line.clear();
+            }
+            if finished {
+                if !line.is_empty() {
+                    on_line(&String::from_utf8_lossy(&line));
+                }
+                return Ok(());
+            }
+            thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+
+    // Re-reads a record, e.g. to see whether a followed run has finished
+    pub fn reload(&self, record: &RunRecord) -> Result<RunRecord, HistoryError> {
+        self.find(&record.id)
+    }
+
+    // Creates the directory of a new run and opens its serial log
+    pub fn start(&self, mode: Option<&str>, test: bool) -> Result<Recording, HistoryError> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Retries can start within the same millisecond
+        let (id, dir) = (0..)
+            .map(|offset| {
+                let id = (millis + offset).to_string();
+                let dir = self.dir.join(&id);
+                (id, dir)
+            })
+            .find(|(_, dir)| !dir.exists())
+            .expect("an unused run id exists");
+        std::fs::create_dir_all(&dir).map_err(|e| HistoryError::Write { source: e })?;
+        let serial_log =
+            File::create(dir.join(SERIAL_LOG)).map_err(|e| HistoryError::Write { source: e })?;
+        debug!("Recording run {} in {:?}", id, dir);
+
+        let recording = Recording {
+            record: RunRecord {
+                started: (millis / 1000) as u64,
+                id,
+                mode: mode.map(str::to_string),
+                test,
+                finished: false,
+                status: None,
+                exit_code: None,
+                duration_ms: 0,
+            },
+            dir,
+            serial_log: Some(serial_log),
+            start: Instant::now(),
+        };
+        recording.save()?;
+        Ok(recording)
+    }
+
+    // Drops the oldest runs beyond `run.history`
+    fn prune(&self) {
+        let Ok(records) = self.list() else {
+            return;
+        };
+        for record in records.iter().skip(self.keep) {
+            let dir = self.dir.join(&record.id);
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                warn!("Failed to remove old run {:?}: {}", dir, e);
+            }
+        }
+    }
+}
+
+// A run in progress; its record is written by `finish`
+pub struct Recording {
+    record: RunRecord,
+    dir: PathBuf,
+    serial_log: Option<File>,
+    start: Instant,
+}
+
+impl Recording {
+    // The serial log is handed to whoever reads the guest output
+    pub fn take_serial_log(&mut self) -> Option<File> {
+        self.serial_log.take()
+    }
+
+    pub fn serial_log_path(&self) -> PathBuf {
+        self.dir.join(SERIAL_LOG)
+    }
+
+    // `outcome` is None when the run failed before QEMU exited
+    pub fn finish(mut self, history: &History, outcome: Option<&RunOutcome>) {
+        self.record.finished = true;
+        self.record.status = outcome.map(|outcome| outcome.status);
+        self.record.exit_code = outcome.and_then(|outcome| outcome.guest_exit_code);
+        self.record.duration_ms = self.start.elapsed().as_millis() as u64;
+        if let Err(e) = self.save() {
+            warn!("{}", e);
+        }
+        history.prune();
+    }
+
+    fn save(&self) -> Result<(), HistoryError> {
+        let json = serde_json::to_string_pretty(&self.record).map_err(|e| HistoryError::Write {
+            source: std::io::Error::other(e),
+        })?;
+        std::fs::write(self.dir.join(RECORD_FILE), json)
+            .map_err(|e| HistoryError::Write { source: e })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("Failed to read run history: {source}")]
+    Read { source: std::io::Error },
+
+    #[error("Failed to parse run record {path:?}: {source}")]
+    ParseRecord {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error("Failed to record run: {source}")]
+    Write { source: std::io::Error },
+
+    #[error("No recorded run '{run}', `limage logs` lists them")]
+    NotFound { run: String },
+}
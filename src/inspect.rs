@@ -0,0 +1,371 @@
+use crate::config::{self, EnvOverride, LimageConfig};
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use toml::{value::Table, Value};
+
+// Provenance comments start at this column when the value fits
+const COMMENT_COLUMN: usize = 48;
+
+const TEMPLATE: &str = r#"# limage configuration. Every key is optional; the values below are the defaults.
+# Run `limage config show` to see what limage resolves, and where each value comes from.
+
+# Format of this file; `limage config migrate` updates older ones
+config_version = 1
+
+[build]
+# Output image and the directories the build stages into
+image_path = "target/kernel.iso"
+ovmf_path = "target/ovmf"
+limine_path = "target/limine"
+iso_root = "target/iso_root"
+# Shell command run before every build, e.g. to compile user programs
+# prebuilder = "make -C userspace"
+# Where to find the kernel `cargo build` produced; by default taken from the cargo config and package
+# target = "x86_64-unknown-none"
+# profile = "release"
+# kernel_bin = "kernel"
+# Target JSON written if missing and passed to cargo with build-std by `limage test` and `limage ci`
+# target_json = "x86_64-kernel.json"
+# "external" runs curl, git, make and xorriso; "native" needs limage built with that feature
+# tools = "external"
+
+[qemu]
+binary = "qemu-system-x86_64"
+base_args = ["-cdrom", "{image}"]
+extra_args = []
+memory = "2G"
+# cpus = 4
+# cpu_model = "max"
+# machine = "q35"
+# "auto" probes for KVM (Linux), HVF (macOS) or WHPX (Windows) and falls back to TCG
+# accel = "auto"
+# "uefi" boots through OVMF, "bios" through QEMU's SeaBIOS
+firmware = "uefi"
+headless = false
+# nographic, gtk, sdl, none, vnc[:<port>] or spice[:<port>]
+# display = "gtk"
+snapshot = false
+tpm = false
+# unix:<path> or tcp:<port>; attach with `limage shell`
+# monitor = "unix:target/limage/monitor.sock"
+# debug, info, warn or danger
+# log_level = "info"
+interactive = false
+gdb_port = 1234
+
+# Named run modes, selected with `limage run mode <name>`
+# [qemu.modes.serial-debug]
+# description = "GDB stub with serial on stdio"
+# extra_args = ["-s", "-S", "-serial", "stdio"]
+# Any [qemu] or [test] setting can be replaced per mode; the rest is inherited
+# qemu = { memory = "4G" }
+# test = { timeout_secs = 1200 }
+
+# [[qemu.serials]]
+# target = "stdio"
+
+# Device tree for -dtb, also staged into the image and named by every limine.conf entry's dtb_path
+# dtb = "virt.dtb"
+# Firmware config blobs; names start with opt/, and each takes a `file` or a `string`
+# [[qemu.fw_cfg]]
+# name = "opt/org.example/config"
+# file = "config.bin"
+
+[test]
+timeout_secs = 300
+success_exit_code = 33
+no_reboot = true
+extra_args = []
+panic_pattern = "panicked at"
+retries = 0
+# idle_timeout_secs = 30
+# case_timeout_secs = 10
+shutdown_grace_secs = 5
+gdb_on_panic = false
+gdb_binary = "gdb"
+gdb_commands = []
+# Print a symbolized backtrace of the kernel addresses in panic output
+symbolize_panics = true
+
+[test.expect]
+# file = "tests/boot.txt"
+normalize_timestamps = true
+normalize_addresses = true
+
+[test.exit_device]
+iobase = 0xf4
+iosize = 0x04
+
+# [test.exit_codes]
+# 33 = "pass"
+# 35 = "skip"
+
+[run]
+# Rebuild the image on every `limage run`
+auto_build = true
+# Ask for the mode when several [qemu.modes] exist and `limage run` names none
+pick_mode = false
+# Runs kept under target/limage/runs for `limage logs`, 0 to stop recording
+history = 20
+
+[package]
+# Archives written by `limage package`; {name}, {version}, {date} and {commit} are substituted
+out_dir = "dist"
+name = "{name}-{version}"
+# "tar.zst" or "zip"
+format = "tar.zst"
+# Kernel ELF shipped for its symbols; by default the one `cargo build` produced
+# symbols = "target/x86_64-unknown-none/debug/kernel"
+include = []
+
+[runner]
+# qemu, bochs or virtualbox
+backend = "qemu"
+"#;
+
+// Renders the resolved configuration as TOML, noting for every value where it came from.
+// `path` is the config file `config` was loaded from, if any
+pub fn show(config: &LimageConfig, path: &Path) -> Result<String, InspectError> {
+    let resolved =
+        match Value::try_from(config).map_err(|e| InspectError::Serialize { source: e })? {
+            Value::Table(table) => table,
+            _ => unreachable!("the configuration serializes to a table"),
+        };
+
+    let file = if path.exists() {
+        let content = std::fs::read_to_string(path).map_err(|e| InspectError::Read {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+        match content.parse::<Value>() {
+            Ok(Value::Table(table)) => Some(with_host_section(table)),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    // The user config and the files `extends` names, nearest last. A broken one fails the load
+    // before this point
+    let layers: Vec<(PathBuf, Table)> = config::user_config()
+        .ok()
+        .flatten()
+        .into_iter()
+        .chain(
+            file.as_ref()
+                .and_then(|file| config::extends_chain(file, &config.root, None).ok())
+                .unwrap_or_default(),
+        )
+        .map(|(layer_path, table)| (layer_path, with_host_section(table)))
+        .collect();
+    let mut layered = Table::new();
+    for (_, layer) in &layers {
+        config::merge_tables(&mut layered, layer);
+    }
+    if let Some(file) = &file {
+        config::merge_tables(&mut layered, file);
+    }
+
+    let sources: Vec<String> = layers
+        .iter()
+        .map(|(layer_path, _)| layer_path.display().to_string())
+        .chain(file.is_some().then(|| path.display().to_string()))
+        .collect();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# Resolved configuration ({})",
+        if sources.is_empty() {
+            "defaults only".to_string()
+        } else {
+            format!("defaults merged with {}", sources.join(" and "))
+        }
+    );
+    let overrides = config::env_overrides(&layered);
+    let layers: Vec<(&Table, &Path)> = layers
+        .iter()
+        .map(|(layer_path, table)| (table, layer_path.as_path()))
+        .collect();
+    render_table(
+        &mut out,
+        &resolved,
+        file.as_ref(),
+        &layers,
+        &[],
+        path,
+        &overrides,
+    )?;
+    Ok(out)
+}
+
+// Values of the [host.<os>] section for this host are attributed to the file that sets them
+fn with_host_section(mut table: Table) -> Table {
+    if let Some(section) = config::host_section(&table).cloned() {
+        config::merge_tables(&mut table, &section);
+    }
+    table
+}
+
+// Writes the commented template to `path`, refusing to replace an existing file unless `force`
+pub fn init(path: &Path, force: bool) -> Result<(), InspectError> {
+    if path.exists() && !force {
+        return Err(InspectError::Exists {
+            path: path.to_path_buf(),
+        });
+    }
+    std::fs::write(path, TEMPLATE).map_err(|e| InspectError::Write {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+fn render_table(
+    out: &mut String,
+    table: &Table,
+    file: Option<&Table>,
+    // The tables at the same path in the configs beneath the file, nearest last, and the files
+    // they came from
+    layers: &[(&Table, &Path)],
+    path: &[&str],
+    file_path: &Path,
+    overrides: &[EnvOverride],
+) -> Result<(), InspectError> {
+    // TOML requires a table's plain values before any of its sub-tables
+    for (key, value) in table.iter().filter(|(_, value)| !is_section(value)) {
+        let mut single = Table::new();
+        single.insert(key.clone(), value.clone());
+        let line = toml::to_string(&single).map_err(|e| InspectError::Serialize { source: e })?;
+        let line = line.trim_end();
+        let env = overrides.iter().find(|env| {
+            env.key.len() == path.len() + 1
+                && env.key.iter().zip(path).all(|(a, b)| a == b)
+                && env.key.last() == Some(key)
+        });
+        let from_layer = layers
+            .iter()
+            .rev()
+            .find(|(layer, _)| layer.contains_key(key));
+        let comment = match (env, file.and_then(|file| file.get(key)), from_layer) {
+            (Some(env), _, _) => env.variable.clone(),
+            (None, Some(_), _) => file_path.display().to_string(),
+            (None, None, Some((_, layer_path))) => layer_path.display().to_string(),
+            (None, None, None) => "default".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{:<width$} # {}",
+            line,
+            comment,
+            width = COMMENT_COLUMN.max(line.len())
+        );
+    }
+
+    for (key, value) in table.iter().filter(|(_, value)| is_section(value)) {
+        let mut child_path = path.to_vec();
+        child_path.push(key);
+        let header = child_path
+            .iter()
+            .map(|key| quote_key(key))
+            .collect::<Vec<_>>()
+            .join(".");
+        let file_value = file.and_then(|file| file.get(key));
+        let layer_values: Vec<(&Value, &Path)> = layers
+            .iter()
+            .filter_map(|(layer, layer_path)| Some((layer.get(key)?, *layer_path)))
+            .collect();
+
+        match value {
+            Value::Table(child) => {
+                // Tables holding nothing but sub-tables need no header of their own
+                if child.values().any(|value| !is_section(value)) {
+                    let _ = writeln!(out, "\n[{}]", header);
+                }
+                let layer_tables: Vec<(&Table, &Path)> = layer_values
+                    .iter()
+                    .filter_map(|(value, layer_path)| Some((value.as_table()?, *layer_path)))
+                    .collect();
+                render_table(
+                    out,
+                    child,
+                    file_value.and_then(Value::as_table),
+                    &layer_tables,
+                    &child_path,
+                    file_path,
+                    overrides,
+                )?;
+            }
+            Value::Array(items) => {
+                for (index, item) in items.iter().enumerate() {
+                    let Value::Table(item) = item else { continue };
+                    let _ = writeln!(out, "\n[[{}]]", header);
+                    let file_item = file_value
+                        .and_then(Value::as_array)
+                        .and_then(|items| items.get(index))
+                        .and_then(Value::as_table);
+                    let layer_items: Vec<(&Table, &Path)> = layer_values
+                        .iter()
+                        .filter_map(|(value, layer_path)| {
+                            Some((value.as_array()?.get(index)?.as_table()?, *layer_path))
+                        })
+                        .collect();
+                    render_table(
+                        out,
+                        item,
+                        file_item,
+                        &layer_items,
+                        &child_path,
+                        file_path,
+                        overrides,
+                    )?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Tables and arrays of tables get their own headers
+fn is_section(value: &Value) -> bool {
+    match value {
+        Value::Table(_) => true,
+        Value::Array(items) => items.first().is_some_and(Value::is_table),
+        _ => false,
+    }
+}
+
+fn quote_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InspectError {
+    #[error("Failed to serialize the configuration: {source}")]
+    Serialize { source: toml::ser::Error },
+
+    #[error("Failed to read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to write {path:?}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{path:?} already exists; pass --force to overwrite it")]
+    Exists { path: PathBuf },
+}
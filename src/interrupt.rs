@@ -0,0 +1,176 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::Child,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tracing::debug;
+
+type Cleanup = Box<dyn FnOnce() + Send>;
+
+// What to undo when limage is interrupted, by the id of the guard that registered it
+static CLEANUPS: Mutex<BTreeMap<u64, Cleanup>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Undoes one cleanup registration when dropped, i.e. once the work it guarded finished normally
+#[must_use]
+pub struct InterruptGuard {
+    id: u64,
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if let Ok(mut cleanups) = CLEANUPS.lock() {
+            cleanups.remove(&self.id);
+        }
+    }
+}
+
+// Runs `cleanup` if limage is interrupted while the guard lives. Without `install` this only
+// records the cleanup, so library users keep their own signal handling
+pub fn on_interrupt(cleanup: impl FnOnce() + Send + 'static) -> InterruptGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut cleanups) = CLEANUPS.lock() {
+        cleanups.insert(id, Box::new(cleanup));
+    }
+    InterruptGuard { id }
+}
+
+// Stops a child process (QEMU, swtpm) that would otherwise outlive limage
+pub fn kill_on_interrupt(child: &Child) -> InterruptGuard {
+    let pid = child.id();
+    on_interrupt(move || terminate(pid))
+}
+
+// Removes a file or directory that is only half written while the guard lives
+pub fn remove_on_interrupt(path: &Path) -> InterruptGuard {
+    let path = path.to_path_buf();
+    on_interrupt(move || remove(&path))
+}
+
+// Keeps a thread that noticed the interrupt's effects, e.g. QEMU dying of the signal, from
+// reporting them; the handler exits once it has cleaned up
+pub fn park_if_interrupted() {
+    while INTERRUPTED.load(Ordering::SeqCst) {
+        std::thread::park();
+    }
+}
+
+// Newest registrations first, so a child is stopped before the files it writes are removed
+fn run_cleanups() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    let cleanups = match CLEANUPS.lock() {
+        Ok(mut cleanups) => std::mem::take(&mut *cleanups),
+        Err(_) => return,
+    };
+    for (_, cleanup) in cleanups.into_iter().rev() {
+        cleanup();
+    }
+}
+
+fn remove(path: &Path) {
+    debug!("Removing {:?} after interrupt", path);
+    let _ = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        sync::atomic::{AtomicI32, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+    const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+    // Write end of the pipe the handler wakes the cleanup thread through
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle(signal: libc::c_int) {
+        let byte = signal as u8;
+        // SAFETY: write(2) is async-signal-safe, and a failed write only loses the cleanup
+        unsafe {
+            libc::write(
+                PIPE.load(Ordering::Relaxed),
+                &byte as *const u8 as *const libc::c_void,
+                1,
+            );
+        }
+    }
+
+    pub fn install() -> std::io::Result<()> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe(2) writes
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let [read, write] = fds;
+        PIPE.store(write, Ordering::Relaxed);
+
+        thread::spawn(move || {
+            let mut byte = 0u8;
+            // SAFETY: reads one byte into `byte` from the pipe created above
+            let read = unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if read == 1 {
+                super::run_cleanups();
+                // The shell convention for a process ended by a signal
+                std::process::exit(128 + i32::from(byte));
+            }
+        });
+
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: `handle` only performs async-signal-safe operations
+            unsafe {
+                libc::signal(signal, handle as *const () as libc::sighandler_t);
+            }
+        }
+        Ok(())
+    }
+
+    // SIGTERM lets QEMU flush its output; whatever ignores it is killed
+    pub fn terminate(pid: u32) {
+        let pid = pid as libc::pid_t;
+        // SAFETY: signals and reaps our own child, which the guard registering it outlives
+        unsafe {
+            libc::kill(pid, libc::SIGTERM);
+            let deadline = Instant::now() + TERMINATE_GRACE;
+            while Instant::now() < deadline {
+                if libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) != 0 {
+                    return;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub fn install() -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub fn terminate(_pid: u32) {}
+}
+
+// Makes Ctrl-C (and SIGTERM/SIGHUP) stop registered children, remove half-written files and
+// restore the terminal before exiting. A no-op outside Unix
+pub fn install() -> std::io::Result<()> {
+    imp::install()
+}
+
+fn terminate(pid: u32) {
+    debug!("Stopping process {} after interrupt", pid);
+    imp::terminate(pid)
+}
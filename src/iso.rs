@@ -0,0 +1,291 @@
+use crate::{
+    artifacts::{ArtifactError, LimineArtifacts},
+    config::ToolBackend,
+    interrupt,
+};
+#[cfg(feature = "native")]
+use crate::{iso9660, native::NativeError};
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+// Where the kernel and modules land in the image, as limine.conf refers to them
+pub const KERNEL_PATH: &str = "boot/kernel/kernel";
+pub const MODULE_DIR: &str = "boot/modules";
+
+// Stages a Limine boot image from explicit inputs and writes it with xorriso, for build scripts
+// and tools that have no limage_config.toml
+pub struct IsoBuilder {
+    limine: LimineArtifacts,
+    kernel: Option<PathBuf>,
+    // Source files and the names they get under boot/modules
+    modules: Vec<(PathBuf, String)>,
+    limine_conf: Option<String>,
+    output: Option<PathBuf>,
+    staging_dir: Option<PathBuf>,
+    tools: ToolBackend,
+}
+
+impl IsoBuilder {
+    // `limine` must already hold the bootloader files; `LimineArtifacts::fetch` gets them
+    pub fn new(limine: LimineArtifacts) -> Self {
+        Self {
+            limine,
+            kernel: None,
+            modules: Vec::new(),
+            limine_conf: None,
+            output: None,
+            staging_dir: None,
+            tools: ToolBackend::default(),
+        }
+    }
+
+    pub fn kernel(mut self, path: impl Into<PathBuf>) -> Self {
+        self.kernel = Some(path.into());
+        self
+    }
+
+    // Copied to boot/modules/<name>, i.e. boot():/boot/modules/<name> in limine.conf
+    pub fn module(mut self, path: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        self.modules.push((path.into(), name.into()));
+        self
+    }
+
+    // Without one, a single entry boots the kernel with every module
+    pub fn limine_conf(mut self, content: impl Into<String>) -> Self {
+        self.limine_conf = Some(content.into());
+        self
+    }
+
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output = Some(path.into());
+        self
+    }
+
+    // Where the image tree is assembled; `<output>.iso_root` next to the image by default
+    pub fn staging_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.staging_dir = Some(path.into());
+        self
+    }
+
+    // xorriso and `limine bios-install`, or the built-in writer, whose image only boots from CD
+    pub fn tools(mut self, tools: ToolBackend) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    // Stages the tree and writes the image, returning its path
+    #[instrument(skip(self), err)]
+    pub fn build(&self) -> Result<PathBuf, IsoError> {
+        let kernel = self.kernel.as_ref().ok_or(IsoError::NoKernel)?;
+        let output = self.output.as_ref().ok_or(IsoError::NoOutput)?;
+        if !self.tools.is_available() {
+            return Err(IsoError::ToolsUnavailable {
+                tools: self.tools.name(),
+                feature: self.tools.feature(),
+            });
+        }
+        let root = self
+            .staging_dir
+            .clone()
+            .unwrap_or_else(|| output.with_extension("iso_root"));
+        self.limine
+            .verify()
+            .map_err(|e| IsoError::Limine { source: e })?;
+
+        info!("Staging the image tree in {:?}", root);
+        copy(kernel, &root.join(KERNEL_PATH))?;
+        for (path, name) in &self.modules {
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(IsoError::InvalidModuleName { name: name.clone() });
+            }
+            copy(path, &root.join(MODULE_DIR).join(name))?;
+        }
+        let limine_conf = match &self.limine_conf {
+            Some(content) => content.clone(),
+            None => self.default_limine_conf(),
+        };
+        write(&root.join("boot/limine/limine.conf"), &limine_conf)?;
+        for file in [
+            "limine-bios.sys",
+            "limine-bios-cd.bin",
+            "limine-uefi-cd.bin",
+        ] {
+            copy(
+                &self.limine.path(file),
+                &root.join("boot/limine").join(file),
+            )?;
+        }
+        for file in ["BOOTX64.EFI", "BOOTIA32.EFI"] {
+            copy(&self.limine.path(file), &root.join("EFI/BOOT").join(file))?;
+        }
+
+        if let Some(parent) = output
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent).map_err(|e| IsoError::Stage {
+                path: parent.to_path_buf(),
+                source: e,
+            })?;
+        }
+        info!("Creating ISO at {:?}", output);
+        let _partial = interrupt::remove_on_interrupt(output);
+        #[cfg(feature = "native")]
+        if self.tools == ToolBackend::Native {
+            iso9660::write(&root, output).map_err(|e| IsoError::Native { source: e })?;
+            return Ok(output.clone());
+        }
+        run("xorriso", &mut mkisofs_command(&root, output))?;
+        run("limine", &mut self.limine.install_command(output))?;
+        Ok(output.clone())
+    }
+
+    fn default_limine_conf(&self) -> String {
+        let mut conf = format!(
+            "timeout: 0\n\n/limage\n    protocol: limine\n    path: boot():/{}\n",
+            KERNEL_PATH
+        );
+        for (_, name) in &self.modules {
+            let _ = writeln!(conf, "    module_path: boot():/{}/{}", MODULE_DIR, name);
+        }
+        conf
+    }
+}
+
+// xorriso turning the staged tree at `root` into a hybrid BIOS/UEFI image
+pub fn mkisofs_command(root: &Path, output: &Path) -> Command {
+    let mut command = Command::new("xorriso");
+    command
+        .args([
+            "-as",
+            "mkisofs",
+            "-b",
+            "boot/limine/limine-bios-cd.bin",
+            "-no-emul-boot",
+            "-boot-load-size",
+            "4",
+            "-boot-info-table",
+            "--efi-boot",
+            "boot/limine/limine-uefi-cd.bin",
+            "-efi-boot-part",
+            "--efi-boot-image",
+            "--protective-msdos-label",
+        ])
+        .arg(root)
+        .arg("-o")
+        .arg(output)
+        .stdout(Stdio::piped());
+    command
+}
+
+fn copy(from: &Path, to: &Path) -> Result<(), IsoError> {
+    debug!("Copying {:?} to {:?}", from, to);
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| IsoError::Stage {
+            path: parent.to_path_buf(),
+            source: e,
+        })?;
+    }
+    std::fs::copy(from, to).map_err(|e| IsoError::Copy {
+        path: from.to_path_buf(),
+        source: e,
+    })?;
+    Ok(())
+}
+
+fn write(path: &Path, content: &str) -> Result<(), IsoError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| IsoError::Stage {
+            path: parent.to_path_buf(),
+            source: e,
+        })?;
+    }
+    std::fs::write(path, content).map_err(|e| IsoError::Stage {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+fn run(tool: &'static str, command: &mut Command) -> Result<(), IsoError> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| IsoError::Spawn { tool, source: e })?;
+    if !output.status.success() {
+        return Err(IsoError::Failed {
+            tool,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum IsoError {
+    #[error("No kernel was given; call .kernel(path)")]
+    NoKernel,
+
+    #[error("No output path was given; call .output(path)")]
+    NoOutput,
+
+    #[error("Module name {name:?} must be a plain file name")]
+    InvalidModuleName { name: String },
+
+    #[error("The Limine files are incomplete: {source}")]
+    Limine { source: ArtifactError },
+
+    #[error("Failed to copy {path:?}: {source}")]
+    Copy {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to stage {path:?}: {source}")]
+    Stage {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to run {tool}: {source}")]
+    Spawn {
+        tool: &'static str,
+        source: std::io::Error,
+    },
+
+    #[error("{tool} failed: {stderr}")]
+    Failed { tool: &'static str, stderr: String },
+
+    #[error("The {tools} tools need limage built with the `{feature}` feature")]
+    ToolsUnavailable {
+        tools: &'static str,
+        feature: &'static str,
+    },
+
+    #[cfg(feature = "native")]
+    #[error(transparent)]
+    Native { source: NativeError },
+}
+
+impl IsoError {
+    // The stable code `limage::Error` reports; a new variant takes the next free code
+    pub fn code(&self) -> &'static str {
+        match self {
+            IsoError::NoKernel => "E080",
+            IsoError::NoOutput => "E081",
+            IsoError::InvalidModuleName { .. } => "E082",
+            IsoError::Limine { source } => source.code(),
+            IsoError::Copy { .. } => "E083",
+            IsoError::Stage { .. } => "E084",
+            IsoError::Spawn { .. } => "E085",
+            IsoError::Failed { .. } => "E086",
+            IsoError::ToolsUnavailable { .. } => "E096",
+            #[cfg(feature = "native")]
+            IsoError::Native { source } => source.code(),
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use crate::native::NativeError;
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, instrument};
+
+const SECTOR: usize = 2048;
+// The same boot images `iso::mkisofs_command` hands xorriso
+const BIOS_BOOT_IMAGE: &str = "boot/limine/limine-bios-cd.bin";
+const UEFI_BOOT_IMAGE: &str = "boot/limine/limine-uefi-cd.bin";
+// Sectors 0-15 are the system area; the volume descriptors follow
+const PRIMARY_DESCRIPTOR: u32 = 16;
+const FIRST_FREE_SECTOR: u32 = 19;
+
+struct Dir {
+    // The ISO 9660 identifier and the Rock Ridge name; both empty for the root
+    id: String,
+    name: String,
+    path: PathBuf,
+    parent: usize,
+    children: Vec<Child>,
+    lba: u32,
+    size: u32,
+}
+
+struct FileEntry {
+    id: String,
+    name: String,
+    path: PathBuf,
+    // Relative to the root, with `/` separators, to find the boot images
+    relative: String,
+    size: u32,
+    lba: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Child {
+    Dir(usize),
+    File(usize),
+}
+
+// Writes the tree at `root` to an El Torito image that boots Limine from CD on BIOS and UEFI,
+// the way xorriso does for `limage build`. Names are stored in ISO 9660 form plus Rock Ridge, and
+// the image has no protective MBR/GPT, so it does not boot when written to a USB stick
+#[instrument(err)]
+pub fn write(root: &Path, output: &Path) -> Result<(), NativeError> {
+    let (mut dirs, mut files) = scan(root)?;
+    let find = |relative: &str| {
+        files
+            .iter()
+            .position(|file| file.relative == relative)
+            .ok_or_else(|| NativeError::MissingBootImage {
+                path: root.join(relative),
+            })
+    };
+    let bios = find(BIOS_BOOT_IMAGE)?;
+    let uefi = find(UEFI_BOOT_IMAGE)?;
+
+    // Descriptors, both path tables, the boot catalog, the directories and then the file data
+    let path_table_size = path_table(&dirs, false).len();
+    let path_table_sectors = sectors(path_table_size);
+    let l_path_table = FIRST_FREE_SECTOR;
+    let m_path_table = l_path_table + path_table_sectors;
+    let catalog = m_path_table + path_table_sectors;
+    let mut next = catalog + 1;
+    for index in 0..dirs.len() {
+        let size = dir_size(&dirs, &files, index)?;
+        dirs[index].lba = next;
+        dirs[index].size = size;
+        next += sectors(size as usize);
+    }
+    for file in &mut files {
+        file.lba = next;
+        next += sectors(file.size as usize);
+    }
+    let volume_sectors = next;
+    debug!(
+        "Writing {} directories and {} files in {} sectors",
+        dirs.len(),
+        files.len(),
+        volume_sectors
+    );
+
+    let io = |e| NativeError::Io {
+        path: output.to_path_buf(),
+        source: e,
+    };
+    let mut out = BufWriter::new(File::create(output).map_err(io)?);
+    let mut image = Vec::with_capacity((catalog as usize + 1) * SECTOR);
+    image.resize(PRIMARY_DESCRIPTOR as usize * SECTOR, 0);
+    image.extend(primary_descriptor(
+        &dirs[0],
+        volume_sectors,
+        path_table_size,
+        l_path_table,
+        m_path_table,
+    ));
+    image.extend(boot_record(catalog));
+    image.extend(terminator());
+    image.extend(pad(path_table(&dirs, false)));
+    image.extend(pad(path_table(&dirs, true)));
+    image.extend(boot_catalog(&files[bios], &files[uefi]));
+    out.write_all(&image).map_err(io)?;
+
+    for index in 0..dirs.len() {
+        out.write_all(&dir_extent(&dirs, &files, index)?)
+            .map_err(io)?;
+    }
+    for (index, file) in files.iter().enumerate() {
+        let mut data = Vec::with_capacity(file.size as usize);
+        File::open(&file.path)
+            .and_then(|mut source| source.read_to_end(&mut data))
+            .map_err(|e| NativeError::Io {
+                path: file.path.clone(),
+                source: e,
+            })?;
+        if data.len() as u64 != u64::from(file.size) {
+            return Err(NativeError::Io {
+                path: file.path.clone(),
+                source: std::io::Error::other("file changed while the image was written"),
+            });
+        }
+        if index == bios {
+            patch_boot_info_table(&mut data, file.lba);
+        }
+        out.write_all(&pad(data)).map_err(io)?;
+    }
+    out.flush().map_err(io)
+}
+
+// Every directory breadth first, which is the order the path tables number them in
+fn scan(root: &Path) -> Result<(Vec<Dir>, Vec<FileEntry>), NativeError> {
+    let mut dirs = vec![Dir {
+        id: String::new(),
+        name: String::new(),
+        path: root.to_path_buf(),
+        parent: 0,
+        children: Vec::new(),
+        lba: 0,
+        size: 0,
+    }];
+    let mut files = Vec::new();
+    let mut index = 0;
+    while index < dirs.len() {
+        let io = |e| NativeError::Io {
+            path: dirs[index].path.clone(),
+            source: e,
+        };
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&dirs[index].path).map_err(io)? {
+            let entry = entry.map_err(io)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry.file_type().map_err(io)?.is_dir();
+            let id = if is_dir {
+                dir_id(&name)
+            } else {
+                file_id(&name)
+            };
+            entries.push((id, name, entry.path(), is_dir));
+        }
+        entries.sort();
+        for pair in entries.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                return Err(NativeError::NameClash {
+                    path: pair[1].2.clone(),
+                    name: pair[1].0.clone(),
+                });
+            }
+        }
+
+        let relative_dir = relative_path(&dirs, index);
+        for (id, name, path, is_dir) in entries {
+            // A record holds at most 255 bytes, the name twice among them
+            if id.len() + name.len() > 170 {
+                return Err(NativeError::NameTooLong { path });
+            }
+            let child = if is_dir {
+                dirs.push(Dir {
+                    id,
+                    name,
+                    path,
+                    parent: index,
+                    children: Vec::new(),
+                    lba: 0,
+                    size: 0,
+                });
+                Child::Dir(dirs.len() - 1)
+            } else {
+                let size = std::fs::metadata(&path)
+                    .map_err(|e| NativeError::Io {
+                        path: path.clone(),
+                        source: e,
+                    })?
+                    .len();
+                let size = u32::try_from(size)
+                    .map_err(|_| NativeError::TooLarge { path: path.clone() })?;
+                files.push(FileEntry {
+                    id,
+                    relative: format!("{}{}", relative_dir, name),
+                    name,
+                    path,
+                    size,
+                    lba: 0,
+                });
+                Child::File(files.len() - 1)
+            };
+            dirs[index].children.push(child);
+        }
+        index += 1;
+    }
+    Ok((dirs, files))
+}
+
+// `boot/limine/` for a directory, `` for the root
+fn relative_path(dirs: &[Dir], mut index: usize) -> String {
+    let mut parts = Vec::new();
+    while index != 0 {
+        parts.push(dirs[index].name.as_str());
+        index = dirs[index].parent;
+    }
+    parts
+        .iter()
+        .rev()
+        .map(|part| format!("{}/", part))
+        .collect()
+}
+
+// Upper case letters, digits and `_` only
+fn d_characters(name: &str, max: usize) -> String {
+    let mangled: String = name
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9') => c,
+            _ => '_',
+        })
+        .take(max)
+        .collect();
+    if mangled.is_empty() {
+        "_".to_string()
+    } else {
+        mangled
+    }
+}
+
+fn dir_id(name: &str) -> String {
+    d_characters(name, 31)
+}
+
+fn file_id(name: &str) -> String {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let extension = if extension.is_empty() {
+        String::new()
+    } else {
+        d_characters(extension, 8)
+    };
+    let base = d_characters(base, 30 - extension.len());
+    format!("{}.{};1", base, extension)
+}
+
+fn sectors(bytes: usize) -> u32 {
+    bytes.div_ceil(SECTOR) as u32
+}
+
+fn pad(mut data: Vec<u8>) -> Vec<u8> {
+    data.resize(sectors(data.len()) as usize * SECTOR, 0);
+    data
+}
+
+fn both_u16(value: u16) -> [u8; 4] {
+    let [a, b] = value.to_le_bytes();
+    [a, b, b, a]
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+    let [a, b, c, d] = value.to_le_bytes();
+    [a, b, c, d, d, c, b, a]
+}
+
+// A directory record (ECMA-119 9.1), padded to an even length
+fn record(id: &[u8], lba: u32, size: u32, is_dir: bool, system_use: &[u8]) -> Vec<u8> {
+    let mut record = vec![0];
+    record.push(0);
+    record.extend(both_u32(lba));
+    record.extend(both_u32(size));
+    // Recording date left unspecified, so that the same tree gives the same image
+    record.extend([0; 7]);
+    record.push(if is_dir { 2 } else { 0 });
+    record.extend([0, 0]);
+    record.extend(both_u16(1));
+    record.push(id.len() as u8);
+    record.extend(id);
+    if id.len().is_multiple_of(2) {
+        record.push(0);
+    }
+    record.extend(system_use);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+    record[0] = record.len() as u8;
+    record
+}
+
+// Rock Ridge entries: read-only POSIX permissions, then the name unless it is `.` or `..`
+fn rock_ridge(is_dir: bool, name: Option<&str>) -> Vec<u8> {
+    let mode: u32 = if is_dir { 0o40555 } else { 0o100444 };
+    let mut entries = vec![b'P', b'X', 36, 1];
+    entries.extend(both_u32(mode));
+    entries.extend(both_u32(1));
+    entries.extend([0; 16]);
+    if let Some(name) = name {
+        entries.extend([b'N', b'M', 5 + name.len() as u8, 1, 0]);
+        entries.extend(name.as_bytes());
+    }
+    entries
+}
+
+// The SUSP indicator and the Rock Ridge extension reference, which readers look for in the
+// root's `.` record before they use the names
+fn rock_ridge_root() -> Vec<u8> {
+    let (id, description) = (b"RRIP_1991A", b"ROCK RIDGE");
+    let mut entries = vec![b'S', b'P', 7, 1, 0xbe, 0xef, 0];
+    entries.extend([b'E', b'R', 8 + (id.len() + description.len()) as u8, 1]);
+    entries.extend([id.len() as u8, description.len() as u8, 0, 1]);
+    entries.extend(id);
+    entries.extend(description);
+    entries
+}
+
+fn dir_records(dirs: &[Dir], files: &[FileEntry], index: usize) -> Vec<Vec<u8>> {
+    let dir = &dirs[index];
+    let parent = &dirs[dir.parent];
+    let mut self_use = if index == 0 {
+        rock_ridge_root()
+    } else {
+        Vec::new()
+    };
+    self_use.extend(rock_ridge(true, None));
+    let mut records = vec![
+        record(&[0], dir.lba, dir.size, true, &self_use),
+        record(&[1], parent.lba, parent.size, true, &rock_ridge(true, None)),
+    ];
+    for child in &dir.children {
+        records.push(match *child {
+            Child::Dir(child) => {
+                let child = &dirs[child];
+                record(
+                    child.id.as_bytes(),
+                    child.lba,
+                    child.size,
+                    true,
+                    &rock_ridge(true, Some(&child.name)),
+                )
+            }
+            Child::File(file) => {
+                let file = &files[file];
+                record(
+                    file.id.as_bytes(),
+                    file.lba,
+                    file.size,
+                    false,
+                    &rock_ridge(false, Some(&file.name)),
+                )
+            }
+        });
+    }
+    records
+}
+
+// Records may not cross a sector boundary, so a record that would is moved to the next sector
+fn dir_extent(dirs: &[Dir], files: &[FileEntry], index: usize) -> Result<Vec<u8>, NativeError> {
+    let mut extent = Vec::new();
+    for record in dir_records(dirs, files, index) {
+        if extent.len() % SECTOR + record.len() > SECTOR {
+            extent = pad(extent);
+        }
+        extent.extend(record);
+    }
+    let extent = pad(extent);
+    if u32::try_from(extent.len()).is_err() {
+        return Err(NativeError::TooLarge {
+            path: dirs[index].path.clone(),
+        });
+    }
+    Ok(extent)
+}
+
+fn dir_size(dirs: &[Dir], files: &[FileEntry], index: usize) -> Result<u32, NativeError> {
+    Ok(dir_extent(dirs, files, index)?.len() as u32)
+}
+
+// The path table (ECMA-119 9.4); `big_endian` picks the M table over the L table
+fn path_table(dirs: &[Dir], big_endian: bool) -> Vec<u8> {
+    let mut table = Vec::new();
+    for dir in dirs {
+        let id: &[u8] = if dir.id.is_empty() {
+            &[0]
+        } else {
+            dir.id.as_bytes()
+        };
+        table.push(id.len() as u8);
+        table.push(0);
+        // Directory numbers start at 1
+        let parent = dir.parent as u16 + 1;
+        if big_endian {
+            table.extend(dir.lba.to_be_bytes());
+            table.extend(parent.to_be_bytes());
+        } else {
+            table.extend(dir.lba.to_le_bytes());
+            table.extend(parent.to_le_bytes());
+        }
+        table.extend(id);
+        if id.len() % 2 == 1 {
+            table.push(0);
+        }
+    }
+    table
+}
+
+fn descriptor_header(kind: u8) -> Vec<u8> {
+    let mut descriptor = vec![kind];
+    descriptor.extend(b"CD001");
+    descriptor.push(1);
+    descriptor
+}
+
+fn padded(text: &str, len: usize) -> Vec<u8> {
+    let mut field = text.as_bytes().to_vec();
+    field.resize(len, b' ');
+    field
+}
+
+fn primary_descriptor(
+    root: &Dir,
+    volume_sectors: u32,
+    path_table_size: usize,
+    l_path_table: u32,
+    m_path_table: u32,
+) -> Vec<u8> {
+    // Dates are "not specified": sixteen ASCII zeros and a zero time zone
+    let unspecified_date = [b"0000000000000000".as_slice(), &[0]].concat();
+    let mut descriptor = descriptor_header(1);
+    descriptor.push(0);
+    descriptor.extend(padded("", 32));
+    descriptor.extend(padded("LIMAGE", 32));
+    descriptor.extend([0; 8]);
+    descriptor.extend(both_u32(volume_sectors));
+    descriptor.extend([0; 32]);
+    descriptor.extend(both_u16(1));
+    descriptor.extend(both_u16(1));
+    descriptor.extend(both_u16(SECTOR as u16));
+    descriptor.extend(both_u32(path_table_size as u32));
+    descriptor.extend(l_path_table.to_le_bytes());
+    descriptor.extend([0; 4]);
+    descriptor.extend(m_path_table.to_be_bytes());
+    descriptor.extend([0; 4]);
+    descriptor.extend(record(&[0], root.lba, root.size, true, &[]));
+    descriptor.extend(padded("", 128 * 4));
+    descriptor.extend(padded("", 37 * 3));
+    for _ in 0..4 {
+        descriptor.extend(&unspecified_date);
+    }
+    descriptor.push(1);
+    descriptor.resize(SECTOR, 0);
+    descriptor
+}
+
+// El Torito boot record pointing at the boot catalog
+fn boot_record(catalog: u32) -> Vec<u8> {
+    let mut descriptor = descriptor_header(0);
+    let mut system = b"EL TORITO SPECIFICATION".to_vec();
+    system.resize(32, 0);
+    descriptor.extend(system);
+    descriptor.extend([0; 32]);
+    descriptor.extend(catalog.to_le_bytes());
+    descriptor.resize(SECTOR, 0);
+    descriptor
+}
+
+fn terminator() -> Vec<u8> {
+    let mut descriptor = descriptor_header(255);
+    descriptor.resize(SECTOR, 0);
+    descriptor
+}
+
+// A no-emulation BIOS entry loading four 512-byte sectors, as `-boot-load-size 4` does, and a
+// UEFI section for the FAT image Limine's EFI loader lives in
+fn boot_catalog(bios: &FileEntry, uefi: &FileEntry) -> Vec<u8> {
+    let mut validation = vec![1, 0, 0, 0];
+    validation.extend([0; 24]);
+    validation.extend([0, 0, 0x55, 0xaa]);
+    let sum = validation.chunks(2).fold(0u16, |sum, word| {
+        sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+    });
+    validation[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    let entry = |sectors: u16, lba: u32| {
+        let mut entry = vec![0x88, 0, 0, 0, 0, 0];
+        entry.extend(sectors.to_le_bytes());
+        entry.extend(lba.to_le_bytes());
+        entry.resize(32, 0);
+        entry
+    };
+    let mut catalog = validation;
+    catalog.extend(entry(4, bios.lba));
+    // The final section header, for the EFI platform, with one entry
+    let mut header = vec![0x91, 0xef, 1, 0];
+    header.resize(32, 0);
+    catalog.extend(header);
+    let uefi_sectors = u16::try_from(uefi.size.div_ceil(512)).unwrap_or(u16::MAX);
+    catalog.extend(entry(uefi_sectors, uefi.lba));
+    catalog.resize(SECTOR, 0);
+    catalog
+}
+
+// Limine's BIOS CD stage expects the boot info table `-boot-info-table` writes: where the volume
+// descriptor and the image itself are, and a checksum of the image past the table
+fn patch_boot_info_table(data: &mut [u8], lba: u32) {
+    if data.len() < 64 {
+        return;
+    }
+    let len = data.len() as u32;
+    let mut checksum = 0u32;
+    for word in data[64..].chunks(4) {
+        let mut bytes = [0; 4];
+        bytes[..word.len()].copy_from_slice(word);
+        checksum = checksum.wrapping_add(u32::from_le_bytes(bytes));
+    }
+    data[8..12].copy_from_slice(&PRIMARY_DESCRIPTOR.to_le_bytes());
+    data[12..16].copy_from_slice(&lba.to_le_bytes());
+    data[16..20].copy_from_slice(&len.to_le_bytes());
+    data[20..24].copy_from_slice(&checksum.to_le_bytes());
+    data[24..64].fill(0);
+}
@@ -1,8 +1,58 @@
+pub mod accel;
+pub mod artifacts;
+pub mod backend;
+pub mod build_fs;
+pub mod build_report;
 pub mod builder;
+pub mod cargo;
+pub mod check;
+pub mod ci;
 pub mod cli;
+pub mod command;
 pub mod config;
+pub mod config_builder;
+pub mod diagnostic;
+pub mod doctor;
+pub mod error;
+pub mod expect;
+pub mod flash;
+pub mod gdb;
+pub mod guest_test;
+pub mod history;
+pub mod inspect;
+pub mod interrupt;
+pub mod iso;
+#[cfg(feature = "native")]
+pub mod iso9660;
+pub mod lockfile;
+pub mod message;
+pub mod migrate;
+pub mod monitor;
+#[cfg(feature = "native")]
+pub mod native;
+pub mod package;
+pub mod picker;
+pub mod qmp;
 pub mod runner;
+pub mod scaffold;
+pub mod scenario;
+pub mod self_update;
+pub mod serial;
+pub mod size;
+pub mod suggest;
+pub mod symbolize;
+pub mod target_spec;
+pub mod tpm;
+pub mod tui;
+pub mod update;
+pub mod version;
 
+pub use artifacts::{LimineArtifacts, OvmfManager};
+pub use build_report::BuildReport;
 pub use builder::Builder;
 pub use config::LimageConfig;
-pub use runner::Runner;
+pub use config_builder::LimageConfigBuilder;
+pub use error::{Error, Result};
+pub use iso::IsoBuilder;
+pub use qmp::QmpHandle;
+pub use runner::{RunHandle, Runner};
@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const LOCKFILE: &str = "limage.lock";
+
+// Versions of the downloaded boot artifacts, written by `limage update` and honored by builds
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    #[serde(default)]
+    pub limine: Option<LimineLock>,
+    #[serde(default)]
+    pub ovmf: Option<OvmfLock>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LimineLock {
+    pub repository: String,
+    pub branch: String,
+    pub commit: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OvmfLock {
+    // Tag of the edk2-ovmf-nightly release the firmware was downloaded from
+    pub release: String,
+}
+
+impl Lockfile {
+    // A missing lockfile means nothing is pinned yet
+    pub fn load(path: &Path) -> Result<Self, LockfileError> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path).map_err(|e| LockfileError::Read {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+        toml::from_str(&content).map_err(|e| LockfileError::Parse {
+            path: path.to_path_buf(),
+            source: e,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), LockfileError> {
+        let content = toml::to_string(self).map_err(|e| LockfileError::Serialize { source: e })?;
+        std::fs::write(
+            path,
+            format!(
+                "# Generated by `limage update`; commit it to pin the bootloader and firmware\n{}",
+                content
+            ),
+        )
+        .map_err(|e| LockfileError::Write {
+            path: path.to_path_buf(),
+            source: e,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LockfileError {
+    #[error("Failed to read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Failed to serialize the lockfile: {source}")]
+    Serialize { source: toml::ser::Error },
+
+    #[error("Failed to write {path:?}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
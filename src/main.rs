@@ -1,71 +1,817 @@
+use anyhow::Context;
 use clap::Parser;
-use std::{path::Path, process};
+use regex::Regex;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process,
+};
+use tracing::{debug, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 use limage::{
-    builder::Builder,
-    cli::{Cli, Commands, RunMode},
-    config::LimageConfig,
-    runner::Runner,
+    build_fs::RealFs,
+    builder::{Builder, CleanTargets},
+    cargo,
+    check::{self, Severity},
+    ci::Pipeline,
+    cli::{self, Cli, Commands, ConfigAction, RunMode},
+    command,
+    config::{self, LimageConfig, CONFIG_FILE},
+    diagnostic,
+    doctor::{self, Status},
+    flash::{self, FlashOptions},
+    history::History,
+    inspect, interrupt,
+    lockfile::{Lockfile, LOCKFILE},
+    message::Message,
+    migrate,
+    monitor::{self, MonitorError},
+    package::Packager,
+    picker,
+    runner::{RunStatus, Runner},
+    scaffold::{Scaffold, Source, TEMPLATES},
+    scenario, self_update,
+    size::{self, SizeReport},
+    symbolize::Symbolizer,
+    target_spec, update, version,
 };
 
 fn main() {
+    let cli = Cli::parse();
     tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(log_filter(&cli))
         .init();
+    // Ctrl-C stops QEMU and removes half-written artifacts instead of leaving them behind
+    if let Err(e) = interrupt::install() {
+        warn!("Failed to install the interrupt handler: {}", e);
+    }
 
-    if let Err(e) = run() {
-        eprintln!("Error: {}", e);
+    // `run` may enter the project directory, so an explicit --config is made absolute up front
+    let config_path = match (&cli.config, std::env::current_dir()) {
+        (Some(path), Ok(dir)) => dir.join(path),
+        (Some(path), Err(_)) => path.clone(),
+        (None, _) => PathBuf::from(CONFIG_FILE),
+    };
+    if let Err(e) = run(cli) {
+        eprintln!("{:?}", diagnostic::report(&e, &config_path));
         process::exit(1);
     }
 }
 
+// -q/-v/-vv/-vvv take precedence over RUST_LOG, which in turn overrides the default of warnings
+fn log_filter(cli: &Cli) -> EnvFilter {
+    let directives = match (cli.quiet, cli.verbose) {
+        (true, _) => "error",
+        (false, 0) => {
+            return EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"))
+        }
+        (false, 1) => "warn,limage=info",
+        (false, 2) => "warn,limage=debug",
+        (false, _) => "warn,limage=trace",
+    };
+    EnvFilter::new(directives)
+}
+
+// cargo builds test harnesses into deps/ and doctests into rustdoctest* directories
 fn is_test_executable(path: &Path) -> bool {
-    if let Some(parent) = path.parent() {
-        if let Some(dirname) = parent.file_name() {
-            if let Some(dirname_str) = dirname.to_str() {
-                return dirname_str.starts_with("rustdoctest") || dirname_str == "deps";
+    path.parent()
+        .and_then(Path::file_name)
+        .and_then(|dirname| dirname.to_str())
+        .is_some_and(|dirname| dirname.starts_with("rustdoctest") || dirname == "deps")
+}
+
+// Without --manifest-dir, a subdirectory runs against the nearest parent with a limage_config.toml,
+// or else the root of its Cargo workspace
+fn project_dir(cli: &Cli) -> Option<PathBuf> {
+    if let Some(dir) = &cli.manifest_dir {
+        return Some(dir.clone());
+    }
+    if Path::new(CONFIG_FILE).exists() || Path::new("limine.conf").exists() {
+        return None;
+    }
+    if let Some(dir) = config::find_config().and_then(|path| path.parent().map(Path::to_path_buf)) {
+        return Some(dir);
+    }
+    cargo::workspace_root()
+        .ok()
+        .filter(|root| root.join(CONFIG_FILE).exists() || root.join("limine.conf").exists())
+}
+
+fn run(mut cli: Cli) -> anyhow::Result<()> {
+    let quiet = cli.quiet;
+    let dry_run = cli.dry_run;
+    let strict = cli.strict;
+    let message_format = cli.message_format;
+    cli.resolve_paths(&std::env::current_dir()?);
+
+    // A new project must not be created inside the directory of an existing one
+    if let Some(Commands::Init {
+        dir,
+        template,
+        name,
+        force,
+        list,
+    }) = cli.command
+    {
+        return init(dir, &template, name, force, list, dry_run);
+    }
+
+    if let Some(dir) = project_dir(&cli) {
+        debug!("Using project directory {:?}", dir);
+        std::env::set_current_dir(&dir)
+            .with_context(|| format!("Failed to enter project directory {:?}", dir))?;
+    }
+    let config_path = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+    let loaded = match &cli.config {
+        Some(path) => LimageConfig::from_file(path),
+        None => LimageConfig::load(),
+    };
+
+    // Reports a broken config as one of its findings instead of bailing out
+    if matches!(cli.command, Some(Commands::Doctor)) {
+        let checks = doctor::run(&loaded, &config_path);
+        for check in &checks {
+            let label = match check.status {
+                Status::Ok => " ok ",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            println!("[{}] {:<20}{}", label, check.name, check.detail);
+            if let Some(hint) = &check.hint {
+                println!("       {:<20}-> {}", "", hint);
             }
         }
+        if checks.iter().any(|check| check.status == Status::Fail) {
+            process::exit(1);
+        }
+        return Ok(());
     }
-    false
-}
 
-fn run() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-    let config = LimageConfig::load()?;
+    // Bug reports need this even when the config is broken
+    if matches!(cli.command, Some(Commands::Version)) {
+        if cli.verbose == 0 {
+            println!("limage {}", version::VERSION);
+            return Ok(());
+        }
+        let config = loaded.unwrap_or_default();
+        let lockfile = Lockfile::load(Path::new(LOCKFILE)).unwrap_or_default();
+        for (name, description) in version::report(&config, &lockfile) {
+            println!("{:<10}{}", name, description);
+        }
+        return Ok(());
+    }
+
+    // Works without a project, and is how a broken limage gets fixed
+    if let Some(Commands::SelfUpdate { yes, check }) = cli.command {
+        let release = self_update::latest_release()?;
+        if !release.is_newer() {
+            println!("limage {} is up to date", version::VERSION);
+            return Ok(());
+        }
+        println!("limage {} -> {}", version::VERSION, release.version);
+        if check {
+            process::exit(1);
+        }
+        if !yes && !update::confirm(&format!("Install limage {}?", release.version))? {
+            println!("Aborted");
+            return Ok(());
+        }
+        let path = self_update::install(&release)?;
+        println!("Installed limage {} to {}", release.version, path.display());
+        return Ok(());
+    }
 
-    config.validate()?;
+    // Reports every problem at once instead of stopping at the first one
+    if matches!(cli.command, Some(Commands::Check)) {
+        let findings = check::run(&config_path);
+        for finding in &findings {
+            let label = match finding.severity {
+                Severity::Error => "FAIL",
+                Severity::Warning => "warn",
+            };
+            println!("[{}] {}: {}", label, finding.location, finding.message);
+        }
+        let errors = findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .count();
+        if findings.is_empty() {
+            println!("No problems found");
+        } else {
+            println!(
+                "{} error(s), {} warning(s)",
+                errors,
+                findings.len() - errors
+            );
+        }
+        if errors > 0 {
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
-    match cli.command.unwrap_or(Commands::Build) {
-        Commands::Build => {
-            let builder = Builder::new(config)?;
-            builder.build(None)?;
+    // Writing a fresh config must not depend on the old one parsing
+    if let Some(Commands::Config {
+        action: ConfigAction::Init { force },
+    }) = cli.command
+    {
+        inspect::init(&config_path, force)?;
+        println!("Wrote {}", config_path.display());
+        return Ok(());
+    }
+
+    // Nor may updating an old one
+    if let Some(Commands::Config {
+        action: ConfigAction::Migrate,
+    }) = cli.command
+    {
+        let migration = migrate::migrate_file(&config_path, dry_run)?;
+        for rename in &migration.renames {
+            println!("{}", rename);
+        }
+        if migration.is_empty() {
+            println!("{} is up to date", config_path.display());
+        } else if dry_run {
+            print!("{}", migration.diff());
+        } else {
+            println!("Updated {}", config_path.display());
+        }
+        return Ok(());
+    }
+
+    let mut config = loaded?;
+    if strict {
+        config.deny_unknown_keys()?;
+    }
+    for key in config.unknown_keys() {
+        warn!("{}: {}", config_path.display(), key);
+    }
+    for rename in config.deprecated_keys() {
+        warn!(
+            "{}: {}; `limage config migrate` updates the file",
+            config_path.display(),
+            rename
+        );
+    }
+
+    let (cli_mode, runs_qemu) = match &cli.command {
+        Some(Commands::Run {
+            mode_name, mode, ..
+        }) => (
+            mode_name
+                .as_deref()
+                .or(mode.as_ref().map(|RunMode::Mode { name }| name.as_str())),
+            true,
+        ),
+        Some(Commands::QemuArgs { mode, .. }) => (mode.as_deref(), false),
+        Some(Commands::Runner { .. } | Commands::Test { .. } | Commands::Ci { .. }) => (None, true),
+        _ => (None, false),
+    };
+    config.validate(cli_mode, runs_qemu && !dry_run)?;
+
+    match cli.command.unwrap_or(Commands::Build {
+        image: None,
+        kernel: None,
+    }) {
+        Commands::Build { image, kernel } => {
+            if let Some(image) = image {
+                config.build.image_path = image;
+            }
+            let builder = Builder::new(config)?
+                .dry_run(dry_run)
+                .message_format(message_format);
+            builder.build(kernel.as_deref())?;
             Ok(())
         }
-        Commands::Run { kernel, mode } => {
+        Commands::Run {
+            kernel,
+            kernel_path,
+            image,
+            headless,
+            test,
+            no_build,
+            hot,
+            tui,
+            interactive,
+            wait_debugger,
+            display,
+            screenshot_on_exit,
+            timeout,
+            success_code,
+            raw_exit_code,
+            log_level,
+            expect,
+            scenario,
+            mode_name,
+            pick,
+            mode,
+        } => {
+            let kernel = kernel_path.or(kernel);
+            let mut mode_name = match (mode_name, mode) {
+                (Some(_), Some(_)) => anyhow::bail!(
+                    "Pass the run mode either as `--mode` or as `mode <NAME>`, not both"
+                ),
+                (name, mode) => name.or(mode.map(|RunMode::Mode { name }| name)),
+            };
+            // Ask before building so the choice is not held up by the build
+            let several_modes = config.qemu.modes.len() > 1;
+            if mode_name.is_none()
+                && scenario.is_none()
+                && (pick || (config.run.pick_mode && several_modes))
+            {
+                if !picker::available() || message_format.is_json() {
+                    if pick {
+                        anyhow::bail!(
+                            "`--pick` needs an interactive terminal; pass `--mode <NAME>` instead"
+                        );
+                    }
+                } else {
+                    mode_name = picker::pick_mode(&config)?;
+                }
+            }
+            // Flags on the command line still win over the mode's settings
+            if let Some(name) = &mode_name {
+                config = config.with_mode(name)?;
+            }
+            if let Some(image) = image {
+                config.build.image_path = image;
+            }
+            if headless {
+                config.qemu.headless = true;
+            }
+            if interactive {
+                config.qemu.interactive = true;
+            }
+            if display.is_some() {
+                config.qemu.display = display;
+            }
+            if screenshot_on_exit.is_some() {
+                config.qemu.screenshot_on_exit = screenshot_on_exit;
+            }
+            if let Some(timeout) = timeout {
+                config.test.timeout_secs = timeout;
+            }
+            if let Some(success_code) = success_code {
+                config.test.success_exit_code = success_code;
+            }
+            if log_level.is_some() {
+                config.qemu.log_level = log_level;
+            }
+            if expect.is_some() {
+                config.test.expect.file = expect;
+            }
+
             let kernel_path = kernel.as_deref();
-            let is_test = kernel_path.map(is_test_executable).unwrap_or(false);
 
-            let builder = Builder::new(config.clone())?;
-            builder.build(kernel_path)?;
+            if no_build || !config.run.auto_build {
+                let image = &config.build.image_path;
+                if !image.exists() && !dry_run {
+                    anyhow::bail!(
+                        "Image {:?} does not exist; build it with `limage build` first",
+                        image
+                    );
+                }
+                debug!("Skipping the build, booting existing image {:?}", image);
+            } else {
+                let builder = Builder::new(config.clone())?
+                    .dry_run(dry_run)
+                    .message_format(message_format)
+                    .test(test);
+                builder.build(kernel_path)?;
+            }
 
-            let mode_name = match mode {
-                Some(RunMode::Mode { name }) => Some(name.as_str().to_owned()),
-                None => None,
-            };
+            if let Some(scenario) = scenario {
+                let exit_code = scenario::run(&config, &scenario, test, dry_run, message_format)?;
+                process::exit(exit_code);
+            }
 
-            let runner = Runner::new(config, is_test);
-            let exit_code = runner.run(mode_name.as_deref())?;
+            if hot && !dry_run && Runner::hot_reload(&config)? {
+                return Ok(());
+            }
+
+            let runner = Runner::new(config, test)
+                .hot(hot)
+                .tui(tui)
+                .kernel(kernel.clone())
+                .wait_debugger(wait_debugger)
+                .raw_exit_code(raw_exit_code)
+                .dry_run(dry_run)
+                .message_format(message_format);
+            let exit_code = runner.run_exit_code(mode_name.as_deref())?;
+            process::exit(exit_code);
+        }
+        Commands::Runner {
+            test,
+            no_test,
+            raw_exit_code,
+            kernel,
+            args,
+        } => {
+            let test = test || (!no_test && is_test_executable(&kernel));
+            if !args.is_empty() {
+                warn!(
+                    "Ignoring arguments passed to the kernel: {}",
+                    args.join(" ")
+                );
+            }
+            // The kernel cargo hands over is always new, so the image is rebuilt regardless of run.auto_build
+            Builder::new(config.clone())?
+                .dry_run(dry_run)
+                .message_format(message_format)
+                .test(test)
+                .build(Some(&kernel))?;
+
+            let runner = Runner::new(config, test)
+                .kernel(Some(kernel))
+                .raw_exit_code(raw_exit_code)
+                .dry_run(dry_run)
+                .message_format(message_format);
+            let exit_code = runner.run_exit_code(None)?;
             process::exit(exit_code);
         }
-        Commands::Clean => {
-            let _ = std::fs::remove_dir_all("target/iso_root");
-            let _ = std::fs::remove_dir_all("target/ovmf");
-            let _ = std::fs::remove_dir_all("target/limine");
-            let _ = std::fs::remove_file(&config.build.image_path);
+        Commands::Test {
+            log_level,
+            filter,
+            cargo_args,
+        } => {
+            if log_level.is_some() {
+                config.qemu.log_level = log_level;
+            }
+            // The guest splits its command line on whitespace
+            if let Some(filter) = filter.as_ref().filter(|f| f.contains(char::is_whitespace)) {
+                anyhow::bail!("--filter {:?} contains whitespace", filter);
+            }
+            let cargo_args = config.target_cargo_args(&cargo_args);
+            if dry_run {
+                // Which images to build and boot is only known once cargo has built the tests
+                println!(
+                    "{}",
+                    command::render(&cargo::test_executables_command(&cargo_args))
+                );
+                return Ok(());
+            }
+            if let Some(path) = &config.build.target_json {
+                target_spec::materialize(&RealFs, path)
+                    .with_context(|| format!("Failed to write target JSON {:?}", path))?;
+            }
+            let executables = cargo::build_test_executables(&cargo_args)?;
+            let mut builder = Builder::new(config.clone())?
+                .dry_run(dry_run)
+                .message_format(message_format)
+                .test(true);
+            if let Some(filter) = filter {
+                builder = builder.cmdline_arg(format!("{}={}", limage_test::FILTER_ARG, filter));
+            }
+
+            let mut failed = Vec::new();
+            for executable in &executables {
+                if !quiet && !message_format.is_json() {
+                    println!("Running {}", executable.display());
+                }
+                builder.build(Some(executable))?;
+
+                let runner = Runner::new(config.clone(), true)
+                    .kernel(Some(executable.clone()))
+                    .dry_run(dry_run)
+                    .message_format(message_format);
+                let reason = match runner.run(None)?.status {
+                    RunStatus::Passed => None,
+                    RunStatus::Failed => Some("FAILED"),
+                    RunStatus::TimedOut => Some("TIMED OUT"),
+                    RunStatus::QemuError => Some("QEMU ERROR"),
+                };
+                if let Some(reason) = reason {
+                    failed.push((executable, reason));
+                }
+            }
+
+            if message_format.is_json() {
+                message_format.emit(&Message::TestSummary {
+                    passed: executables.len() - failed.len(),
+                    failed: failed.len(),
+                });
+            } else {
+                println!(
+                    "{} of {} test executables passed",
+                    executables.len() - failed.len(),
+                    executables.len()
+                );
+                for (executable, reason) in &failed {
+                    println!("    {} {}", reason, executable.display());
+                }
+            }
+            if !failed.is_empty() {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Commands::Ci { junit, cargo_args } => {
+            let mut pipeline = Pipeline::new(config)
+                .cargo_args(cargo_args)
+                .dry_run(dry_run);
+            if let Some(junit) = junit {
+                pipeline = pipeline.junit(junit);
+            }
+            let report = pipeline.run()?;
+            process::exit(report.exit_code());
+        }
+        Commands::Clean {
+            ovmf,
+            limine,
+            iso,
+            all,
+        } => {
+            // Without a selection, clean every build artifact but keep per-run state
+            let everything = !(ovmf || limine || iso || all);
+            let targets = CleanTargets {
+                ovmf: ovmf || all || everything,
+                limine: limine || all || everything,
+                iso: iso || all || everything,
+                work_dir: all,
+            };
+
+            let removed = Builder::new(config)?.clean(&targets, dry_run)?;
+            if removed.is_empty() {
+                println!("Nothing to clean");
+            }
+            for path in &removed {
+                let verb = if dry_run { "Would remove" } else { "Removed" };
+                println!("{} {}", verb, path.display());
+            }
+            Ok(())
+        }
+        Commands::Shell => {
+            let target = config
+                .monitor_target()?
+                .ok_or(MonitorError::NotConfigured)?;
+            monitor::attach(&target)?;
+            Ok(())
+        }
+        Commands::Package {
+            format,
+            out_dir,
+            name,
+            no_build,
+        } => {
+            if let Some(format) = format {
+                config.package.format = format;
+            }
+            if let Some(out_dir) = out_dir {
+                config.package.out_dir = out_dir;
+            }
+            if let Some(name) = name {
+                config.package.name = name;
+            }
+            if !no_build {
+                Builder::new(config.clone())?
+                    .dry_run(dry_run)
+                    .message_format(message_format)
+                    .build(None)?;
+            }
+
+            let archive = Packager::new(config)
+                .dry_run(dry_run)
+                .message_format(message_format)
+                .package()?;
+            if !dry_run && !quiet && !message_format.is_json() {
+                println!("Packaged {}", archive.display());
+            }
+            Ok(())
+        }
+        Commands::Flash { device, yes, force } => {
+            let options = FlashOptions {
+                assume_yes: yes,
+                force,
+            };
+            flash::flash(&config.build.image_path, &device, &options)?;
             Ok(())
         }
+        Commands::Update { yes, check } => {
+            let lockfile_path = Path::new(LOCKFILE);
+            let artifacts = update::check(&config, &Lockfile::load(lockfile_path)?)?;
+            for artifact in &artifacts {
+                let current = artifact.current.as_deref().unwrap_or("not cached");
+                if artifact.is_outdated() {
+                    println!("{:<10}{} -> {}", artifact.name(), current, artifact.latest);
+                } else {
+                    println!("{:<10}{} (up to date)", artifact.name(), current);
+                }
+            }
+
+            let outdated = artifacts.iter().any(|artifact| artifact.is_outdated());
+            if check {
+                process::exit(if outdated { 1 } else { 0 });
+            }
+            if !outdated && lockfile_path.exists() {
+                return Ok(());
+            }
+            if outdated && !yes && !update::confirm("Download the new versions?")? {
+                println!("Aborted");
+                return Ok(());
+            }
+
+            update::apply(&config, &artifacts, lockfile_path)?;
+            println!("Recorded versions in {}", LOCKFILE);
+            Ok(())
+        }
+        Commands::Completions { shell } => {
+            cli::write_completions(shell, &config, &mut std::io::stdout());
+            Ok(())
+        }
+        Commands::Config { action } => match action {
+            ConfigAction::Show => {
+                print!("{}", inspect::show(&config, &config_path)?);
+                Ok(())
+            }
+            ConfigAction::Init { .. } | ConfigAction::Migrate => {
+                unreachable!("handled before the configuration is loaded")
+            }
+        },
+        Commands::Init { .. } => unreachable!("handled before the configuration is loaded"),
+        Commands::Doctor | Commands::Check | Commands::Version | Commands::SelfUpdate { .. } => {
+            unreachable!("handled before the configuration is validated")
+        }
+        Commands::Modes => {
+            for (name, mode) in config.modes() {
+                println!(
+                    "{:<16}{}",
+                    name,
+                    mode.description.as_deref().unwrap_or_default()
+                );
+                if !mode.extra_args.is_empty() {
+                    println!("{:<16}{}", "", mode.extra_args.join(" "));
+                }
+            }
+            Ok(())
+        }
+        Commands::Addr2line { addresses, kernel } => {
+            let kernel = kernel.unwrap_or_else(|| config.staged_kernel());
+            let symbolizer = Symbolizer::new(&kernel)?;
+            let lines = if addresses.is_empty() {
+                let mut panic_output = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut panic_output)
+                    .context("Failed to read panic output from stdin")?;
+                symbolizer.backtrace(&panic_output)
+            } else {
+                let addresses = addresses
+                    .iter()
+                    .map(|address| {
+                        let digits = address.trim_start_matches("0x");
+                        u64::from_str_radix(digits, 16)
+                            .with_context(|| format!("Invalid address '{}'", address))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                symbolizer.resolve(&addresses)
+            };
+            if lines.is_empty() {
+                warn!("No address in the input belongs to {:?}", kernel);
+            }
+            for line in lines {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Commands::QemuArgs { mode, test, shell } => {
+            if let Some(name) = &mode {
+                config = config.with_mode(name)?;
+            }
+            let command = Runner::new(config, test).command(mode.as_deref())?;
+            if !shell {
+                println!("{}", command.get_program().to_string_lossy());
+                for arg in command.get_args() {
+                    println!("{}", arg.to_string_lossy());
+                }
+                println!();
+            }
+            println!("{}", command::render(&command));
+            Ok(())
+        }
+        Commands::Size { files } => {
+            let current = SizeReport::measure(&config)?;
+            let previous = SizeReport::previous(&config);
+            print!(
+                "{}",
+                size::render(&config, &current, previous.as_ref(), files)
+            );
+            Ok(())
+        }
+        Commands::Logs {
+            run,
+            last,
+            grep,
+            tail,
+            follow,
+        } => {
+            let history = History::new(&config);
+            let pattern = grep
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .context("Invalid --grep pattern")?;
+            let matches = |line: &str| pattern.as_ref().is_none_or(|p| p.is_match(line));
+
+            let Some(run) = run.or(last.then(|| "1".to_string())) else {
+                let records = history.list()?;
+                if pattern.is_some() {
+                    for record in &records {
+                        for line in history.read_log(record)?.lines().filter(|l| matches(l)) {
+                            println!("{}: {}", record.id, line);
+                        }
+                    }
+                    return Ok(());
+                }
+                if records.is_empty() {
+                    println!("No recorded runs");
+                    return Ok(());
+                }
+                println!(
+                    "{:>3}  {:<13}  {:<19}  {:<4}  {:<12}  {:<10}  {:>4}  DURATION",
+                    "#", "ID", "STARTED (UTC)", "TYPE", "MODE", "STATUS", "EXIT"
+                );
+                for (index, record) in records.iter().enumerate() {
+                    let status = match (record.finished, record.status) {
+                        (false, _) => "running",
+                        (true, None) => "error",
+                        (true, Some(status)) => status.name(),
+                    };
+                    println!(
+                        "{:>3}  {:<13}  {:<19}  {:<4}  {:<12}  {:<10}  {:>4}  {:.1}s",
+                        index + 1,
+                        record.id,
+                        record.started_at(),
+                        if record.test { "test" } else { "run" },
+                        record.mode.as_deref().unwrap_or("-"),
+                        status,
+                        record
+                            .exit_code
+                            .map_or("-".to_string(), |code| code.to_string()),
+                        record.duration_ms as f64 / 1000.0
+                    );
+                }
+                return Ok(());
+            };
+
+            let record = history.find(&run)?;
+            let log = history.read_log(&record)?;
+            if log.is_empty() && record.finished {
+                warn!(
+                    "Run {} recorded no serial output; runs started from a terminal are only recorded when [qemu] log_level is set",
+                    record.id
+                );
+            }
+            let lines: Vec<&str> = log.lines().filter(|line| matches(line)).collect();
+            let skip = tail.map_or(0, |tail| lines.len().saturating_sub(tail));
+            for line in &lines[skip..] {
+                println!("{}", line);
+            }
+            if follow {
+                history.follow(&record, log.len() as u64, |line| {
+                    if matches(line) {
+                        println!("{}", line);
+                    }
+                })?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn init(
+    dir: PathBuf,
+    template: &str,
+    name: Option<String>,
+    force: bool,
+    list: bool,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    if list {
+        for template in TEMPLATES {
+            println!("{:<14}{}", template.name, template.description);
+        }
+        return Ok(());
+    }
+    // `limage init` names the current directory as `<cwd>/.`
+    let dir: PathBuf = dir.components().collect();
+    let files = Scaffold::new(Source::parse(template)?, dir.clone())
+        .name(name)
+        .force(force)
+        .dry_run(dry_run)
+        .create()?;
+    if dry_run {
+        return Ok(());
+    }
+    for file in &files {
+        println!("Wrote {}", dir.join(file).display());
     }
+    println!(
+        "Created a kernel project from the {} template; `limage run` boots it and `limage test` runs its tests",
+        template
+    );
+    Ok(())
 }
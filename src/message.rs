@@ -0,0 +1,112 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{io::Write, path::Path};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    #[default]
+    Human,
+    // One JSON object per line on stdout, for CI and wrapper tools
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Message<'a> {
+    StepStarted {
+        step: &'a str,
+    },
+    StepFinished {
+        step: &'a str,
+        duration_ms: u128,
+    },
+    StepFailed {
+        step: &'a str,
+        error: &'a str,
+    },
+    Artifact {
+        kind: &'a str,
+        path: &'a Path,
+    },
+    // Bytes written so far; only produced when an `EventSink` is attached
+    DownloadProgress {
+        url: &'a str,
+        path: &'a Path,
+        bytes: u64,
+        finished: bool,
+    },
+    // A line a build command printed; only produced when an `EventSink` is attached
+    CommandOutput {
+        step: &'a str,
+        stream: &'a str,
+        line: &'a str,
+    },
+    Serial {
+        line: &'a str,
+    },
+    QemuExited {
+        exit_code: i32,
+    },
+    TestResult {
+        executable: &'a Path,
+        outcome: &'a str,
+        exit_code: i32,
+        // More than 1 when `test.retries` re-ran a failed test
+        attempts: usize,
+        flaky: bool,
+    },
+    // A failed test attempt that `test.retries` is about to re-run
+    TestAttempt {
+        executable: &'a Path,
+        attempt: u32,
+        outcome: &'a str,
+        exit_code: i32,
+    },
+    TestSummary {
+        passed: usize,
+        failed: usize,
+    },
+    // A `#[test_case]` a limage-test guest finished, with the panic message of a failed one or the
+    // timeout of a timed-out one
+    GuestTest {
+        name: &'a str,
+        status: &'a str,
+        duration_ms: u128,
+        message: Option<&'a str>,
+    },
+    GuestTestSummary {
+        passed: usize,
+        failed: usize,
+        not_run: usize,
+        filtered_out: usize,
+    },
+}
+
+// Receives every message of a build or run as it happens, for embedders drawing their own progress
+pub trait EventSink: Send + Sync {
+    fn event(&self, message: &Message);
+}
+
+impl<F: Fn(&Message) + Send + Sync> EventSink for F {
+    fn event(&self, message: &Message) {
+        self(message)
+    }
+}
+
+impl MessageFormat {
+    pub fn is_json(self) -> bool {
+        self == MessageFormat::Json
+    }
+
+    // Human output is produced by the callers themselves, so only JSON messages are written here
+    pub fn emit(self, message: &Message) {
+        if !self.is_json() {
+            return;
+        }
+        let mut out = std::io::stdout().lock();
+        if serde_json::to_writer(&mut out, message).is_ok() {
+            let _ = writeln!(out);
+            let _ = out.flush();
+        }
+    }
+}
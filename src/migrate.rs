@@ -0,0 +1,534 @@
+use crate::config::CONFIG_VERSION;
+use similar::TextDiff;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use toml::{value::Table, Value};
+use toml_edit::{DocumentMut, InlineTable, Item, Key};
+
+// Keys renamed since the first config format, as dotted paths where `*` matches any name.
+// Applied in order, so a later rename sees the result of the earlier ones
+const RENAMES: [(&str, &str); 2] = [
+    // Run modes lived at the top level before [qemu] grew its own settings
+    ("modes.*", "qemu.modes.*"),
+    // Mode arguments were called `args` in the pre-clap era
+    ("qemu.modes.*.args", "qemu.modes.*.extra_args"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rename {
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for Rename {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` is now `{}`", self.old, self.new)
+    }
+}
+
+pub struct Migration {
+    pub path: PathBuf,
+    pub original: String,
+    pub content: String,
+    pub renames: Vec<Rename>,
+}
+
+impl Migration {
+    pub fn is_empty(&self) -> bool {
+        self.original == self.content
+    }
+
+    pub fn diff(&self) -> String {
+        let name = self.path.display().to_string();
+        TextDiff::from_lines(&self.original, &self.content)
+            .unified_diff()
+            .context_radius(3)
+            .header(&name, &name)
+            .to_string()
+    }
+}
+
+// The deprecated keys in `table`, renamed the way `limage config migrate` would
+pub fn deprecated_keys(table: &Table) -> Vec<Rename> {
+    renames(table)
+        .into_iter()
+        .map(|(old, new)| Rename {
+            old: old.join("."),
+            new: new.join("."),
+        })
+        .collect()
+}
+
+// The paths in `table` that have an old name and their new ones, in the order to move them in
+fn renames(table: &Table) -> Vec<(Vec<String>, Vec<String>)> {
+    let mut table = table.clone();
+    let mut renames = Vec::new();
+    for (from, to) in RENAMES {
+        let from: Vec<&str> = from.split('.').collect();
+        let to: Vec<&str> = to.split('.').collect();
+        for old in find(&table, &from, &[]) {
+            let new = substitute(&old, &from, &to);
+            let Some(value) = remove(&mut table, &old) else {
+                continue;
+            };
+            // The new spelling wins when both are present, as it does on load
+            insert(&mut table, &new, value);
+            renames.push((old, new));
+        }
+    }
+    renames
+}
+
+// Rewrites the config at `path` to the current format. The keys are renamed on the parsed
+// document, so comments and layout survive. Nothing is written when `dry_run` is set
+pub fn migrate_file(path: &Path, dry_run: bool) -> Result<Migration, MigrateError> {
+    let original = std::fs::read_to_string(path).map_err(|e| MigrateError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let table: Table = toml::from_str(&original).map_err(|e| MigrateError::Parse { source: e })?;
+    if let Some(version) = table.get("config_version").and_then(Value::as_integer) {
+        if version > CONFIG_VERSION.into() {
+            return Err(MigrateError::NewerVersion { version });
+        }
+    }
+
+    let renames = deprecated_keys(&table);
+    let content = rewrite(&original, &table)?;
+
+    // Every rename should have found its table; check rather than write a half-migrated file
+    let rewritten: Table =
+        toml::from_str(&content).map_err(|e| MigrateError::Parse { source: e })?;
+    let remaining = deprecated_keys(&rewritten);
+    if !remaining.is_empty() {
+        return Err(MigrateError::Incomplete {
+            keys: remaining
+                .iter()
+                .map(|rename| rename.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        });
+    }
+
+    let migration = Migration {
+        path: path.to_path_buf(),
+        original,
+        content,
+        renames,
+    };
+    if !dry_run && !migration.is_empty() {
+        std::fs::write(path, &migration.content).map_err(|e| MigrateError::Write {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+    }
+    Ok(migration)
+}
+
+// `content` with the deprecated keys of `table`, its parsed form, renamed and the version set
+fn rewrite(content: &str, table: &Table) -> Result<String, MigrateError> {
+    let mut document: DocumentMut = content
+        .parse()
+        .map_err(|e| MigrateError::Document { source: e })?;
+    for (old, new) in renames(table) {
+        rename(document.as_item_mut(), &old, &new);
+    }
+    set_version(&mut document);
+
+    let rewritten = document.to_string();
+    // New lines are written as \n whatever the file used
+    if content.contains("\r\n") {
+        Ok(rewritten.replace("\r\n", "\n").replace('\n', "\r\n"))
+    } else {
+        Ok(rewritten)
+    }
+}
+
+// Moves the entry at `old` to `new` with its comments, keeping its place when only the last
+// segment changes
+fn rename(root: &mut Item, old: &[String], new: &[String]) {
+    let (Some((old_name, old_parent)), Some((new_name, new_parent))) =
+        (old.split_last(), new.split_last())
+    else {
+        return;
+    };
+    let Some(parent) = item_at(root, old_parent) else {
+        return;
+    };
+    let inline = parent.is_inline_table();
+    let Some(parent) = parent.as_table_like_mut() else {
+        return;
+    };
+    let Some(old_key) = parent.key(old_name) else {
+        return;
+    };
+    let key = Key::new(new_name.as_str())
+        .with_leaf_decor(old_key.leaf_decor().clone())
+        .with_dotted_decor(old_key.dotted_decor().clone());
+
+    if old_parent == new_parent {
+        if parent.contains_key(new_name) {
+            parent.remove(old_name);
+            return;
+        }
+        // Tables keep their keys in insertion order, so everything from the old key on is
+        // inserted again behind the new one
+        let names: Vec<String> = parent
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .skip_while(|name| name != old_name)
+            .collect();
+        for name in names {
+            let Some(entry_key) = parent.key(&name).cloned() else {
+                continue;
+            };
+            let Some(item) = parent.remove(&name) else {
+                continue;
+            };
+            let entry_key = if name == *old_name { &key } else { &entry_key };
+            parent.entry_format(entry_key).or_insert(item);
+        }
+        return;
+    }
+
+    let Some(item) = parent.remove(old_name) else {
+        return;
+    };
+    prune(root, old_parent);
+    put(root, new_parent, key, item, inline);
+}
+
+// Inserts `item`, taken from an inline table if `inline` is set, under `parents`, creating the
+// tables missing on the way. The new spelling wins when both are present, as it does on load
+fn put(root: &mut Item, parents: &[String], mut key: Key, mut item: Item, inline: bool) {
+    let mut table = root;
+    for name in parents {
+        let child = if table.is_inline_table() {
+            Item::Value(InlineTable::new().into())
+        } else {
+            // A header needs its parents implicit, anything else extends the dotted key
+            let mut child = toml_edit::Table::new();
+            if item.as_table().is_some_and(|table| !table.is_dotted()) {
+                child.set_implicit(true);
+            } else {
+                child.set_dotted(true);
+            }
+            Item::Table(child)
+        };
+        let Some(parent) = table.as_table_like_mut() else {
+            return;
+        };
+        table = parent.entry(name).or_insert(child);
+    }
+
+    // Spacing inside braces looks wrong outside them, and comments are not allowed inside them
+    if inline || table.is_inline_table() {
+        key.leaf_decor_mut().clear();
+        key.dotted_decor_mut().clear();
+        if let Some(value) = item.as_value_mut() {
+            value.decor_mut().clear();
+        }
+    }
+    let item = match table.is_inline_table() {
+        true => match item.into_value() {
+            Ok(value) => Item::Value(value),
+            Err(_) => return,
+        },
+        false => item,
+    };
+    if let Some(parent) = table.as_table_like_mut() {
+        if !parent.contains_key(key.get()) {
+            parent.entry_format(&key).or_insert(item);
+        }
+    }
+}
+
+// Drops the tables along `path` a move left empty
+fn prune(root: &mut Item, path: &[String]) {
+    for end in (1..=path.len()).rev() {
+        let (name, parents) = path[..end].split_last().expect("end is at least 1");
+        let Some(parent) = item_at(root, parents).and_then(Item::as_table_like_mut) else {
+            return;
+        };
+        match parent.get(name).and_then(Item::as_table_like) {
+            Some(table) if table.is_empty() => {
+                parent.remove(name);
+            }
+            _ => return,
+        }
+    }
+}
+
+fn item_at<'a>(item: &'a mut Item, path: &[String]) -> Option<&'a mut Item> {
+    path.iter()
+        .try_fold(item, |item, name| item.as_table_like_mut()?.get_mut(name))
+}
+
+// Updates `config_version` where it is, or adds it first among the top-level keys, below the
+// comments heading the file
+fn set_version(document: &mut DocumentMut) {
+    let version = i64::from(CONFIG_VERSION);
+    if let Some(Item::Value(value)) = document.get_mut("config_version") {
+        let decor = value.decor().clone();
+        *value = version.into();
+        *value.decor_mut() = decor;
+        return;
+    }
+
+    let mut key = Key::new("config_version");
+    // A line's comments belong to the last segment of its key
+    let first: Option<Vec<String>> = document
+        .get_values()
+        .first()
+        .map(|(path, _)| path.iter().map(|key| key.get().to_string()).collect());
+    let first_key = first.as_deref().and_then(|path| {
+        let (name, parents) = path.split_last()?;
+        item_at(document.as_item_mut(), parents)?
+            .as_table_like_mut()?
+            .key_mut(name)
+    });
+    match first_key {
+        Some(mut first) => {
+            let decor = first.leaf_decor_mut();
+            if let Some(prefix) = decor.prefix().cloned() {
+                key.leaf_decor_mut().set_prefix(prefix);
+                decor.set_prefix("");
+            }
+        }
+        // Without top-level keys, a blank line separates the version from the first header
+        None => {
+            if let Some(header) = first_header(document.as_table_mut()) {
+                let prefix = header
+                    .decor()
+                    .prefix()
+                    .and_then(|p| p.as_str())
+                    .unwrap_or("");
+                let prefix = format!("\n{}", prefix);
+                header.decor_mut().set_prefix(prefix);
+            }
+        }
+    }
+
+    let root = document.as_table_mut();
+    let names: Vec<String> = root.iter().map(|(name, _)| name.to_string()).collect();
+    let entries: Vec<_> = names
+        .iter()
+        .filter_map(|name| root.remove_entry(name))
+        .collect();
+    root.insert_formatted(&key, toml_edit::value(version));
+    for (key, item) in entries {
+        root.insert_formatted(&key, item);
+    }
+}
+
+// The table whose header comes first in the file
+fn first_header(table: &mut toml_edit::Table) -> Option<&mut toml_edit::Table> {
+    let mut first: Option<&mut toml_edit::Table> = None;
+    for (_, item) in table.iter_mut() {
+        let header = match item {
+            Item::Table(table) => match table.is_implicit() || table.is_dotted() {
+                true => first_header(table),
+                false => Some(table),
+            },
+            Item::ArrayOfTables(array) => array.iter_mut().next(),
+            _ => None,
+        };
+        let Some(header) = header.filter(|header| header.position().is_some()) else {
+            continue;
+        };
+        if first
+            .as_ref()
+            .is_none_or(|first| header.position() < first.position())
+        {
+            first = Some(header);
+        }
+    }
+    first
+}
+
+// The concrete paths in `table` matching `pattern`
+fn find(table: &Table, pattern: &[&str], prefix: &[String]) -> Vec<Vec<String>> {
+    let Some((first, rest)) = pattern.split_first() else {
+        return Vec::new();
+    };
+    let mut found = Vec::new();
+    for (key, value) in table {
+        if *first != "*" && first != key {
+            continue;
+        }
+        let mut path = prefix.to_vec();
+        path.push(key.clone());
+        if rest.is_empty() {
+            found.push(path);
+        } else if let Value::Table(child) = value {
+            found.extend(find(child, rest, &path));
+        }
+    }
+    found
+}
+
+// Fills the `*` segments of `to` with the names `path` matched in `from`
+fn substitute(path: &[String], from: &[&str], to: &[&str]) -> Vec<String> {
+    let mut names = path
+        .iter()
+        .zip(from)
+        .filter(|(_, pattern)| **pattern == "*")
+        .map(|(segment, _)| segment.clone());
+    to.iter()
+        .map(|segment| match *segment {
+            "*" => names.next().unwrap_or_default(),
+            segment => segment.to_string(),
+        })
+        .collect()
+}
+
+fn remove(table: &mut Table, path: &[String]) -> Option<Value> {
+    let (last, parents) = path.split_last()?;
+    let mut table = table;
+    for key in parents {
+        table = table.get_mut(key)?.as_table_mut()?;
+    }
+    table.remove(last)
+}
+
+fn insert(table: &mut Table, path: &[String], value: Value) {
+    let Some((last, parents)) = path.split_last() else {
+        return;
+    };
+    let mut table = table;
+    for key in parents {
+        let child = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        let Value::Table(child) = child else { return };
+        table = child;
+    }
+    table.entry(last.clone()).or_insert(value);
+}
+
+#[derive(Debug, Error)]
+pub enum MigrateError {
+    #[error("Failed to read {path:?}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse the config")]
+    Parse { source: toml::de::Error },
+
+    #[error("Failed to parse the config for editing")]
+    Document { source: toml_edit::TomlError },
+
+    #[error(
+        "The config is version {version}, newer than this limage understands ({})",
+        CONFIG_VERSION
+    )]
+    NewerVersion { version: i64 },
+
+    #[error("Could not rewrite every deprecated key, rename them by hand: {keys}")]
+    Incomplete { keys: String },
+
+    #[error("Failed to write {path:?}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrate(content: &str) -> String {
+        rewrite(content, &toml::from_str(content).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn renames_mode_headers_and_their_keys() {
+        let original = "# Modes\n[modes.debug] # kept\nargs = [\"-s\"]\ndescription = \"GDB\"\n";
+        assert_eq!(
+            migrate(original),
+            "config_version = 1\n\n# Modes\n[qemu.modes.debug] # kept\nextra_args = [\"-s\"]\ndescription = \"GDB\"\n"
+        );
+    }
+
+    #[test]
+    fn renames_dotted_keys() {
+        let original = "config_version = 1\nmodes.debug.args = []\n\n[qemu]\nmodes.\"my mode\".args = [\"-S\"]\nmemory = \"2G\"\n";
+        assert_eq!(
+            migrate(original),
+            "config_version = 1\n\n[qemu]\nmodes.\"my mode\".extra_args = [\"-S\"]\nmodes.debug.extra_args = []\nmemory = \"2G\"\n"
+        );
+        assert_eq!(
+            migrate("# Debugging\nmodes.debug.args = [] # GDB\n"),
+            "# Debugging\nconfig_version = 1\nqemu.modes.debug.extra_args = [] # GDB\n"
+        );
+    }
+
+    #[test]
+    fn renames_keys_in_inline_tables() {
+        let original = "config_version = 1\nmodes = { debug = { args = [\"-s\"], description = \"GDB\" } }\n\n[qemu]\nmemory = \"2G\"\n";
+        assert_eq!(
+            migrate(original),
+            "config_version = 1\n\n[qemu]\nmemory = \"2G\"\nmodes.debug = { extra_args = [\"-s\"], description = \"GDB\" }\n"
+        );
+    }
+
+    #[test]
+    fn new_names_win_over_old_ones() {
+        let original =
+            "config_version = 1\n[qemu.modes.debug]\nargs = [\"-S\"]\nextra_args = [\"-s\"]\n";
+        assert_eq!(
+            migrate(original),
+            "config_version = 1\n[qemu.modes.debug]\nextra_args = [\"-s\"]\n"
+        );
+    }
+
+    #[test]
+    fn keeps_multi_line_values() {
+        let original = "config_version = 1\n[qemu.modes.debug]\nargs = [\n    \"-s\",\n    # args = 1\n    \"-S\",\n]\nargs_too = 1\n";
+        assert_eq!(
+            migrate(original),
+            "config_version = 1\n[qemu.modes.debug]\nextra_args = [\n    \"-s\",\n    # args = 1\n    \"-S\",\n]\nargs_too = 1\n"
+        );
+    }
+
+    #[test]
+    fn inserts_or_updates_config_version() {
+        assert_eq!(
+            migrate("# Project\nimage_path = \"a.iso\"\n"),
+            "# Project\nconfig_version = 1\nimage_path = \"a.iso\"\n"
+        );
+        assert_eq!(migrate(""), "config_version = 1\n");
+        assert_eq!(
+            migrate("config_version = 0 # old\n[qemu]\n"),
+            "config_version = 1 # old\n[qemu]\n"
+        );
+        assert_eq!(
+            migrate("[qemu]\r\nmemory = \"1G\"\r\n"),
+            "config_version = 1\r\n\r\n[qemu]\r\nmemory = \"1G\"\r\n"
+        );
+    }
+
+    #[test]
+    fn deprecated_keys_names_every_rename() {
+        let table: Table = toml::from_str("[modes.debug]\nargs = []\n").unwrap();
+        assert_eq!(
+            deprecated_keys(&table),
+            [
+                Rename {
+                    old: "modes.debug".to_string(),
+                    new: "qemu.modes.debug".to_string(),
+                },
+                Rename {
+                    old: "qemu.modes.debug.args".to_string(),
+                    new: "qemu.modes.debug.extra_args".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    path::PathBuf,
+    thread,
+};
+use thiserror::Error;
+use tracing::{debug, instrument};
+
+// `unix:<path>` or `tcp:<port>`, as accepted by `qemu.monitor`
+pub enum MonitorTarget {
+    Unix(PathBuf),
+    Tcp(u16),
+}
+
+impl MonitorTarget {
+    pub fn parse(target: &str) -> Result<Self, MonitorError> {
+        match target.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            Some(("tcp", port)) => {
+                port.parse()
+                    .map(Self::Tcp)
+                    .map_err(|_| MonitorError::InvalidTarget {
+                        target: target.to_string(),
+                    })
+            }
+            _ => Err(MonitorError::InvalidTarget {
+                target: target.to_string(),
+            }),
+        }
+    }
+
+    pub fn qemu_arg(&self) -> String {
+        match self {
+            Self::Unix(path) => format!("unix:{},server=on,wait=off", path.display()),
+            Self::Tcp(port) => format!("tcp:127.0.0.1:{},server=on,wait=off", port),
+        }
+    }
+}
+
+// Bridges the terminal to a running QEMU monitor until stdin closes or QEMU hangs up
+#[instrument(skip_all, err)]
+pub fn attach(target: &MonitorTarget) -> Result<(), MonitorError> {
+    match target {
+        #[cfg(unix)]
+        MonitorTarget::Unix(path) => {
+            debug!("Connecting to QEMU monitor at {:?}", path);
+            let stream = std::os::unix::net::UnixStream::connect(path)
+                .map_err(|e| MonitorError::Connect { source: e })?;
+            let clone = || {
+                stream
+                    .try_clone()
+                    .map_err(|e| MonitorError::Connect { source: e })
+            };
+            let hang_up = clone()?;
+            bridge(clone()?, clone()?, move || {
+                let _ = hang_up.shutdown(Shutdown::Both);
+            })
+        }
+        #[cfg(not(unix))]
+        MonitorTarget::Unix(_) => Err(MonitorError::UnixUnsupported),
+        MonitorTarget::Tcp(port) => {
+            debug!("Connecting to QEMU monitor on port {}", port);
+            let stream = TcpStream::connect(("127.0.0.1", *port))
+                .map_err(|e| MonitorError::Connect { source: e })?;
+            let clone = || {
+                stream
+                    .try_clone()
+                    .map_err(|e| MonitorError::Connect { source: e })
+            };
+            let hang_up = clone()?;
+            bridge(clone()?, clone()?, move || {
+                let _ = hang_up.shutdown(Shutdown::Both);
+            })
+        }
+    }
+}
+
+// Returns once QEMU closes the monitor. A read from stdin cannot be interrupted, so the thread
+// forwarding it may outlive the call until the next line, which then fails to write and ends it
+fn bridge(
+    mut reader: impl Read,
+    mut writer: impl Write + Send + 'static,
+    hang_up: impl FnOnce() + Send + 'static,
+) -> Result<(), MonitorError> {
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut std::io::stdin(), &mut writer);
+        // Stdin closed, e.g. Ctrl-D, so end the output side below too
+        hang_up();
+    });
+
+    std::io::copy(&mut reader, &mut std::io::stdout())
+        .map_err(|e| MonitorError::Io { source: e })?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum MonitorError {
+    #[error("Invalid monitor target '{target}', expected unix:<path> or tcp:<port>")]
+    InvalidTarget { target: String },
+
+    #[error("Failed to connect to QEMU monitor: {source}\nIs `limage run` active?")]
+    Connect { source: std::io::Error },
+
+    #[error("Unix socket monitors are not supported on this platform")]
+    UnixUnsupported,
+
+    #[error("Monitor I/O error: {source}")]
+    Io { source: std::io::Error },
+
+    #[error("No monitor configured, set qemu.monitor in limage_config.toml")]
+    NotConfigured,
+}
@@ -0,0 +1,145 @@
+use crate::artifacts::LimineArtifacts;
+use flate2::read::GzDecoder;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+const REACHABLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Downloads `url` to `path`, calling `progress` with the bytes written so far
+#[instrument(skip(progress), err)]
+pub fn download(url: &str, path: &Path, mut progress: impl FnMut(u64)) -> Result<(), NativeError> {
+    let mut reader = get(url)?.into_reader();
+    let mut file = File::create(path).map_err(|e| NativeError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    loop {
+        let read = reader.read(&mut buffer).map_err(|e| NativeError::Read {
+            url: url.to_string(),
+            source: e,
+        })?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read])
+            .map_err(|e| NativeError::Io {
+                path: path.to_path_buf(),
+                source: e,
+            })?;
+        bytes += read as u64;
+        progress(bytes);
+    }
+    debug!("Downloaded {} bytes from {}", bytes, url);
+    Ok(())
+}
+
+// Whether `url` answers a HEAD request, following redirects
+pub fn reachable(url: &str) -> bool {
+    ureq::head(url).timeout(REACHABLE_TIMEOUT).call().is_ok()
+}
+
+// Unpacks the GitHub tarball of Limine's binary branch (or the pinned commit) into the
+// artifacts' directory. Nothing is compiled, so there is no `limine` utility afterwards
+#[instrument(skip(limine), err)]
+pub fn fetch_limine(limine: &LimineArtifacts) -> Result<(), NativeError> {
+    let url = limine.tarball_url();
+    info!("Downloading Limine from {}", url);
+    let mut archive = tar::Archive::new(GzDecoder::new(get(&url)?.into_reader()));
+    let unpack = |e| NativeError::Unpack {
+        url: url.clone(),
+        source: e,
+    };
+    for entry in archive.entries().map_err(unpack)? {
+        let mut entry = entry.map_err(unpack)?;
+        let path = entry.path().map_err(unpack)?.into_owned();
+        // Everything sits in a limine-<ref>/ directory
+        let relative: PathBuf = path.components().skip(1).collect();
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(NativeError::UnsafePath { path });
+        }
+        let destination = limine.dir().join(&relative);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| NativeError::Io {
+                path: parent.to_path_buf(),
+                source: e,
+            })?;
+        }
+        entry.unpack(&destination).map_err(unpack)?;
+    }
+    Ok(())
+}
+
+fn get(url: &str) -> Result<ureq::Response, NativeError> {
+    debug!("GET {}", url);
+    ureq::get(url).call().map_err(|e| NativeError::Http {
+        url: url.to_string(),
+        source: Box::new(e),
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum NativeError {
+    #[error("Download failed: {source}")]
+    Http {
+        url: String,
+        source: Box<ureq::Error>,
+    },
+
+    #[error("Failed to read {url}: {source}")]
+    Read { url: String, source: std::io::Error },
+
+    #[error("Failed to unpack {url}: {source}")]
+    Unpack { url: String, source: std::io::Error },
+
+    #[error("Refusing to unpack {path:?}, which points outside the Limine directory")]
+    UnsafePath { path: PathBuf },
+
+    #[error("Failed to write {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{path:?} and another file in the same directory get the ISO 9660 name {name}")]
+    NameClash { path: PathBuf, name: String },
+
+    #[error("File name of {path:?} is too long for the image")]
+    NameTooLong { path: PathBuf },
+
+    #[error("{path:?} is too large for an ISO 9660 image")]
+    TooLarge { path: PathBuf },
+
+    #[error("The staged tree has no boot image at {path:?}")]
+    MissingBootImage { path: PathBuf },
+}
+
+impl NativeError {
+    // The stable code `limage::Error` reports; a new variant takes the next free code
+    pub fn code(&self) -> &'static str {
+        match self {
+            NativeError::Http { .. } => "E087",
+            NativeError::Read { .. } => "E088",
+            NativeError::Unpack { .. } => "E089",
+            NativeError::UnsafePath { .. } => "E090",
+            NativeError::Io { .. } => "E091",
+            NativeError::NameClash { .. } => "E092",
+            NativeError::NameTooLong { .. } => "E093",
+            NativeError::TooLarge { .. } => "E094",
+            NativeError::MissingBootImage { .. } => "E095",
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{
+    builder::{BuildError, Builder},
+    cargo::{self, CargoError},
+    config::{ArchiveFormat, LimageConfig},
+    message::{Message, MessageFormat},
+};
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+pub struct Packager {
+    config: LimageConfig,
+    dry_run: bool,
+    message_format: MessageFormat,
+}
+
+impl Packager {
+    pub fn new(config: LimageConfig) -> Self {
+        Self {
+            config,
+            dry_run: false,
+            message_format: MessageFormat::Human,
+        }
+    }
+
+    // Lists what would be archived instead of writing anything
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn message_format(mut self, message_format: MessageFormat) -> Self {
+        self.message_format = message_format;
+        self
+    }
+
+    // Bundles the image, kernel symbols, limine.conf and the configured extra files together
+    // with their checksums into an archive under `package.out_dir`, and returns its path
+    #[instrument(skip(self), err)]
+    pub fn package(&self) -> Result<PathBuf, PackageError> {
+        let package = &self.config.package;
+        let stem = archive_stem(&package.name)?;
+        let archive = package
+            .out_dir
+            .join(format!("{}.{}", stem, package.format.extension()));
+
+        let contents = self.contents()?;
+        if self.dry_run {
+            for (path, name) in &contents {
+                println!("Would package {} as {}/{}", path.display(), stem, name);
+            }
+            println!("Would write {}", archive.display());
+            return Ok(archive);
+        }
+
+        let mut checksums = String::new();
+        for (path, name) in &contents {
+            checksums.push_str(&format!("{}  {}\n", sha256(path)?, name));
+        }
+
+        std::fs::create_dir_all(&package.out_dir).map_err(|e| PackageError::Write {
+            path: package.out_dir.clone(),
+            source: e,
+        })?;
+
+        // Written next to the archive first so a failed run never leaves a truncated archive behind
+        let partial = archive.with_extension(format!("{}.part", package.format.extension()));
+        info!("Writing {:?}", archive);
+        let written = match package.format {
+            ArchiveFormat::TarZst => write_tar_zst(&partial, &stem, &contents, &checksums),
+            ArchiveFormat::Zip => write_zip(&partial, &stem, &contents, &checksums),
+        }
+        .map_err(|e| PackageError::Write {
+            path: archive.clone(),
+            source: e,
+        });
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        std::fs::rename(&partial, &archive).map_err(|e| PackageError::Write {
+            path: archive.clone(),
+            source: e,
+        })?;
+
+        self.message_format.emit(&Message::Artifact {
+            kind: "package",
+            path: &archive,
+        });
+        Ok(archive)
+    }
+
+    // Source paths and the names they get inside the archive
+    fn contents(&self) -> Result<Vec<(PathBuf, String)>, PackageError> {
+        let symbols = match &self.config.package.symbols {
+            Some(symbols) => symbols.clone(),
+            None => Builder::new(self.config.clone())?.cargo_kernel()?,
+        };
+        let mut paths = vec![
+            self.config.build.image_path.clone(),
+            symbols,
+            self.config.limine_conf(),
+        ];
+        paths.extend(self.config.package.include.iter().cloned());
+
+        let mut contents: Vec<(PathBuf, String)> = Vec::new();
+        for path in paths {
+            if !path.is_file() && !self.dry_run {
+                return Err(PackageError::MissingFile { path });
+            }
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| PackageError::MissingFile { path: path.clone() })?;
+            if name == CHECKSUMS_FILE || contents.iter().any(|(_, other)| *other == name) {
+                return Err(PackageError::DuplicateName { name });
+            }
+            debug!("Packaging {:?} as {}", path, name);
+            contents.push((path, name));
+        }
+        Ok(contents)
+    }
+}
+
+// Substitutes the placeholders of the `package.name` template
+fn archive_stem(template: &str) -> Result<String, PackageError> {
+    let placeholder = Regex::new(r"\{(\w+)\}").expect("placeholder pattern is valid");
+    let needs_package = placeholder
+        .captures_iter(template)
+        .any(|caps| matches!(&caps[1], "name" | "version"));
+    let (name, version) = if needs_package {
+        cargo::package_info()?
+    } else {
+        Default::default()
+    };
+
+    let mut error = None;
+    let stem = placeholder.replace_all(template, |caps: &Captures| {
+        let value = match &caps[1] {
+            "name" => Ok(name.clone()),
+            "version" => Ok(version.clone()),
+            "date" => Ok(utc_date(SystemTime::now())),
+            "commit" => git_commit(),
+            other => Err(PackageError::UnknownPlaceholder {
+                placeholder: other.to_string(),
+            }),
+        };
+        value.unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            String::new()
+        })
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    let stem = stem.into_owned();
+    if stem.is_empty() || stem.contains(['/', '\\']) {
+        return Err(PackageError::InvalidName { name: stem });
+    }
+    Ok(stem)
+}
+
+// YYYY-MM-DD in UTC, from the days-since-epoch conversion in Howard Hinnant's date algorithms
+pub fn utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn git_commit() -> Result<String, PackageError> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| PackageError::Git {
+            detail: e.to_string(),
+        })?;
+    if !output.status.success() {
+        return Err(PackageError::Git {
+            detail: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn sha256(path: &Path) -> Result<String, PackageError> {
+    let read_error = |e| PackageError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    };
+    let mut file = File::open(path).map_err(read_error)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(read_error)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn write_tar_zst(
+    archive: &Path,
+    stem: &str,
+    contents: &[(PathBuf, String)],
+    checksums: &str,
+) -> std::io::Result<()> {
+    let encoder = zstd::Encoder::new(File::create(archive)?, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    let mut tar = tar::Builder::new(encoder);
+    for (path, name) in contents {
+        tar.append_path_with_name(path, format!("{}/{}", stem, name))?;
+    }
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(checksums.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+    );
+    tar.append_data(
+        &mut header,
+        format!("{}/{}", stem, CHECKSUMS_FILE),
+        checksums.as_bytes(),
+    )?;
+    tar.into_inner()?.finish()?.flush()
+}
+
+fn write_zip(
+    archive: &Path,
+    stem: &str,
+    contents: &[(PathBuf, String)],
+    checksums: &str,
+) -> std::io::Result<()> {
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(true);
+    let mut zip = zip::ZipWriter::new(File::create(archive)?);
+    for (path, name) in contents {
+        zip.start_file(format!("{}/{}", stem, name), options)?;
+        std::io::copy(&mut File::open(path)?, &mut zip)?;
+    }
+    zip.start_file(format!("{}/{}", stem, CHECKSUMS_FILE), options)?;
+    zip.write_all(checksums.as_bytes())?;
+    zip.finish()?.flush()
+}
+
+#[derive(Debug, Error)]
+pub enum PackageError {
+    #[error("File to package {path:?} does not exist")]
+    MissingFile { path: PathBuf },
+
+    #[error("Two packaged files are both named {name}")]
+    DuplicateName { name: String },
+
+    #[error("Unknown placeholder {{{placeholder}}} in package.name")]
+    UnknownPlaceholder { placeholder: String },
+
+    #[error("package.name expands to {name:?}, which is not a valid file name")]
+    InvalidName { name: String },
+
+    #[error("Failed to determine the git commit for {{commit}}: {detail}")]
+    Git { detail: String },
+
+    #[error("Failed to read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to write {path:?}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Cargo(#[from] CargoError),
+
+    #[error(transparent)]
+    Build(#[from] BuildError),
+}
@@ -0,0 +1,49 @@
+use crate::config::LimageConfig;
+use dialoguer::Select;
+use std::io::IsTerminal;
+use thiserror::Error;
+
+// The picker only makes sense when someone is at the terminal to answer it
+pub fn available() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+// Lets the user choose a run mode; `Ok(None)` selects the plain run without a mode
+pub fn pick_mode(config: &LimageConfig) -> Result<Option<String>, PickerError> {
+    let modes = config.modes();
+    let width = modes
+        .keys()
+        .map(String::len)
+        .max()
+        .unwrap_or_default()
+        .max(6);
+
+    let mut names = vec![None];
+    let mut items = vec![format!("{:<width$}  boot without a mode", "(none)")];
+    for (name, mode) in modes {
+        items.push(format!(
+            "{:<width$}  {}",
+            name,
+            mode.description.as_deref().unwrap_or_default()
+        ));
+        names.push(Some(name));
+    }
+
+    let selection = Select::new()
+        .with_prompt("Run mode")
+        .items(&items)
+        .default(0)
+        .interact_opt()
+        .map_err(|e| PickerError::Prompt { source: e })?
+        .ok_or(PickerError::Cancelled)?;
+    Ok(names.swap_remove(selection))
+}
+
+#[derive(Debug, Error)]
+pub enum PickerError {
+    #[error("Failed to show the mode picker: {source}")]
+    Prompt { source: dialoguer::Error },
+
+    #[error("No run mode selected")]
+    Cancelled,
+}
@@ -0,0 +1,191 @@
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, instrument, trace};
+
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct QmpHandle {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl QmpHandle {
+    // Picks a free localhost port for QEMU to listen on
+    pub fn allocate_addr() -> Result<SocketAddr, QmpError> {
+        TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map_err(|e| QmpError::Io { source: e })
+    }
+
+    pub fn qemu_args(addr: SocketAddr) -> Vec<String> {
+        vec![
+            "-qmp".to_string(),
+            format!("tcp:{},server=on,wait=off", addr),
+        ]
+    }
+
+    #[instrument(err)]
+    pub fn connect(addr: SocketAddr, timeout: Duration) -> Result<Self, QmpError> {
+        let deadline = Instant::now() + timeout;
+        let stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(QmpError::Connect { addr, source: e })
+                }
+                Err(_) => thread::sleep(CONNECT_RETRY_INTERVAL),
+            }
+        };
+
+        stream
+            .set_read_timeout(Some(RESPONSE_TIMEOUT))
+            .map_err(|e| QmpError::Io { source: e })?;
+        let writer = stream.try_clone().map_err(|e| QmpError::Io { source: e })?;
+
+        let mut handle = Self {
+            reader: BufReader::new(stream),
+            writer,
+        };
+
+        let greeting = handle.read_message()?;
+        debug!("QMP greeting: {}", greeting);
+        handle.execute("qmp_capabilities", None)?;
+        Ok(handle)
+    }
+
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, QmpError> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        trace!("QMP request: {}", request);
+
+        writeln!(self.writer, "{}", request).map_err(|e| QmpError::Io { source: e })?;
+
+        loop {
+            let mut message = self.read_message()?;
+            if message.get("event").is_some() {
+                trace!("Skipping QMP event: {}", message);
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Err(QmpError::Command {
+                    command: command.to_string(),
+                    desc: error["desc"]
+                        .as_str()
+                        .unwrap_or("unknown error")
+                        .to_string(),
+                });
+            }
+            return Ok(message["return"].take());
+        }
+    }
+
+    pub fn system_powerdown(&mut self) -> Result<(), QmpError> {
+        self.execute("system_powerdown", None).map(|_| ())
+    }
+
+    pub fn system_reset(&mut self) -> Result<(), QmpError> {
+        self.execute("system_reset", None).map(|_| ())
+    }
+
+    pub fn quit(&mut self) -> Result<(), QmpError> {
+        self.execute("quit", None).map(|_| ())
+    }
+
+    pub fn query_status(&mut self) -> Result<String, QmpError> {
+        let status = self.execute("query-status", None)?;
+        Ok(status["status"].as_str().unwrap_or("unknown").to_string())
+    }
+
+    pub fn screendump(&mut self, path: &Path) -> Result<(), QmpError> {
+        let mut arguments = json!({ "filename": path.display().to_string() });
+        if path.extension().is_some_and(|ext| ext == "png") {
+            arguments["format"] = json!("png");
+        }
+        self.execute("screendump", Some(arguments)).map(|_| ())
+    }
+
+    pub fn query_block(&mut self) -> Result<Value, QmpError> {
+        self.execute("query-block", None)
+    }
+
+    // Swaps the medium in the first removable drive, i.e. the `-cdrom` image
+    pub fn change_cdrom(&mut self, path: &Path) -> Result<(), QmpError> {
+        let blocks = self.query_block()?;
+        let device = blocks
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|block| block["removable"].as_bool() == Some(true))
+            .and_then(|block| block["device"].as_str())
+            .ok_or(QmpError::NoCdrom)?
+            .to_string();
+
+        debug!("Changing medium of {} to {:?}", device, path);
+        self.execute(
+            "blockdev-change-medium",
+            Some(json!({
+                "device": device,
+                "filename": path.display().to_string(),
+                "format": "raw",
+            })),
+        )
+        .map(|_| ())
+    }
+
+    pub fn dump_guest_memory(&mut self, path: &Path) -> Result<(), QmpError> {
+        self.execute(
+            "dump-guest-memory",
+            Some(json!({
+                "paging": false,
+                "protocol": format!("file:{}", path.display()),
+            })),
+        )
+        .map(|_| ())
+    }
+
+    fn read_message(&mut self) -> Result<Value, QmpError> {
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|e| QmpError::Io { source: e })?;
+        if read == 0 {
+            return Err(QmpError::Closed);
+        }
+        serde_json::from_str(&line).map_err(|e| QmpError::Parse { source: e })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QmpError {
+    #[error("Failed to connect to QMP socket at {addr}: {source}")]
+    Connect {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+
+    #[error("QMP I/O error: {source}")]
+    Io { source: std::io::Error },
+
+    #[error("Malformed QMP message: {source}")]
+    Parse { source: serde_json::Error },
+
+    #[error("QMP command '{command}' failed: {desc}")]
+    Command { command: String, desc: String },
+
+    #[error("QMP connection closed by QEMU")]
+    Closed,
+
+    #[error("QEMU has no removable drive to load the image into")]
+    NoCdrom,
+}
@@ -1,64 +1,1399 @@
-use crate::config::{ConfigError, LimageConfig};
-use std::{process::Command, time::Duration};
+#[cfg(unix)]
+use crate::serial::RawTerminal;
+use crate::{
+    backend::{self, BackendError, VmBackend},
+    command,
+    config::{BackendKind, ConfigError, LimageConfig, TestOutcome},
+    expect::{self, ExpectError, Normalizer},
+    gdb::{self, GdbError},
+    guest_test::{GuestEvent, GuestTest, GuestTests},
+    history::{History, Recording},
+    interrupt::{self, InterruptGuard},
+    message::{EventSink, Message, MessageFormat},
+    monitor::MonitorTarget,
+    qmp::{QmpError, QmpHandle},
+    serial::{forward_stdin, SerialPrinter},
+    symbolize::Symbolizer,
+    tpm::{Swtpm, TpmError},
+    tui::{self, TuiError, TuiExit, TuiOptions},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, IsTerminal, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
+use tracing::{debug, info, warn};
 use wait_timeout::ChildExt;
 
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const QMP_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const QUIT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const SCREENSHOT_INTERVAL: Duration = Duration::from_secs(1);
+// How long a guest that panicked gets to finish printing, e.g. its backtrace, and exit by itself
+const PANIC_EXIT_GRACE: Duration = Duration::from_secs(1);
+
+// Reported for test runs that hit `test.timeout_secs`, as opposed to guest failures (1)
+pub const TIMED_OUT_EXIT_CODE: i32 = 2;
+// Reported with --raw-exit-code when QEMU itself died; even, like the timeout code, so it never
+// collides with the odd `(code << 1) | 1` values of isa-debug-exit
+pub const QEMU_ERROR_EXIT_CODE: i32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Passed,
+    Failed,
+    TimedOut,
+    // QEMU was killed by a signal instead of exiting
+    QemuError,
+}
+
+impl RunStatus {
+    // As reported in `test_result` messages and the run history
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunStatus::Passed => "passed",
+            RunStatus::Failed => "failed",
+            RunStatus::TimedOut => "timed_out",
+            RunStatus::QemuError => "qemu_error",
+        }
+    }
+}
+
+// How a run ended, before it is collapsed into limage's own exit code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub status: RunStatus,
+    // QEMU's exit code, which isa-debug-exit sets from the guest; None when limage stopped the VM
+    pub guest_exit_code: Option<i32>,
+    // How long the VM ran; with retries, the attempt that decided the outcome
+    pub duration: Duration,
+    // The guest's serial output as recorded for `limage logs`; None with `run.history = 0`
+    pub log_path: Option<PathBuf>,
+    // The `#[test_case]`s a limage-test guest reported, in the order they finished
+    pub tests: Vec<GuestTest>,
+    // With `test.retries`, the failed attempts before this one, oldest first
+    pub retried: Vec<RunOutcome>,
+}
+
+impl RunOutcome {
+    fn stopped(status: RunStatus) -> Self {
+        Self {
+            status,
+            guest_exit_code: None,
+            duration: Duration::ZERO,
+            log_path: None,
+            tests: Vec::new(),
+            retried: Vec::new(),
+        }
+    }
+
+    // The 0 (passed) / 1 (failed) / 2 (timed out) convention of test runs
+    pub fn exit_code(&self) -> i32 {
+        match self.status {
+            RunStatus::Passed => 0,
+            RunStatus::Failed | RunStatus::QemuError => 1,
+            RunStatus::TimedOut => TIMED_OUT_EXIT_CODE,
+        }
+    }
+
+    // A test that only passed after failed attempts
+    pub fn flaky(&self) -> bool {
+        self.status == RunStatus::Passed && !self.retried.is_empty()
+    }
+
+    // The guest's own exit code where there is one, so several statuses can be multiplexed through it
+    pub fn raw_exit_code(&self, config: &LimageConfig) -> i32 {
+        match (self.status, self.guest_exit_code) {
+            (RunStatus::TimedOut, _) => TIMED_OUT_EXIT_CODE,
+            (RunStatus::QemuError, _) => QEMU_ERROR_EXIT_CODE,
+            (RunStatus::Failed, None | Some(0)) => 1,
+            // A guest that exited with a passing code but failed on the host, e.g. its output did
+            // not match the transcript or limage-test reported a failed test
+            (RunStatus::Failed, Some(exit_code))
+                if config.test_outcome(exit_code) != TestOutcome::Fail =>
+            {
+                1
+            }
+            (_, Some(exit_code)) => exit_code,
+            (RunStatus::Passed, None) => 0,
+        }
+    }
+}
+
 pub struct Runner {
     config: LimageConfig,
     is_test: bool,
+    hot: bool,
+    tui: bool,
+    kernel: Option<PathBuf>,
+    wait_debugger: bool,
+    dry_run: bool,
+    message_format: MessageFormat,
+    events: Option<Arc<dyn EventSink>>,
+    raw_exit_code: bool,
+    qmp_addr: Mutex<Option<SocketAddr>>,
+    serial_log: Mutex<Option<File>>,
 }
 
 impl Runner {
     pub fn new(config: LimageConfig, is_test: bool) -> Self {
-        Self { config, is_test }
+        Self {
+            config,
+            is_test,
+            hot: false,
+            tui: false,
+            kernel: None,
+            wait_debugger: false,
+            dry_run: false,
+            message_format: MessageFormat::Human,
+            events: None,
+            raw_exit_code: false,
+            qmp_addr: Mutex::new(None),
+            serial_log: Mutex::new(None),
+        }
     }
 
-    pub fn run(&self, mode: Option<&str>) -> Result<i32, RunError> {
-        let cmd_args =
-            self.config
-                .get_qemu_command(&self.config.build.image_path, self.is_test, mode)?;
-        let mut command = Command::new(&cmd_args[0]);
-        command.args(&cmd_args[1..]);
+    // Advertises this run's QMP socket so later `limage run --hot` invocations can reuse the VM
+    pub fn hot(mut self, hot: bool) -> Self {
+        self.hot = hot;
+        self
+    }
+
+    // Replaces the plain serial passthrough with an interactive terminal UI
+    pub fn tui(mut self, tui: bool) -> Self {
+        self.tui = tui;
+        self
+    }
+
+    // The kernel ELF the image was built from, used for symbols in gdb post-mortems
+    pub fn kernel(mut self, kernel: Option<PathBuf>) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    // Starts QEMU paused with a gdbstub and holds the run until a debugger resumes the guest
+    pub fn wait_debugger(mut self, wait_debugger: bool) -> Self {
+        self.wait_debugger = wait_debugger;
+        self
+    }
+
+    // Prints the commands a run would execute instead of booting anything
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // JSON output needs guest serial output captured so it can be wrapped in messages
+    pub fn message_format(mut self, message_format: MessageFormat) -> Self {
+        self.message_format = message_format;
+        self
+    }
+
+    // Passes every run message to `sink` too, including the guest's serial output
+    pub fn events(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.events = Some(sink);
+        self
+    }
+
+    // Makes test runs return the guest's exit code instead of 0/1/2
+    pub fn raw_exit_code(mut self, raw_exit_code: bool) -> Self {
+        self.raw_exit_code = raw_exit_code;
+        self
+    }
+
+    // Loads the freshly built image into a VM started with `hot`, returning false if none is running
+    pub fn hot_reload(config: &LimageConfig) -> Result<bool, RunError> {
+        let addr_file = hot_addr_file(config);
+        let Some(addr) = std::fs::read_to_string(&addr_file)
+            .ok()
+            .and_then(|addr| addr.trim().parse().ok())
+        else {
+            return Ok(false);
+        };
+
+        let mut qmp = match QmpHandle::connect(addr, QMP_CONNECT_TIMEOUT) {
+            Ok(qmp) => qmp,
+            Err(e) => {
+                debug!("Stale hot-reload address in {:?}: {}", addr_file, e);
+                let _ = std::fs::remove_file(&addr_file);
+                return Ok(false);
+            }
+        };
 
-        if self.is_test {
-            self.handle_test_execution(&mut command)
+        info!("Reloading running VM at {}", addr);
+        qmp.change_cdrom(&config.build.image_path)?;
+        qmp.system_reset()?;
+        Ok(true)
+    }
+
+    // Saves the guest display of the active run; `.png` paths are saved as PNG, anything else as PPM
+    pub fn screenshot(&self, path: &Path) -> Result<(), RunError> {
+        let qmp_addr = self
+            .qmp_addr
+            .lock()
+            .map_err(|_| RunError::NotRunning)?
+            .ok_or(RunError::NotRunning)?;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| RunError::Screenshot { source: e })?;
+        }
+
+        let mut qmp = QmpHandle::connect(qmp_addr, QMP_CONNECT_TIMEOUT)?;
+        qmp.screendump(path)?;
+        debug!("Saved screenshot to {:?}", path);
+        Ok(())
+    }
+
+    // The exit code for limage itself: plain runs pass QEMU's exit code through; test runs
+    // collapse it unless raw exit codes are asked for
+    pub fn run_exit_code(&self, mode: Option<&str>) -> Result<i32, RunError> {
+        let outcome = self.run(mode)?;
+        if self.is_test && !self.raw_exit_code {
+            Ok(outcome.exit_code())
         } else {
-            self.handle_normal_execution(&mut command)
+            Ok(outcome.raw_exit_code(&self.config))
         }
     }
 
-    fn handle_normal_execution(&self, command: &mut Command) -> Result<i32, RunError> {
-        let status = command
-            .status()
-            .map_err(|e| RunError::StartQemu { source: e })?;
+    pub fn run(&self, mode: Option<&str>) -> Result<RunOutcome, RunError> {
+        if !self.is_test {
+            return self.run_once(mode);
+        }
+        let outcome = self.run_with_retries(mode)?;
+        self.emit(&Message::TestResult {
+            executable: self.executable(),
+            outcome: outcome.status.name(),
+            exit_code: outcome.raw_exit_code(&self.config),
+            attempts: outcome.retried.len() + 1,
+            flaky: outcome.flaky(),
+        });
+        Ok(outcome)
+    }
+
+    fn executable(&self) -> &Path {
+        self.kernel
+            .as_deref()
+            .unwrap_or(&self.config.build.image_path)
+    }
+
+    fn emit(&self, message: &Message) {
+        self.message_format.emit(message);
+        if let Some(sink) = &self.events {
+            sink.event(message);
+        }
+    }
+
+    fn run_with_retries(&self, mode: Option<&str>) -> Result<RunOutcome, RunError> {
+        let attempts = self.config.test.retries.saturating_add(1);
+        let mut retried = Vec::new();
+        let mut attempt = 1;
+        loop {
+            let outcome = self.run_once(mode)?;
+            if outcome.status == RunStatus::Passed {
+                if attempt > 1 {
+                    warn!("Test passed on attempt {} of {}", attempt, attempts);
+                }
+                return Ok(RunOutcome { retried, ..outcome });
+            }
+            if attempt == attempts {
+                warn!(
+                    "Test failed on all {} attempts (last exit code {})",
+                    attempts,
+                    outcome.raw_exit_code(&self.config)
+                );
+                return Ok(RunOutcome { retried, ..outcome });
+            }
+            warn!(
+                "Test attempt {} of {} failed with exit code {}, retrying",
+                attempt,
+                attempts,
+                outcome.raw_exit_code(&self.config)
+            );
+            self.emit(&Message::TestAttempt {
+                executable: self.executable(),
+                attempt,
+                outcome: outcome.status.name(),
+                exit_code: outcome.raw_exit_code(&self.config),
+            });
+            retried.push(outcome);
+            attempt += 1;
+        }
+    }
 
-        Ok(status.code().unwrap_or(1))
+    fn run_once(&self, mode: Option<&str>) -> Result<RunOutcome, RunError> {
+        let backend = backend::for_config(&self.config);
+        if self.dry_run {
+            return self.print_commands(mode);
+        }
+
+        std::fs::create_dir_all(self.config.work_dir())
+            .map_err(|e| RunError::WorkDir { source: e })?;
+        let history = History::new(&self.config);
+        let recording = self.start_recording(&history, mode);
+        let log_path = recording.as_ref().map(Recording::serial_log_path);
+        let start = Instant::now();
+        let result = if self.config.runner.backend != BackendKind::Qemu {
+            self.run_external(backend.as_ref(), mode)
+        } else {
+            self.run_qemu(backend.as_ref(), mode)
+        }
+        .map(|outcome| RunOutcome {
+            duration: start.elapsed(),
+            log_path,
+            ..outcome
+        });
+        if let Some(recording) = recording {
+            if let Ok(mut serial_log) = self.serial_log.lock() {
+                *serial_log = None;
+            }
+            recording.finish(&history, result.as_ref().ok());
+        }
+        result
     }
 
-    fn handle_test_execution(&self, command: &mut Command) -> Result<i32, RunError> {
+    // Keeps the run for `limage logs`; a history that cannot be written never fails the run
+    fn start_recording(&self, history: &History, mode: Option<&str>) -> Option<Recording> {
+        if !history.enabled() {
+            return None;
+        }
+        match history.start(mode, self.is_test) {
+            Ok(mut recording) => {
+                if let Ok(mut serial_log) = self.serial_log.lock() {
+                    *serial_log = recording.take_serial_log();
+                }
+                Some(recording)
+            }
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+
+    // Starts the VM and returns at once, leaving its lifecycle to the caller, e.g. a GUI frontend.
+    // Only QEMU is supported; the run is not recorded for `limage logs`
+    pub fn spawn(&self, mode: Option<&str>) -> Result<RunHandle, RunError> {
+        let backend = backend::for_config(&self.config);
+        if self.config.runner.backend != BackendKind::Qemu {
+            return Err(RunError::SpawnUnsupported {
+                backend: backend.name(),
+            });
+        }
+        std::fs::create_dir_all(self.config.work_dir())
+            .map_err(|e| RunError::WorkDir { source: e })?;
+
+        let PreparedQemu {
+            mut command,
+            qmp_addr,
+            gdb_port: _,
+            snapshot_vars,
+            swtpm,
+        } = self.prepare_qemu(backend.as_ref(), mode)?;
+        command.stdin(Stdio::null()).stdout(Stdio::piped());
         let mut child = command
             .spawn()
             .map_err(|e| RunError::StartQemu { source: e })?;
+        Ok(RunHandle {
+            _interrupt: interrupt::kill_on_interrupt(&child),
+            stdout: child.stdout.take(),
+            child,
+            qmp_addr,
+            config: self.config.clone(),
+            is_test: self.is_test,
+            start: Instant::now(),
+            snapshot_vars,
+            _swtpm: swtpm,
+            outcome: None,
+        })
+    }
+
+    fn run_qemu(
+        &self,
+        backend: &dyn VmBackend,
+        mode: Option<&str>,
+    ) -> Result<RunOutcome, RunError> {
+        // Dropped (and torn down) once QEMU has exited
+        let PreparedQemu {
+            mut command,
+            qmp_addr,
+            gdb_port,
+            snapshot_vars,
+            swtpm: _swtpm,
+        } = self.prepare_qemu(backend, mode)?;
+        let _remove_vars = snapshot_vars.as_deref().map(interrupt::remove_on_interrupt);
+
+        if let Ok(mut addr) = self.qmp_addr.lock() {
+            *addr = Some(qmp_addr);
+        }
+        if self.hot {
+            std::fs::write(hot_addr_file(&self.config), qmp_addr.to_string())
+                .map_err(|e| RunError::WorkDir { source: e })?;
+        }
+
+        let result = if self.tui {
+            self.handle_tui_execution(&mut command, qmp_addr)
+        } else if self.is_test {
+            self.handle_test_execution(&mut command, qmp_addr, gdb_port)
+        } else {
+            self.handle_normal_execution(&mut command, qmp_addr)
+        };
+
+        if let Ok(mut addr) = self.qmp_addr.lock() {
+            *addr = None;
+        }
+        if self.hot {
+            let _ = std::fs::remove_file(hot_addr_file(&self.config));
+        }
+        if let Some(snapshot_vars) = snapshot_vars {
+            let _ = std::fs::remove_file(snapshot_vars);
+        }
+        result
+    }
+
+    // The QEMU command line with limage's plumbing added, and what has to outlive QEMU
+    fn prepare_qemu(
+        &self,
+        backend: &dyn VmBackend,
+        mode: Option<&str>,
+    ) -> Result<PreparedQemu, RunError> {
+        let mut command = backend.command(&self.config.build.image_path, self.is_test, mode)?;
+
+        // Boot from a throwaway copy so the cached vars file is never mutated
+        let snapshot_vars = if self.config.qemu.snapshot {
+            let source = self.config.ovmf_vars(mode)?;
+            let copy = LimageConfig::snapshot_ovmf_vars();
+            debug!("Copying OVMF vars from {:?} to {:?}", source, copy);
+            std::fs::copy(&source, &copy).map_err(|e| RunError::SnapshotVars { source: e })?;
+            Some(copy)
+        } else {
+            None
+        };
+
+        for log_file in self.config.serial_log_files()? {
+            if let Some(parent) = log_file.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| RunError::SerialLog { source: e })?;
+            }
+            debug!("Guest serial output will be written to {:?}", log_file);
+        }
+
+        if let Some(MonitorTarget::Unix(socket)) = self.config.monitor_target()? {
+            if let Some(parent) = socket.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| RunError::SerialLog { source: e })?;
+            }
+            let _ = std::fs::remove_file(&socket);
+            info!(
+                "QEMU monitor available at {:?}, attach with `limage shell`",
+                socket
+            );
+        }
+
+        let swtpm = if self.config.qemu.tpm {
+            Some(Swtpm::start(&self.config.tpm_state_dir())?)
+        } else {
+            None
+        };
+
+        let qmp_addr = QmpHandle::allocate_addr()?;
+        command.args(QmpHandle::qemu_args(qmp_addr));
+
+        if self.wait_debugger {
+            if self.tui {
+                warn!("--wait-debugger is not supported together with --tui");
+            } else {
+                command.args([
+                    "-S".to_string(),
+                    "-gdb".to_string(),
+                    format!("tcp::{}", self.config.qemu.gdb_port),
+                ]);
+            }
+        }
+
+        let gdb_port = if self.is_test && self.config.test.gdb_on_panic {
+            let port = gdb::allocate_port()?;
+            command.args(gdb::qemu_args(port));
+            Some(port)
+        } else {
+            None
+        };
+        Ok(PreparedQemu {
+            command,
+            qmp_addr,
+            gdb_port,
+            snapshot_vars,
+            swtpm,
+        })
+    }
+
+    // The VM command line of a run in `mode`, after placeholder expansion and mode merging. QMP and
+    // the gdbstub for post-mortems are limage's own plumbing, so they are left out
+    pub fn command(&self, mode: Option<&str>) -> Result<Command, RunError> {
+        let backend = backend::for_config(&self.config);
+        let mut command = backend.command(&self.config.build.image_path, self.is_test, mode)?;
+        if self.config.runner.backend == BackendKind::Qemu && self.wait_debugger {
+            command.args([
+                "-S".to_string(),
+                "-gdb".to_string(),
+                format!("tcp::{}", self.config.qemu.gdb_port),
+            ]);
+        }
+        Ok(command)
+    }
+
+    fn print_commands(&self, mode: Option<&str>) -> Result<RunOutcome, RunError> {
+        if self.config.runner.backend == BackendKind::Qemu {
+            if self.config.qemu.snapshot {
+                println!(
+                    "cp {} {}",
+                    command::quote(&self.config.ovmf_vars(mode)?.to_string_lossy()),
+                    command::quote(&LimageConfig::snapshot_ovmf_vars().to_string_lossy())
+                );
+            }
+            if self.config.qemu.tpm {
+                let state_dir = self.config.tpm_state_dir();
+                println!("mkdir -p {}", command::quote(&state_dir.to_string_lossy()));
+                println!("{} &", command::render(&Swtpm::command(&state_dir)));
+            }
+        }
+        println!("{}", command::render(&self.command(mode)?));
+        Ok(RunOutcome::stopped(RunStatus::Passed))
+    }
+
+    // Bochs and VirtualBox have no QMP, so runs are limited to waiting for exit or timing out
+    fn run_external(
+        &self,
+        backend: &dyn VmBackend,
+        mode: Option<&str>,
+    ) -> Result<RunOutcome, RunError> {
+        if self.tui {
+            warn!(
+                "--tui needs QMP and is ignored by the {} backend",
+                backend.name()
+            );
+        }
+
+        let image = &self.config.build.image_path;
+        backend.prepare(image)?;
+        let mut command = backend.command(image, self.is_test, mode)?;
+
+        info!("Booting {:?} with {}", image, backend.name());
+        let mut child = command.spawn().map_err(|e| RunError::StartBackend {
+            backend: backend.name(),
+            source: e,
+        })?;
+
+        if !self.is_test {
+            let status = child.wait().map_err(|e| RunError::WaitQemu { source: e })?;
+            return Ok(self.exited(status));
+        }
 
         let timeout = Duration::from_secs(self.config.test.timeout_secs.into());
         match child
             .wait_timeout(timeout)
             .map_err(|e| RunError::WaitTimeout { source: e })?
         {
+            Some(status) => Ok(self.exited_external(status)),
             None => {
-                // Timeout occurred
-                child.kill().map_err(|e| RunError::KillQemu { source: e })?;
-                child.wait().map_err(|e| RunError::WaitQemu { source: e })?;
-                Ok(2) // Timeout exit code
-            }
-            Some(status) => {
-                let exit_code = status.code().unwrap_or(1);
-                if exit_code == self.config.test.success_exit_code {
-                    Ok(0) // Success
-                } else {
-                    Ok(1) // Failure
+                warn!("Test timed out after {:?}", timeout);
+                backend.stop(&mut child)?;
+                Ok(RunOutcome::stopped(RunStatus::TimedOut))
+            }
+        }
+    }
+
+    fn handle_normal_execution(
+        &self,
+        command: &mut Command,
+        qmp_addr: SocketAddr,
+    ) -> Result<RunOutcome, RunError> {
+        // Piping stdout would line-buffer interactive consoles, so only filter when asked to
+        let log_level = self.config.qemu.log_level;
+        let expect = self.config.test.expect.file.is_some();
+        let serial_log = self.take_serial_log();
+        // Recording is skipped while someone may be typing at a guest prompt
+        let record = serial_log.is_some() && !std::io::stdin().is_terminal();
+        if log_level.is_some()
+            || expect
+            || record
+            || self.message_format.is_json()
+            || self.events.is_some()
+        {
+            command.stdout(Stdio::piped());
+        }
+
+        let interactive = self.config.qemu.interactive && std::io::stdin().is_terminal();
+        if interactive {
+            if !has_stdio_serial(command) {
+                return Err(RunError::InteractiveSerial);
+            }
+            command.stdin(Stdio::piped());
+        }
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| RunError::StartQemu { source: e })?;
+        let _interrupt = interrupt::kill_on_interrupt(&child);
+        if self.wait_debugger {
+            self.wait_for_debugger(&mut child, qmp_addr)?;
+        }
+
+        let serial = Arc::new(SerialState::new(expect, serial_log));
+        let serial_watcher = child.stdout.take().map(|stdout| {
+            let serial = Arc::clone(&serial);
+            let printer = SerialPrinter::new(log_level)
+                .message_format(self.message_format)
+                .events(self.events.clone());
+            thread::spawn(move || watch_serial(stdout, "", &serial, &printer))
+        });
+
+        // Restored when dropped at the end of this run
+        #[cfg(unix)]
+        let _raw_terminal = if interactive {
+            Some(RawTerminal::enable().map_err(|e| RunError::Console { source: e })?)
+        } else {
+            None
+        };
+        let quit = Arc::new(AtomicBool::new(false));
+        if let Some(stdin) = child.stdin.take() {
+            info!("Forwarding keyboard input to the guest serial console, press Ctrl-A x to stop QEMU");
+            let quit = Arc::clone(&quit);
+            thread::spawn(move || forward_stdin(stdin, quit));
+        }
+
+        let mut last_screenshot = Instant::now();
+        let outcome = loop {
+            if let Some(status) = child
+                .wait_timeout(POLL_INTERVAL)
+                .map_err(|e| RunError::WaitQemu { source: e })?
+            {
+                break self.exited(status);
+            }
+
+            // The display is gone once QEMU exits, so keep the latest frame on disk
+            if let Some(screenshot) = &self.config.qemu.screenshot_on_exit {
+                if last_screenshot.elapsed() >= SCREENSHOT_INTERVAL {
+                    self.capture_screenshot(screenshot);
+                    last_screenshot = Instant::now();
+                }
+            }
+
+            if quit.load(Ordering::SeqCst) {
+                info!("Stopping QEMU");
+                stop_qemu(&mut child, qmp_addr)?;
+                break RunOutcome::stopped(RunStatus::Passed);
+            }
+        };
+
+        if let Some(watcher) = serial_watcher {
+            let _ = watcher.join();
+        }
+        let outcome = RunOutcome {
+            tests: serial.take_guest_tests().results,
+            ..outcome
+        };
+        if !self.transcript_matches(&serial)? {
+            return Ok(RunOutcome {
+                status: RunStatus::Failed,
+                ..outcome
+            });
+        }
+        Ok(outcome)
+    }
+
+    fn handle_test_execution(
+        &self,
+        command: &mut Command,
+        qmp_addr: SocketAddr,
+        gdb_port: Option<u16>,
+    ) -> Result<RunOutcome, RunError> {
+        let panic_pattern = self.config.test.panic_pattern.clone();
+        let log_level = self.config.qemu.log_level;
+        let idle_timeout = self
+            .config
+            .test
+            .idle_timeout_secs
+            .map(|secs| Duration::from_secs(secs.into()));
+        let expect = self.config.test.expect.file.is_some();
+        let serial_log = self.take_serial_log();
+        if !panic_pattern.is_empty()
+            || log_level.is_some()
+            || idle_timeout.is_some()
+            || expect
+            || serial_log.is_some()
+            || self.message_format.is_json()
+            || self.events.is_some()
+        {
+            command.stdout(Stdio::piped());
+        }
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| RunError::StartQemu { source: e })?;
+        let _interrupt = interrupt::kill_on_interrupt(&child);
+        // The timeouts only start once the debugger has let the guest run
+        if self.wait_debugger {
+            self.wait_for_debugger(&mut child, qmp_addr)?;
+        }
+
+        let serial = Arc::new(SerialState::new(expect, serial_log));
+        let serial_watcher = child.stdout.take().map(|stdout| {
+            let serial = Arc::clone(&serial);
+            let printer = SerialPrinter::new(log_level)
+                .message_format(self.message_format)
+                .events(self.events.clone());
+            thread::spawn(move || watch_serial(stdout, &panic_pattern, &serial, &printer))
+        });
+
+        let timeout = Duration::from_secs(self.config.test.timeout_secs.into());
+        let start = Instant::now();
+        let mut last_screenshot = start;
+        let outcome = loop {
+            if let Some(status) = child
+                .wait_timeout(POLL_INTERVAL)
+                .map_err(|e| RunError::WaitTimeout { source: e })?
+            {
+                break self.exited(status);
+            }
+
+            if let Some(screenshot) = &self.config.qemu.screenshot_on_exit {
+                if last_screenshot.elapsed() >= SCREENSHOT_INTERVAL {
+                    self.capture_screenshot(screenshot);
+                    last_screenshot = Instant::now();
+                }
+            }
+
+            if serial.panicked.load(Ordering::SeqCst) {
+                warn!("Guest panicked, stopping QEMU");
+                self.capture_final_screenshot();
+                if let Some(port) = gdb_port {
+                    self.capture_gdb_report(port);
+                }
+                // A panic handler that writes the exit code, like limage-test's, decides the outcome
+                match child
+                    .wait_timeout(PANIC_EXIT_GRACE)
+                    .map_err(|e| RunError::WaitTimeout { source: e })?
+                {
+                    Some(status) => break self.exited(status),
+                    None => {
+                        stop_qemu(&mut child, qmp_addr)?;
+                        break RunOutcome::stopped(RunStatus::Failed);
+                    }
+                }
+            }
+
+            // A guest that has gone quiet is most likely deadlocked and won't handle ACPI either
+            if let Some(idle_timeout) = idle_timeout {
+                if serial.idle_for() >= idle_timeout {
+                    warn!(
+                        "No guest serial output for {:?}, stopping QEMU",
+                        idle_timeout
+                    );
+                    self.capture_final_screenshot();
+                    stop_qemu(&mut child, qmp_addr)?;
+                    break RunOutcome::stopped(RunStatus::TimedOut);
+                }
+            }
+
+            if start.elapsed() >= timeout {
+                warn!("Test timed out after {:?}", timeout);
+                self.capture_final_screenshot();
+                shutdown_qemu(&mut child, qmp_addr, self.shutdown_grace_period())?;
+                break RunOutcome::stopped(RunStatus::TimedOut);
+            }
+        };
+
+        if let Some(watcher) = serial_watcher {
+            let _ = watcher.join();
+        }
+        if serial.panicked.load(Ordering::SeqCst) && self.config.test.symbolize_panics {
+            self.symbolize_panic(&serial);
+        }
+        let guest_tests = serial.take_guest_tests();
+        let mut outcome = RunOutcome {
+            tests: guest_tests.results.clone(),
+            ..outcome
+        };
+        // A harness that reported a failure but still exited with the success code
+        if outcome.status == RunStatus::Passed && guest_tests.failed() {
+            warn!("The guest reported failed tests");
+            outcome.status = RunStatus::Failed;
+        }
+        if outcome.status == RunStatus::Passed && !self.transcript_matches(&serial)? {
+            return Ok(RunOutcome {
+                status: RunStatus::Failed,
+                ..outcome
+            });
+        }
+        Ok(outcome)
+    }
+
+    // Compares the captured serial output against `test.expect.file`, printing a diff on mismatch
+    fn transcript_matches(&self, serial: &SerialState) -> Result<bool, RunError> {
+        let Some(expected_file) = &self.config.test.expect.file else {
+            return Ok(true);
+        };
+
+        let expected = expect::read_expected(expected_file)?;
+        let actual = serial
+            .transcript
+            .lock()
+            .map(|transcript| transcript.clone())
+            .unwrap_or_default();
+
+        match Normalizer::new(&self.config.test.expect)?.compare(&expected, &actual) {
+            None => {
+                info!("Guest output matches {:?}", expected_file);
+                Ok(true)
+            }
+            Some(diff) => {
+                warn!("Guest output does not match {:?}", expected_file);
+                eprint!("{}", diff);
+                Ok(false)
+            }
+        }
+    }
+
+    fn handle_tui_execution(
+        &self,
+        command: &mut Command,
+        qmp_addr: SocketAddr,
+    ) -> Result<RunOutcome, RunError> {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| RunError::StartQemu { source: e })?;
+        let _interrupt = interrupt::kill_on_interrupt(&child);
+
+        let options = TuiOptions {
+            title: self.config.build.image_path.display().to_string(),
+            timeout: self
+                .is_test
+                .then(|| Duration::from_secs(self.config.test.timeout_secs.into())),
+            screenshot: self
+                .config
+                .qemu
+                .screenshot_on_exit
+                .clone()
+                .unwrap_or_else(|| self.config.work_dir().join("screenshot.ppm")),
+            log_level: self.config.qemu.log_level,
+        };
+
+        match tui::run(&mut child, qmp_addr, options)? {
+            TuiExit::Exited(exit_code) => {
+                self.emit(&Message::QemuExited { exit_code });
+                Ok(self.completed(exit_code))
+            }
+            TuiExit::TimedOut => {
+                warn!("Test timed out after {}s", self.config.test.timeout_secs);
+                shutdown_qemu(&mut child, qmp_addr, self.shutdown_grace_period())?;
+                Ok(RunOutcome::stopped(RunStatus::TimedOut))
+            }
+            TuiExit::Killed => Ok(RunOutcome::stopped(RunStatus::Failed)),
+        }
+    }
+
+    fn exited(&self, status: ExitStatus) -> RunOutcome {
+        if let Some(exit_code) = status.code() {
+            self.emit(&Message::QemuExited { exit_code });
+        }
+        exit_outcome(&self.config, self.is_test, status)
+    }
+
+    // Bochs and VirtualBox have no exit device for the guest to report through, so a clean exit
+    // passes a test unless [test.exit_codes] says what 0 means
+    fn exited_external(&self, status: ExitStatus) -> RunOutcome {
+        let outcome = self.exited(status);
+        let mapped = self
+            .config
+            .test
+            .exit_codes
+            .keys()
+            .any(|code| code.parse() == Ok(0));
+        if outcome.guest_exit_code == Some(0) && !mapped {
+            return RunOutcome {
+                status: RunStatus::Passed,
+                ..outcome
+            };
+        }
+        outcome
+    }
+
+    fn take_serial_log(&self) -> Option<File> {
+        self.serial_log
+            .lock()
+            .ok()
+            .and_then(|mut serial_log| serial_log.take())
+    }
+
+    fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.config.test.shutdown_grace_secs.into())
+    }
+
+    fn completed(&self, exit_code: i32) -> RunOutcome {
+        completed(&self.config, self.is_test, exit_code)
+    }
+
+    // gdbstub attach has no QMP event, so watch the run state leave `prelaunch` instead
+    fn wait_for_debugger(&self, child: &mut Child, qmp_addr: SocketAddr) -> Result<(), RunError> {
+        let symbols = self
+            .kernel
+            .as_ref()
+            .map(|kernel| format!(" {}", kernel.display()))
+            .unwrap_or_default();
+        info!(
+            "QEMU is paused before the first instruction, attach with: gdb -ex 'target remote localhost:{}'{}",
+            self.config.qemu.gdb_port, symbols
+        );
+
+        let mut qmp = QmpHandle::connect(qmp_addr, QMP_CONNECT_TIMEOUT)?;
+        let mut attached = false;
+        loop {
+            if child
+                .try_wait()
+                .map_err(|e| RunError::WaitQemu { source: e })?
+                .is_some()
+            {
+                return Ok(());
+            }
+
+            match qmp.query_status()?.as_str() {
+                "prelaunch" => {}
+                "running" => {
+                    info!("Debugger resumed the guest");
+                    return Ok(());
+                }
+                status if !attached => {
+                    attached = true;
+                    info!(
+                        "Debugger attached (VM {}), set breakpoints and `continue` to start the guest",
+                        status
+                    );
+                }
+                _ => {}
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    // Post-mortem registers and backtrace of a panicked guest, saved next to the other run artifacts
+    fn capture_gdb_report(&self, port: u16) {
+        let report = match gdb::capture_report(
+            &self.config.test.gdb_binary,
+            port,
+            self.kernel.as_deref(),
+            &self.config.test.gdb_commands,
+        ) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Failed to capture gdb report: {}", e);
+                return;
+            }
+        };
+
+        let path = self.config.work_dir().join("panic-gdb.txt");
+        eprintln!("{}", report);
+        match std::fs::write(&path, &report) {
+            Ok(()) => info!("Saved gdb post-mortem report to {:?}", path),
+            Err(e) => warn!("Failed to save gdb report to {:?}: {}", path, e),
+        }
+    }
+
+    // Resolves the addresses in the panic output against the kernel, like a post-mortem addr2line
+    fn symbolize_panic(&self, serial: &SerialState) {
+        let kernel = self
+            .kernel
+            .clone()
+            .unwrap_or_else(|| self.config.staged_kernel());
+        let symbolizer = match Symbolizer::new(&kernel) {
+            Ok(symbolizer) => symbolizer,
+            Err(e) => {
+                warn!("Not symbolizing the panic: {}", e);
+                return;
+            }
+        };
+        let panic_output = serial
+            .panic_output
+            .lock()
+            .map(|panic_output| panic_output.clone())
+            .unwrap_or_default();
+        let backtrace = symbolizer.backtrace(&panic_output);
+        if backtrace.is_empty() {
+            debug!("Panic output contains no kernel addresses");
+            return;
+        }
+
+        let report = backtrace.join("\n");
+        eprintln!("Symbolized backtrace:\n{}", report);
+        let path = self.config.work_dir().join("panic-backtrace.txt");
+        match std::fs::write(&path, report + "\n") {
+            Ok(()) => info!("Saved symbolized backtrace to {:?}", path),
+            Err(e) => warn!("Failed to save symbolized backtrace to {:?}: {}", path, e),
+        }
+    }
+
+    fn capture_screenshot(&self, path: &Path) {
+        if let Err(e) = self.screenshot(path) {
+            debug!("Failed to capture screenshot: {}", e);
+        }
+    }
+
+    fn capture_final_screenshot(&self) {
+        if let Some(screenshot) = &self.config.qemu.screenshot_on_exit {
+            self.capture_screenshot(screenshot);
+        }
+    }
+}
+
+// What `prepare_qemu` sets up; the snapshot vars and swtpm must outlive QEMU
+struct PreparedQemu {
+    command: Command,
+    qmp_addr: SocketAddr,
+    gdb_port: Option<u16>,
+    snapshot_vars: Option<PathBuf>,
+    swtpm: Option<Swtpm>,
+}
+
+// A VM started by `Runner::spawn`. Dropping the handle kills the VM if it is still running
+pub struct RunHandle {
+    child: Child,
+    stdout: Option<ChildStdout>,
+    qmp_addr: SocketAddr,
+    config: LimageConfig,
+    is_test: bool,
+    start: Instant,
+    snapshot_vars: Option<PathBuf>,
+    _swtpm: Option<Swtpm>,
+    _interrupt: InterruptGuard,
+    outcome: Option<RunOutcome>,
+}
+
+impl RunHandle {
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn qmp_addr(&self) -> SocketAddr {
+        self.qmp_addr
+    }
+
+    // A fresh QMP connection; QEMU accepts one client at a time
+    pub fn qmp(&self) -> Result<QmpHandle, RunError> {
+        Ok(QmpHandle::connect(self.qmp_addr, QMP_CONNECT_TIMEOUT)?)
+    }
+
+    // QEMU's stdout, usually the guest's serial console. Can be taken once, and must then be
+    // read: QEMU blocks once the pipe is full
+    pub fn stdout_stream(&mut self) -> Option<ChildStdout> {
+        self.stdout.take()
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<RunOutcome>, RunError> {
+        if self.outcome.is_none() {
+            if let Some(status) = self
+                .child
+                .try_wait()
+                .map_err(|e| RunError::WaitQemu { source: e })?
+            {
+                self.finish(exit_outcome(&self.config, self.is_test, status));
+            }
+        }
+        Ok(self.outcome.clone())
+    }
+
+    // None if the VM is still running after `timeout`
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<RunOutcome>, RunError> {
+        if self.outcome.is_none() {
+            if let Some(status) = self
+                .child
+                .wait_timeout(timeout)
+                .map_err(|e| RunError::WaitTimeout { source: e })?
+            {
+                self.finish(exit_outcome(&self.config, self.is_test, status));
+            }
+        }
+        Ok(self.outcome.clone())
+    }
+
+    pub fn wait(&mut self) -> Result<RunOutcome, RunError> {
+        if self.outcome.is_none() {
+            let status = self
+                .child
+                .wait()
+                .map_err(|e| RunError::WaitQemu { source: e })?;
+            self.finish(exit_outcome(&self.config, self.is_test, status));
+        }
+        Ok(self.outcome.clone().expect("the outcome was just set"))
+    }
+
+    // Quits QEMU over QMP, killing it if that fails. A test stopped this way has failed
+    pub fn kill(&mut self) -> Result<RunOutcome, RunError> {
+        if let Some(outcome) = self.try_wait()? {
+            return Ok(outcome);
+        }
+        stop_qemu(&mut self.child, self.qmp_addr)?;
+        self.finish(RunOutcome::stopped(if self.is_test {
+            RunStatus::Failed
+        } else {
+            RunStatus::Passed
+        }));
+        Ok(self.outcome.clone().expect("the outcome was just set"))
+    }
+
+    fn finish(&mut self, mut outcome: RunOutcome) {
+        outcome.duration = self.start.elapsed();
+        self.outcome = Some(outcome);
+        if let Some(snapshot_vars) = self.snapshot_vars.take() {
+            let _ = std::fs::remove_file(snapshot_vars);
+        }
+    }
+}
+
+impl Drop for RunHandle {
+    fn drop(&mut self) {
+        if self.outcome.is_none() {
+            let _ = kill_qemu(&mut self.child);
+        }
+        if let Some(snapshot_vars) = self.snapshot_vars.take() {
+            let _ = std::fs::remove_file(snapshot_vars);
+        }
+    }
+}
+
+// Plain runs pass on a clean QEMU exit, test runs map the guest's code through [test]
+fn completed(config: &LimageConfig, is_test: bool, exit_code: i32) -> RunOutcome {
+    let status = if !is_test {
+        if exit_code == 0 {
+            RunStatus::Passed
+        } else {
+            RunStatus::Failed
+        }
+    } else {
+        match config.test_outcome(exit_code) {
+            TestOutcome::Pass => RunStatus::Passed,
+            TestOutcome::Skip => {
+                info!(
+                    "Guest reported the test as skipped (exit code {})",
+                    exit_code
+                );
+                RunStatus::Passed
+            }
+            TestOutcome::Fail => RunStatus::Failed,
+        }
+    };
+    RunOutcome {
+        status,
+        guest_exit_code: Some(exit_code),
+        duration: Duration::ZERO,
+        log_path: None,
+        tests: Vec::new(),
+        retried: Vec::new(),
+    }
+}
+
+fn exit_outcome(config: &LimageConfig, is_test: bool, status: ExitStatus) -> RunOutcome {
+    let Some(exit_code) = status.code() else {
+        interrupt::park_if_interrupted();
+        warn!("QEMU was terminated ({})", status);
+        return RunOutcome::stopped(RunStatus::QemuError);
+    };
+    debug!("QEMU exited with code {}", exit_code);
+    completed(config, is_test, exit_code)
+}
+
+fn hot_addr_file(config: &LimageConfig) -> PathBuf {
+    config.work_dir().join("hot-qmp")
+}
+
+// Requests an ACPI power-off so the guest can flush its state, falling back to `stop_qemu`
+fn shutdown_qemu(child: &mut Child, qmp_addr: SocketAddr, grace: Duration) -> Result<(), RunError> {
+    let powerdown = QmpHandle::connect(qmp_addr, QMP_CONNECT_TIMEOUT)
+        .and_then(|mut qmp| qmp.system_powerdown());
+    match powerdown {
+        Ok(()) => {
+            if child
+                .wait_timeout(grace)
+                .map_err(|e| RunError::WaitQemu { source: e })?
+                .is_some()
+            {
+                debug!("Guest powered off after ACPI shutdown request");
+                return Ok(());
+            }
+            warn!("Guest did not power off within {:?}", grace);
+        }
+        Err(e) => warn!("Failed to request ACPI shutdown: {}", e),
+    }
+
+    stop_qemu(child, qmp_addr)
+}
+
+// Asks QEMU to quit over QMP so buffered output is flushed, killing it if that fails
+fn stop_qemu(child: &mut Child, qmp_addr: SocketAddr) -> Result<(), RunError> {
+    let quit = QmpHandle::connect(qmp_addr, QMP_CONNECT_TIMEOUT).and_then(|mut qmp| qmp.quit());
+    match quit {
+        Ok(()) => {
+            if child
+                .wait_timeout(QUIT_GRACE_PERIOD)
+                .map_err(|e| RunError::WaitQemu { source: e })?
+                .is_some()
+            {
+                debug!("QEMU exited after QMP quit");
+                return Ok(());
+            }
+            warn!(
+                "QEMU did not exit within {:?} of QMP quit",
+                QUIT_GRACE_PERIOD
+            );
+        }
+        // QEMU may close the socket before replying to `quit`
+        Err(QmpError::Closed) => debug!("QMP connection closed during quit"),
+        Err(e) => warn!("Failed to quit QEMU over QMP: {}", e),
+    }
+
+    kill_qemu(child)
+}
+
+// Whether QEMU puts a serial port on its stdio, which is where keystrokes are forwarded to
+fn has_stdio_serial(command: &Command) -> bool {
+    let args: Vec<_> = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy())
+        .collect();
+    args.iter().any(|arg| arg == "-nographic")
+        || args.windows(2).any(|pair| {
+            let value = pair[1].as_ref();
+            match pair[0].as_ref() {
+                "-serial" => value == "stdio" || value == "mon:stdio",
+                "-chardev" => value.starts_with("stdio,"),
+                _ => false,
+            }
+        })
+}
+
+fn kill_qemu(child: &mut Child) -> Result<(), RunError> {
+    if child
+        .try_wait()
+        .map_err(|e| RunError::WaitQemu { source: e })?
+        .is_some()
+    {
+        return Ok(());
+    }
+    child.kill().map_err(|e| RunError::KillQemu { source: e })?;
+    child.wait().map_err(|e| RunError::WaitQemu { source: e })?;
+    Ok(())
+}
+
+// Shared between a test run and the thread echoing its serial output
+struct SerialState {
+    panicked: AtomicBool,
+    last_output: Mutex<Instant>,
+    capture: bool,
+    transcript: Mutex<String>,
+    // The line matching the panic pattern and everything after it
+    panic_output: Mutex<String>,
+    // The run's serial log in the history, written as lines arrive
+    log: Mutex<Option<File>>,
+    guest_tests: Mutex<GuestTests>,
+}
+
+impl SerialState {
+    fn new(capture: bool, log: Option<File>) -> Self {
+        Self {
+            panicked: AtomicBool::new(false),
+            last_output: Mutex::new(Instant::now()),
+            capture,
+            transcript: Mutex::new(String::new()),
+            panic_output: Mutex::new(String::new()),
+            log: Mutex::new(log),
+            guest_tests: Mutex::new(GuestTests::default()),
+        }
+    }
+
+    fn take_guest_tests(&self) -> GuestTests {
+        self.guest_tests
+            .lock()
+            .map(|mut guest_tests| std::mem::take(&mut *guest_tests))
+            .unwrap_or_default()
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_output
+            .lock()
+            .map(|last_output| last_output.elapsed())
+            .unwrap_or_default()
+    }
+}
+
+// Echoes the guest serial output, collects the test events of a limage-test guest and flags the
+// first line matching `pattern`
+fn watch_serial(stdout: impl Read, pattern: &str, state: &SerialState, printer: &SerialPrinter) {
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    let mut host_stdout = std::io::stdout();
+
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+
+        match GuestEvent::parse(&String::from_utf8_lossy(&line)) {
+            Some(event) => {
+                if let Ok(mut guest_tests) = state.guest_tests.lock() {
+                    let summary = matches!(event, GuestEvent::Summary { .. });
+                    if let Some(test) = guest_tests.record(event) {
+                        let _ = printer.print_guest_test(&mut host_stdout, test);
+                    } else if let Some(summary) = guest_tests.summary.filter(|_| summary) {
+                        let _ = printer.print_guest_summary(&mut host_stdout, &summary);
+                    }
                 }
             }
+            None => {
+                let _ = printer.print(&mut host_stdout, &line);
+            }
+        }
+        if let Ok(mut last_output) = state.last_output.lock() {
+            *last_output = Instant::now();
+        }
+        if let Ok(mut log) = state.log.lock() {
+            if let Some(file) = log.as_mut() {
+                let _ = file.write_all(&line);
+            }
+        }
+        if state.capture {
+            if let Ok(mut transcript) = state.transcript.lock() {
+                transcript.push_str(&String::from_utf8_lossy(&line));
+            }
+        }
+
+        if !pattern.is_empty() && String::from_utf8_lossy(&line).contains(pattern) {
+            debug!("Panic pattern {:?} matched guest output", pattern);
+            state.panicked.store(true, Ordering::SeqCst);
+        }
+        if state.panicked.load(Ordering::SeqCst) {
+            if let Ok(mut panic_output) = state.panic_output.lock() {
+                panic_output.push_str(&String::from_utf8_lossy(&line));
+            }
+        }
+        line.clear();
+    }
+
+    // A guest that crashed or was stopped mid-run never sends its summary
+    if let Ok(mut guest_tests) = state.guest_tests.lock() {
+        if let Some(summary) = guest_tests.finish() {
+            let _ = printer.print_guest_summary(&mut host_stdout, &summary);
         }
     }
 }
@@ -68,9 +1403,21 @@ pub enum RunError {
     #[error("Configuration error: {source}")]
     Config { source: ConfigError },
 
-    #[error("Failed to start QEMU: {source}\nMake sure QEMU is installed and available in PATH")]
+    #[error("Failed to start QEMU: {source}")]
     StartQemu { source: std::io::Error },
 
+    #[error("Runner::spawn supports the QEMU backend only, not {backend}")]
+    SpawnUnsupported { backend: &'static str },
+
+    #[error("Failed to start {backend}: {source}")]
+    StartBackend {
+        backend: &'static str,
+        source: std::io::Error,
+    },
+
+    #[error("VM backend error: {source}")]
+    Backend { source: BackendError },
+
     #[error("Wait timeout error: {source}")]
     WaitTimeout { source: std::io::Error },
 
@@ -79,6 +1426,72 @@ pub enum RunError {
 
     #[error("Failed to wait for QEMU process: {source}")]
     WaitQemu { source: std::io::Error },
+
+    #[error("QMP error: {source}")]
+    Qmp { source: QmpError },
+
+    #[error("Failed to save screenshot: {source}")]
+    Screenshot { source: std::io::Error },
+
+    #[error("QEMU is not running")]
+    NotRunning,
+
+    #[error("Failed to copy OVMF vars for snapshot run: {source}")]
+    SnapshotVars { source: std::io::Error },
+
+    #[error("Golden output error: {source}")]
+    Expect { source: ExpectError },
+
+    #[error("Failed to set up the interactive serial console: {source}")]
+    Console { source: std::io::Error },
+
+    #[error("GDB error: {source}")]
+    Gdb { source: GdbError },
+
+    #[error("TUI error: {source}")]
+    Tui { source: TuiError },
+
+    #[error("TPM error: {source}")]
+    Tpm { source: TpmError },
+
+    #[error("Failed to create limage work directory: {source}")]
+    WorkDir { source: std::io::Error },
+
+    #[error("Failed to prepare serial log or monitor directory: {source}")]
+    SerialLog { source: std::io::Error },
+
+    #[error(
+        "Interactive runs forward keystrokes to a serial port on stdio, but this run has none"
+    )]
+    InteractiveSerial,
+}
+
+impl RunError {
+    // The stable code `limage::Error` reports; a new variant takes the next free code
+    pub fn code(&self) -> &'static str {
+        match self {
+            RunError::Config { source } => source.code(),
+            RunError::StartQemu { .. } => "E050",
+            RunError::SpawnUnsupported { .. } => "E051",
+            RunError::StartBackend { .. } => "E052",
+            RunError::Backend { .. } => "E053",
+            RunError::WaitTimeout { .. } => "E054",
+            RunError::KillQemu { .. } => "E055",
+            RunError::WaitQemu { .. } => "E056",
+            RunError::Qmp { .. } => "E057",
+            RunError::Screenshot { .. } => "E058",
+            RunError::NotRunning => "E059",
+            RunError::SnapshotVars { .. } => "E060",
+            RunError::Expect { .. } => "E061",
+            RunError::Console { .. } => "E062",
+            RunError::Gdb { .. } => "E063",
+            RunError::Tui { .. } => "E064",
+            RunError::Tpm { .. } => "E065",
+            RunError::WorkDir { .. } => "E066",
+            RunError::SerialLog { .. } => "E067",
+            RunError::InteractiveSerial => "E068",
+        }
+    }
 }
 
 impl From<ConfigError> for RunError {
@@ -86,3 +1499,84 @@ impl From<ConfigError> for RunError {
         RunError::Config { source: error }
     }
 }
+
+impl From<BackendError> for RunError {
+    fn from(error: BackendError) -> Self {
+        RunError::Backend { source: error }
+    }
+}
+
+impl From<ExpectError> for RunError {
+    fn from(error: ExpectError) -> Self {
+        RunError::Expect { source: error }
+    }
+}
+
+impl From<GdbError> for RunError {
+    fn from(error: GdbError) -> Self {
+        RunError::Gdb { source: error }
+    }
+}
+
+impl From<TuiError> for RunError {
+    fn from(error: TuiError) -> Self {
+        RunError::Tui { source: error }
+    }
+}
+
+impl From<TpmError> for RunError {
+    fn from(error: TpmError) -> Self {
+        RunError::Tpm { source: error }
+    }
+}
+
+impl From<QmpError> for RunError {
+    fn from(error: QmpError) -> Self {
+        RunError::Qmp { source: error }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(status: RunStatus, guest_exit_code: Option<i32>) -> RunOutcome {
+        RunOutcome {
+            guest_exit_code,
+            ..RunOutcome::stopped(status)
+        }
+    }
+
+    #[test]
+    fn raw_exit_code_passes_the_guest_code_through() {
+        let config = LimageConfig::builder().build().unwrap();
+        let success = config.test.success_exit_code;
+        assert_eq!(
+            outcome(RunStatus::Passed, Some(success)).raw_exit_code(&config),
+            success
+        );
+        assert_eq!(
+            outcome(RunStatus::Failed, Some(35)).raw_exit_code(&config),
+            35
+        );
+        assert_eq!(
+            outcome(RunStatus::TimedOut, None).raw_exit_code(&config),
+            TIMED_OUT_EXIT_CODE
+        );
+    }
+
+    #[test]
+    fn raw_exit_code_of_a_host_failure_is_not_the_success_code() {
+        let config = LimageConfig::builder().build().unwrap();
+        let success = config.test.success_exit_code;
+        assert_eq!(
+            outcome(RunStatus::Failed, Some(success)).raw_exit_code(&config),
+            1
+        );
+        assert_eq!(
+            outcome(RunStatus::Failed, Some(0)).raw_exit_code(&config),
+            1
+        );
+        assert_eq!(outcome(RunStatus::Failed, None).raw_exit_code(&config), 1);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{command, target_spec};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+// Replaced by the project name in every file of a template, embedded or cloned
+const NAME_PLACEHOLDER: &str = "{{name}}";
+
+// What every embedded template shares: an x86_64 kernel built with build-std for its own target
+// JSON, linked by its own script and booted by Limine
+const COMMON_FILES: &[(&str, &str)] = &[
+    ("Cargo.toml", include_str!("../templates/Cargo.toml.in")),
+    (
+        ".cargo/config.toml",
+        include_str!("../templates/cargo-config.toml"),
+    ),
+    (
+        "rust-toolchain.toml",
+        include_str!("../templates/rust-toolchain.toml"),
+    ),
+    ("x86_64-kernel.json", target_spec::X86_64_KERNEL),
+    ("linker.ld", include_str!("../templates/linker.ld")),
+    ("limine.conf", include_str!("../templates/limine.conf")),
+    (".gitignore", include_str!("../templates/gitignore")),
+];
+
+// A starter project built into limage
+pub struct Template {
+    pub name: &'static str,
+    pub description: &'static str,
+    files: &'static [(&'static str, &'static str)],
+}
+
+pub const TEMPLATES: &[Template] = &[
+    Template {
+        name: "minimal",
+        description: "Serial output and a #[test_case] run by `limage test`",
+        files: &[
+            ("src/main.rs", include_str!("../templates/minimal/main.rs")),
+            (
+                "limage_config.toml",
+                include_str!("../templates/minimal/limage_config.toml"),
+            ),
+        ],
+    },
+    Template {
+        name: "framebuffer",
+        description: "Draws on the framebuffer Limine sets up",
+        files: &[
+            (
+                "src/main.rs",
+                include_str!("../templates/framebuffer/main.rs"),
+            ),
+            (
+                "limage_config.toml",
+                include_str!("../templates/framebuffer/limage_config.toml"),
+            ),
+        ],
+    },
+    Template {
+        name: "smp",
+        description: "Starts every CPU through Limine's MP request, booted with 4",
+        files: &[
+            ("src/main.rs", include_str!("../templates/smp/main.rs")),
+            (
+                "limage_config.toml",
+                include_str!("../templates/smp/limage_config.toml"),
+            ),
+        ],
+    },
+];
+
+impl Template {
+    pub fn find(name: &str) -> Option<&'static Template> {
+        TEMPLATES.iter().find(|template| template.name == name)
+    }
+}
+
+// Where a new project's files come from
+pub enum Source {
+    Embedded(&'static Template),
+    // A repository cloned as is, apart from its .git directory and the name placeholder
+    Git(String),
+}
+
+impl Source {
+    // An embedded template by name, or anything that looks like a git URL
+    pub fn parse(template: &str) -> Result<Self, ScaffoldError> {
+        if is_git_url(template) {
+            return Ok(Source::Git(template.to_string()));
+        }
+        Template::find(template)
+            .map(Source::Embedded)
+            .ok_or_else(|| ScaffoldError::UnknownTemplate {
+                name: template.to_string(),
+                known: TEMPLATES
+                    .iter()
+                    .map(|template| template.name)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            })
+    }
+}
+
+pub struct Scaffold {
+    source: Source,
+    dir: PathBuf,
+    name: Option<String>,
+    force: bool,
+    dry_run: bool,
+}
+
+impl Scaffold {
+    pub fn new(source: Source, dir: PathBuf) -> Self {
+        Self {
+            source,
+            dir,
+            name: None,
+            force: false,
+            dry_run: false,
+        }
+    }
+
+    // The crate name; by default the name of the directory
+    pub fn name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    // Replace files that already exist instead of refusing to start
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    // Lists the files instead of writing them; a git template is not cloned either
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // Writes the template into the directory and returns the files it wrote, relative to it.
+    // Nothing is written if any of them already exists without `force`
+    #[instrument(skip(self), fields(dir = ?self.dir), err)]
+    pub fn create(&self) -> Result<Vec<PathBuf>, ScaffoldError> {
+        let name = self.crate_name()?;
+        let files = match &self.source {
+            Source::Embedded(template) => COMMON_FILES
+                .iter()
+                .chain(template.files)
+                .map(|(path, contents)| (PathBuf::from(path), contents.as_bytes().to_vec()))
+                .collect(),
+            Source::Git(url) if self.dry_run => {
+                println!(
+                    "Would run {}",
+                    command::render(&clone_command(url, Path::new("<tmp>")))
+                );
+                return Ok(Vec::new());
+            }
+            Source::Git(url) => clone(url)?,
+        };
+
+        if !self.force {
+            if let Some((path, _)) = files.iter().find(|(path, _)| self.dir.join(path).exists()) {
+                return Err(ScaffoldError::Exists {
+                    path: self.dir.join(path),
+                });
+            }
+        }
+
+        let mut written = Vec::new();
+        for (path, contents) in files {
+            let target = self.dir.join(&path);
+            if self.dry_run {
+                println!("Would write {}", target.display());
+                written.push(path);
+                continue;
+            }
+            let write_error = |e| ScaffoldError::Write {
+                path: target.clone(),
+                source: e,
+            };
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(write_error)?;
+            }
+            fs::write(&target, with_name(contents, &name)).map_err(write_error)?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    fn crate_name(&self) -> Result<String, ScaffoldError> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => {
+                // `.` has no file name of its own
+                let dir = fs::canonicalize(&self.dir).unwrap_or_else(|_| self.dir.clone());
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            }
+        };
+        let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(ScaffoldError::InvalidName { name });
+        }
+        Ok(name)
+    }
+}
+
+// https://, ssh://, git@host:path and anything ending in .git
+fn is_git_url(template: &str) -> bool {
+    template.contains("://") || template.starts_with("git@") || template.ends_with(".git")
+}
+
+fn clone_command(url: &str, dir: &Path) -> Command {
+    let mut clone = Command::new("git");
+    clone
+        .args(["clone", "--depth=1", "--quiet", url])
+        .arg(dir)
+        .stdin(Stdio::null());
+    clone
+}
+
+// Every file of the repository's default branch, its .git directory left out
+fn clone(url: &str) -> Result<Vec<(PathBuf, Vec<u8>)>, ScaffoldError> {
+    let checkout = std::env::temp_dir().join(format!("limage-template-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&checkout);
+    info!("Cloning template {}", url);
+    let output = clone_command(url, &checkout)
+        .output()
+        .map_err(|e| ScaffoldError::Clone {
+            url: url.to_string(),
+            detail: e.to_string(),
+        })?;
+    if !output.status.success() {
+        let _ = fs::remove_dir_all(&checkout);
+        return Err(ScaffoldError::Clone {
+            url: url.to_string(),
+            detail: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    let mut files = Vec::new();
+    let read = read_tree(&checkout, Path::new(""), &mut files);
+    if let Err(e) = fs::remove_dir_all(&checkout) {
+        debug!(
+            "Failed to remove the template checkout {:?}: {}",
+            checkout, e
+        );
+    }
+    read.map(|()| files)
+}
+
+fn read_tree(
+    root: &Path,
+    relative: &Path,
+    files: &mut Vec<(PathBuf, Vec<u8>)>,
+) -> Result<(), ScaffoldError> {
+    let dir = root.join(relative);
+    let read_error = |path: &Path, e| ScaffoldError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    };
+    let mut entries = fs::read_dir(&dir)
+        .map_err(|e| read_error(&dir, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| read_error(&dir, e))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if relative.as_os_str().is_empty() && entry.file_name() == ".git" {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        let file_type = entry
+            .file_type()
+            .map_err(|e| read_error(&entry.path(), e))?;
+        if file_type.is_dir() {
+            read_tree(root, &path, files)?;
+        } else {
+            let contents = fs::read(entry.path()).map_err(|e| read_error(&entry.path(), e))?;
+            files.push((path, contents));
+        }
+    }
+    Ok(())
+}
+
+// Binary files are written untouched
+fn with_name(contents: Vec<u8>, name: &str) -> Vec<u8> {
+    match String::from_utf8(contents) {
+        Ok(text) => text.replace(NAME_PLACEHOLDER, name).into_bytes(),
+        Err(e) => e.into_bytes(),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ScaffoldError {
+    #[error("Unknown template '{name}', expected one of {known} or a git URL")]
+    UnknownTemplate { name: String, known: String },
+
+    #[error("'{name}' is not a valid crate name; pass one with --name")]
+    InvalidName { name: String },
+
+    #[error("{path:?} already exists; pass --force to overwrite it")]
+    Exists { path: PathBuf },
+
+    #[error("Failed to clone template {url}: {detail}")]
+    Clone { url: String, detail: String },
+
+    #[error("Failed to read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to write {path:?}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
@@ -0,0 +1,225 @@
+use crate::{
+    command,
+    config::{LimageConfig, SerialConfig, TestOutcome},
+    message::MessageFormat,
+    runner::{RunError, TIMED_OUT_EXIT_CODE},
+    serial::SerialPrinter,
+};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, Command, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Vm {
+    name: String,
+    child: Child,
+    serial: Option<JoinHandle<()>>,
+    exit_code: Option<i32>,
+}
+
+impl Drop for Vm {
+    // A scenario that fails to start a later VM must not leave the earlier ones on the network
+    fn drop(&mut self) {
+        if matches!(self.child.try_wait(), Ok(None)) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+// Boots every VM of a scenario on one shared network and combines their results; a dry run only
+// prints the commands
+pub fn run(
+    config: &LimageConfig,
+    name: &str,
+    is_test: bool,
+    dry_run: bool,
+    message_format: MessageFormat,
+) -> Result<i32, RunError> {
+    let scenario = config.get_scenario(name)?;
+    let dir = config.work_dir().join("scenario").join(name);
+    if dry_run {
+        println!("mkdir -p {}", command::quote(&dir.to_string_lossy()));
+    } else {
+        std::fs::create_dir_all(&dir).map_err(|e| RunError::WorkDir { source: e })?;
+    }
+
+    let mut vms = Vec::new();
+    for (index, vm) in scenario.vms.iter().enumerate() {
+        let mut vm_config = match &vm.mode {
+            Some(mode) => config.with_mode(mode)?,
+            None => config.clone(),
+        };
+        // Per-process resources would clash between instances, and serial is captured per VM
+        vm_config.qemu.monitor = None;
+        vm_config.qemu.tpm = false;
+        vm_config.qemu.snapshot = false;
+        vm_config.qemu.serials = vec![SerialConfig {
+            target: "stdio".to_string(),
+        }];
+        if vm_config.qemu.headless {
+            vm_config.qemu.headless = false;
+            vm_config.qemu.display = Some("none".to_string());
+        }
+
+        let mut mode = match &vm.mode {
+            Some(mode) => config.get_mode(mode)?,
+            None => Default::default(),
+        };
+
+        // Each VM needs writable firmware vars of its own
+        let source_vars = config.ovmf_vars(vm.mode.as_deref())?;
+        if source_vars.exists() {
+            let vars = dir.join(format!("{}-ovmf-vars.fd", vm.name));
+            if dry_run {
+                println!(
+                    "cp {} {}",
+                    command::quote(&source_vars.to_string_lossy()),
+                    command::quote(&vars.to_string_lossy())
+                );
+            } else {
+                std::fs::copy(&source_vars, &vars)
+                    .map_err(|e| RunError::SnapshotVars { source: e })?;
+            }
+            mode.ovmf_vars = Some(vars);
+        }
+
+        mode.extra_args
+            .extend(LimageConfig::scenario_network_args(&scenario, index, vm));
+        if config.qemu.snapshot {
+            mode.extra_args.push("-snapshot".to_string());
+        }
+        mode.extra_args.extend(vm.extra_args.iter().cloned());
+
+        let mode_name = format!("scenario-{}", vm.name);
+        vm_config.qemu.modes.insert(mode_name.clone(), mode.clone());
+        let cmd_args =
+            vm_config.get_qemu_command(&config.build.image_path, is_test, Some(&mode_name))?;
+        debug!("Starting VM '{}': {}", vm.name, cmd_args.join(" "));
+
+        let mut command = Command::new(&cmd_args[0]);
+        command.args(&cmd_args[1..]).envs(mode.env);
+        if dry_run {
+            println!("{} &", command::render(&command));
+            continue;
+        }
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| RunError::StartQemu { source: e })?;
+
+        let log_path = dir.join(format!("{}.log", vm.name));
+        let log = File::create(&log_path).map_err(|e| RunError::SerialLog { source: e })?;
+        info!("VM '{}' serial output is logged to {:?}", vm.name, log_path);
+
+        let serial = child.stdout.take().map(|stdout| {
+            let printer = SerialPrinter::new(config.qemu.log_level)
+                .prefix(format!("[{}] ", vm.name))
+                .message_format(message_format);
+            thread::spawn(move || tee_serial(stdout, log, &printer))
+        });
+
+        vms.push(Vm {
+            name: vm.name.clone(),
+            child,
+            serial,
+            exit_code: None,
+        });
+    }
+
+    if dry_run {
+        return Ok(0);
+    }
+
+    let timeout = is_test.then(|| Duration::from_secs(config.test.timeout_secs.into()));
+    let start = Instant::now();
+    let mut timed_out = false;
+    while vms.iter().any(|vm| vm.exit_code.is_none()) {
+        for vm in vms.iter_mut().filter(|vm| vm.exit_code.is_none()) {
+            if let Some(status) = vm
+                .child
+                .try_wait()
+                .map_err(|e| RunError::WaitQemu { source: e })?
+            {
+                let exit_code = status.code().unwrap_or(1);
+                debug!("VM '{}' exited with code {}", vm.name, exit_code);
+                vm.exit_code = Some(exit_code);
+            }
+        }
+
+        // One failing node decides the scenario, so don't wait for its peers to time out
+        let failed = is_test
+            && vms.iter().any(|vm| {
+                vm.exit_code
+                    .is_some_and(|code| config.test_outcome(code) == TestOutcome::Fail)
+            });
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            warn!("Scenario '{}' timed out after {:?}", name, start.elapsed());
+            timed_out = true;
+        }
+        if timed_out || failed {
+            for vm in vms.iter_mut().filter(|vm| vm.exit_code.is_none()) {
+                vm.child
+                    .kill()
+                    .map_err(|e| RunError::KillQemu { source: e })?;
+                vm.child
+                    .wait()
+                    .map_err(|e| RunError::WaitQemu { source: e })?;
+            }
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let mut result = 0;
+    for vm in &mut vms {
+        if let Some(serial) = vm.serial.take() {
+            let _ = serial.join();
+        }
+
+        let (exit_code, label) = match vm.exit_code {
+            None if timed_out => (TIMED_OUT_EXIT_CODE, "timed out".to_string()),
+            None => (1, "stopped after another VM failed".to_string()),
+            Some(exit_code) if !is_test => (exit_code, format!("exit code {}", exit_code)),
+            Some(exit_code) => match config.test_outcome(exit_code) {
+                TestOutcome::Pass => (0, "passed".to_string()),
+                TestOutcome::Skip => (0, "skipped".to_string()),
+                TestOutcome::Fail => (1, format!("failed (exit code {})", exit_code)),
+            },
+        };
+        if !message_format.is_json() {
+            println!("{:<16}{}", vm.name, label);
+        }
+        if result == 0 {
+            result = exit_code;
+        }
+    }
+
+    if timed_out && result == 0 {
+        result = TIMED_OUT_EXIT_CODE;
+    }
+    Ok(result)
+}
+
+// Writes a VM's serial output to its log and echoes it to the terminal with the VM name
+fn tee_serial(stdout: impl Read, mut log: File, printer: &SerialPrinter) {
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    let mut host_stdout = std::io::stdout();
+
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+        let _ = log.write_all(&line);
+        let _ = printer.print(&mut host_stdout, &line);
+        line.clear();
+    }
+}
@@ -0,0 +1,202 @@
+use crate::version::VERSION;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+// Lists `<sha256>  <asset name>` for every binary of a release
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+#[derive(Debug, Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    assets: Vec<GithubAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+pub struct Release {
+    pub version: String,
+    binary_url: String,
+    checksums_url: String,
+}
+
+impl Release {
+    pub fn is_newer(&self) -> bool {
+        parse_version(&self.version) > parse_version(VERSION)
+    }
+}
+
+// Release binaries are named after the host they run on, e.g. `limage-x86_64-linux`
+pub fn asset_name() -> String {
+    format!(
+        "limage-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+// The newest release on GitHub that has a binary for this host
+#[instrument(err)]
+pub fn latest_release() -> Result<Release, SelfUpdateError> {
+    let repository = env!("CARGO_PKG_REPOSITORY")
+        .trim_end_matches('/')
+        .trim_start_matches("https://github.com/");
+    let url = format!(
+        "https://api.github.com/repos/{}/releases/latest",
+        repository
+    );
+    let output = curl(&["-sSfL", "-H", "Accept: application/vnd.github+json", &url])?;
+    let release: GithubRelease =
+        serde_json::from_slice(&output).map_err(|e| SelfUpdateError::ParseRelease { source: e })?;
+    debug!("Latest release is {}", release.tag_name);
+
+    let asset_url = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.browser_download_url.clone())
+            .ok_or_else(|| SelfUpdateError::MissingAsset {
+                release: release.tag_name.clone(),
+                asset: name.to_string(),
+            })
+    };
+    Ok(Release {
+        binary_url: asset_url(&asset_name())?,
+        checksums_url: asset_url(CHECKSUMS_ASSET)?,
+        version: release.tag_name.trim_start_matches('v').to_string(),
+    })
+}
+
+// Downloads the release binary, verifies it against the release checksums and swaps it in
+// for the running executable
+#[instrument(skip(release), fields(version = %release.version), err)]
+pub fn install(release: &Release) -> Result<PathBuf, SelfUpdateError> {
+    let current = std::env::current_exe()
+        .and_then(|path| path.canonicalize())
+        .map_err(|e| SelfUpdateError::CurrentExe { source: e })?;
+
+    let checksums =
+        String::from_utf8_lossy(&curl(&["-sSfL", &release.checksums_url])?).into_owned();
+    let asset = asset_name();
+    let expected = checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim().trim_start_matches('*') == asset)
+        .map(|(hash, _)| hash.to_ascii_lowercase())
+        .ok_or_else(|| SelfUpdateError::MissingChecksum {
+            asset: asset.clone(),
+        })?;
+
+    // Staged next to the executable so the final rename stays on one filesystem
+    let staged = current.with_extension("new");
+    info!("Downloading {} to {:?}", release.binary_url, staged);
+    curl(&[
+        "-sSfL",
+        "-o",
+        &staged.display().to_string(),
+        &release.binary_url,
+    ])?;
+
+    let binary = std::fs::read(&staged).map_err(|e| SelfUpdateError::Replace { source: e })?;
+    let actual: String = Sha256::digest(&binary)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if actual != expected {
+        let _ = std::fs::remove_file(&staged);
+        return Err(SelfUpdateError::ChecksumMismatch {
+            asset,
+            expected,
+            actual,
+        });
+    }
+
+    replace(&staged, &current).map_err(|e| {
+        let _ = std::fs::remove_file(&staged);
+        SelfUpdateError::Replace { source: e }
+    })?;
+    Ok(current)
+}
+
+#[cfg(unix)]
+fn replace(staged: &Path, current: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(staged, std::fs::Permissions::from_mode(0o755))?;
+    // The running process keeps its inode, so renaming over it is safe
+    std::fs::rename(staged, current)
+}
+
+#[cfg(not(unix))]
+fn replace(staged: &Path, current: &Path) -> std::io::Result<()> {
+    // A running executable cannot be overwritten on Windows, but it can be renamed away
+    let old = current.with_extension("old");
+    let _ = std::fs::remove_file(&old);
+    std::fs::rename(current, &old)?;
+    std::fs::rename(staged, current)
+}
+
+fn curl(args: &[&str]) -> Result<Vec<u8>, SelfUpdateError> {
+    let output = Command::new("curl")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| SelfUpdateError::Curl { source: e })?;
+    if !output.status.success() {
+        return Err(SelfUpdateError::Download {
+            detail: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+// `1.2.3` as numbers, so that 0.10.0 sorts after 0.9.0
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum SelfUpdateError {
+    #[error("Failed to run curl: {source}")]
+    Curl { source: std::io::Error },
+
+    #[error("Failed to download from GitHub: {detail}")]
+    Download { detail: String },
+
+    #[error("Failed to parse the GitHub release: {source}")]
+    ParseRelease { source: serde_json::Error },
+
+    #[error("Release {release} has no {asset} asset")]
+    MissingAsset { release: String, asset: String },
+
+    #[error("{CHECKSUMS_ASSET} of the release lists no checksum for {asset}")]
+    MissingChecksum { asset: String },
+
+    #[error(
+        "Checksum of the downloaded {asset} does not match: expected {expected}, got {actual}"
+    )]
+    ChecksumMismatch {
+        asset: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Failed to locate the running executable: {source}")]
+    CurrentExe { source: std::io::Error },
+
+    #[error("Failed to replace the running executable: {source}")]
+    Replace { source: std::io::Error },
+}
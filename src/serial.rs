@@ -0,0 +1,290 @@
+#[cfg(unix)]
+use crate::interrupt::{self, InterruptGuard};
+use crate::{
+    guest_test::{GuestSummary, GuestTest, GuestTestStatus},
+    message::{EventSink, Message, MessageFormat},
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{IsTerminal, Read, Write},
+    process::ChildStdin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// Ctrl-A, as in QEMU's own mux: Ctrl-A x stops the VM, Ctrl-A Ctrl-A sends a literal Ctrl-A
+pub const ESCAPE_KEY: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestLogLevel {
+    Debug,
+    Info,
+    Warn,
+    Danger,
+}
+
+impl GuestLogLevel {
+    // Recognizes the `LEVEL:` prefixes emitted by the kernel's logging macros
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_start();
+        [
+            ("DEBUG:", GuestLogLevel::Debug),
+            ("INFO:", GuestLogLevel::Info),
+            ("WARN:", GuestLogLevel::Warn),
+            ("DANGER:", GuestLogLevel::Danger),
+        ]
+        .into_iter()
+        .find(|(prefix, _)| line.starts_with(prefix))
+        .map(|(_, level)| level)
+    }
+
+    fn ansi_color(self) -> &'static str {
+        match self {
+            GuestLogLevel::Debug => "2",
+            GuestLogLevel::Info => "32",
+            GuestLogLevel::Warn => "33",
+            GuestLogLevel::Danger => "1;31",
+        }
+    }
+}
+
+// Echoes guest serial lines to the host, dropping those below `min_level` and coloring by level
+pub struct SerialPrinter {
+    min_level: Option<GuestLogLevel>,
+    color: bool,
+    prefix: String,
+    message_format: MessageFormat,
+    events: Option<Arc<dyn EventSink>>,
+}
+
+impl SerialPrinter {
+    pub fn new(min_level: Option<GuestLogLevel>) -> Self {
+        Self {
+            min_level,
+            color: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            prefix: String::new(),
+            message_format: MessageFormat::Human,
+            events: None,
+        }
+    }
+
+    // With JSON output every shown line becomes a `serial` message instead
+    pub fn message_format(mut self, message_format: MessageFormat) -> Self {
+        self.message_format = message_format;
+        self
+    }
+
+    // Every shown line is also passed to `sink` as a `serial` message
+    pub fn events(mut self, sink: Option<Arc<dyn EventSink>>) -> Self {
+        self.events = sink;
+        self
+    }
+
+    // Tags every printed line, e.g. with the VM name when several guests share the terminal
+    pub fn prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    pub fn print(&self, out: &mut impl Write, line: &[u8]) -> std::io::Result<()> {
+        let text = String::from_utf8_lossy(line);
+        let level = GuestLogLevel::parse(&text);
+
+        // Lines without a level prefix are always shown
+        if let (Some(level), Some(min_level)) = (level, self.min_level) {
+            if level < min_level {
+                return Ok(());
+            }
+        }
+
+        if self.message_format.is_json() || self.events.is_some() {
+            let line = format!("{}{}", self.prefix, text.trim_end_matches(['\r', '\n']));
+            if self.emit(&Message::Serial { line: &line }) {
+                return Ok(());
+            }
+        }
+
+        out.write_all(self.prefix.as_bytes())?;
+        match level {
+            Some(level) if self.color => {
+                let body = text.trim_end_matches(['\r', '\n']);
+                write!(
+                    out,
+                    "\x1b[{}m{}\x1b[0m{}",
+                    level.ansi_color(),
+                    body,
+                    &text[body.len()..]
+                )?;
+            }
+            _ => out.write_all(line)?,
+        }
+        out.flush()
+    }
+
+    // A finished guest test, shown like a line of `cargo test` output
+    pub fn print_guest_test(&self, out: &mut impl Write, test: &GuestTest) -> std::io::Result<()> {
+        let duration_ms = test.duration.as_millis();
+        if self.emit(&Message::GuestTest {
+            name: &test.name,
+            status: test.status.name(),
+            duration_ms,
+            message: test.message.as_deref(),
+        }) {
+            return Ok(());
+        }
+        let (verdict, color) = match test.status {
+            GuestTestStatus::Passed => ("ok", "32"),
+            GuestTestStatus::Failed => ("FAILED", "1;31"),
+            GuestTestStatus::TimedOut => ("TIMED OUT", "1;31"),
+        };
+        write!(out, "{}test {} ... ", self.prefix, test.name)?;
+        self.write_colored(out, verdict, color)?;
+        writeln!(out, " ({} ms)", duration_ms)?;
+        if let Some(message) = &test.message {
+            writeln!(out, "{}    {}", self.prefix, message)?;
+        }
+        out.flush()
+    }
+
+    pub fn print_guest_summary(
+        &self,
+        out: &mut impl Write,
+        summary: &GuestSummary,
+    ) -> std::io::Result<()> {
+        if self.emit(&Message::GuestTestSummary {
+            passed: summary.passed,
+            failed: summary.failed,
+            not_run: summary.not_run,
+            filtered_out: summary.filtered_out,
+        }) {
+            return Ok(());
+        }
+        let (verdict, color) = if summary.failed == 0 {
+            ("ok", "32")
+        } else {
+            ("FAILED", "1;31")
+        };
+        write!(out, "{}test result: ", self.prefix)?;
+        self.write_colored(out, verdict, color)?;
+        writeln!(
+            out,
+            ". {} passed; {} failed; {} not run; {} filtered out",
+            summary.passed, summary.failed, summary.not_run, summary.filtered_out
+        )?;
+        out.flush()
+    }
+
+    // Passes `message` to the sink, returning whether JSON output took the place of the text
+    fn emit(&self, message: &Message) -> bool {
+        if let Some(sink) = &self.events {
+            sink.event(message);
+        }
+        if self.message_format.is_json() {
+            self.message_format.emit(message);
+        }
+        self.message_format.is_json()
+    }
+
+    fn write_colored(&self, out: &mut impl Write, text: &str, color: &str) -> std::io::Result<()> {
+        if self.color {
+            write!(out, "\x1b[{}m{}\x1b[0m", color, text)
+        } else {
+            out.write_all(text.as_bytes())
+        }
+    }
+}
+
+// Puts the host terminal into non-canonical, no-echo mode without signals so Ctrl-C reaches the
+// guest; output processing stays on so a bare `\n` from the guest still returns the cursor
+#[cfg(unix)]
+pub struct RawTerminal {
+    original: libc::termios,
+    _interrupt: InterruptGuard,
+}
+
+#[cfg(unix)]
+impl RawTerminal {
+    pub fn enable() -> std::io::Result<Self> {
+        let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr fully initializes `original` when it returns 0
+        let original = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            original.assume_init()
+        };
+
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a valid termios derived from the current settings
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            original,
+            _interrupt: interrupt::on_interrupt(move || restore_terminal(&original)),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        restore_terminal(&self.original);
+    }
+}
+
+#[cfg(unix)]
+fn restore_terminal(original: &libc::termios) {
+    // SAFETY: restores settings captured by tcgetattr
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+    }
+}
+
+// Copies host keystrokes to the guest until the escape sequence sets `quit` or either side closes
+pub fn forward_stdin(mut guest: ChildStdin, quit: Arc<AtomicBool>) {
+    let mut stdin = std::io::stdin().lock();
+    let mut buffer = [0u8; 64];
+    let mut escaped = false;
+
+    while let Ok(read) = stdin.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+
+        let mut forward = Vec::with_capacity(read);
+        for &byte in &buffer[..read] {
+            if escaped {
+                escaped = false;
+                match byte {
+                    b'x' | b'X' => {
+                        quit.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    ESCAPE_KEY => forward.push(ESCAPE_KEY),
+                    _ => {}
+                }
+            } else if byte == ESCAPE_KEY {
+                escaped = true;
+            } else {
+                forward.push(byte);
+            }
+        }
+
+        if guest
+            .write_all(&forward)
+            .and_then(|_| guest.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::config::LimageConfig;
+use object::{elf, Object, ObjectSection, SectionFlags};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+const KERNEL_FILE: &str = "boot/kernel/kernel";
+const GROUPS: [&str; 4] = ["kernel", "limine", "modules", "assets"];
+const SECTIONS: [&str; 6] = [".text", ".rodata", ".data", ".bss", "other", "debug"];
+// Size report of the image a build is about to replace, for `limage size` to diff against
+const PREVIOUS_FILE: &str = "size-previous.json";
+
+// Sizes of the built image, the files staged into it and the kernel's loaded sections
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SizeReport {
+    pub image: u64,
+    // Paths relative to the ISO root
+    pub files: BTreeMap<String, u64>,
+    // Grouped as .text/.rodata/.data/.bss/other, plus `debug` for what is never loaded
+    pub sections: BTreeMap<String, u64>,
+}
+
+impl SizeReport {
+    pub fn measure(config: &LimageConfig) -> Result<Self, SizeError> {
+        let image = std::fs::metadata(&config.build.image_path)
+            .map_err(|e| SizeError::Read {
+                path: config.build.image_path.clone(),
+                source: e,
+            })?
+            .len();
+
+        let mut files = BTreeMap::new();
+        staged_files(&config.build.iso_root, "", &mut files).map_err(|e| SizeError::Read {
+            path: config.build.iso_root.clone(),
+            source: e,
+        })?;
+
+        let kernel = config.build.iso_root.join(KERNEL_FILE);
+        let sections = if kernel.exists() {
+            kernel_sections(&kernel)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            image,
+            files,
+            sections,
+        })
+    }
+
+    // The report of the image the last build replaced, if any build replaced one
+    pub fn previous(config: &LimageConfig) -> Option<Self> {
+        let content = std::fs::read_to_string(config.work_dir().join(PREVIOUS_FILE)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    // Called before a build overwrites the image
+    pub fn save_previous(config: &LimageConfig) -> Result<(), SizeError> {
+        if !config.build.image_path.exists() {
+            return Ok(());
+        }
+        let report = Self::measure(config)?;
+        let path = config.work_dir().join(PREVIOUS_FILE);
+        let write = std::fs::create_dir_all(config.work_dir()).and_then(|()| {
+            let json = serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?;
+            std::fs::write(&path, json)
+        });
+        write.map_err(|e| SizeError::Write { path, source: e })
+    }
+
+    // Staged files grouped into kernel, Limine, boot modules (from limine.conf) and other assets
+    fn groups(&self, modules: &BTreeSet<String>) -> Vec<(&'static str, u64)> {
+        let mut groups = GROUPS.map(|group| (group, 0));
+        for (path, size) in &self.files {
+            let group = if path == KERNEL_FILE {
+                "kernel"
+            } else if path.starts_with("boot/limine/") || path.starts_with("EFI/BOOT/") {
+                "limine"
+            } else if modules.contains(path) {
+                "modules"
+            } else {
+                "assets"
+            };
+            if let Some((_, total)) = groups.iter_mut().find(|(name, _)| *name == group) {
+                *total += size;
+            }
+        }
+        groups.to_vec()
+    }
+}
+
+// A table of `current`, diffed against `previous` where there is one; `files` lists every staged file
+pub fn render(
+    config: &LimageConfig,
+    current: &SizeReport,
+    previous: Option<&SizeReport>,
+    files: bool,
+) -> String {
+    let modules = limine_modules(&config.limine_conf());
+    let mut out = String::new();
+    let row = |out: &mut String, name: &str, size: u64, before: Option<u64>| {
+        let _ = writeln!(
+            out,
+            "  {:<28}{:>12}{:>14}",
+            name,
+            format_size(size),
+            before
+                .map(|before| format_change(size, before))
+                .unwrap_or_default()
+        );
+    };
+
+    let _ = writeln!(out, "Image {}", config.build.image_path.display());
+    row(
+        &mut out,
+        "total",
+        current.image,
+        previous.map(|previous| previous.image),
+    );
+
+    let _ = writeln!(out, "\nContents ({})", config.build.iso_root.display());
+    let previous_groups = previous.map(|previous| previous.groups(&modules));
+    for (group, size) in current.groups(&modules) {
+        let before = previous_groups.as_ref().map(|groups| {
+            groups
+                .iter()
+                .find(|(name, _)| *name == group)
+                .map_or(0, |(_, size)| *size)
+        });
+        row(&mut out, group, size, before);
+    }
+
+    if files {
+        let _ = writeln!(out, "\nFiles");
+        let mut paths: BTreeSet<&String> = current.files.keys().collect();
+        if let Some(previous) = previous {
+            paths.extend(previous.files.keys());
+        }
+        for path in paths {
+            let size = current.files.get(path).copied().unwrap_or_default();
+            let before =
+                previous.map(|previous| previous.files.get(path).copied().unwrap_or_default());
+            row(&mut out, path, size, before);
+        }
+    }
+
+    if !current.sections.is_empty() {
+        let _ = writeln!(out, "\nKernel sections ({})", KERNEL_FILE);
+        for section in SECTIONS {
+            let size = current.sections.get(section).copied().unwrap_or_default();
+            let before = previous
+                .map(|previous| previous.sections.get(section).copied().unwrap_or_default());
+            let name = if section == "debug" {
+                "debug info and symbols"
+            } else {
+                section
+            };
+            row(&mut out, name, size, before);
+        }
+    }
+
+    if previous.is_none() {
+        let _ = writeln!(
+            out,
+            "\nNo previous build to compare against; the next build records this one"
+        );
+    }
+    out
+}
+
+// Every file under `dir`, keyed by its `/`-separated path below the ISO root
+fn staged_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut BTreeMap<String, u64>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            staged_files(&entry.path(), &format!("{}/", path), files)?;
+        } else {
+            files.insert(path, metadata.len());
+        }
+    }
+    Ok(())
+}
+
+// Sizes of the kernel's sections; .bss takes up memory but no space in the file
+fn kernel_sections(path: &Path) -> Result<BTreeMap<String, u64>, SizeError> {
+    let data = std::fs::read(path).map_err(|e| SizeError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let file = object::File::parse(&*data).map_err(|e| SizeError::ParseKernel {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+
+    let mut sections = BTreeMap::new();
+    for section in file.sections() {
+        // The null section has no name
+        let Ok(name) = section.name() else {
+            continue;
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let loaded = match section.flags() {
+            SectionFlags::Elf { sh_flags } => sh_flags & u64::from(elf::SHF_ALLOC) != 0,
+            _ => true,
+        };
+        let group = if !loaded {
+            "debug"
+        } else {
+            [".text", ".rodata", ".data", ".bss"]
+                .into_iter()
+                .find(|prefix| name == *prefix || name.starts_with(&format!("{}.", prefix)))
+                .unwrap_or("other")
+        };
+        *sections.entry(group.to_string()).or_default() += section.size();
+    }
+    Ok(sections)
+}
+
+// `boot():/` paths of the modules limine.conf loads, relative to the ISO root
+fn limine_modules(path: &Path) -> BTreeSet<String> {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case("module_path"))
+        .filter_map(|(_, value)| value.trim().strip_prefix("boot():/"))
+        .map(|module| module.split('#').next().unwrap_or_default().to_string())
+        .collect()
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        0..=1023 => format!("{} B", size),
+        1024..=1_048_575 => format!("{:.1} KiB", size as f64 / 1024.0),
+        _ => format!("{:.1} MiB", size as f64 / 1_048_576.0),
+    }
+}
+
+fn format_change(size: u64, before: u64) -> String {
+    if size == before {
+        return "=".to_string();
+    }
+    let sign = if size > before { '+' } else { '-' };
+    format!("{}{}", sign, format_size(size.abs_diff(before)))
+}
+
+#[derive(Debug, Error)]
+pub enum SizeError {
+    #[error("Failed to read {path:?}, build the image first: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse kernel ELF {path:?}: {source}")]
+    ParseKernel {
+        path: PathBuf,
+        source: object::Error,
+    },
+
+    #[error("Failed to save size report to {path:?}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
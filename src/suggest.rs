@@ -0,0 +1,242 @@
+use crate::config::LimageConfig;
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor},
+    Deserialize,
+};
+use std::fmt;
+
+// A config key serde skipped over, which the config would otherwise silently ignore
+#[derive(Clone, Debug)]
+pub struct UnknownKey {
+    // Dotted path, e.g. `qemu.extra_arg` or `qemu.drives.0.fle`
+    pub key: String,
+    // The known key in the same table closest to the unknown one, as a full path
+    pub suggestion: Option<String>,
+}
+
+impl UnknownKey {
+    pub fn new(path: &serde_ignored::Path) -> Self {
+        Self::in_table(None, path)
+    }
+
+    // For a path relative to the top-level `table`, e.g. the [qemu] settings of a run mode
+    pub fn in_table(table: Option<&str>, path: &serde_ignored::Path) -> Self {
+        let mut segments: Vec<String> = table.into_iter().map(str::to_string).collect();
+        collect_segments(path, &mut segments);
+        let suggestion = segments.split_last().and_then(|(name, table)| {
+            closest(name, fields(table)).map(|field| {
+                let mut key = table.to_vec();
+                key.push(field.to_string());
+                key.join(".")
+            })
+        });
+        Self {
+            key: segments.join("."),
+            suggestion,
+        }
+    }
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown key `{}`", self.key)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean `{}`?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+// Options and newtypes show up as `?` in serde_ignored's own formatting; they are not keys
+fn collect_segments(path: &serde_ignored::Path, segments: &mut Vec<String>) {
+    match path {
+        serde_ignored::Path::Root => {}
+        serde_ignored::Path::Seq { parent, index } => {
+            collect_segments(parent, segments);
+            segments.push(index.to_string());
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            collect_segments(parent, segments);
+            segments.push(key.clone());
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => collect_segments(parent, segments),
+    }
+}
+
+// The candidate spelled most like `name`, if any is close enough to be a plausible typo
+pub fn closest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let limit = name.chars().count().max(3) / 3;
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+// Levenshtein distance: the number of inserted, removed or replaced characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Keys of the config table at `path`, e.g. `["qemu"]` gives the keys of [qemu]. Taken from the
+// types themselves, so fields that are unset by default are included too
+pub fn fields(path: &[String]) -> &'static [&'static str] {
+    let mut fields = None;
+    let _ = LimageConfig::deserialize(Probe {
+        path,
+        fields: &mut fields,
+    });
+    fields.unwrap_or_default()
+}
+
+// A deserializer that follows `path` into the config types and records the field names of the
+// struct it ends at, then stops
+struct Probe<'a> {
+    path: &'a [String],
+    fields: &'a mut Option<&'static [&'static str]>,
+}
+
+#[derive(Debug)]
+struct Stop;
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("probe stopped")
+    }
+}
+
+impl std::error::Error for Stop {}
+
+impl de::Error for Stop {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        Stop
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Probe<'_> {
+    type Error = Stop;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Stop> {
+        Err(Stop)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Stop> {
+        match self.path.split_first() {
+            None => {
+                *self.fields = Some(fields);
+                Err(Stop)
+            }
+            Some((key, rest)) => visitor.visit_map(Entry {
+                key: Some(key),
+                rest,
+                fields: self.fields,
+            }),
+        }
+    }
+
+    // Tables keyed by name, e.g. [qemu.modes.<name>]
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Stop> {
+        let (key, rest) = self.path.split_first().ok_or(Stop)?;
+        visitor.visit_map(Entry {
+            key: Some(key),
+            rest,
+            fields: self.fields,
+        })
+    }
+
+    // Arrays of tables, e.g. [[qemu.drives]], where the path holds the index
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Stop> {
+        let (_, rest) = self.path.split_first().ok_or(Stop)?;
+        visitor.visit_seq(Element {
+            rest: Some(rest),
+            fields: self.fields,
+        })
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Stop> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Stop> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct tuple tuple_struct enum identifier ignored_any
+    }
+}
+
+struct Entry<'a> {
+    key: Option<&'a String>,
+    rest: &'a [String],
+    fields: &'a mut Option<&'static [&'static str]>,
+}
+
+impl<'de> MapAccess<'de> for Entry<'_> {
+    type Error = Stop;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Stop> {
+        match self.key.take() {
+            Some(key) => seed.deserialize(key.as_str().into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Stop> {
+        seed.deserialize(Probe {
+            path: self.rest,
+            fields: self.fields,
+        })
+    }
+}
+
+struct Element<'a> {
+    rest: Option<&'a [String]>,
+    fields: &'a mut Option<&'static [&'static str]>,
+}
+
+impl<'de> SeqAccess<'de> for Element<'_> {
+    type Error = Stop;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Stop> {
+        match self.rest.take() {
+            Some(path) => seed
+                .deserialize(Probe {
+                    path,
+                    fields: self.fields,
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use addr2line::Loader;
+use object::{Object, ObjectSection, SectionKind};
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+// One function an address resolves to; inlined calls give several frames for the same address
+pub struct Frame {
+    pub function: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+// Resolves kernel addresses to functions and source lines through the kernel's DWARF info,
+// falling back to the ELF symbol table for code built without debug info
+pub struct Symbolizer {
+    loader: Loader,
+    // Address ranges of the executable sections; DWARF also covers discarded code at address 0
+    code: Vec<Range<u64>>,
+}
+
+impl Symbolizer {
+    pub fn new(kernel: &Path) -> Result<Self, SymbolizeError> {
+        let load_error = |detail: String| SymbolizeError::Load {
+            path: kernel.to_path_buf(),
+            detail,
+        };
+        let data = std::fs::read(kernel).map_err(|e| load_error(e.to_string()))?;
+        let file = object::File::parse(&*data).map_err(|e| load_error(e.to_string()))?;
+        let code = file
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .map(|section| section.address()..section.address() + section.size())
+            .collect();
+        let loader = Loader::new(kernel).map_err(|e| load_error(e.to_string()))?;
+        Ok(Self { loader, code })
+    }
+
+    // Innermost frame first; empty when the address is not part of the kernel
+    pub fn frames(&self, address: u64) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        if !self.code.iter().any(|range| range.contains(&address)) {
+            return frames;
+        }
+        if let Ok(mut iter) = self.loader.find_frames(address) {
+            while let Ok(Some(frame)) = iter.next() {
+                let function = frame
+                    .function
+                    .as_ref()
+                    .and_then(|function| function.demangle().ok())
+                    .map(Cow::into_owned);
+                let Some(function) = function.or_else(|| self.symbol(address)) else {
+                    continue;
+                };
+                frames.push(Frame {
+                    function,
+                    file: frame
+                        .location
+                        .as_ref()
+                        .and_then(|location| location.file)
+                        .map(str::to_string),
+                    line: frame.location.as_ref().and_then(|location| location.line),
+                });
+            }
+        }
+        if frames.is_empty() {
+            if let Some(function) = self.symbol(address) {
+                frames.push(Frame {
+                    function,
+                    file: None,
+                    line: None,
+                });
+            }
+        }
+        frames
+    }
+
+    fn symbol(&self, address: u64) -> Option<String> {
+        self.loader
+            .find_symbol(address)
+            .map(|name| addr2line::demangle_auto(Cow::Borrowed(name), None).into_owned())
+    }
+
+    // A gdb-style backtrace of every kernel address in `text`, in order of appearance
+    pub fn backtrace(&self, text: &str) -> Vec<String> {
+        let mut seen = BTreeSet::new();
+        // Values that are not kernel code, e.g. register dumps, are left out
+        let resolved: Vec<(u64, Vec<Frame>)> = addresses(text)
+            .into_iter()
+            .filter(|address| seen.insert(*address))
+            .map(|address| (address, self.frames(address)))
+            .filter(|(_, frames)| !frames.is_empty())
+            .collect();
+        format_backtrace(&resolved)
+    }
+
+    // Like `backtrace`, but for addresses given explicitly, so unknown ones are shown as `??`
+    pub fn resolve(&self, addresses: &[u64]) -> Vec<String> {
+        let resolved: Vec<(u64, Vec<Frame>)> = addresses
+            .iter()
+            .map(|address| (*address, self.frames(*address)))
+            .collect();
+        format_backtrace(&resolved)
+    }
+}
+
+fn format_backtrace(resolved: &[(u64, Vec<Frame>)]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (number, (address, frames)) in resolved.iter().enumerate() {
+        let prefix = format!("#{:<2} {:#018x} in", number, address);
+        if frames.is_empty() {
+            lines.push(format!("{} ??", prefix));
+        }
+        for (inline, frame) in frames.iter().enumerate() {
+            let location = match (&frame.file, frame.line) {
+                (Some(file), Some(line)) => format!(" at {}:{}", file, line),
+                (Some(file), None) => format!(" at {}", file),
+                _ => String::new(),
+            };
+            // Inlined frames share the address of the frame they were inlined into
+            let prefix = if inline == 0 {
+                prefix.clone()
+            } else {
+                format!("{:>width$}", "inlined into", width = prefix.len())
+            };
+            lines.push(format!("{} {}{}", prefix, frame.function, location));
+        }
+    }
+    lines
+}
+
+// Hexadecimal addresses in panic output, e.g. `0xffffffff80123456` or `ffffffff80123456`
+pub fn addresses(text: &str) -> Vec<u64> {
+    let pattern = Regex::new(r"\b(?:0x)?([0-9a-fA-F]{8,16})\b").expect("address pattern is valid");
+    pattern
+        .captures_iter(text)
+        .filter(|caps| caps[0].starts_with("0x") || caps[1].len() == 16)
+        .filter_map(|caps| u64::from_str_radix(&caps[1], 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum SymbolizeError {
+    #[error("Failed to load kernel symbols from {path:?}: {detail}")]
+    Load { path: PathBuf, detail: String },
+}
@@ -0,0 +1,65 @@
+use crate::build_fs::BuildFs;
+use object::{Architecture, Object};
+use std::path::Path;
+
+// The target JSON limage writes for `build.target_json`, and the one `limage init` projects start
+// with: a freestanding x86_64 kernel in the top 2 GiB, without SSE and the red zone, with frame
+// pointers for `limage_test::print_backtrace`
+pub const X86_64_KERNEL: &str = include_str!("../templates/x86_64-kernel.json");
+
+// Writes the known-good target to `path` unless something is there already, which is left alone
+// so a project can adjust its copy. Returns whether it wrote the file
+pub fn materialize(fs: &dyn BuildFs, path: &Path) -> std::io::Result<bool> {
+    if fs.exists(path) {
+        return Ok(false);
+    }
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs.create_dir_all(parent)?;
+    }
+    fs.write(path, X86_64_KERNEL.as_bytes())?;
+    Ok(true)
+}
+
+// What cargo needs to build for a custom target: core, alloc and compiler_builtins from source
+pub fn cargo_args(path: &Path) -> Vec<String> {
+    vec![
+        "--target".to_string(),
+        path.display().to_string(),
+        "-Zbuild-std=core,compiler_builtins,alloc".to_string(),
+        "-Zbuild-std-features=compiler-builtins-mem".to_string(),
+    ]
+}
+
+// Whether `kernel` looks built for the target `spec` describes: an ELF for its `arch`, with no
+// program interpreter the way a host executable has one
+pub fn check_kernel(kernel: &[u8], spec: &str) -> Result<(), String> {
+    let spec: serde_json::Value =
+        serde_json::from_str(spec).map_err(|e| format!("the target JSON is invalid: {}", e))?;
+    let arch = spec
+        .get("arch")
+        .and_then(|arch| arch.as_str())
+        .unwrap_or("");
+    let expected = match arch {
+        "x86_64" => Architecture::X86_64,
+        "x86" => Architecture::I386,
+        "aarch64" => Architecture::Aarch64,
+        "riscv64" => Architecture::Riscv64,
+        // Nothing to compare against
+        _ => return Ok(()),
+    };
+    let file = object::File::parse(kernel).map_err(|e| format!("it is not an ELF file: {}", e))?;
+    if file.architecture() != expected {
+        return Err(format!(
+            "it is built for {:?}, not {}",
+            file.architecture(),
+            arch
+        ));
+    }
+    if file.section_by_name(".interp").is_some() {
+        return Err("it has a program interpreter, like a program built for the host".to_string());
+    }
+    Ok(())
+}
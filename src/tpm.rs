@@ -0,0 +1,116 @@
+use crate::interrupt::{self, InterruptGuard};
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument, warn};
+
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// An swtpm instance that lives as long as the QEMU run it backs
+pub struct Swtpm {
+    child: Child,
+    _interrupt: InterruptGuard,
+}
+
+impl Swtpm {
+    pub fn socket_path(state_dir: &Path) -> PathBuf {
+        state_dir.join("swtpm.sock")
+    }
+
+    pub fn qemu_args(state_dir: &Path) -> Vec<String> {
+        vec![
+            "-chardev".to_string(),
+            format!(
+                "socket,id=limage-chrtpm,path={}",
+                Self::socket_path(state_dir).display()
+            ),
+            "-tpmdev".to_string(),
+            "emulator,id=limage-tpm0,chardev=limage-chrtpm".to_string(),
+            "-device".to_string(),
+            "tpm-tis,tpmdev=limage-tpm0".to_string(),
+        ]
+    }
+
+    pub fn command(state_dir: &Path) -> Command {
+        let mut command = Command::new("swtpm");
+        command
+            .arg("socket")
+            .arg("--tpm2")
+            .arg("--terminate")
+            .arg("--tpmstate")
+            .arg(format!("dir={}", state_dir.display()))
+            .arg("--ctrl")
+            .arg(format!(
+                "type=unixio,path={}",
+                Self::socket_path(state_dir).display()
+            ));
+        command
+    }
+
+    #[instrument(err)]
+    pub fn start(state_dir: &Path) -> Result<Self, TpmError> {
+        std::fs::create_dir_all(state_dir).map_err(|e| TpmError::StateDir { source: e })?;
+
+        let socket = Self::socket_path(state_dir);
+        if socket.exists() {
+            debug!("Removing stale swtpm socket {:?}", socket);
+            let _ = std::fs::remove_file(&socket);
+        }
+
+        info!("Starting swtpm with state in {:?}", state_dir);
+        let child = Self::command(state_dir)
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| TpmError::StartSwtpm { source: e })?;
+        let mut swtpm = Self {
+            _interrupt: interrupt::kill_on_interrupt(&child),
+            child,
+        };
+
+        let deadline = Instant::now() + SOCKET_TIMEOUT;
+        while !socket.exists() {
+            if let Ok(Some(status)) = swtpm.child.try_wait() {
+                return Err(TpmError::SwtpmExited { status });
+            }
+            if Instant::now() >= deadline {
+                return Err(TpmError::SocketTimeout { path: socket });
+            }
+            thread::sleep(SOCKET_POLL_INTERVAL);
+        }
+
+        debug!("swtpm listening on {:?}", socket);
+        Ok(swtpm)
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            debug!("Stopping swtpm");
+            if let Err(e) = self.child.kill() {
+                warn!("Failed to stop swtpm: {}", e);
+            }
+        }
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TpmError {
+    #[error("Failed to create TPM state directory: {source}")]
+    StateDir { source: std::io::Error },
+
+    #[error("Failed to start swtpm: {source}")]
+    StartSwtpm { source: std::io::Error },
+
+    #[error("swtpm exited before it was ready ({status})")]
+    SwtpmExited { status: std::process::ExitStatus },
+
+    #[error("Timed out waiting for swtpm socket at {path:?}")]
+    SocketTimeout { path: PathBuf },
+}
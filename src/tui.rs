@@ -0,0 +1,294 @@
+use crate::{
+    interrupt,
+    qmp::{QmpError, QmpHandle},
+    serial::GuestLogLevel,
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Gauge, Paragraph},
+    DefaultTerminal, Frame,
+};
+use std::{
+    io::{BufRead, BufReader, Read},
+    net::SocketAddr,
+    path::PathBuf,
+    process::Child,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::debug;
+
+const TICK: Duration = Duration::from_millis(100);
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+const QMP_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_LINES: usize = 10_000;
+
+pub struct TuiOptions {
+    pub title: String,
+    pub timeout: Option<Duration>,
+    pub screenshot: PathBuf,
+    pub log_level: Option<GuestLogLevel>,
+}
+
+pub enum TuiExit {
+    Exited(i32),
+    TimedOut,
+    Killed,
+}
+
+struct App {
+    options: TuiOptions,
+    lines: Vec<String>,
+    scroll: usize,
+    status: String,
+    message: String,
+    started: Instant,
+    qmp: Option<QmpHandle>,
+    qmp_addr: SocketAddr,
+}
+
+// Takes over the terminal until QEMU exits, the timeout expires or the user kills the VM
+pub fn run(
+    child: &mut Child,
+    qmp_addr: SocketAddr,
+    options: TuiOptions,
+) -> Result<TuiExit, TuiError> {
+    let (sender, receiver) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_lines(stdout, sender.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, sender);
+    }
+
+    let mut app = App {
+        options,
+        lines: Vec::new(),
+        scroll: 0,
+        status: "starting".to_string(),
+        message: String::new(),
+        started: Instant::now(),
+        qmp: None,
+        qmp_addr,
+    };
+
+    let mut terminal = ratatui::try_init().map_err(|e| TuiError::Terminal { source: e })?;
+    let _restore = interrupt::on_interrupt(ratatui::restore);
+    let result = app.event_loop(&mut terminal, child, &receiver);
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn event_loop(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        child: &mut Child,
+        receiver: &Receiver<String>,
+    ) -> Result<TuiExit, TuiError> {
+        let mut last_status = Instant::now() - STATUS_INTERVAL;
+        loop {
+            self.lines.extend(receiver.try_iter());
+            if self.lines.len() > MAX_LINES {
+                self.lines.drain(..self.lines.len() - MAX_LINES);
+            }
+
+            if let Some(status) = child.try_wait().map_err(|e| TuiError::Wait { source: e })? {
+                return Ok(TuiExit::Exited(status.code().unwrap_or(1)));
+            }
+            if let Some(timeout) = self.options.timeout {
+                if self.started.elapsed() >= timeout {
+                    return Ok(TuiExit::TimedOut);
+                }
+            }
+            if last_status.elapsed() >= STATUS_INTERVAL {
+                self.refresh_status();
+                last_status = Instant::now();
+            }
+
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(|e| TuiError::Terminal { source: e })?;
+
+            if !event::poll(TICK).map_err(|e| TuiError::Terminal { source: e })? {
+                continue;
+            }
+            let Event::Key(key) = event::read().map_err(|e| TuiError::Terminal { source: e })?
+            else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('r') => {
+                    self.message = match self.with_qmp(|qmp| qmp.system_reset()) {
+                        Ok(()) => "Reset guest".to_string(),
+                        Err(e) => format!("Reset failed: {}", e),
+                    };
+                }
+                KeyCode::Char('s') => {
+                    let path = self.options.screenshot.clone();
+                    if let Some(parent) = path.parent() {
+                        let _ = std::fs::create_dir_all(parent);
+                    }
+                    self.message = match self.with_qmp(|qmp| qmp.screendump(&path)) {
+                        Ok(()) => format!("Saved screenshot to {}", path.display()),
+                        Err(e) => format!("Screenshot failed: {}", e),
+                    };
+                }
+                KeyCode::Char('k') => {
+                    child.kill().map_err(|e| TuiError::Wait { source: e })?;
+                    child.wait().map_err(|e| TuiError::Wait { source: e })?;
+                    return Ok(TuiExit::Killed);
+                }
+                KeyCode::Char('q') => {
+                    // Falls through to the next try_wait once QEMU has gone away
+                    let result = self.with_qmp(|qmp| qmp.quit());
+                    if let Err(e) = result {
+                        if !matches!(e, QmpError::Closed) {
+                            self.message = format!("Quit failed: {}", e);
+                        }
+                    }
+                }
+                KeyCode::Up => self.scroll = self.scroll.saturating_add(1),
+                KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_add(20),
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(20),
+                KeyCode::End => self.scroll = 0,
+                _ => {}
+            }
+        }
+    }
+
+    fn with_qmp<T>(
+        &mut self,
+        f: impl FnOnce(&mut QmpHandle) -> Result<T, QmpError>,
+    ) -> Result<T, QmpError> {
+        if self.qmp.is_none() {
+            self.qmp = Some(QmpHandle::connect(self.qmp_addr, QMP_CONNECT_TIMEOUT)?);
+        }
+        let result = f(self.qmp.as_mut().expect("QMP handle was just connected"));
+        // Reconnect on the next request after I/O failures
+        if matches!(
+            result,
+            Err(QmpError::Io { .. }) | Err(QmpError::Closed) | Err(QmpError::Parse { .. })
+        ) {
+            self.qmp = None;
+        }
+        result
+    }
+
+    fn refresh_status(&mut self) {
+        match self.with_qmp(|qmp| qmp.query_status()) {
+            Ok(status) => self.status = status,
+            Err(e) => debug!("Failed to query QEMU status: {}", e),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, output, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let elapsed = self.started.elapsed();
+        let status = format!(
+            "{} | {} | {}s elapsed",
+            self.options.title,
+            self.status,
+            elapsed.as_secs()
+        );
+        match self.options.timeout {
+            Some(timeout) => {
+                let ratio = (elapsed.as_secs_f64() / timeout.as_secs_f64()).min(1.0);
+                let gauge = Gauge::default()
+                    .block(Block::bordered().title(" limage "))
+                    .gauge_style(Style::default().fg(Color::Cyan))
+                    .ratio(ratio)
+                    .label(format!("{} / {}s timeout", status, timeout.as_secs()));
+                frame.render_widget(gauge, header);
+            }
+            None => {
+                let paragraph = Paragraph::new(status).block(Block::bordered().title(" limage "));
+                frame.render_widget(paragraph, header);
+            }
+        }
+
+        let visible: Vec<Line> = self
+            .lines
+            .iter()
+            .filter_map(|line| self.styled_line(line))
+            .collect();
+        let height = output.height.saturating_sub(2) as usize;
+        let end = visible.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let paragraph =
+            Paragraph::new(visible[start..end].to_vec()).block(Block::bordered().title(" serial "));
+        frame.render_widget(paragraph, output);
+
+        let keys = Line::from(vec![
+            Span::styled(" r ", Style::default().add_modifier(Modifier::REVERSED)),
+            Span::raw(" reset  "),
+            Span::styled(" s ", Style::default().add_modifier(Modifier::REVERSED)),
+            Span::raw(" screenshot  "),
+            Span::styled(" q ", Style::default().add_modifier(Modifier::REVERSED)),
+            Span::raw(" quit  "),
+            Span::styled(" k ", Style::default().add_modifier(Modifier::REVERSED)),
+            Span::raw(" kill  "),
+            Span::raw(&self.message),
+        ]);
+        frame.render_widget(Paragraph::new(keys), footer);
+    }
+
+    fn styled_line<'a>(&self, line: &'a str) -> Option<Line<'a>> {
+        let level = GuestLogLevel::parse(line);
+        if let (Some(level), Some(min_level)) = (level, self.options.log_level) {
+            if level < min_level {
+                return None;
+            }
+        }
+
+        let style = match level {
+            Some(GuestLogLevel::Debug) => Style::default().add_modifier(Modifier::DIM),
+            Some(GuestLogLevel::Info) => Style::default().fg(Color::Green),
+            Some(GuestLogLevel::Warn) => Style::default().fg(Color::Yellow),
+            Some(GuestLogLevel::Danger) => {
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+            }
+            None => Style::default(),
+        };
+        Some(Line::styled(line, style))
+    }
+}
+
+fn forward_lines(output: impl Read + Send + 'static, sender: Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else { break };
+            if sender
+                .send(line.trim_end_matches('\r').to_string())
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+}
+
+#[derive(Debug, Error)]
+pub enum TuiError {
+    #[error("Terminal error: {source}")]
+    Terminal { source: std::io::Error },
+
+    #[error("Failed to wait for QEMU process: {source}")]
+    Wait { source: std::io::Error },
+}
@@ -0,0 +1,142 @@
+use crate::{
+    artifacts::{ArtifactError, LimineArtifacts, OvmfManager, LIMINE_BRANCH, LIMINE_REPOSITORY},
+    builder::{BuildError, Builder},
+    config::LimageConfig,
+    lockfile::{LimineLock, Lockfile, LockfileError, OvmfLock},
+};
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+use thiserror::Error;
+use tracing::{info, instrument};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Limine,
+    Ovmf,
+}
+
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    // Version in the local cache, if anything is cached
+    pub current: Option<String>,
+    pub latest: String,
+}
+
+impl Artifact {
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            ArtifactKind::Limine => "limine",
+            ArtifactKind::Ovmf => "ovmf",
+        }
+    }
+
+    pub fn is_outdated(&self) -> bool {
+        self.current.as_deref() != Some(self.latest.as_str())
+    }
+}
+
+// Compares the cached Limine checkout and OVMF firmware against the newest upstream versions
+#[instrument(skip(config), err)]
+pub fn check(config: &LimageConfig, lockfile: &Lockfile) -> Result<Vec<Artifact>, UpdateError> {
+    let limine_current = LimineArtifacts::new(&config.build.limine_path).version()?;
+
+    // The cached firmware carries no version, so trust the lockfile only while the files exist
+    let ovmf = OvmfManager::new(&config.build.ovmf_path)
+        .release(lockfile.ovmf.as_ref().map(|ovmf| ovmf.release.clone()));
+    let ovmf_current = ovmf
+        .version()
+        .filter(|_| ovmf.is_cached())
+        .map(str::to_string);
+
+    Ok(vec![
+        Artifact {
+            kind: ArtifactKind::Limine,
+            current: limine_current,
+            latest: LimineArtifacts::latest_commit()?,
+        },
+        Artifact {
+            kind: ArtifactKind::Ovmf,
+            current: ovmf_current,
+            latest: OvmfManager::latest_release()?,
+        },
+    ])
+}
+
+// Re-fetches the outdated artifacts and records the new versions in the lockfile
+#[instrument(skip_all, err)]
+pub fn apply(
+    config: &LimageConfig,
+    artifacts: &[Artifact],
+    lockfile_path: &Path,
+) -> Result<(), UpdateError> {
+    let mut lockfile = Lockfile::load(lockfile_path)?;
+    for artifact in artifacts {
+        match artifact.kind {
+            ArtifactKind::Limine => {
+                if artifact.is_outdated() && config.build.limine_path.exists() {
+                    info!("Removing cached Limine in {:?}", config.build.limine_path);
+                    std::fs::remove_dir_all(&config.build.limine_path)
+                        .map_err(|e| UpdateError::RemoveCache { source: e })?;
+                }
+                lockfile.limine = Some(LimineLock {
+                    repository: LIMINE_REPOSITORY.to_string(),
+                    branch: LIMINE_BRANCH.to_string(),
+                    commit: artifact.latest.clone(),
+                });
+            }
+            ArtifactKind::Ovmf => {
+                if artifact.is_outdated() {
+                    for file in OvmfManager::FILES {
+                        let path = config.build.ovmf_path.join(file);
+                        if path.exists() {
+                            std::fs::remove_file(&path)
+                                .map_err(|e| UpdateError::RemoveCache { source: e })?;
+                        }
+                    }
+                }
+                lockfile.ovmf = Some(OvmfLock {
+                    release: artifact.latest.clone(),
+                });
+            }
+        }
+    }
+
+    // The builder downloads the pinned versions, so the lockfile has to be in place first
+    lockfile.save(lockfile_path)?;
+    Builder::new(config.clone())?.fetch_artifacts()?;
+    Ok(())
+}
+
+pub fn confirm(question: &str) -> Result<bool, UpdateError> {
+    print!("{} [y/N] ", question);
+    std::io::stdout()
+        .flush()
+        .map_err(|e| UpdateError::Prompt { source: e })?;
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| UpdateError::Prompt { source: e })?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("Failed to read the answer: {source}")]
+    Prompt { source: std::io::Error },
+
+    #[error("Failed to remove cached artifact: {source}")]
+    RemoveCache { source: std::io::Error },
+
+    #[error(transparent)]
+    Artifact(#[from] ArtifactError),
+
+    #[error(transparent)]
+    Lockfile(#[from] LockfileError),
+
+    #[error(transparent)]
+    Build(#[from] BuildError),
+}
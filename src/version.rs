@@ -0,0 +1,83 @@
+use crate::{config::LimageConfig, lockfile::Lockfile, package::utc_date};
+use std::process::{Command, Stdio};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Everything a bug report needs to reproduce a build: limage itself, the host, the cached
+// boot artifacts and the external tools, as (name, description) pairs
+pub fn report(config: &LimageConfig, lockfile: &Lockfile) -> Vec<(&'static str, String)> {
+    let mut lines = vec![
+        ("limage", VERSION.to_string()),
+        (
+            "host",
+            format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        ),
+    ];
+
+    let limine_path = &config.build.limine_path;
+    // Without its own .git, `git -C` would report the enclosing project's commit
+    let limine_cached = if limine_path.join(".git").exists() {
+        Command::new("git")
+            .arg("-C")
+            .arg(limine_path)
+            .args(["rev-parse", "HEAD"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    };
+    let limine_locked = lockfile.limine.as_ref().map(|limine| &limine.commit);
+    lines.push((
+        "limine",
+        match (limine_cached, limine_locked) {
+            (Some(cached), Some(locked)) if cached == *locked => format!("{} (locked)", cached),
+            (Some(cached), Some(locked)) => format!("{} (locked {})", cached, locked),
+            (Some(cached), None) => format!("{} (not locked)", cached),
+            (None, Some(locked)) => format!("not downloaded (locked {})", locked),
+            (None, None) => "not downloaded".to_string(),
+        },
+    ));
+
+    // The firmware carries no version of its own; the download date stands in for the build date
+    let ovmf_downloaded = std::fs::metadata(config.build.ovmf_path.join("ovmf-code-x86_64.fd"))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(utc_date);
+    let ovmf_locked = lockfile.ovmf.as_ref().map(|ovmf| &ovmf.release);
+    lines.push((
+        "ovmf",
+        match (ovmf_downloaded, ovmf_locked) {
+            (Some(date), Some(release)) => format!("{}, downloaded {}", release, date),
+            (Some(date), None) => format!("latest release, downloaded {}", date),
+            (None, Some(release)) => format!("not downloaded (locked {})", release),
+            (None, None) => "not downloaded".to_string(),
+        },
+    ));
+
+    lines.push(("qemu", tool_version(&config.qemu.binary)));
+    lines.push(("xorriso", tool_version("xorriso")));
+    lines
+}
+
+// First line of `<binary> --version`
+pub fn tool_version(binary: &str) -> String {
+    match Command::new(binary)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+    {
+        Ok(output) => {
+            let text = if output.stdout.is_empty() {
+                String::from_utf8_lossy(&output.stderr).into_owned()
+            } else {
+                String::from_utf8_lossy(&output.stdout).into_owned()
+            };
+            text.lines().next().unwrap_or("unknown").trim().to_string()
+        }
+        Err(_) => "not found".to_string(),
+    }
+}
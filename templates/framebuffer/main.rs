@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(limage_test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{arch::asm, panic::PanicInfo};
+use limage_test::serial_println;
+use limine::{
+    framebuffer::Framebuffer,
+    request::{
+        ExecutableCmdlineRequest, FramebufferRequest, RequestsEndMarker, RequestsStartMarker,
+    },
+    BaseRevision,
+};
+
+#[used]
+#[link_section = ".limine_requests"]
+static BASE_REVISION: BaseRevision = BaseRevision::new();
+
+// limage passes test settings, e.g. `limage test --filter`, on the command line
+#[used]
+#[link_section = ".limine_requests"]
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
+#[used]
+#[link_section = ".limine_requests"]
+static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
+
+#[used]
+#[link_section = ".limine_requests_start"]
+static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
+
+#[used]
+#[link_section = ".limine_requests_end"]
+static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
+
+#[no_mangle]
+extern "C" fn kmain() -> ! {
+    assert!(BASE_REVISION.is_supported());
+    if let Some(cmdline) = CMDLINE_REQUEST
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+    {
+        limage_test::set_cmdline(cmdline);
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    match FRAMEBUFFER_REQUEST
+        .get_response()
+        .and_then(|response| response.framebuffers().next())
+    {
+        Some(framebuffer) => {
+            serial_println!(
+                "Drawing on a {}x{} framebuffer",
+                framebuffer.width(),
+                framebuffer.height()
+            );
+            draw_gradient(&framebuffer);
+        }
+        None => serial_println!("Limine provided no framebuffer"),
+    }
+    halt()
+}
+
+// Red across, green down
+fn draw_gradient(framebuffer: &Framebuffer) {
+    if framebuffer.bpp() != 32 {
+        serial_println!("Only 32-bit pixels are drawn, not {}-bit", framebuffer.bpp());
+        return;
+    }
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    for y in 0..height {
+        for x in 0..width {
+            let color = gradient(x, y, width, height);
+            let offset = y * framebuffer.pitch() + x * 4;
+            // SAFETY: the offset lies within the framebuffer Limine mapped
+            unsafe {
+                framebuffer
+                    .addr()
+                    .add(offset as usize)
+                    .cast::<u32>()
+                    .write_volatile(color)
+            };
+        }
+    }
+}
+
+fn gradient(x: u64, y: u64, width: u64, height: u64) -> u32 {
+    let red = (x * 255 / width.max(1)) as u32;
+    let green = (y * 255 / height.max(1)) as u32;
+    red << 16 | green << 8 | 0x40
+}
+
+fn halt() -> ! {
+    loop {
+        // SAFETY: hlt only waits for the next interrupt
+        unsafe { asm!("hlt") };
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("{}", info);
+    limage_test::print_backtrace();
+    halt()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    limage_test::test_panic_handler(info)
+}
+
+#[test_case]
+fn gradient_corners() {
+    assert_eq!(gradient(0, 0, 800, 600), 0x000040);
+    assert_eq!(gradient(800, 600, 800, 600), 0xffff40);
+}
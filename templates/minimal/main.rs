@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(limage_test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{arch::asm, panic::PanicInfo};
+use limage_test::serial_println;
+use limine::{
+    request::{ExecutableCmdlineRequest, RequestsEndMarker, RequestsStartMarker},
+    BaseRevision,
+};
+
+#[used]
+#[link_section = ".limine_requests"]
+static BASE_REVISION: BaseRevision = BaseRevision::new();
+
+// limage passes test settings, e.g. `limage test --filter`, on the command line
+#[used]
+#[link_section = ".limine_requests"]
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
+#[used]
+#[link_section = ".limine_requests_start"]
+static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
+
+#[used]
+#[link_section = ".limine_requests_end"]
+static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
+
+#[no_mangle]
+extern "C" fn kmain() -> ! {
+    assert!(BASE_REVISION.is_supported());
+    if let Some(cmdline) = CMDLINE_REQUEST
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+    {
+        limage_test::set_cmdline(cmdline);
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    serial_println!("Hello from {{name}}!");
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        // SAFETY: hlt only waits for the next interrupt
+        unsafe { asm!("hlt") };
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("{}", info);
+    limage_test::print_backtrace();
+    halt()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    limage_test::test_panic_handler(info)
+}
+
+#[test_case]
+fn trivial_assertion() {
+    assert_eq!(1 + 1, 2);
+}
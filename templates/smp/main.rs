@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(limage_test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use limage_test::serial_println;
+use limine::{
+    mp::Cpu,
+    request::{ExecutableCmdlineRequest, MpRequest, RequestsEndMarker, RequestsStartMarker},
+    BaseRevision,
+};
+
+#[used]
+#[link_section = ".limine_requests"]
+static BASE_REVISION: BaseRevision = BaseRevision::new();
+
+// limage passes test settings, e.g. `limage test --filter`, on the command line
+#[used]
+#[link_section = ".limine_requests"]
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
+#[used]
+#[link_section = ".limine_requests"]
+static MP_REQUEST: MpRequest = MpRequest::new();
+
+#[used]
+#[link_section = ".limine_requests_start"]
+static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
+
+#[used]
+#[link_section = ".limine_requests_end"]
+static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
+
+// The bootstrap processor counts itself
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+#[no_mangle]
+extern "C" fn kmain() -> ! {
+    assert!(BASE_REVISION.is_supported());
+    if let Some(cmdline) = CMDLINE_REQUEST
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+    {
+        limage_test::set_cmdline(cmdline);
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    let Some(mp) = MP_REQUEST.get_response() else {
+        serial_println!("Limine started no other CPUs");
+        halt()
+    };
+    let cpus = mp.cpus();
+    for cpu in cpus.iter().filter(|cpu| cpu.lapic_id != mp.bsp_lapic_id()) {
+        cpu.goto_address.write(ap_main);
+    }
+    while ONLINE.load(Ordering::SeqCst) < cpus.len() {
+        core::hint::spin_loop();
+    }
+    // The application processors only count themselves, so their output cannot interleave
+    serial_println!("{} of {} CPUs online", ONLINE.load(Ordering::SeqCst), cpus.len());
+    halt()
+}
+
+// Where Limine sends every application processor once `goto_address` is written
+unsafe extern "C" fn ap_main(_cpu: &Cpu) -> ! {
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        // SAFETY: hlt only waits for the next interrupt
+        unsafe { asm!("hlt") };
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("{}", info);
+    limage_test::print_backtrace();
+    halt()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    limage_test::test_panic_handler(info)
+}
+
+#[test_case]
+fn bootstrap_processor_is_online() {
+    assert!(ONLINE.load(Ordering::SeqCst) >= 1);
+}